@@ -0,0 +1,10 @@
+-- pragmas of --bulk-load, on top of those of setup.sql (which aren't applied when a build is
+-- continued). they only last as long as the connection, except for the journal mode, which the
+-- writer switches back to a rollback journal at the end
+PRAGMA synchronous = OFF;
+PRAGMA journal_mode = WAL;
+-- keeps the WAL index in private memory; nothing else can read the database during the build
+PRAGMA locking_mode = EXCLUSIVE;
+-- about 1 GB of page cache
+PRAGMA cache_size = -1000000;
+PRAGMA temp_store = MEMORY;
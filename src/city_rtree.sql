@@ -0,0 +1,26 @@
+-- R*Tree index of the coordinates of the cities, for bounding-box and nearest-neighbor queries.
+-- R*Tree IDs are integers, so its IDs are the numbers of the IDs of the cities (64 for Q64).
+-- It's filled when it's created at the end of a build, and the triggers keep it up to date when
+-- the output is updated or post-processed later.
+create virtual table if not exists cities_rtree using rtree(id, min_lat, max_lat, min_lon, max_lon);
+
+create trigger if not exists cities_rtree_insert after insert on cities
+    when new.lat is not null and new.lon is not null
+begin
+    insert or replace into cities_rtree values (
+        cast(substr(new.id, 2) as integer), new.lat, new.lat, new.lon, new.lon
+    );
+end;
+
+create trigger if not exists cities_rtree_update after update of id, lat, lon on cities
+begin
+    delete from cities_rtree where id = cast(substr(old.id, 2) as integer);
+    insert into cities_rtree
+    select cast(substr(new.id, 2) as integer), new.lat, new.lat, new.lon, new.lon
+    where new.lat is not null and new.lon is not null;
+end;
+
+create trigger if not exists cities_rtree_delete after delete on cities
+begin
+    delete from cities_rtree where id = cast(substr(old.id, 2) as integer);
+end;
@@ -0,0 +1,48 @@
+//! Config files for reproducible runs.
+//!
+//! A config file is a TOML table with the same keys as the long command line flags, e.g.
+//!
+//! ```toml
+//! output = "geo.db"
+//! entity-types = ["country", "territorial-entity", "settlement"]
+//! statement-coords = true
+//! label-languages = ["en", "eo"]
+//!
+//! # only available in config files
+//! [class-roots]
+//! settlement = ["Q486972"]
+//! ```
+//!
+//! Flags passed on the command line take precedence over the file.
+
+use crate::options::Options;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::{fs, io};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    #[error("TOML error: {0}")]
+    Toml(#[from] toml::de::Error),
+}
+
+#[derive(Deserialize)]
+struct ConfigFile {
+    #[serde(flatten)]
+    options: Options,
+    /// Keys that aren't options; collected so they can be reported instead of ignored.
+    #[serde(flatten)]
+    unknown: BTreeMap<String, toml::Value>,
+}
+
+/// Loads options from a config file. Options missing from the file have their default values.
+pub fn load(path: &str) -> Result<Options, ConfigError> {
+    let config: ConfigFile = toml::from_str(&fs::read_to_string(path)?)?;
+    for key in config.unknown.keys() {
+        warn!("unknown key {:?} in config file {}", key, path);
+    }
+    Ok(config.options)
+}
@@ -1,160 +1,1213 @@
+use crate::export::column_names;
+use crate::intern::Symbol;
+use crate::migrate;
 use crossbeam::channel::Receiver;
-use rusqlite::{params, Connection, Transaction};
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, ToSql, Transaction};
+use serde::{Deserialize, Serialize};
 use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub enum DataEntry {
     TerritorialEntity {
-        id: String,
+        id: Symbol,
         is_2nd: bool,
         iso: Option<String>,
+        /// Representative point (P625), usually the centroid.
+        lat: Option<f64>,
+        lon: Option<f64>,
+        /// Precision of the coordinates in degrees.
+        coord_precision: Option<f64>,
+        /// Wikidata revision (`lastrevid`) the entry was extracted from.
+        revision: Option<u64>,
     },
     TerritorialEntityParent {
-        id: String,
-        parent: String,
+        id: Symbol,
+        parent: Symbol,
     },
     ObjectLanguage {
-        id: String,
-        lang_id: String,
+        id: Symbol,
+        lang_id: Symbol,
         index: u32,
+        kind: LanguageKind,
     },
     Language {
-        id: String,
+        id: Symbol,
         code: String,
+        revision: Option<u64>,
     },
     City {
-        id: String,
+        id: Symbol,
         population: Option<u64>,
+        /// Raw Wikidata time of the P585 (point in time) qualifier of the population.
+        population_time: Option<String>,
+        /// Wikidata time precision of `population_time` (9: year, 10: month, 11: day).
+        population_precision: Option<u8>,
         lat: Option<f64>,
         lon: Option<f64>,
+        revision: Option<u64>,
     },
     CityCountry {
-        id: String,
-        country: String,
+        id: Symbol,
+        country: Symbol,
         priority: u32,
     },
     ObjectLabel {
-        id: String,
-        lang: String,
+        id: Symbol,
+        lang: Symbol,
         label: String,
         native_order: Option<u64>,
+        source: LabelSource,
     },
     Country {
-        id: String,
-        iso: String,
+        id: Symbol,
+        /// P297 ISO code, missing for some sovereign states.
+        iso: Option<String>,
+        /// Whether the entity has multiple differing active ISO codes.
+        conflicting: bool,
+        /// Whether the entity is a sovereign state rather than a dependency.
+        sovereign: bool,
+        revision: Option<u64>,
     },
     MissingP17 {
-        id: String,
+        id: Symbol,
+    },
+    SisterCity {
+        id: Symbol,
+        other_id: Symbol,
+    },
+    AdminLevel {
+        id: Symbol,
+        level: u8,
+    },
+    StatementCoord {
+        id: Symbol,
+        property: Symbol,
+        lat: f64,
+        lon: f64,
+    },
+    Replaces {
+        id: Symbol,
+        predecessor_id: Symbol,
+    },
+    PartOf {
+        id: Symbol,
+        parent_id: Symbol,
+    },
+    Alias {
+        id: Symbol,
+        lang: Symbol,
+        alias: String,
+    },
+    /// A problem with a property of an entity, e.g. a claim that had to be skipped.
+    QualityEvent {
+        id: Symbol,
+        property: Symbol,
+        reason: String,
+    },
+    BordersWith {
+        id: Symbol,
+        neighbor_id: Symbol,
+    },
+    /// Removes all stored entries of the entity. Sent before the entries of an entity when
+    /// updating an existing database.
+    RemoveEntity {
+        id: Symbol,
+    },
+    /// Marks that the entries after it, up to the next one, come from this input line. Lines
+    /// are handled in parallel, so entries of a line can be sent before the checkpoint of an
+    /// earlier line.
+    Line {
+        line_number: u64,
+    },
+    /// Marks that all entries of input lines up to and including this one have been sent.
+    Checkpoint {
+        line_number: u64,
+        /// Offset in the decompressed dump after the line, if the input is a dump.
+        offset: Option<u64>,
     },
+    /// Information about the run, e.g. the dump date.
+    Metadata {
+        key: String,
+        value: String,
+    },
+}
+
+/// Where an object language comes from. Official languages take precedence over the same
+/// languages used, as an object has one row per language.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum LanguageKind {
+    /// P37: official language
+    Official,
+    /// P2936: language used
+    Used,
+}
+
+impl LanguageKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LanguageKind::Official => "official",
+            LanguageKind::Used => "used",
+        }
+    }
 }
 
-pub fn db_writer(out_file: &str, recv: Receiver<DataEntry>) -> rusqlite::Result<()> {
-    debug!("Setting up database");
-    let mut conn = Connection::open(out_file)?;
+/// Where an object label comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum LabelSource {
+    /// The entity's labels
+    Label,
+    /// P1705: native label, or P1448: official name
+    NativeName,
+    /// P1813: short name, for languages without a label
+    ShortNameFallback,
+}
 
-    conn.execute_batch(include_str!("setup.sql"))?;
+impl LabelSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LabelSource::Label => "label",
+            LabelSource::NativeName => "native_name",
+            LabelSource::ShortNameFallback => "short_name_fallback",
+        }
+    }
+}
 
-    debug!("Database set up");
+impl DataEntry {
+    /// The ID of the entity this entry belongs to. Line markers, checkpoints and metadata don't
+    /// belong to any entity.
+    pub fn id(&self) -> Option<Symbol> {
+        Some(match self {
+            DataEntry::TerritorialEntity { id, .. }
+            | DataEntry::TerritorialEntityParent { id, .. }
+            | DataEntry::ObjectLanguage { id, .. }
+            | DataEntry::Language { id, .. }
+            | DataEntry::City { id, .. }
+            | DataEntry::CityCountry { id, .. }
+            | DataEntry::ObjectLabel { id, .. }
+            | DataEntry::Country { id, .. }
+            | DataEntry::MissingP17 { id }
+            | DataEntry::SisterCity { id, .. }
+            | DataEntry::AdminLevel { id, .. }
+            | DataEntry::StatementCoord { id, .. }
+            | DataEntry::Replaces { id, .. }
+            | DataEntry::PartOf { id, .. }
+            | DataEntry::Alias { id, .. }
+            | DataEntry::QualityEvent { id, .. }
+            | DataEntry::BordersWith { id, .. }
+            | DataEntry::RemoveEntity { id } => *id,
+            DataEntry::Line { .. } | DataEntry::Checkpoint { .. } | DataEntry::Metadata { .. } => {
+                return None
+            }
+        })
+    }
+}
 
-    let mut item_buffer = VecDeque::with_capacity(128);
+/// Output backends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    Sqlite,
+    Sled,
+    Ndjson,
+    /// Only available with `--features postgres`.
+    Postgres,
+}
+
+impl Backend {
+    pub const NAMES: [&'static str; 4] = ["sqlite", "sled", "ndjson", "postgres"];
+
+    /// The backend of a database URL for `--db`.
+    pub fn for_url(url: &str) -> Option<Self> {
+        if url.starts_with("postgres://") || url.starts_with("postgresql://") {
+            Some(Backend::Postgres)
+        } else {
+            None
+        }
+    }
+}
+
+impl std::str::FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sqlite" => Ok(Backend::Sqlite),
+            "sled" => Ok(Backend::Sled),
+            "ndjson" => Ok(Backend::Ndjson),
+            "postgres" => Ok(Backend::Postgres),
+            s => Err(format!("unknown backend {:?}", s)),
+        }
+    }
+}
+
+/// A target that extracted entries are written to.
+///
+/// [`db_writer`] drives any implementation from the entry channel, so new outputs only need to
+/// implement this trait and be added to [`Backend`]. There's no library target, so they have to
+/// be part of geo-db. See [`crate::output::NdjsonSink`] for a minimal example.
+pub trait DataSink {
+    type Error: std::fmt::Display;
+
+    /// Writes an entry. It may be buffered until the next flush.
+    fn write(&mut self, entry: DataEntry) -> Result<(), Self::Error>;
+
+    /// Durably stores all entries written so far.
+    fn flush(&mut self) -> Result<(), Self::Error>;
+
+    /// Called after a checkpoint was written. Returns whether the entries written so far are now
+    /// durably stored; sinks that commit in large batches may leave that to a later checkpoint.
+    fn checkpoint(&mut self) -> Result<bool, Self::Error> {
+        self.flush()?;
+        Ok(true)
+    }
+
+    /// Flushes and closes the sink once all entries have been written.
+    fn finish(mut self) -> Result<(), Self::Error>
+    where
+        Self: Sized,
+    {
+        self.flush()
+    }
+}
+
+/// Number of entries passed to the sink by [`db_writer`].
+pub static ENTRIES_WRITTEN: AtomicU64 = AtomicU64::new(0);
+
+/// Number of batches written by [`db_writer`], and the total time it took to write them.
+pub static BATCHES_WRITTEN: AtomicU64 = AtomicU64::new(0);
+pub static BATCH_WRITE_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// Writes entries from the channel to the sink until the channel is closed.
+///
+/// Entries are sent in batches, usually all entries of an entity, so that there is one message
+/// per entity instead of one per label or parent.
+///
+/// Once the sink has stored a checkpoint and the entries before it, `committed_line` is set to the
+/// checkpoint's line number, so every entry of the lines up to it is durably stored.
+pub fn db_writer<S: DataSink>(
+    mut sink: S,
+    recv: Receiver<Vec<DataEntry>>,
+    committed_line: &AtomicU64,
+) -> Result<(), S::Error> {
+    // checkpoint that the sink hasn't committed yet
+    let mut pending_checkpoint = None;
     loop {
-        let item = match recv.recv() {
-            Ok(item) => item,
+        let batch = match recv.recv() {
+            Ok(batch) => batch,
             Err(e) => {
                 debug!("closing DB writer because channel was disconnected: {}", e);
                 break;
             }
         };
 
-        item_buffer.push_back(item);
+        let started = Instant::now();
+        for item in batch {
+            let checkpoint = match item {
+                DataEntry::Checkpoint { line_number, .. } => Some(line_number),
+                _ => None,
+            };
+            sink.write(item)?;
+            ENTRIES_WRITTEN.fetch_add(1, Ordering::Relaxed);
 
-        if item_buffer.len() >= 127 {
-            let tx = conn.transaction()?;
-            for item in item_buffer.drain(..) {
-                insert_entry(&tx, item)?;
+            if let Some(line_number) = checkpoint {
+                if sink.checkpoint()? {
+                    committed_line.store(line_number, Ordering::SeqCst);
+                    pending_checkpoint = None;
+                } else {
+                    pending_checkpoint = Some(line_number);
+                }
             }
-            tx.commit()?;
         }
+        BATCHES_WRITTEN.fetch_add(1, Ordering::Relaxed);
+        BATCH_WRITE_NANOS.fetch_add(started.elapsed().as_nanos() as u64, Ordering::Relaxed);
     }
 
-    if !item_buffer.is_empty() {
-        let tx = conn.transaction()?;
-        for item in item_buffer.drain(..) {
+    sink.finish()?;
+    if let Some(line_number) = pending_checkpoint {
+        committed_line.store(line_number, Ordering::SeqCst);
+    }
+    Ok(())
+}
+
+/// Entries per transaction of the SQLite writer.
+pub const DEFAULT_ROWS_PER_COMMIT: usize = 127;
+/// Entries per transaction of the SQLite writer with `--bulk-load`.
+pub const BULK_LOAD_ROWS_PER_COMMIT: usize = 100_000;
+
+/// How the SQLite writer trades safety for speed.
+#[derive(Debug, Clone, Copy)]
+pub struct SqliteSettings {
+    /// Whether the pragmas of bulk_load.sql are used, and transactions are only committed at
+    /// checkpoints.
+    pub bulk_load: bool,
+    /// Entries per transaction. With a bulk load, a transaction is committed at the first
+    /// checkpoint after this many entries.
+    pub rows_per_commit: usize,
+}
+
+impl Default for SqliteSettings {
+    fn default() -> Self {
+        SqliteSettings {
+            bulk_load: false,
+            rows_per_commit: DEFAULT_ROWS_PER_COMMIT,
+        }
+    }
+}
+
+/// SQLite output. Entries are written in transactions of up to 127 entries by default.
+pub struct SqliteSink {
+    conn: Connection,
+    settings: SqliteSettings,
+    item_buffer: VecDeque<DataEntry>,
+    /// Input line of the entries that are being written, if they come from one.
+    line: Option<u64>,
+}
+
+impl SqliteSink {
+    pub fn open(out_file: &str, settings: SqliteSettings) -> rusqlite::Result<Self> {
+        debug!("Setting up database");
+        let conn = Connection::open(out_file)?;
+
+        conn.execute_batch(include_str!("setup.sql"))?;
+        conn.execute_batch(&format!(
+            "PRAGMA user_version = {}",
+            crate::migrate::SCHEMA_VERSION
+        ))?;
+
+        debug!("Database set up");
+
+        Self::with_settings(conn, settings)
+    }
+
+    /// Opens a database built earlier to update it.
+    pub fn open_existing(out_file: &str, settings: SqliteSettings) -> rusqlite::Result<Self> {
+        let conn = Connection::open_with_flags(out_file, OpenFlags::SQLITE_OPEN_READ_WRITE)?;
+        Self::with_settings(conn, settings)
+    }
+
+    fn with_settings(conn: Connection, settings: SqliteSettings) -> rusqlite::Result<Self> {
+        if settings.bulk_load {
+            conn.execute_batch(include_str!("bulk_load.sql"))?;
+        }
+        // one statement per kind of entry, and one per entity table to remove entities
+        conn.set_prepared_statement_cache_capacity(64);
+
+        Ok(SqliteSink {
+            conn,
+            settings,
+            item_buffer: VecDeque::with_capacity(settings.rows_per_commit.min(4096) + 1),
+            line: None,
+        })
+    }
+}
+
+impl DataSink for SqliteSink {
+    type Error = rusqlite::Error;
+
+    fn write(&mut self, entry: DataEntry) -> rusqlite::Result<()> {
+        self.item_buffer.push_back(entry);
+
+        if !self.settings.bulk_load && self.item_buffer.len() >= self.settings.rows_per_commit {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn checkpoint(&mut self) -> rusqlite::Result<bool> {
+        if self.settings.bulk_load && self.item_buffer.len() < self.settings.rows_per_commit {
+            return Ok(false);
+        }
+        self.flush()?;
+        Ok(true)
+    }
+
+    fn flush(&mut self) -> rusqlite::Result<()> {
+        if self.item_buffer.is_empty() {
+            return Ok(());
+        }
+
+        let tx = self.conn.transaction()?;
+        // entities are journaled with their line in the same transaction as their entries, so
+        // that a build that continues from the last checkpoint can remove exactly the entries of
+        // the lines after it
+        let mut journaled = None;
+        for item in self.item_buffer.drain(..) {
+            if let DataEntry::Line { line_number } = item {
+                self.line = Some(line_number);
+                journaled = None;
+                continue;
+            }
+            match item.id() {
+                Some(id) if journaled != Some(id) => {
+                    tx.prepare_cached(
+                        "insert into build_journal (id, line_number) values (?1, ?2) on conflict (id) do update set line_number = max(line_number, excluded.line_number)",
+                    )?
+                    .execute(params![id, self.line])?;
+                    journaled = Some(id);
+                }
+                Some(_) => (),
+                // the checkpoint clears the journal up to its line
+                None => journaled = None,
+            }
             insert_entry(&tx, item)?;
         }
-        tx.commit()?;
+        tx.commit()
+    }
+
+    fn finish(mut self) -> rusqlite::Result<()> {
+        self.flush()?;
+        create_city_rtree(&self.conn)?;
+        // merges the WAL into the database file, which is then self-contained again
+        self.conn
+            .query_row("PRAGMA journal_mode = DELETE", [], |_| Ok(()))
     }
+}
 
+/// Creates the R*Tree index of the coordinates of the cities (see city_rtree.sql) and fills it,
+/// unless the output has it already.
+fn create_city_rtree(conn: &Connection) -> rusqlite::Result<()> {
+    let exists: bool = conn.query_row(
+        "select count(*) > 0 from sqlite_master where type = 'table' and name = 'cities_rtree'",
+        [],
+        |row| row.get(0),
+    )?;
+    conn.execute_batch(include_str!("city_rtree.sql"))?;
+    if !exists {
+        debug!("Indexing city coordinates");
+        conn.execute(
+            "insert into cities_rtree
+            select cast(substr(id, 2) as integer), lat, lat, lon, lon from cities
+            where lat is not null and lon is not null",
+            [],
+        )?;
+    }
     Ok(())
 }
 
+/// Reads a value from the metadata table of a SQLite output.
+pub fn read_metadata(out_file: &str, key: &str) -> rusqlite::Result<Option<String>> {
+    let conn = Connection::open_with_flags(out_file, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    conn.query_row(
+        "select value from metadata where key = ?1",
+        params![key],
+        |row| row.get(0),
+    )
+    .optional()
+}
+
+/// Whether the post-processing of a SQLite output was cleaned up. The cleanup drops most tables
+/// of the build and replaces the countries of the cities with their ISO codes, so these outputs
+/// can't be updated.
+pub fn is_cleaned_up(out_file: &str) -> rusqlite::Result<bool> {
+    let conn = Connection::open_with_flags(out_file, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    conn.query_row(
+        "select count(*) > 0 from sqlite_master where type = 'table' and name = 'cities_labels'",
+        [],
+        |row| row.get(0),
+    )
+}
+
+/// Reads the last checkpoint of a SQLite output: the line number and the offset in the
+/// decompressed dump after it.
+pub fn read_build_state(out_file: &str) -> rusqlite::Result<Option<(u64, Option<u64>)>> {
+    let conn = Connection::open_with_flags(out_file, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    conn.query_row(
+        "select line_number, byte_offset from build_state where id = 0",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )
+    .optional()
+}
+
+/// Tables with entries of entities, and their entity ID column.
+pub const ENTITY_TABLES: [(&str, &str); 17] = [
+    ("countries", "id"),
+    ("object_languages", "id"),
+    ("languages", "id"),
+    ("territorial_entities", "id"),
+    ("territorial_entities_parents", "id"),
+    ("admin_levels", "id"),
+    ("cities", "id"),
+    ("cities_countries", "city"),
+    ("object_labels", "id"),
+    ("missing_p17", "id"),
+    ("sister_cities", "id"),
+    ("statement_coords", "id"),
+    ("replaces", "id"),
+    ("part_of", "id"),
+    ("aliases", "id"),
+    ("data_quality", "id"),
+    ("borders_with", "id"),
+];
+
+fn remove_entity(tx: &Transaction, id: &dyn ToSql) -> rusqlite::Result<()> {
+    for (table, column) in &ENTITY_TABLES {
+        tx.prepare_cached(&format!("delete from {} where {} = ?1", table, column))?
+            .execute(params![id])?;
+    }
+    Ok(())
+}
+
+/// Removes the entries of the entities that were committed after the last checkpoint of a SQLite
+/// output, so that their lines can be handled again when the build continues. Returns the number
+/// of entities, or None if the output has no build journal because it was built before there was
+/// one.
+pub fn roll_back_to_checkpoint(out_file: &str) -> rusqlite::Result<Option<usize>> {
+    let mut conn = Connection::open_with_flags(out_file, OpenFlags::SQLITE_OPEN_READ_WRITE)?;
+    let has_journal: bool = conn.query_row(
+        "select count(*) > 0 from sqlite_master where type = 'table' and name = 'build_journal'",
+        [],
+        |row| row.get(0),
+    )?;
+    if !has_journal {
+        return Ok(None);
+    }
+
+    let tx = conn.transaction()?;
+    let ids = tx
+        .prepare("select id from build_journal")?
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    for id in &ids {
+        remove_entity(&tx, id)?;
+    }
+    tx.execute("delete from build_journal", [])?;
+    tx.commit()?;
+    Ok(Some(ids.len()))
+}
+
 fn insert_entry(tx: &Transaction, entry: DataEntry) -> rusqlite::Result<()> {
     match entry {
-        DataEntry::TerritorialEntity { id, is_2nd, iso } => {
-            tx.execute(
-                "insert into territorial_entities (id, is_2nd, iso) values (?1, ?2, ?3)",
-                params![id, is_2nd, iso],
-            )?;
+        DataEntry::TerritorialEntity {
+            id,
+            is_2nd,
+            iso,
+            lat,
+            lon,
+            coord_precision,
+            revision,
+        } => {
+            tx.prepare_cached(
+                "insert into territorial_entities (id, is_2nd, iso, lat, lon, coord_precision, revision) values (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            )?
+            .execute(params![id, is_2nd, iso, lat, lon, coord_precision, revision])?;
         }
         DataEntry::TerritorialEntityParent { id, parent } => {
-            tx.execute(
+            tx.prepare_cached(
                 "insert into territorial_entities_parents (id, parent) values (?1, ?2) on conflict (id, parent) do nothing",
-                params![id, parent],
-            )?;
+            )?
+            .execute(params![id, parent])?;
         }
-        DataEntry::ObjectLanguage { id, lang_id, index } => {
-            tx.execute(
-                "insert into object_languages (id, lang_id, lang_index) values (?1, ?2, ?3) on conflict (id, lang_id) do nothing",
-                params![id, lang_id, index],
-            )?;
+        DataEntry::ObjectLanguage {
+            id,
+            lang_id,
+            index,
+            kind,
+        } => {
+            tx.prepare_cached(
+                "insert into object_languages (id, lang_id, lang_index, kind) values (?1, ?2, ?3, ?4) on conflict (id, lang_id) do nothing",
+            )?
+            .execute(params![id, lang_id, index, kind.as_str()])?;
         }
-        DataEntry::Language { id, code } => {
-            tx.execute(
-                "insert into languages (id, code) values (?1, ?2)",
-                params![id, code],
-            )?;
+        DataEntry::Language { id, code, revision } => {
+            tx.prepare_cached("insert into languages (id, code, revision) values (?1, ?2, ?3)")?
+                .execute(params![id, code, revision])?;
         }
         DataEntry::City {
             id,
             population,
+            population_time,
+            population_precision,
             lat,
             lon,
+            revision,
         } => {
-            tx.execute(
-                "insert into cities (id, population, lat, lon) values (?1, ?2, ?3, ?4)",
-                params![id, population, lat, lon],
-            )?;
+            tx.prepare_cached(
+                "insert into cities (id, population, population_time, population_precision, lat, lon, revision) values (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            )?
+            .execute(params![id, population, population_time, population_precision, lat, lon, revision])?;
         }
         DataEntry::CityCountry {
             id,
             country,
             priority,
         } => {
-            tx.execute(
+            tx.prepare_cached(
                 "insert or ignore into cities_countries (city, country, priority) values (?1, ?2, ?3)",
-                params![id, country, priority],
-            )?;
+            )?
+            .execute(params![id, country, priority])?;
         }
         DataEntry::ObjectLabel {
             id,
             lang,
             label,
             native_order,
+            source,
+        } => {
+            tx.prepare_cached(
+                "insert into object_labels (id, lang, label, native_order, source) values (?1, ?2, ?3, ?4, ?5)",
+            )?
+            .execute(params![id, lang, label, native_order, source.as_str()])?;
+        }
+        DataEntry::Country {
+            id,
+            iso,
+            conflicting,
+            sovereign,
+            revision,
+        } => {
+            tx.prepare_cached(
+                "insert into countries (id, iso, conflicting, sovereign, revision) values (?1, ?2, ?3, ?4, ?5)",
+            )?
+            .execute(params![id, iso, conflicting, sovereign, revision])?;
+        }
+        DataEntry::MissingP17 { id } => {
+            tx.prepare_cached("insert into missing_p17 (id) values (?1)")?
+                .execute(params![id])?;
+        }
+        DataEntry::AdminLevel { id, level } => {
+            tx.prepare_cached("insert into admin_levels (id, level) values (?1, ?2)")?
+                .execute(params![id, level])?;
+        }
+        DataEntry::StatementCoord {
+            id,
+            property,
+            lat,
+            lon,
         } => {
+            tx.prepare_cached(
+                "insert into statement_coords (id, property, lat, lon) values (?1, ?2, ?3, ?4)",
+            )?
+            .execute(params![id, property, lat, lon])?;
+        }
+        DataEntry::Replaces { id, predecessor_id } => {
+            tx.prepare_cached(
+                "insert into replaces (id, predecessor_id) values (?1, ?2) on conflict (id, predecessor_id) do nothing",
+            )?
+            .execute(params![id, predecessor_id])?;
+        }
+        DataEntry::PartOf { id, parent_id } => {
+            tx.prepare_cached(
+                "insert into part_of (id, parent_id) values (?1, ?2) on conflict (id, parent_id) do nothing",
+            )?
+            .execute(params![id, parent_id])?;
+        }
+        DataEntry::Alias { id, lang, alias } => {
+            tx.prepare_cached(
+                "insert into aliases (id, lang, alias) values (?1, ?2, ?3) on conflict (id, lang, alias) do nothing",
+            )?
+            .execute(params![id, lang, alias])?;
+        }
+        DataEntry::QualityEvent {
+            id,
+            property,
+            reason,
+        } => {
+            tx.prepare_cached(
+                "insert into data_quality (id, property, reason) values (?1, ?2, ?3)",
+            )?
+            .execute(params![id, property, reason])?;
+        }
+        DataEntry::BordersWith { id, neighbor_id } => {
+            tx.prepare_cached(
+                "insert into borders_with (id, neighbor_id) values (?1, ?2) on conflict (id, neighbor_id) do nothing",
+            )?
+            .execute(params![id, neighbor_id])?;
+        }
+        DataEntry::RemoveEntity { id } => remove_entity(tx, &id)?,
+        DataEntry::Checkpoint {
+            line_number,
+            offset,
+        } => {
+            tx.prepare_cached(
+                "insert into build_state (id, line_number, byte_offset) values (0, ?1, ?2) on conflict (id) do update set line_number = excluded.line_number, byte_offset = excluded.byte_offset",
+            )?
+            .execute(params![line_number, offset])?;
+            // entities of later lines may have been written before the checkpoint
+            tx.prepare_cached(
+                "delete from build_journal where line_number is null or line_number <= ?1",
+            )?
+            .execute(params![line_number])?;
+        }
+        // journaled by the sink
+        DataEntry::Line { .. } => (),
+        DataEntry::Metadata { key, value } => {
+            tx.prepare_cached(
+                "insert into metadata (key, value) values (?1, ?2) on conflict (key) do update set value = excluded.value",
+            )?
+            .execute(params![key, value])?;
+        }
+        DataEntry::SisterCity { id, other_id } => {
+            tx.prepare_cached(
+                "insert into sister_cities (id, other_id) values (?1, ?2) on conflict (id, other_id) do nothing",
+            )?
+            .execute(params![id, other_id])?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Default)]
+pub struct MergeStats {
+    pub entities: u64,
+    /// Entities that were in more than one input.
+    pub conflicts: u64,
+}
+
+/// Combines SQLite outputs, e.g. ones built with different filters, into a new database.
+///
+/// All rows of an entity are taken from a single input, so entries of different inputs are never
+/// mixed and labels aren't duplicated. If an entity is in several inputs, the one with its latest
+/// revision wins, or the first of them if that doesn't tell them apart. Metadata keys are also
+/// taken from the first input that has them. Post-processing should be run on the result.
+pub fn merge(out_file: &str, inputs: &[&str]) -> rusqlite::Result<MergeStats> {
+    let mut conn = SqliteSink::open(out_file, SqliteSettings::default())?.conn;
+    conn.execute_batch(
+        "create temp table candidates (id text not null, source integer not null, revision integer);
+        create temp table owners (id text not null primary key, source integer not null);",
+    )?;
+
+    for (source, input) in inputs.iter().enumerate() {
+        info!("Reading entities of {}", input);
+        let tables = attach_input(&conn, input)?;
+        let mut ids = Vec::new();
+        for (table, column, columns) in &tables {
+            let revision = if columns.iter().any(|(column, _)| column == "revision") {
+                "revision"
+            } else {
+                "null"
+            };
+            ids.push(format!(
+                "select {} as id, {} as revision from input.{}",
+                column, revision, table
+            ));
+        }
+        if !ids.is_empty() {
+            conn.execute(
+                &format!(
+                    "insert into candidates select id, ?1, max(revision) from ({}) group by id",
+                    ids.join(" union all ")
+                ),
+                params![source as i64],
+            )?;
+        }
+        conn.execute("detach input", [])?;
+    }
+
+    // unknown revisions sort last
+    conn.execute_batch(
+        "insert into owners
+        select id, source from (
+            select id, source, row_number() over (
+                partition by id order by coalesce(revision, -1) desc, source
+            ) as rank
+            from candidates
+        ) where rank = 1;
+        create index candidates_id_index on candidates (id);",
+    )?;
+    let stats = conn.query_row(
+        "select count(*), count(*) filter (where inputs > 1)
+        from (select count(*) as inputs from candidates group by id)",
+        [],
+        |row| {
+            Ok(MergeStats {
+                entities: row.get(0)?,
+                conflicts: row.get(1)?,
+            })
+        },
+    )?;
+
+    for (source, input) in inputs.iter().enumerate() {
+        info!("Copying entities of {}", input);
+        let tables = attach_input(&conn, input)?;
+        let tx = conn.transaction()?;
+        for (table, column, columns) in &tables {
+            let names: Vec<_> = columns.iter().map(|(name, _)| name.as_str()).collect();
+            let values: Vec<_> = columns.iter().map(|(_, value)| value.as_str()).collect();
             tx.execute(
-                "insert into object_labels (id, lang, label, native_order) values (?1, ?2, ?3, ?4)",
-                params![id, lang, label, native_order],
+                &format!(
+                    "insert into {0} ({1}) select {2} from input.{0} as row
+                    where exists (select 1 from owners where id = row.{3} and source = ?1)",
+                    table,
+                    names.join(", "),
+                    values.join(", "),
+                    column
+                ),
+                params![source as i64],
             )?;
         }
-        DataEntry::Country { id, iso } => {
+        let has_metadata: bool = tx.query_row(
+            "select count(*) > 0 from input.sqlite_master where type = 'table' and name = 'metadata'",
+            [],
+            |row| row.get(0),
+        )?;
+        if has_metadata {
             tx.execute(
-                "insert into countries (id, iso) values (?1, ?2)",
-                params![id, iso],
+                "insert or ignore into metadata select key, value from input.metadata",
+                [],
             )?;
         }
-        DataEntry::MissingP17 { id } => {
-            tx.execute("insert into missing_p17 (id) values (?1)", params![id])?;
+        tx.commit()?;
+        conn.execute("detach input", [])?;
+    }
+    conn.execute_batch("drop table candidates; drop table owners;")?;
+    create_city_rtree(&conn)?;
+    // setup.sql switched the output to a WAL journal
+    conn.query_row("PRAGMA journal_mode = DELETE", [], |_| Ok(()))?;
+    Ok(stats)
+}
+
+/// An entity table of an input of [`merge`]: its name, its ID column, and the columns to copy with
+/// their values.
+type InputTable = (&'static str, &'static str, Vec<(String, String)>);
+
+/// Attaches an input of [`merge`] as `input` and returns the entity tables it has, with their ID
+/// column and the columns of the output that can be copied, with the values to copy into them.
+/// Inputs of older versions may lack some columns, which are left out, or filled in if they can't
+/// be null.
+fn attach_input(conn: &Connection, input: &str) -> rusqlite::Result<Vec<InputTable>> {
+    conn.execute("attach database ?1 as input", params![input])?;
+    let input_tables: Vec<String> = {
+        let mut stmt = conn
+            .prepare("select name from input.sqlite_master where type = 'table' order by name")?;
+        let names = stmt.query_map([], |row| row.get(0))?;
+        names.collect::<rusqlite::Result<_>>()?
+    };
+
+    let mut tables = Vec::new();
+    for (table, column) in &ENTITY_TABLES {
+        if !input_tables.iter().any(|name| name == table) {
+            continue;
+        }
+        let input_columns = {
+            let mut stmt = conn.prepare(&format!("pragma input.table_info({})", table))?;
+            let names = stmt.query_map([], |row| row.get::<_, String>(1))?;
+            names.collect::<rusqlite::Result<Vec<_>>>()?
+        };
+        let columns = column_names(conn, table)?
+            .into_iter()
+            .filter_map(|column| {
+                if input_columns.contains(&column) {
+                    return Some((column.clone(), column));
+                }
+                // columns that can't be null are filled in like when migrating
+                migrate::FILLED_COLUMNS
+                    .iter()
+                    .find(|(t, c, _)| t == table && *c == column)
+                    .map(|(_, _, value)| (column, value.to_string()))
+            })
+            .collect();
+        tables.push((*table, *column, columns));
+    }
+    Ok(tables)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alias(id: &str) -> DataEntry {
+        DataEntry::Alias {
+            id: id.into(),
+            lang: "en".into(),
+            alias: id.into(),
         }
     }
-    Ok(())
+
+    fn aliases(out_file: &str) -> Vec<String> {
+        let conn = Connection::open(out_file).unwrap();
+        let mut stmt = conn.prepare("select id from aliases order by id").unwrap();
+        let ids = stmt.query_map([], |row| row.get(0)).unwrap();
+        ids.collect::<rusqlite::Result<_>>().unwrap()
+    }
+
+    #[test]
+    fn remove_entities() {
+        let dir = tempfile::tempdir().unwrap();
+        let out_file = dir.path().join("geo.db");
+        let out_file = out_file.to_str().unwrap();
+
+        let city = |id: &str| DataEntry::City {
+            id: id.into(),
+            population: Some(1000),
+            population_time: None,
+            population_precision: None,
+            lat: None,
+            lon: None,
+            revision: None,
+        };
+        let mut sink = SqliteSink::open(out_file, SqliteSettings::default()).unwrap();
+        for entry in [city("Q64"), alias("Q64"), city("Q90"), alias("Q90")] {
+            sink.write(entry).unwrap();
+        }
+        sink.finish().unwrap();
+
+        // as in an update, where an entity is removed before it's extracted again
+        let mut sink = SqliteSink::open_existing(out_file, SqliteSettings::default()).unwrap();
+        for entry in [
+            DataEntry::RemoveEntity { id: "Q64".into() },
+            DataEntry::RemoveEntity { id: "Q90".into() },
+            city("Q90"),
+        ] {
+            sink.write(entry).unwrap();
+        }
+        sink.finish().unwrap();
+
+        let conn = Connection::open(out_file).unwrap();
+        let cities: Vec<String> = conn
+            .prepare("select id from cities order by id")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(cities, ["Q90"]);
+        assert!(aliases(out_file).is_empty());
+    }
+
+    #[test]
+    fn cleaned_up_outputs() {
+        let dir = tempfile::tempdir().unwrap();
+        for (fixture, cleaned_up) in [
+            (include_str!("../tests/fixtures/output_v0.sql"), false),
+            (
+                include_str!("../tests/fixtures/cleaned_output_v0.sql"),
+                true,
+            ),
+        ] {
+            let out_file = dir.path().join(format!("{}.db", cleaned_up));
+            let out_file = out_file.to_str().unwrap();
+            Connection::open(out_file)
+                .unwrap()
+                .execute_batch(fixture)
+                .unwrap();
+            assert_eq!(is_cleaned_up(out_file).unwrap(), cleaned_up);
+        }
+    }
+
+    #[test]
+    fn official_languages_take_precedence() {
+        let dir = tempfile::tempdir().unwrap();
+        let out_file = dir.path().join("geo.db");
+        let out_file = out_file.to_str().unwrap();
+
+        let language = |id: &str, lang_id: &str, index, kind| DataEntry::ObjectLanguage {
+            id: id.into(),
+            lang_id: lang_id.into(),
+            index,
+            kind,
+        };
+        let mut sink = SqliteSink::open(out_file, SqliteSettings::default()).unwrap();
+        // in the order of handle_line: official languages first
+        for entry in [
+            language("Q1", "Q188", 0, LanguageKind::Official),
+            language("Q1", "Q150", 0, LanguageKind::Used),
+            language("Q1", "Q188", 1, LanguageKind::Used),
+            language("Q2", "Q150", 0, LanguageKind::Used),
+        ] {
+            sink.write(entry).unwrap();
+        }
+        sink.finish().unwrap();
+
+        let conn = Connection::open(out_file).unwrap();
+        let languages = |conn: &Connection| -> Vec<(String, String, u32, String)> {
+            let mut stmt = conn
+                .prepare("select id, lang_id, lang_index, kind from object_languages order by id, lang_id")
+                .unwrap();
+            let rows = stmt
+                .query_map([], |row| {
+                    Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+                })
+                .unwrap();
+            rows.collect::<rusqlite::Result<_>>().unwrap()
+        };
+        let row = |id: &str, lang_id: &str, index, kind: &str| {
+            (id.to_string(), lang_id.to_string(), index, kind.to_string())
+        };
+        assert_eq!(
+            languages(&conn),
+            [
+                row("Q1", "Q150", 0, "used"),
+                row("Q1", "Q188", 0, "official"),
+                row("Q2", "Q150", 0, "used"),
+            ]
+        );
+        conn.execute_batch(include_str!("post/object_languages.sql"))
+            .unwrap();
+        assert_eq!(languages(&conn).len(), 3);
+        let preferred: Vec<(String, String)> = conn
+            .prepare("select id, lang_id from preferred_object_languages order by id, lang_id")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(
+            preferred,
+            [("Q1".into(), "Q188".into()), ("Q2".into(), "Q150".into())]
+        );
+    }
+
+    fn city(id: &str, population: u64, revision: Option<u64>) -> DataEntry {
+        DataEntry::City {
+            id: id.into(),
+            population: Some(population),
+            population_time: None,
+            population_precision: None,
+            lat: None,
+            lon: None,
+            revision,
+        }
+    }
+
+    fn build(out_file: &str, entries: Vec<DataEntry>) {
+        let mut sink = SqliteSink::open(out_file, SqliteSettings::default()).unwrap();
+        for entry in entries {
+            sink.write(entry).unwrap();
+        }
+        sink.finish().unwrap();
+    }
+
+    #[test]
+    fn merge_outputs() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        let label = |id: &str, alias: &str| DataEntry::Alias {
+            id: id.into(),
+            lang: "en".into(),
+            alias: alias.into(),
+        };
+        let metadata = |value: &str| DataEntry::Metadata {
+            key: "dump_date".into(),
+            value: value.into(),
+        };
+        build(
+            &path("a.db"),
+            vec![
+                city("Q64", 1000, Some(10)),
+                label("Q64", "a"),
+                city("Q90", 1000, Some(5)),
+                // unknown revisions sort last
+                city("Q1", 1000, None),
+                label("Q2", "a"),
+                metadata("20240101"),
+            ],
+        );
+        build(
+            &path("b.db"),
+            vec![
+                city("Q64", 2000, Some(20)),
+                label("Q64", "b"),
+                city("Q1", 2000, Some(1)),
+                city("Q3", 2000, None),
+                label("Q2", "b"),
+                metadata("20240201"),
+            ],
+        );
+
+        let stats = merge(&path("merged.db"), &[&path("a.db"), &path("b.db")]).unwrap();
+        assert_eq!((stats.entities, stats.conflicts), (5, 3));
+
+        let conn = Connection::open(path("merged.db")).unwrap();
+        let cities: Vec<(String, u64)> = conn
+            .prepare("select id, population from cities order by id")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        let expected = [("Q1", 2000), ("Q3", 2000), ("Q64", 2000), ("Q90", 1000)];
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|&(id, population)| (id.to_string(), population))
+            .collect();
+        assert_eq!(cities, expected);
+        // the rows of an entity all come from the input that won, or the first one on a tie
+        let aliases: Vec<(String, String)> = conn
+            .prepare("select id, alias from aliases order by id")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(
+            aliases,
+            [("Q2".into(), "a".into()), ("Q64".into(), "b".into())]
+        );
+        let dump_date: String = conn
+            .query_row(
+                "select value from metadata where key = 'dump_date'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(dump_date, "20240101");
+    }
+
+    #[test]
+    fn merge_outputs_of_older_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let old = dir.path().join("old.db");
+        let old = old.to_str().unwrap();
+        Connection::open(old)
+            .unwrap()
+            .execute_batch(include_str!("../tests/fixtures/output_v0.sql"))
+            .unwrap();
+        let new = dir.path().join("new.db");
+        let new = new.to_str().unwrap();
+        build(new, vec![city("Q64", 2000, Some(1))]);
+
+        let merged = dir.path().join("merged.db");
+        let merged = merged.to_str().unwrap();
+        let stats = merge(merged, &[old, new]).unwrap();
+        assert_eq!(stats.conflicts, 1);
+        let conn = Connection::open(merged).unwrap();
+        let population: u64 = conn
+            .query_row(
+                "select population from cities where id = 'Q64'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(population, 2000);
+        let countries: u64 = conn
+            .query_row("select count(*) from countries", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(countries, 1);
+    }
+
+    #[test]
+    fn roll_back_lines_after_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let out_file = dir.path().join("geo.db");
+        let out_file = out_file.to_str().unwrap();
+
+        let mut sink = SqliteSink::open(out_file, SqliteSettings::default()).unwrap();
+        // line 3 finished before line 2, so its entries were written before the checkpoint
+        for entry in vec![
+            DataEntry::Line { line_number: 1 },
+            alias("Q1"),
+            DataEntry::Line { line_number: 3 },
+            alias("Q3"),
+            DataEntry::Line { line_number: 2 },
+            alias("Q2"),
+            DataEntry::Checkpoint {
+                line_number: 2,
+                offset: Some(200),
+            },
+            DataEntry::Line { line_number: 4 },
+            alias("Q4"),
+        ] {
+            sink.write(entry).unwrap();
+        }
+        sink.flush().unwrap();
+        // the build is interrupted here
+        drop(sink);
+
+        assert_eq!(read_build_state(out_file).unwrap(), Some((2, Some(200))));
+        assert_eq!(roll_back_to_checkpoint(out_file).unwrap(), Some(2));
+        assert_eq!(aliases(out_file), vec!["Q1", "Q2"]);
+    }
 }
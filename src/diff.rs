@@ -0,0 +1,547 @@
+//! Compares two NDJSON exports (see [`NdjsonSink`](crate::output::NdjsonSink)) entry by entry,
+//! or two SQLite databases row by row.
+//!
+//! Entries are grouped by entity ID and variant, so e.g. a changed population shows up as one
+//! `changed` record for the `City` entry. Rows of databases are grouped by entity ID and table
+//! instead. Both sides are sorted on disk in chunks first, so memory use is bounded by
+//! [`CHUNK_LINES`] regardless of their size.
+
+use crate::database::ENTITY_TABLES;
+use crate::export::{column_names, row_to_json, table_names};
+use crate::output::{OutputCompression, OutputWriter};
+use rusqlite::{Connection, OpenFlags};
+use serde_json::{json, Value};
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, BinaryHeap};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Lines, Read, Write};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// Number of lines sorted in memory at once.
+const CHUNK_LINES: usize = 1_000_000;
+
+#[derive(Debug, Error)]
+pub enum DiffError {
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    #[error("{0} line {1}: invalid entry: {2}")]
+    Entry(String, usize, String),
+    #[error("database error: {0}")]
+    Db(#[from] rusqlite::Error),
+    #[error("an NDJSON export can't be compared with a database")]
+    Mismatch,
+}
+
+/// One side of a comparison.
+pub enum DiffSource {
+    /// An NDJSON export.
+    Export(String),
+    /// A SQLite database, of which the tables of entities are compared.
+    Database(String),
+}
+
+impl DiffSource {
+    /// Recognizes databases by their header and NDJSON exports by their name. Returns None for
+    /// dumps, i.e. URLs, `.torrent` files and files named `*.json` (optionally compressed).
+    pub fn detect(path: &str) -> io::Result<Option<Self>> {
+        let mut header = [0; 16];
+        if let Ok(mut file) = File::open(path) {
+            if file.read_exact(&mut header).is_ok() && &header == b"SQLite format 3\0" {
+                return Ok(Some(DiffSource::Database(path.into())));
+            }
+        }
+        let name = [".bz2", ".gz", ".zst"]
+            .iter()
+            .find_map(|suffix| path.strip_suffix(suffix))
+            .unwrap_or(path);
+        if path.contains("://") || path.ends_with(".torrent") || name.ends_with(".json") {
+            return Ok(None);
+        }
+        // reports a missing export before anything is extracted
+        File::open(path)?;
+        Ok(Some(DiffSource::Export(path.into())))
+    }
+
+    fn path(&self) -> &str {
+        match self {
+            DiffSource::Export(path) | DiffSource::Database(path) => path,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct DiffStats {
+    pub added: usize,
+    pub removed: usize,
+    pub changed: usize,
+}
+
+/// Opens an export, decompressing it if it ends in `.gz` or `.zst`.
+fn open_export(path: &str) -> io::Result<Box<dyn BufRead>> {
+    let file = File::open(path)?;
+    let read: Box<dyn Read> = if path.ends_with(".gz") {
+        Box::new(flate2::read::MultiGzDecoder::new(file))
+    } else if path.ends_with(".zst") {
+        Box::new(zstd::Decoder::new(file)?)
+    } else {
+        Box::new(file)
+    };
+    Ok(Box::new(BufReader::new(read)))
+}
+
+/// Returns the grouping key of an entry line: the entity ID and the variant name, separated by a
+/// tab. Serialized JSON never contains a raw tab, so keys sort correctly as a prefix of the line.
+fn entry_key(line: &str) -> Result<String, String> {
+    let entry: Value = serde_json::from_str(line).map_err(|e| e.to_string())?;
+    let (variant, fields) = entry
+        .as_object()
+        .and_then(|entry| entry.iter().next())
+        .ok_or("not an externally tagged entry")?;
+    let id = fields
+        .get("id")
+        .and_then(|id| id.as_str())
+        .ok_or("entry has no id")?;
+    Ok(format!("{}\t{}", id, variant))
+}
+
+/// Sorts the entries or rows of a diff source by key into chunk files in `tmp_dir`.
+fn sort_chunks(source: &DiffSource, tmp_dir: &Path, name: &str) -> Result<Vec<PathBuf>, DiffError> {
+    let mut chunks = Vec::new();
+    let mut lines = Vec::with_capacity(CHUNK_LINES);
+    let mut write_chunk = |lines: &mut Vec<String>| -> io::Result<()> {
+        lines.sort_unstable();
+        let chunk_path = tmp_dir.join(format!("{}-{}", name, chunks.len()));
+        let mut file = BufWriter::new(File::create(&chunk_path)?);
+        for line in lines.drain(..) {
+            writeln!(file, "{}", line)?;
+        }
+        file.flush()?;
+        chunks.push(chunk_path);
+        Ok(())
+    };
+    let mut push_line = |line: String| -> io::Result<()> {
+        lines.push(line);
+        if lines.len() == CHUNK_LINES {
+            write_chunk(&mut lines)?;
+        }
+        Ok(())
+    };
+
+    match source {
+        DiffSource::Export(path) => {
+            for (i, line) in open_export(path)?.lines().enumerate() {
+                let line = line?;
+                if line.is_empty() {
+                    continue;
+                }
+                let key = entry_key(&line).map_err(|e| DiffError::Entry(path.clone(), i + 1, e))?;
+                push_line(format!("{}\t{}", key, line))?;
+            }
+        }
+        DiffSource::Database(path) => {
+            let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+            let tables = table_names(&conn)?;
+            for (table, id_column) in &ENTITY_TABLES {
+                // databases of older versions may lack some tables
+                if !tables.iter().any(|name| name == table) {
+                    continue;
+                }
+                let columns = column_names(&conn, table)?;
+                let mut stmt = conn.prepare(&format!("select * from {}", table))?;
+                let mut rows = stmt.query([])?;
+                while let Some(row) = rows.next()? {
+                    let id: String = row.get(*id_column)?;
+                    let row = serde_json::to_string(&row_to_json(row, &columns)?)
+                        .map_err(io::Error::from)?;
+                    push_line(format!("{}\t{}\t{}", id, table, row))?;
+                }
+            }
+        }
+    }
+    if !lines.is_empty() {
+        write_chunk(&mut lines)?;
+    }
+    debug!("Sorted {} into {} chunks", source.path(), chunks.len());
+    Ok(chunks)
+}
+
+/// Merges sorted chunk files into one sorted stream of `(key, entry)` groups.
+struct SortedGroups {
+    chunks: Vec<Lines<BufReader<File>>>,
+    heap: BinaryHeap<Reverse<(String, usize)>>,
+}
+
+impl SortedGroups {
+    fn open(chunks: &[PathBuf]) -> io::Result<Self> {
+        let mut groups = SortedGroups {
+            chunks: Vec::new(),
+            heap: BinaryHeap::new(),
+        };
+        for path in chunks {
+            groups
+                .chunks
+                .push(BufReader::new(File::open(path)?).lines());
+            groups.advance(groups.chunks.len() - 1)?;
+        }
+        Ok(groups)
+    }
+
+    fn advance(&mut self, chunk: usize) -> io::Result<()> {
+        if let Some(line) = self.chunks[chunk].next() {
+            self.heap.push(Reverse((line?, chunk)));
+        }
+        Ok(())
+    }
+
+    fn peek_key(&self) -> Option<&str> {
+        self.heap.peek().map(|Reverse((line, _))| split_key(line).0)
+    }
+
+    /// Returns all entries with the given key, or an empty set if the next key differs.
+    fn take_group(&mut self, key: &str) -> io::Result<BTreeSet<String>> {
+        let mut entries = BTreeSet::new();
+        while self.peek_key() == Some(key) {
+            let Reverse((line, chunk)) = self.heap.pop().unwrap();
+            entries.insert(split_key(&line).1.to_string());
+            self.advance(chunk)?;
+        }
+        Ok(entries)
+    }
+}
+
+/// Splits a sorted line into its key and the entry JSON.
+fn split_key(line: &str) -> (&str, &str) {
+    let mut parts = line.splitn(3, '\t');
+    let id = parts.next().unwrap_or("");
+    let variant = parts.next().unwrap_or("");
+    let entry = parts.next().unwrap_or("");
+    (&line[..id.len() + 1 + variant.len()], entry)
+}
+
+/// Writes one change record per added or removed entry. If exactly one entry of a group was
+/// replaced by another, a single `changed` record with both is written instead.
+fn write_changes(
+    out: &mut OutputWriter,
+    key: &str,
+    old: &BTreeSet<String>,
+    new: &BTreeSet<String>,
+    stats: &mut DiffStats,
+) -> Result<(), DiffError> {
+    let removed: Vec<_> = old.difference(new).collect();
+    let added: Vec<_> = new.difference(old).collect();
+    let (id, variant) = key.split_at(key.find('\t').unwrap_or(0));
+    let variant = variant.trim_start_matches('\t');
+    let parse = |entry: &str| serde_json::from_str::<Value>(entry).unwrap_or(Value::Null);
+
+    let mut write = |record: Value| -> io::Result<()> {
+        serde_json::to_writer(&mut *out, &record)?;
+        out.write_all(b"\n")
+    };
+    if removed.len() == 1 && added.len() == 1 {
+        stats.changed += 1;
+        write(json!({
+            "change": "changed",
+            "id": id,
+            "variant": variant,
+            "old": parse(removed[0]),
+            "new": parse(added[0]),
+        }))?;
+        return Ok(());
+    }
+    for entry in removed {
+        stats.removed += 1;
+        write(json!({ "change": "removed", "id": id, "variant": variant, "entry": parse(entry) }))?;
+    }
+    for entry in added {
+        stats.added += 1;
+        write(json!({ "change": "added", "id": id, "variant": variant, "entry": parse(entry) }))?;
+    }
+    Ok(())
+}
+
+/// Changes of the entity that is currently being compared, when changes are written by entity.
+#[derive(Default)]
+struct EntityChange {
+    id: String,
+    in_old: bool,
+    in_new: bool,
+    /// Variants (or tables) whose entries differ.
+    variants: Vec<String>,
+}
+
+/// Writes one record for the entity if any of its entries differ.
+fn write_entity_change(
+    out: &mut OutputWriter,
+    entity: &EntityChange,
+    stats: &mut DiffStats,
+) -> io::Result<()> {
+    if entity.variants.is_empty() {
+        return Ok(());
+    }
+    let change = match (entity.in_old, entity.in_new) {
+        (false, _) => {
+            stats.added += 1;
+            "added"
+        }
+        (_, false) => {
+            stats.removed += 1;
+            "removed"
+        }
+        _ => {
+            stats.changed += 1;
+            "changed"
+        }
+    };
+    let record = json!({ "change": change, "id": entity.id, "variants": entity.variants });
+    serde_json::to_writer(&mut *out, &record)?;
+    out.write_all(b"\n")
+}
+
+/// Diffs two exports or databases and writes the changes to `out_file` as NDJSON.
+///
+/// If `by_entity` is set, one record is written per added, removed or changed entity instead of
+/// one per entry, and the stats count entities.
+pub fn run(
+    old: &DiffSource,
+    new: &DiffSource,
+    out_file: &str,
+    compression: OutputCompression,
+    by_entity: bool,
+) -> Result<DiffStats, DiffError> {
+    if matches!(
+        (old, new),
+        (DiffSource::Export(_), DiffSource::Database(_))
+            | (DiffSource::Database(_), DiffSource::Export(_))
+    ) {
+        return Err(DiffError::Mismatch);
+    }
+    let tmp_dir = std::env::temp_dir().join(format!("geo-db-diff-{}", std::process::id()));
+    fs::create_dir_all(&tmp_dir)?;
+    let result = diff_in(old, new, out_file, compression, by_entity, &tmp_dir);
+    if let Err(e) = fs::remove_dir_all(&tmp_dir) {
+        warn!("Failed to remove {}: {}", tmp_dir.display(), e);
+    }
+    result
+}
+
+fn diff_in(
+    old: &DiffSource,
+    new: &DiffSource,
+    out_file: &str,
+    compression: OutputCompression,
+    by_entity: bool,
+    tmp_dir: &Path,
+) -> Result<DiffStats, DiffError> {
+    info!("Sorting {}", old.path());
+    let old_chunks = sort_chunks(old, tmp_dir, "old")?;
+    info!("Sorting {}", new.path());
+    let new_chunks = sort_chunks(new, tmp_dir, "new")?;
+
+    info!("Comparing {} and {}", old.path(), new.path());
+    let mut old_groups = SortedGroups::open(&old_chunks)?;
+    let mut new_groups = SortedGroups::open(&new_chunks)?;
+    let mut out = OutputWriter::create(out_file, compression)?;
+    let mut stats = DiffStats::default();
+    let mut entity = EntityChange::default();
+    loop {
+        let key = match (old_groups.peek_key(), new_groups.peek_key()) {
+            (Some(a), Some(b)) => a.min(b).to_string(),
+            (Some(a), None) => a.to_string(),
+            (None, Some(b)) => b.to_string(),
+            (None, None) => break,
+        };
+        let old_entries = old_groups.take_group(&key)?;
+        let new_entries = new_groups.take_group(&key)?;
+        if !by_entity {
+            if old_entries != new_entries {
+                write_changes(&mut out, &key, &old_entries, &new_entries, &mut stats)?;
+            }
+            continue;
+        }
+
+        // keys start with the entity ID, so the groups of an entity are next to each other
+        let (id, variant) = key.split_once('\t').unwrap_or((&key, ""));
+        if id != entity.id {
+            write_entity_change(&mut out, &entity, &mut stats)?;
+            entity = EntityChange {
+                id: id.to_string(),
+                ..EntityChange::default()
+            };
+        }
+        entity.in_old |= !old_entries.is_empty();
+        entity.in_new |= !new_entries.is_empty();
+        if old_entries != new_entries {
+            entity.variants.push(variant.to_string());
+        }
+    }
+    write_entity_change(&mut out, &entity, &mut stats)?;
+    out.finish()?;
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{DataEntry, DataSink, SqliteSettings, SqliteSink};
+
+    /// Writes the lines to an export in the directory.
+    fn export(dir: &Path, name: &str, lines: &[&str]) -> DiffSource {
+        let path = dir.join(name);
+        fs::write(&path, lines.join("\n") + "\n").unwrap();
+        DiffSource::Export(path.to_str().unwrap().into())
+    }
+
+    /// Writes the aliases to a database in the directory.
+    fn database(dir: &Path, name: &str, aliases: &[(&str, &str)]) -> DiffSource {
+        let path = dir.join(name);
+        let path = path.to_str().unwrap();
+        let mut sink = SqliteSink::open(path, SqliteSettings::default()).unwrap();
+        for &(id, alias) in aliases {
+            sink.write(DataEntry::Alias {
+                id: id.into(),
+                lang: "en".into(),
+                alias: alias.into(),
+            })
+            .unwrap();
+        }
+        sink.finish().unwrap();
+        DiffSource::Database(path.into())
+    }
+
+    /// Diffs the sources and returns the stats as (added, removed, changed) and the records.
+    fn diff(
+        old: &DiffSource,
+        new: &DiffSource,
+        by_entity: bool,
+    ) -> ((usize, usize, usize), Vec<Value>) {
+        let dir = tempfile::tempdir().unwrap();
+        let out_file = dir.path().join("diff.ndjson");
+        let out_file = out_file.to_str().unwrap();
+        let stats = diff_in(
+            old,
+            new,
+            out_file,
+            OutputCompression::None,
+            by_entity,
+            dir.path(),
+        )
+        .unwrap();
+        let records = fs::read_to_string(out_file)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        ((stats.added, stats.removed, stats.changed), records)
+    }
+
+    const OLD: &[&str] = &[
+        r#"{"City":{"id":"Q64","population":3600000}}"#,
+        r#"{"Alias":{"id":"Q64","lang":"en","alias":"Berlin"}}"#,
+        r#"{"Alias":{"id":"Q90","lang":"en","alias":"Paris"}}"#,
+    ];
+    const NEW: &[&str] = &[
+        r#"{"Alias":{"id":"Q64","lang":"en","alias":"Berlin"}}"#,
+        r#"{"City":{"id":"Q64","population":3700000}}"#,
+        r#"{"City":{"id":"Q1","population":null}}"#,
+    ];
+
+    #[test]
+    fn diff_exports() {
+        let dir = tempfile::tempdir().unwrap();
+        let old = export(dir.path(), "old.ndjson", OLD);
+        let new = export(dir.path(), "new.ndjson", NEW);
+        let (stats, records) = diff(&old, &new, false);
+        assert_eq!(stats, (1, 1, 1));
+        assert_eq!(
+            records,
+            [
+                json!({"change": "added", "id": "Q1", "variant": "City", "entry": {"City": {"id": "Q1", "population": null}}}),
+                json!({
+                    "change": "changed",
+                    "id": "Q64",
+                    "variant": "City",
+                    "old": {"City": {"id": "Q64", "population": 3600000}},
+                    "new": {"City": {"id": "Q64", "population": 3700000}},
+                }),
+                json!({"change": "removed", "id": "Q90", "variant": "Alias", "entry": {"Alias": {"id": "Q90", "lang": "en", "alias": "Paris"}}}),
+            ]
+        );
+
+        let (stats, records) = diff(&old, &old, false);
+        assert_eq!(stats, (0, 0, 0));
+        assert!(records.is_empty());
+    }
+
+    #[test]
+    fn diff_exports_by_entity() {
+        let dir = tempfile::tempdir().unwrap();
+        let old = export(dir.path(), "old.ndjson", OLD);
+        let new = export(dir.path(), "new.ndjson", NEW);
+        let (stats, records) = diff(&old, &new, true);
+        assert_eq!(stats, (1, 1, 1));
+        assert_eq!(
+            records,
+            [
+                json!({"change": "added", "id": "Q1", "variants": ["City"]}),
+                json!({"change": "changed", "id": "Q64", "variants": ["City"]}),
+                json!({"change": "removed", "id": "Q90", "variants": ["Alias"]}),
+            ]
+        );
+    }
+
+    #[test]
+    fn diff_databases() {
+        let dir = tempfile::tempdir().unwrap();
+        let old = database(dir.path(), "old.db", &[("Q64", "Berlin"), ("Q90", "Paris")]);
+        let new = database(
+            dir.path(),
+            "new.db",
+            &[("Q64", "Berlin"), ("Q64", "BER"), ("Q90", "Paris")],
+        );
+        let (stats, records) = diff(&old, &new, false);
+        assert_eq!(stats, (1, 0, 0));
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0]["change"], "added");
+        assert_eq!(records[0]["id"], "Q64");
+        assert_eq!(records[0]["variant"], "aliases");
+        assert_eq!(records[0]["entry"]["alias"], "BER");
+
+        let (stats, records) = diff(&new, &old, true);
+        assert_eq!(stats, (0, 0, 1));
+        assert_eq!(
+            records,
+            [json!({"change": "changed", "id": "Q64", "variants": ["aliases"]})]
+        );
+    }
+
+    #[test]
+    fn detect_sources() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = database(dir.path(), "geo.db", &[]);
+        assert!(matches!(
+            DiffSource::detect(db.path()).unwrap(),
+            Some(DiffSource::Database(_))
+        ));
+        let export = export(dir.path(), "geo.ndjson", OLD);
+        assert!(matches!(
+            DiffSource::detect(export.path()).unwrap(),
+            Some(DiffSource::Export(_))
+        ));
+        for dump in [
+            "latest-all.json.bz2",
+            "dump.json",
+            "dump.torrent",
+            "https://dumps.wikimedia.org/latest-all.json.gz",
+        ] {
+            assert!(DiffSource::detect(dump).unwrap().is_none(), "{}", dump);
+        }
+        let missing = dir.path().join("missing.ndjson");
+        assert!(DiffSource::detect(missing.to_str().unwrap()).is_err());
+
+        assert!(matches!(
+            run(&db, &export, "diff.ndjson", OutputCompression::None, false),
+            Err(DiffError::Mismatch)
+        ));
+    }
+}
@@ -0,0 +1,175 @@
+//! Self-contained JSON objects of cities, with their countries, administrative hierarchy and
+//! labels (`export --format entities`), for search indexers like Elasticsearch or Meilisearch.
+
+use super::ExportError;
+use crate::output::{OutputCompression, OutputWriter};
+use rusqlite::types::ValueRef;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde_json::{json, Map, Value};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::Write;
+
+/// Ancestors listed in the hierarchy of a city at most, in case the parents have a cycle.
+const MAX_ANCESTORS: usize = 32;
+
+/// A value of a `string` column, which SQLite stores as a number if it looks like one (e.g. a
+/// label like "1984").
+pub(super) fn text(value: ValueRef) -> Option<String> {
+    match value {
+        ValueRef::Text(s) => Some(String::from_utf8_lossy(s).into_owned()),
+        ValueRef::Integer(n) => Some(n.to_string()),
+        ValueRef::Real(n) => Some(n.to_string()),
+        ValueRef::Null | ValueRef::Blob(_) => None,
+    }
+}
+
+/// Labels of an entity by language, preferring its labels over short names, and its native names
+/// in order.
+fn labels(conn: &Connection, id: &str) -> rusqlite::Result<(Map<String, Value>, Vec<Value>)> {
+    let mut stmt = conn.prepare_cached(
+        "select lang, label, source from object_labels where id = ?1 order by native_order",
+    )?;
+    let mut rows = stmt.query(params![id])?;
+    let mut labels = Map::new();
+    let mut native_names = Vec::new();
+    while let Some(row) = rows.next()? {
+        let lang: String = row.get(0)?;
+        let label = text(row.get_ref(1)?).unwrap_or_default();
+        let source: String = row.get(2)?;
+        match source.as_str() {
+            "native_name" => native_names.push(json!({ "lang": lang, "label": label })),
+            "label" => {
+                labels.insert(lang, label.into());
+            }
+            // short names are only stored for languages without a label
+            _ => {
+                labels.entry(lang).or_insert_with(|| label.into());
+            }
+        }
+    }
+    Ok((labels, native_names))
+}
+
+fn aliases(conn: &Connection, id: &str) -> rusqlite::Result<Map<String, Value>> {
+    let mut stmt = conn.prepare_cached("select lang, alias from aliases where id = ?1")?;
+    let mut rows = stmt.query(params![id])?;
+    let mut aliases = Map::new();
+    while let Some(row) = rows.next()? {
+        let lang: String = row.get(0)?;
+        let alias = text(row.get_ref(1)?).unwrap_or_default();
+        if let Value::Array(list) = aliases.entry(lang).or_insert_with(|| json!([])) {
+            list.push(alias.into());
+        }
+    }
+    Ok(aliases)
+}
+
+/// Countries and territorial entities, which are the same for many cities.
+struct Places<'a> {
+    conn: &'a Connection,
+    cache: HashMap<String, Value>,
+}
+
+impl Places<'_> {
+    /// A country or territorial entity with its ISO code, admin level and labels.
+    fn get(&mut self, id: &str) -> rusqlite::Result<Value> {
+        if let Some(place) = self.cache.get(id) {
+            return Ok(place.clone());
+        }
+        let iso: Option<String> = self
+            .conn
+            .prepare_cached(
+                "select iso from countries where id = ?1 union all select iso from territorial_entities where id = ?1",
+            )?
+            .query_row(params![id], |row| Ok(text(row.get_ref(0)?)))
+            .optional()?
+            .flatten();
+        let level: Option<i64> = self
+            .conn
+            .prepare_cached("select level from admin_levels where id = ?1")?
+            .query_row(params![id], |row| row.get(0))
+            .optional()?;
+        let (labels, _) = labels(self.conn, id)?;
+        let place = json!({ "id": id, "iso": iso, "level": level, "labels": labels });
+        self.cache.insert(id.into(), place.clone());
+        Ok(place)
+    }
+
+    /// Territorial entities that contain the entity, nearest first.
+    fn ancestors(&mut self, id: &str) -> rusqlite::Result<Vec<Value>> {
+        let mut ancestors = Vec::new();
+        let mut seen = HashSet::from([id.to_string()]);
+        let mut queue = VecDeque::from(vec![id.to_string()]);
+        while let Some(id) = queue.pop_front() {
+            let mut stmt = self
+                .conn
+                .prepare_cached("select parent from territorial_entities_parents where id = ?1")?;
+            let parents = stmt
+                .query_map(params![id], |row| row.get::<_, String>(0))?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            for parent in parents {
+                if ancestors.len() >= MAX_ANCESTORS || !seen.insert(parent.clone()) {
+                    continue;
+                }
+                ancestors.push(self.get(&parent)?);
+                queue.push_back(parent);
+            }
+        }
+        Ok(ancestors)
+    }
+}
+
+/// Writes one JSON object per city and returns the number of cities.
+pub fn write_entities(
+    conn: &Connection,
+    out_file: &str,
+    compression: OutputCompression,
+) -> Result<u64, ExportError> {
+    let mut out = OutputWriter::create(out_file, compression)?;
+    let mut places = Places {
+        conn,
+        cache: HashMap::new(),
+    };
+    let mut stmt = conn.prepare(
+        "select id, population, population_time, population_precision, lat, lon, revision from cities order by id",
+    )?;
+    let mut rows = stmt.query([])?;
+    let mut count = 0;
+    while let Some(row) = rows.next()? {
+        let id: String = row.get(0)?;
+        let lat: Option<f64> = row.get(4)?;
+        let lon: Option<f64> = row.get(5)?;
+        let (labels, native_names) = labels(conn, &id)?;
+
+        let mut countries = Vec::new();
+        let mut country_stmt = conn.prepare_cached(
+            "select country from cities_countries where city = ?1 order by priority",
+        )?;
+        let country_ids = country_stmt
+            .query_map(params![id], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        for country in country_ids {
+            countries.push(places.get(&country)?);
+        }
+
+        let city = json!({
+            "id": id,
+            "labels": labels,
+            "native_names": native_names,
+            "aliases": aliases(conn, &id)?,
+            "population": row.get::<_, Option<i64>>(1)?,
+            "population_time": text(row.get_ref(2)?),
+            "population_precision": row.get::<_, Option<i64>>(3)?,
+            // the shape of an Elasticsearch geo_point
+            "location": lat.zip(lon).map(|(lat, lon)| json!({ "lat": lat, "lon": lon })),
+            "countries": countries,
+            "hierarchy": places.ancestors(&id)?,
+            "revision": row.get::<_, Option<i64>>(6)?,
+        });
+        serde_json::to_writer(&mut out, &city)?;
+        out.write_all(b"\n")?;
+        count += 1;
+    }
+    out.finish()?;
+    Ok(count)
+}
@@ -0,0 +1,427 @@
+//! GeoJSON FeatureCollection of the cities and territorial entities with coordinates
+//! (`export --format geojson`), for mapping tools.
+
+use super::entities::text;
+use super::{ExportError, ExportSettings};
+use crate::options::CoordCrs;
+use crate::output::OutputWriter;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde_json::json;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::Write;
+
+/// Ancestors searched for the country of a territorial entity at most.
+const MAX_ANCESTORS: usize = 32;
+
+/// Countries of the places, as their ID and ISO code.
+struct Countries<'a> {
+    conn: &'a Connection,
+    /// Country of each territorial entity that was looked up.
+    cache: HashMap<String, Option<(String, Option<String>)>>,
+}
+
+impl Countries<'_> {
+    fn iso(&self, country: &str) -> rusqlite::Result<Option<String>> {
+        self.conn
+            .prepare_cached("select iso from countries where id = ?1")?
+            .query_row(params![country], |row| Ok(text(row.get_ref(0)?)))
+            .optional()
+            .map(Option::flatten)
+    }
+
+    /// The country of a city with the highest priority.
+    fn of_city(&self, id: &str) -> rusqlite::Result<Option<(String, Option<String>)>> {
+        let country: Option<String> = self
+            .conn
+            .prepare_cached(
+                "select country from cities_countries where city = ?1 order by priority limit 1",
+            )?
+            .query_row(params![id], |row| row.get(0))
+            .optional()?;
+        match country {
+            Some(country) => Ok(Some((country.clone(), self.iso(&country)?))),
+            None => Ok(None),
+        }
+    }
+
+    /// The nearest country among the ancestors of a territorial entity, or the entity itself if
+    /// it's a country.
+    fn of_territorial_entity(
+        &mut self,
+        id: &str,
+    ) -> rusqlite::Result<Option<(String, Option<String>)>> {
+        if let Some(country) = self.cache.get(id) {
+            return Ok(country.clone());
+        }
+        let mut country = None;
+        let mut seen = HashSet::new();
+        let mut queue = VecDeque::from(vec![id.to_string()]);
+        while let Some(place) = queue.pop_front() {
+            let is_country: bool = self
+                .conn
+                .prepare_cached("select count(*) > 0 from countries where id = ?1")?
+                .query_row(params![place], |row| row.get(0))?;
+            if is_country {
+                let iso = self.iso(&place)?;
+                country = Some((place, iso));
+                break;
+            }
+            let mut stmt = self
+                .conn
+                .prepare_cached("select parent from territorial_entities_parents where id = ?1")?;
+            let parents = stmt
+                .query_map(params![place], |row| row.get::<_, String>(0))?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            for parent in parents {
+                if seen.len() < MAX_ANCESTORS && seen.insert(parent.clone()) {
+                    queue.push_back(parent);
+                }
+            }
+        }
+        self.cache.insert(id.into(), country.clone());
+        Ok(country)
+    }
+}
+
+/// The tables that places are read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Schema {
+    /// The tables of a build, also after post-processing.
+    Built,
+    /// The tables left by the cleanup of post-processing: territorial entities and countries
+    /// are gone, `cities.country` is the ISO code of the country, and there's one label per
+    /// language in `cities_labels`. Outputs cleaned up before the source of the labels was kept
+    /// have no `source` column.
+    CleanedUp { label_sources: bool },
+}
+
+impl Schema {
+    fn detect(conn: &Connection) -> Result<Self, ExportError> {
+        let tables = super::table_names(conn)?;
+        let has_table = |name: &&str| tables.iter().any(|table| table == name);
+        let built_tables = [
+            "countries",
+            "territorial_entities",
+            "territorial_entities_parents",
+            "object_labels",
+        ];
+        if built_tables.iter().all(has_table) {
+            Ok(Schema::Built)
+        } else if has_table(&"cities_labels") && !has_table(&"countries") {
+            let columns = super::column_names(conn, "cities_labels")?;
+            Ok(Schema::CleanedUp {
+                label_sources: columns.iter().any(|column| column == "source"),
+            })
+        } else {
+            Err(ExportError::UnknownSchema)
+        }
+    }
+
+    /// Query for the label of a place in a language, or its first native name.
+    fn name_query(self) -> &'static str {
+        match self {
+            Schema::Built => {
+                "select label from object_labels where id = ?1 and (lang = ?2 or source = 'native_name')
+                order by lang = ?2 desc, source = 'label' desc, native_order limit 1"
+            }
+            Schema::CleanedUp {
+                label_sources: true,
+            } => {
+                "select label from cities_labels where id = ?1 and (lang = ?2 or source = 'native_name')
+                order by lang = ?2 desc, source = 'label' desc limit 1"
+            }
+            Schema::CleanedUp {
+                label_sources: false,
+            } => "select label from cities_labels where id = ?1 and lang = ?2",
+        }
+    }
+}
+
+/// Label of a place in the language, or its first native name.
+fn name(
+    conn: &Connection,
+    schema: Schema,
+    id: &str,
+    lang: &str,
+) -> rusqlite::Result<Option<String>> {
+    conn.prepare_cached(schema.name_query())?
+        .query_row(params![id, lang], |row| Ok(text(row.get_ref(0)?)))
+        .optional()
+        .map(Option::flatten)
+}
+
+/// CRS of the coordinates of an output, which are in WGS84 in outputs built before it was
+/// recorded.
+fn coord_crs(conn: &Connection) -> Result<CoordCrs, ExportError> {
+    let has_metadata: bool = conn.query_row(
+        "select count(*) > 0 from sqlite_master where type = 'table' and name = 'metadata'",
+        [],
+        |row| row.get(0),
+    )?;
+    if !has_metadata {
+        return Ok(CoordCrs::Wgs84);
+    }
+    let crs: Option<String> = conn
+        .query_row(
+            "select value from metadata where key = 'coord_crs'",
+            [],
+            |row| row.get(0),
+        )
+        .optional()?;
+    match crs {
+        Some(crs) => crs.parse().map_err(ExportError::Crs),
+        None => Ok(CoordCrs::Wgs84),
+    }
+}
+
+/// A city or territorial entity with coordinates in WGS84.
+pub(super) struct Place {
+    /// `city` or `territorial_entity`.
+    pub kind: &'static str,
+    pub id: String,
+    pub lat: f64,
+    pub lon: f64,
+    pub name: Option<String>,
+    pub population: Option<i64>,
+    /// ID of the country of the place, which cleaned-up outputs don't have.
+    pub country: Option<String>,
+    pub country_iso: Option<String>,
+}
+
+/// Calls `f` with every place with coordinates that matches the filters of the settings, cities
+/// first. Coordinates of outputs built with another `--coord-crs` are converted back to WGS84,
+/// which GeoJSON requires.
+///
+/// Outputs whose post-processing was cleaned up only have cities, with the ISO code of their
+/// country.
+pub(super) fn for_each_place(
+    conn: &Connection,
+    settings: &ExportSettings,
+    mut f: impl FnMut(Place) -> Result<(), ExportError>,
+) -> Result<(), ExportError> {
+    let schema = Schema::detect(conn)?;
+    let crs = coord_crs(conn)?;
+    let mut countries = Countries {
+        conn,
+        cache: HashMap::new(),
+    };
+    let matches_country = |country: &Option<String>, iso: &Option<String>| match &settings.country {
+        Some(filter) => {
+            country.as_ref() == Some(filter)
+                || iso
+                    .as_ref()
+                    .is_some_and(|iso| iso.eq_ignore_ascii_case(filter))
+        }
+        None => true,
+    };
+    let min_population = settings.min_population.map(|population| population as i64);
+
+    // the country of cities is looked up in built outputs
+    let country_iso = match schema {
+        Schema::Built => "null",
+        Schema::CleanedUp { .. } => "country",
+    };
+    let population_filter = match min_population {
+        Some(_) => " and population >= ?1",
+        None => "",
+    };
+    let mut queries = vec![(
+        "city",
+        format!(
+            "select id, lat, lon, population, {} from cities where lat is not null and lon is not null{}",
+            country_iso, population_filter
+        ),
+    )];
+    // territorial entities have no population, so they're left out with a minimum population
+    if schema == Schema::Built && min_population.is_none() {
+        queries.push((
+            "territorial_entity",
+            "select id, lat, lon, null, null from territorial_entities where lat is not null and lon is not null".into(),
+        ));
+    }
+    for (kind, query) in queries {
+        let mut stmt = conn.prepare(&query)?;
+        let mut rows = match min_population {
+            Some(population) => stmt.query(params![population])?,
+            None => stmt.query([])?,
+        };
+        while let Some(row) = rows.next()? {
+            let id: String = row.get(0)?;
+            let (country, country_iso) = match (schema, kind) {
+                (Schema::CleanedUp { .. }, _) => (None, row.get(4)?),
+                (Schema::Built, "city") => countries.of_city(&id)?.unzip(),
+                (Schema::Built, _) => countries.of_territorial_entity(&id)?.unzip(),
+            };
+            let country_iso = country_iso.flatten();
+            if !matches_country(&country, &country_iso) {
+                continue;
+            }
+            let (lat, lon) = crs.unproject(row.get(1)?, row.get(2)?);
+            f(Place {
+                kind,
+                lat,
+                lon,
+                name: name(conn, schema, &id, &settings.name_language)?,
+                population: row.get(3)?,
+                country,
+                country_iso,
+                id,
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes the places with coordinates that match the filters of the settings as a
+/// FeatureCollection, and returns the number of features.
+pub fn write_geojson(
+    conn: &Connection,
+    out_file: &str,
+    settings: &ExportSettings,
+) -> Result<u64, ExportError> {
+    let mut out = OutputWriter::create(out_file, settings.compression)?;
+    out.write_all(b"{\"type\":\"FeatureCollection\",\"features\":[\n")?;
+    let mut count = 0;
+    for_each_place(conn, settings, |place| {
+        let feature = json!({
+            "type": "Feature",
+            "id": place.id,
+            "geometry": { "type": "Point", "coordinates": [place.lon, place.lat] },
+            "properties": {
+                "id": place.id,
+                "kind": place.kind,
+                "name": place.name,
+                "population": place.population,
+                "country": place.country,
+                "country_iso": place.country_iso,
+            },
+        });
+        if count > 0 {
+            out.write_all(b",\n")?;
+        }
+        serde_json::to_writer(&mut out, &feature)?;
+        count += 1;
+        Ok(())
+    })?;
+    out.write_all(b"\n]}\n")?;
+    out.finish()?;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::export::ExportFormat;
+    use crate::output::OutputCompression;
+    use serde_json::Value;
+
+    fn settings() -> ExportSettings {
+        ExportSettings {
+            format: ExportFormat::GeoJson,
+            compression: OutputCompression::None,
+            delimiter: None,
+            country: None,
+            min_population: None,
+            name_language: "en".into(),
+        }
+    }
+
+    /// An output with Berlin and the coordinates in the CRS.
+    fn output(crs: CoordCrs) -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(include_str!("../setup.sql")).unwrap();
+        let (lat, lon) = crs.project(52.516_667, 13.383_333);
+        conn.execute(
+            "insert into cities (id, lat, lon, population) values ('Q64', ?1, ?2, 3755251)",
+            params![lat, lon],
+        )
+        .unwrap();
+        conn.execute(
+            "insert into metadata (key, value) values ('coord_crs', ?1)",
+            params![crs.as_str()],
+        )
+        .unwrap();
+        conn
+    }
+
+    fn features(conn: &Connection) -> Vec<Value> {
+        let dir = tempfile::tempdir().unwrap();
+        let out_file = dir.path().join("places.geojson");
+        let out_file = out_file.to_str().unwrap();
+        assert_eq!(write_geojson(conn, out_file, &settings()).unwrap(), 1);
+        let collection: Value =
+            serde_json::from_str(&std::fs::read_to_string(out_file).unwrap()).unwrap();
+        collection["features"].as_array().unwrap().clone()
+    }
+
+    #[test]
+    fn web_mercator_is_converted_to_wgs84() {
+        for crs in [CoordCrs::Wgs84, CoordCrs::WebMercator] {
+            let features = features(&output(crs));
+            let coordinates = &features[0]["geometry"]["coordinates"];
+            let (lon, lat) = (coordinates[0].as_f64(), coordinates[1].as_f64());
+            assert!((lon.unwrap() - 13.383_333).abs() < 1e-9, "{:?}", crs);
+            assert!((lat.unwrap() - 52.516_667).abs() < 1e-9, "{:?}", crs);
+        }
+    }
+
+    #[test]
+    fn unknown_crs() {
+        let conn = output(CoordCrs::Wgs84);
+        conn.execute("update metadata set value = '27700'", [])
+            .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let out_file = dir.path().join("places.geojson");
+        let result = write_geojson(&conn, out_file.to_str().unwrap(), &settings());
+        assert!(matches!(result, Err(ExportError::Crs(_))));
+    }
+
+    #[test]
+    fn cleaned_up_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_file = dir.path().join("out.db");
+        let db_file = db_file.to_str().unwrap();
+        let conn = Connection::open(db_file).unwrap();
+        conn.execute_batch(include_str!("../setup.sql")).unwrap();
+        conn.execute_batch(
+            "insert into countries (id, iso, conflicting, sovereign) values ('Q183', 'DE', 0, 1);
+            insert into cities (id, country, lat, lon, population)
+                values ('Q64', 'Q183', 52.516667, 13.383333, 3755251);
+            insert into cities_countries (city, priority, country) values ('Q64', 0, 'Q183');
+            insert into object_labels (id, lang, native_order, label, source) values
+                ('Q64', 'de', 0, 'Berlin', 'native_name'),
+                ('Q64', 'en', null, 'Berlin', 'label');",
+        )
+        .unwrap();
+        drop(conn);
+        crate::post::run(db_file, true, true, false).unwrap();
+
+        let conn = Connection::open(db_file).unwrap();
+        assert_eq!(
+            Schema::detect(&conn).unwrap(),
+            Schema::CleanedUp {
+                label_sources: true
+            }
+        );
+        let features = features(&conn);
+        assert_eq!(features[0]["properties"]["id"], "Q64");
+        assert_eq!(features[0]["properties"]["name"], "Berlin");
+        assert_eq!(features[0]["properties"]["country"], Value::Null);
+        assert_eq!(features[0]["properties"]["country_iso"], "DE");
+    }
+
+    #[test]
+    fn interrupted_cleanup() {
+        let conn = output(CoordCrs::Wgs84);
+        // as after the first step of the cleanup
+        conn.execute_batch(
+            "drop table territorial_entities; drop table territorial_entities_parents;
+            drop table admin_levels;",
+        )
+        .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let out_file = dir.path().join("places.geojson");
+        let result = write_geojson(&conn, out_file.to_str().unwrap(), &settings());
+        assert!(matches!(result, Err(ExportError::UnknownSchema)));
+    }
+}
@@ -0,0 +1,129 @@
+//! OGC GeoPackage of the cities and territorial entities with coordinates
+//! (`export --format gpkg`), which opens in QGIS without conversion.
+//!
+//! Each kind of place is a feature table with a point geometry in WGS 84 and an R-tree spatial
+//! index, which is filled while the features are inserted.
+
+use super::geojson::for_each_place;
+use super::{ExportError, ExportSettings};
+use rusqlite::{params, Connection};
+use std::fs;
+use std::io;
+
+/// EPSG code of WGS 84, which the coordinates of Wikidata are in.
+const SRS_ID: i32 = 4326;
+
+/// Feature tables of the kinds of places, and their identifiers in `gpkg_contents`.
+const FEATURE_TABLES: [(&str, &str); 2] = [
+    ("cities", "Cities"),
+    ("territorial_entities", "Territorial entities"),
+];
+
+/// A point in the GeoPackage geometry format: a header with the SRS and without an envelope,
+/// followed by the point as little-endian WKB.
+fn point(lon: f64, lat: f64) -> Vec<u8> {
+    let mut blob = Vec::with_capacity(29);
+    blob.extend_from_slice(b"GP");
+    // version 1, and flags for a little-endian header without an envelope
+    blob.extend_from_slice(&[0, 0b0000_0001]);
+    blob.extend_from_slice(&SRS_ID.to_le_bytes());
+    // little-endian WKB point
+    blob.push(1);
+    blob.extend_from_slice(&1u32.to_le_bytes());
+    blob.extend_from_slice(&lon.to_le_bytes());
+    blob.extend_from_slice(&lat.to_le_bytes());
+    blob
+}
+
+/// Writes the places with coordinates that match the filters of the settings to a new
+/// GeoPackage, replacing `out_file`, and returns the number of features.
+pub fn write_geopackage(
+    conn: &Connection,
+    out_file: &str,
+    settings: &ExportSettings,
+) -> Result<u64, ExportError> {
+    match fs::remove_file(out_file) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+        _ => (),
+    }
+    let mut gpkg = Connection::open(out_file)?;
+    gpkg.execute_batch(include_str!("geopackage.sql"))?;
+
+    let tx = gpkg.transaction()?;
+    for (table, _) in &FEATURE_TABLES {
+        tx.execute_batch(&format!(
+            "create virtual table rtree_{}_geom using rtree(id, minx, maxx, miny, maxy)",
+            table
+        ))?;
+    }
+
+    let mut count = 0;
+    for_each_place(conn, settings, |place| {
+        let geom = point(place.lon, place.lat);
+        let table = match place.kind {
+            "city" => {
+                tx.prepare_cached(
+                    "insert into cities (geom, id, name, population, country, country_iso)
+                    values (?1, ?2, ?3, ?4, ?5, ?6)",
+                )?
+                .execute(params![
+                    geom,
+                    place.id,
+                    place.name,
+                    place.population,
+                    place.country,
+                    place.country_iso
+                ])?;
+                "cities"
+            }
+            _ => {
+                tx.prepare_cached(
+                    "insert into territorial_entities (geom, id, name, country, country_iso)
+                    values (?1, ?2, ?3, ?4, ?5)",
+                )?
+                .execute(params![
+                    geom,
+                    place.id,
+                    place.name,
+                    place.country,
+                    place.country_iso
+                ])?;
+                "territorial_entities"
+            }
+        };
+        tx.prepare_cached(&format!(
+            "insert into rtree_{}_geom values (?1, ?2, ?2, ?3, ?3)",
+            table
+        ))?
+        .execute(params![tx.last_insert_rowid(), place.lon, place.lat])?;
+        count += 1;
+        Ok(())
+    })?;
+
+    for (table, identifier) in &FEATURE_TABLES {
+        // the bounds of the index are rounded outwards to 32-bit floats, which is fine for the
+        // informative bounds of the contents
+        tx.execute(
+            &format!(
+                "insert into gpkg_contents
+                (table_name, data_type, identifier, min_x, min_y, max_x, max_y, srs_id)
+                select ?1, 'features', ?2, min(minx), min(miny), max(maxx), max(maxy), ?3
+                from rtree_{}_geom",
+                table
+            ),
+            params![table, identifier, SRS_ID],
+        )?;
+        tx.execute(
+            "insert into gpkg_geometry_columns values (?1, 'geom', 'POINT', ?2, 0, 0)",
+            params![table, SRS_ID],
+        )?;
+        tx.execute(
+            "insert into gpkg_extensions values
+            (?1, 'geom', 'gpkg_rtree_index', 'http://www.geopackage.org/spec120/#extension_rtree', 'write-only')",
+            params![table],
+        )?;
+        tx.execute_batch(&include_str!("geopackage_rtree.sql").replace("{table}", table))?;
+    }
+    tx.commit()?;
+    Ok(count)
+}
@@ -0,0 +1,47 @@
+-- Spatial index of a feature table of a GeoPackage and the triggers that keep it up to date
+-- (GeoPackage 1.3, annex F.3), with `{table}` in place of the name of the table. The ST_*
+-- functions are provided by the programs that edit GeoPackages, like QGIS, so the triggers are
+-- created after the features are inserted.
+
+create trigger rtree_{table}_geom_insert after insert on {table}
+    when (new.geom not null and not ST_IsEmpty(new.geom))
+begin
+    insert or replace into rtree_{table}_geom values (
+        new.fid, ST_MinX(new.geom), ST_MaxX(new.geom), ST_MinY(new.geom), ST_MaxY(new.geom)
+    );
+end;
+
+create trigger rtree_{table}_geom_update1 after update of geom on {table}
+    when old.fid = new.fid and (new.geom notnull and not ST_IsEmpty(new.geom))
+begin
+    insert or replace into rtree_{table}_geom values (
+        new.fid, ST_MinX(new.geom), ST_MaxX(new.geom), ST_MinY(new.geom), ST_MaxY(new.geom)
+    );
+end;
+
+create trigger rtree_{table}_geom_update2 after update of geom on {table}
+    when old.fid = new.fid and (new.geom isnull or ST_IsEmpty(new.geom))
+begin
+    delete from rtree_{table}_geom where id = old.fid;
+end;
+
+create trigger rtree_{table}_geom_update3 after update on {table}
+    when old.fid != new.fid and (new.geom notnull and not ST_IsEmpty(new.geom))
+begin
+    delete from rtree_{table}_geom where id = old.fid;
+    insert or replace into rtree_{table}_geom values (
+        new.fid, ST_MinX(new.geom), ST_MaxX(new.geom), ST_MinY(new.geom), ST_MaxY(new.geom)
+    );
+end;
+
+create trigger rtree_{table}_geom_update4 after update on {table}
+    when old.fid != new.fid and (new.geom isnull or ST_IsEmpty(new.geom))
+begin
+    delete from rtree_{table}_geom where id in (old.fid, new.fid);
+end;
+
+create trigger rtree_{table}_geom_delete after delete on {table}
+    when old.geom not null
+begin
+    delete from rtree_{table}_geom where id = old.fid;
+end;
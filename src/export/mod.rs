@@ -0,0 +1,306 @@
+//! Reading data back out of a SQLite output database.
+
+use crate::output::{OutputCompression, OutputWriter};
+use rusqlite::types::ValueRef;
+use rusqlite::{params, Connection, Row};
+use serde_json::{Map, Value};
+use std::io::{self, Write};
+use std::path::Path;
+use std::str::FromStr;
+use thiserror::Error;
+
+mod entities;
+mod geojson;
+mod geopackage;
+#[cfg(feature = "parquet")]
+mod parquet;
+
+#[derive(Debug, Error)]
+pub enum ExportError {
+    #[error("database error: {0}")]
+    Db(#[from] rusqlite::Error),
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("CSV error: {0}")]
+    Csv(#[from] csv::Error),
+    #[error("no table named {0:?}")]
+    NoSuchTable(String),
+    #[error("{0} exports aren't written per table")]
+    NotPerTable(&'static str),
+    #[error("{0} in the metadata")]
+    Crs(String),
+    #[error("the output has neither the tables of a build nor those left by the cleanup of post-processing; was the cleanup interrupted?")]
+    UnknownSchema,
+    #[cfg(feature = "parquet")]
+    #[error("Parquet error: {0}")]
+    Parquet(#[from] ::parquet::errors::ParquetError),
+    #[cfg(feature = "parquet")]
+    #[error("Arrow error: {0}")]
+    Arrow(#[from] arrow_schema::ArrowError),
+    #[cfg(not(feature = "parquet"))]
+    #[error("geo-db was built without the {0} feature")]
+    MissingFeature(&'static str),
+}
+
+/// File formats of `geo-db export`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Ndjson,
+    /// Only available with `--features parquet`.
+    Parquet,
+    Csv,
+    /// CSV with tabs instead of commas.
+    Tsv,
+    /// One JSON object per city, with its countries, hierarchy and labels.
+    Entities,
+    /// A FeatureCollection of the cities and territorial entities with coordinates.
+    GeoJson,
+    /// A GeoPackage with the cities and territorial entities with coordinates as features.
+    GeoPackage,
+}
+
+impl ExportFormat {
+    pub const NAMES: [&'static str; 7] = [
+        "ndjson", "parquet", "csv", "tsv", "entities", "geojson", "gpkg",
+    ];
+
+    /// Extension of the files of the tables with `--all-tables`.
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Ndjson => "ndjson",
+            ExportFormat::Parquet => "parquet",
+            ExportFormat::Csv => "csv",
+            ExportFormat::Tsv => "tsv",
+            ExportFormat::Entities => "jsonl",
+            ExportFormat::GeoJson => "geojson",
+            ExportFormat::GeoPackage => "gpkg",
+        }
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ndjson" => Ok(ExportFormat::Ndjson),
+            "parquet" => Ok(ExportFormat::Parquet),
+            "csv" => Ok(ExportFormat::Csv),
+            "tsv" => Ok(ExportFormat::Tsv),
+            "entities" => Ok(ExportFormat::Entities),
+            "geojson" => Ok(ExportFormat::GeoJson),
+            "gpkg" => Ok(ExportFormat::GeoPackage),
+            s => Err(format!("unknown export format {:?}", s)),
+        }
+    }
+}
+
+/// Converts a row into a JSON object keyed by column name.
+pub fn row_to_json(row: &Row, columns: &[String]) -> rusqlite::Result<Value> {
+    let mut object = Map::new();
+    for (i, column) in columns.iter().enumerate() {
+        let value = match row.get_ref(i)? {
+            ValueRef::Null => Value::Null,
+            ValueRef::Integer(n) => n.into(),
+            ValueRef::Real(n) => n.into(),
+            ValueRef::Text(s) => String::from_utf8_lossy(s).into(),
+            ValueRef::Blob(_) => Value::Null,
+        };
+        object.insert(column.clone(), value);
+    }
+    Ok(Value::Object(object))
+}
+
+pub fn table_names(conn: &Connection) -> rusqlite::Result<Vec<String>> {
+    let mut stmt =
+        conn.prepare("SELECT name FROM sqlite_master WHERE type = 'table' ORDER BY name")?;
+    let names = stmt.query_map([], |row| row.get(0))?;
+    names.collect()
+}
+
+/// Virtual tables, like the R*Tree of the cities. Their data is stored in shadow tables, which
+/// are named after them with a suffix.
+fn virtual_table_names(conn: &Connection) -> rusqlite::Result<Vec<String>> {
+    let mut stmt = conn.prepare(
+        "SELECT name FROM sqlite_master WHERE type = 'table' AND sql LIKE 'create virtual table%'",
+    )?;
+    let names = stmt.query_map([], |row| row.get(0))?;
+    names.collect()
+}
+
+pub fn column_names(conn: &Connection, table: &str) -> rusqlite::Result<Vec<String>> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info(\"{}\")", table))?;
+    let names = stmt.query_map([], |row| row.get(1))?;
+    names.collect()
+}
+
+/// How tables are written by [`export`].
+#[derive(Debug, Clone)]
+pub struct ExportSettings {
+    pub format: ExportFormat,
+    /// Compression of NDJSON, CSV and GeoJSON files. Parquet files are compressed with Snappy.
+    pub compression: OutputCompression,
+    /// Field delimiter of CSV files, instead of a comma (or a tab for TSV).
+    pub delimiter: Option<u8>,
+    /// Only places in this country, by ISO code or ID, in GeoJSON files and GeoPackages.
+    pub country: Option<String>,
+    /// Only cities with at least this population in GeoJSON files and GeoPackages.
+    pub min_population: Option<u64>,
+    /// Language of the names of places in GeoJSON files and GeoPackages. Places without a label in it are
+    /// named by their first native name.
+    pub name_language: String,
+}
+
+/// Writes every row of a table to `out_file` and returns the number of rows. Entities, GeoJSON
+/// and GeoPackage exports always write the cities (and territorial entities for the latter two).
+pub fn export(
+    db_file: &str,
+    table: &str,
+    out_file: &str,
+    settings: &ExportSettings,
+) -> Result<u64, ExportError> {
+    let conn = Connection::open(db_file)?;
+    // the table name can't be a query parameter, so it has to be an existing table
+    if !table_names(&conn)?.iter().any(|name| name == table) {
+        return Err(ExportError::NoSuchTable(table.into()));
+    }
+    match settings.format {
+        ExportFormat::Ndjson => write_ndjson(&conn, table, out_file, settings.compression),
+        ExportFormat::Csv => {
+            let delimiter = settings.delimiter.unwrap_or(b',');
+            write_csv(&conn, table, out_file, delimiter, settings.compression)
+        }
+        ExportFormat::Tsv => {
+            let delimiter = settings.delimiter.unwrap_or(b'\t');
+            write_csv(&conn, table, out_file, delimiter, settings.compression)
+        }
+        ExportFormat::Entities => entities::write_entities(&conn, out_file, settings.compression),
+        ExportFormat::GeoJson => geojson::write_geojson(&conn, out_file, settings),
+        ExportFormat::GeoPackage => geopackage::write_geopackage(&conn, out_file, settings),
+        #[cfg(feature = "parquet")]
+        ExportFormat::Parquet => self::parquet::write_table(&conn, table, out_file),
+        #[cfg(not(feature = "parquet"))]
+        ExportFormat::Parquet => Err(ExportError::MissingFeature("parquet")),
+    }
+}
+
+/// Writes every table to a file named after it in `out_dir`.
+pub fn export_all(
+    db_file: &str,
+    out_dir: &str,
+    settings: &ExportSettings,
+) -> Result<(), ExportError> {
+    match settings.format {
+        ExportFormat::Entities => return Err(ExportError::NotPerTable("entities")),
+        ExportFormat::GeoJson => return Err(ExportError::NotPerTable("GeoJSON")),
+        ExportFormat::GeoPackage => return Err(ExportError::NotPerTable("GeoPackage")),
+        _ => (),
+    }
+    std::fs::create_dir_all(out_dir)?;
+    let conn = Connection::open(db_file)?;
+    let tables = table_names(&conn)?;
+    let virtual_tables = virtual_table_names(&conn)?;
+    // e.g. sqlite_sequence, and indexes like the R*Tree of the cities with its shadow tables
+    let is_data = |table: &&String| {
+        !table.starts_with("sqlite_")
+            && !virtual_tables
+                .iter()
+                .any(|name| *table == name || table.starts_with(&format!("{}_", name)))
+    };
+    for table in tables.iter().filter(is_data) {
+        let path = Path::new(out_dir).join(format!("{}.{}", table, settings.format.extension()));
+        let count = export(db_file, table, &path.to_string_lossy(), settings)?;
+        info!("Exported {} rows of {} to {}", count, table, path.display());
+    }
+    Ok(())
+}
+
+fn write_ndjson(
+    conn: &Connection,
+    table: &str,
+    out_file: &str,
+    compression: OutputCompression,
+) -> Result<u64, ExportError> {
+    let columns = column_names(conn, table)?;
+
+    let mut out = OutputWriter::create(out_file, compression)?;
+    let mut stmt = conn.prepare(&format!("SELECT * FROM \"{}\"", table))?;
+    let mut rows = stmt.query([])?;
+    let mut count = 0;
+    while let Some(row) = rows.next()? {
+        serde_json::to_writer(&mut out, &row_to_json(row, &columns)?)?;
+        out.write_all(b"\n")?;
+        count += 1;
+    }
+    out.finish()?;
+    Ok(count)
+}
+
+/// Writes a table as CSV with a header row. Fields are quoted if they contain the delimiter, a
+/// quote or a line break, and nulls are empty fields.
+fn write_csv(
+    conn: &Connection,
+    table: &str,
+    out_file: &str,
+    delimiter: u8,
+    compression: OutputCompression,
+) -> Result<u64, ExportError> {
+    let columns = column_names(conn, table)?;
+
+    let mut out = csv::WriterBuilder::new()
+        .delimiter(delimiter)
+        .from_writer(OutputWriter::create(out_file, compression)?);
+    out.write_record(&columns)?;
+    let mut stmt = conn.prepare(&format!("SELECT * FROM \"{}\"", table))?;
+    let mut rows = stmt.query([])?;
+    let mut count = 0;
+    let mut record = csv::ByteRecord::new();
+    while let Some(row) = rows.next()? {
+        record.clear();
+        for i in 0..columns.len() {
+            match row.get_ref(i)? {
+                ValueRef::Null | ValueRef::Blob(_) => record.push_field(b""),
+                ValueRef::Integer(n) => record.push_field(n.to_string().as_bytes()),
+                ValueRef::Real(n) => record.push_field(n.to_string().as_bytes()),
+                ValueRef::Text(s) => record.push_field(s),
+            }
+        }
+        out.write_byte_record(&record)?;
+        count += 1;
+    }
+    let out = out.into_inner().map_err(|e| e.into_error())?;
+    out.finish()?;
+    Ok(count)
+}
+
+/// Prints the rows of all tables that have an `id` column matching the entity ID, as one JSON
+/// object per table.
+pub fn query(db_file: &str, id: &str) -> Result<(), ExportError> {
+    let conn = Connection::open(db_file)?;
+    let mut found = false;
+    for table in table_names(&conn)? {
+        let columns = column_names(&conn, &table)?;
+        if !columns.iter().any(|column| column == "id") {
+            continue;
+        }
+
+        let mut stmt = conn.prepare(&format!("SELECT * FROM \"{}\" WHERE id = ?1", table))?;
+        let mut rows = stmt.query(params![id])?;
+        let mut results = Vec::new();
+        while let Some(row) = rows.next()? {
+            results.push(row_to_json(row, &columns)?);
+        }
+        if !results.is_empty() {
+            found = true;
+            let mut object = Map::new();
+            object.insert(table, Value::Array(results));
+            println!("{}", serde_json::to_string_pretty(&object)?);
+        }
+    }
+    if !found {
+        warn!("{} was not found in {}", id, db_file);
+    }
+    Ok(())
+}
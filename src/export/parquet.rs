@@ -0,0 +1,147 @@
+//! Parquet files of the tables of a SQLite output (`export --format parquet`).
+
+use super::ExportError;
+use arrow_array::builder::{BooleanBuilder, Float64Builder, Int64Builder, StringBuilder};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_schema::{ArrowError, DataType, Field, Schema};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use rusqlite::types::ValueRef;
+use rusqlite::Connection;
+use std::fs::File;
+use std::sync::Arc;
+
+/// Rows per record batch, which is also the size of the row groups.
+const BATCH_ROWS: usize = 65_536;
+
+/// Arrow type of a column, from the type it was declared with in setup.sql.
+fn data_type(declared: &str) -> DataType {
+    let declared = declared.to_ascii_lowercase();
+    if declared.contains("int") {
+        DataType::Int64
+    } else if declared == "boolean" {
+        DataType::Boolean
+    } else if declared.starts_with("decimal") || declared == "double" || declared == "real" {
+        DataType::Float64
+    } else {
+        DataType::Utf8
+    }
+}
+
+/// Columns of a table, and the types they were declared with.
+fn columns(conn: &Connection, table: &str) -> rusqlite::Result<Vec<(String, String)>> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info(\"{}\")", table))?;
+    let columns = stmt.query_map([], |row| Ok((row.get(1)?, row.get(2)?)))?;
+    columns.collect()
+}
+
+/// Collects the values of a column for the next record batch. SQLite doesn't enforce the
+/// declared types (e.g. `string` columns have numeric affinity), so values are converted.
+enum ColumnBuilder {
+    Int(Int64Builder),
+    Bool(BooleanBuilder),
+    Float(Float64Builder),
+    Text(StringBuilder),
+}
+
+impl ColumnBuilder {
+    fn new(data_type: &DataType) -> Self {
+        match data_type {
+            DataType::Int64 => ColumnBuilder::Int(Int64Builder::new()),
+            DataType::Boolean => ColumnBuilder::Bool(BooleanBuilder::new()),
+            DataType::Float64 => ColumnBuilder::Float(Float64Builder::new()),
+            _ => ColumnBuilder::Text(StringBuilder::new()),
+        }
+    }
+
+    fn append(&mut self, value: ValueRef) {
+        let text = |s| std::str::from_utf8(s).ok();
+        match self {
+            ColumnBuilder::Int(builder) => builder.append_option(match value {
+                ValueRef::Integer(n) => Some(n),
+                ValueRef::Real(n) => Some(n as i64),
+                ValueRef::Text(s) => text(s).and_then(|s| s.parse().ok()),
+                ValueRef::Null | ValueRef::Blob(_) => None,
+            }),
+            ColumnBuilder::Bool(builder) => builder.append_option(match value {
+                ValueRef::Integer(n) => Some(n != 0),
+                _ => None,
+            }),
+            ColumnBuilder::Float(builder) => builder.append_option(match value {
+                ValueRef::Integer(n) => Some(n as f64),
+                ValueRef::Real(n) => Some(n),
+                ValueRef::Text(s) => text(s).and_then(|s| s.parse().ok()),
+                ValueRef::Null | ValueRef::Blob(_) => None,
+            }),
+            ColumnBuilder::Text(builder) => match value {
+                ValueRef::Integer(n) => builder.append_value(n.to_string()),
+                ValueRef::Real(n) => builder.append_value(n.to_string()),
+                ValueRef::Text(s) => builder.append_value(String::from_utf8_lossy(s)),
+                ValueRef::Null | ValueRef::Blob(_) => builder.append_null(),
+            },
+        }
+    }
+
+    fn finish(&mut self) -> ArrayRef {
+        match self {
+            ColumnBuilder::Int(builder) => Arc::new(builder.finish()),
+            ColumnBuilder::Bool(builder) => Arc::new(builder.finish()),
+            ColumnBuilder::Float(builder) => Arc::new(builder.finish()),
+            ColumnBuilder::Text(builder) => Arc::new(builder.finish()),
+        }
+    }
+}
+
+fn record_batch(
+    schema: &Arc<Schema>,
+    builders: &mut [ColumnBuilder],
+) -> Result<RecordBatch, ArrowError> {
+    let columns = builders.iter_mut().map(ColumnBuilder::finish).collect();
+    RecordBatch::try_new(Arc::clone(schema), columns)
+}
+
+/// Writes every row of a table to a Snappy-compressed Parquet file and returns the number of rows.
+pub fn write_table(conn: &Connection, table: &str, out_file: &str) -> Result<u64, ExportError> {
+    // every column is nullable, since values that can't be converted are stored as nulls
+    let fields: Vec<_> = columns(conn, table)?
+        .iter()
+        .map(|(name, declared)| Field::new(name, data_type(declared), true))
+        .collect();
+    let schema = Arc::new(Schema::new(fields));
+
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .build();
+    let mut writer = ArrowWriter::try_new(
+        File::create(out_file)?,
+        Arc::clone(&schema),
+        Some(properties),
+    )?;
+    let mut builders: Vec<_> = schema
+        .fields()
+        .iter()
+        .map(|field| ColumnBuilder::new(field.data_type()))
+        .collect();
+
+    let mut stmt = conn.prepare(&format!("SELECT * FROM \"{}\"", table))?;
+    let mut rows = stmt.query([])?;
+    let mut count = 0;
+    let mut batch_rows = 0;
+    while let Some(row) = rows.next()? {
+        for (i, builder) in builders.iter_mut().enumerate() {
+            builder.append(row.get_ref(i)?);
+        }
+        count += 1;
+        batch_rows += 1;
+        if batch_rows == BATCH_ROWS {
+            writer.write(&record_batch(&schema, &mut builders)?)?;
+            batch_rows = 0;
+        }
+    }
+    if batch_rows > 0 {
+        writer.write(&record_batch(&schema, &mut builders)?)?;
+    }
+    writer.close()?;
+    Ok(count)
+}
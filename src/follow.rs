@@ -0,0 +1,453 @@
+//! Keeping an existing database up to date with the edits made on Wikidata.
+//!
+//! Edits are read from the recent changes stream of Wikimedia EventStreams (server-sent events).
+//! Edited items are collected for a few seconds and then fetched through the API in batches, and
+//! are run through [`handle_line`] like the entities of an incremental dump, which replaces their
+//! stored entries.
+
+use crate::database::DataEntry;
+use crate::input::http::USER_AGENT;
+use crate::options::Options;
+use crate::wiki_data_line::handle_line;
+use crate::wiki_sparql::Classes;
+use crossbeam::channel::{Receiver, Sender};
+use reqwest::{header, Url};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, BufReader};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+const STREAM_URL: &str = "https://stream.wikimedia.org/v2/stream/recentchange";
+const API_URL: &str = "https://www.wikidata.org/w/api.php";
+/// Most entities the API returns for one request.
+const BATCH_SIZE: usize = 50;
+/// How long edits are collected before the items are fetched, so that an item that is edited
+/// several times in a row is only fetched once.
+const BATCH_INTERVAL: Duration = Duration::from_secs(10);
+/// How often the number of applied changes is logged.
+const LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Error)]
+pub enum FollowError {
+    #[error("http error: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    #[error("unexpected API response: {0}")]
+    Api(String),
+    #[error("the recent changes stream ended")]
+    StreamEnded,
+    #[error("the database writer has stopped")]
+    Disconnected,
+}
+
+/// An event of the recent changes stream. Only the fields that are needed are parsed.
+#[derive(Deserialize)]
+struct RecentChange {
+    wiki: String,
+    namespace: i64,
+    title: String,
+    meta: EventMeta,
+}
+
+#[derive(Deserialize)]
+struct EventMeta {
+    /// Time of the event (ISO 8601).
+    dt: String,
+}
+
+struct Follower<'a> {
+    options: &'a Options,
+    classes: &'a Classes,
+    sink: &'a Sender<Vec<DataEntry>>,
+    client: reqwest::blocking::Client,
+    /// Items edited since the last batch was fetched.
+    pending: BTreeSet<String>,
+    /// When the first of the pending items was edited.
+    pending_since: Option<Instant>,
+    /// Time of the last event that was read.
+    last_event_time: Option<String>,
+    /// Events read so far, which are counted as input lines in checkpoints.
+    events: u64,
+    items_applied: u64,
+    last_log: Instant,
+}
+
+impl<'a> Follower<'a> {
+    fn send(&self, entries: Vec<DataEntry>) -> Result<(), FollowError> {
+        self.sink
+            .send(entries)
+            .map_err(|_| FollowError::Disconnected)
+    }
+
+    /// Reads the stream until it fails or reading is cancelled.
+    fn read_stream(
+        &mut self,
+        since: Option<&str>,
+        cancel: &Receiver<()>,
+    ) -> Result<(), FollowError> {
+        let mut url = Url::parse(STREAM_URL).expect("bad STREAM_URL!");
+        if let Some(since) = since {
+            url.query_pairs_mut().append_pair("since", since);
+        }
+        let response = self
+            .client
+            .get(url)
+            .header(header::ACCEPT, "text/event-stream")
+            .send()?
+            .error_for_status()?;
+        match since {
+            Some(since) => info!("Following recent changes since {}", since),
+            None => info!("Following recent changes"),
+        }
+
+        for line in BufReader::new(response).lines() {
+            if cancel.try_recv().is_ok() {
+                info!("Received interrupt or termination signal, stopping");
+                return Ok(());
+            }
+            // events are also preceded by event: and id: lines, which aren't needed
+            if let Some(data) = line?.strip_prefix("data: ") {
+                self.push_event(data);
+            }
+            if matches!(self.pending_since, Some(time) if time.elapsed() >= BATCH_INTERVAL) {
+                self.apply_pending()?;
+            }
+        }
+        Err(FollowError::StreamEnded)
+    }
+
+    fn push_event(&mut self, data: &str) {
+        let change: RecentChange = match serde_json::from_str(data) {
+            Ok(change) => change,
+            Err(e) => {
+                debug!("skipping recent change event: {}", e);
+                return;
+            }
+        };
+        self.events += 1;
+        self.last_event_time = Some(change.meta.dt);
+        // items are in the main namespace
+        if change.wiki != "wikidatawiki" || change.namespace != 0 {
+            return;
+        }
+        if self.pending.is_empty() {
+            self.pending_since = Some(Instant::now());
+        }
+        self.pending.insert(change.title);
+    }
+
+    /// Fetches the pending items and replaces their entries.
+    fn apply_pending(&mut self) -> Result<(), FollowError> {
+        let ids: Vec<String> = self.pending.iter().cloned().collect();
+        for batch in ids.chunks(BATCH_SIZE) {
+            for entity in self.fetch(batch)? {
+                self.apply(entity)?;
+            }
+        }
+        self.items_applied += ids.len() as u64;
+        self.pending.clear();
+        self.pending_since = None;
+
+        let mut entries = Vec::new();
+        if let Some(time) = &self.last_event_time {
+            entries.push(DataEntry::Metadata {
+                key: "follow_since".into(),
+                value: time.clone(),
+            });
+        }
+        entries.push(DataEntry::Checkpoint {
+            line_number: self.events,
+            offset: None,
+        });
+        self.send(entries)?;
+
+        if self.last_log.elapsed() >= LOG_INTERVAL {
+            info!(
+                "Applied {} changed items, up to {}",
+                self.items_applied,
+                self.last_event_time.as_deref().unwrap_or("now")
+            );
+            self.last_log = Instant::now();
+        }
+        Ok(())
+    }
+
+    fn fetch(&self, ids: &[String]) -> Result<Vec<Value>, FollowError> {
+        let mut url = Url::parse(API_URL).expect("bad API_URL!");
+        url.query_pairs_mut()
+            .append_pair("action", "wbgetentities")
+            .append_pair("ids", &ids.join("|"))
+            .append_pair("format", "json");
+        let mut response: Value = self.client.get(url).send()?.error_for_status()?.json()?;
+
+        match response.get_mut("entities").map(Value::take) {
+            Some(Value::Object(entities)) => Ok(entities.into_iter().map(|(_, e)| e).collect()),
+            _ => Err(FollowError::Api(match response.get("error") {
+                Some(error) => error.to_string(),
+                None => "no entities".into(),
+            })),
+        }
+    }
+
+    fn apply(&self, entity: Value) -> Result<(), FollowError> {
+        let id = match entity.get("id").and_then(Value::as_str) {
+            Some(id) => id,
+            None => return Err(FollowError::Api("entity without an ID".into())),
+        };
+        if entity.get("missing").is_some() {
+            // deleted
+            return self.send(vec![DataEntry::RemoveEntity { id: id.into() }]);
+        }
+        let mut entries = Vec::new();
+        if let Some(from) = entity.pointer("/redirects/from").and_then(Value::as_str) {
+            // merged into this one
+            entries.push(DataEntry::RemoveEntity { id: from.into() });
+        }
+
+        let line = entity.to_string();
+        if let Err(e) = handle_line(&line, self.classes, self.options, &mut entries, None, false) {
+            error!("error handling {}: {}", id, e);
+        }
+        self.send(entries)
+    }
+}
+
+/// Applies the edits made since the given time (or from now on) until reading is cancelled.
+///
+/// The stream is reconnected after errors with the usual HTTP retry settings, and continues at
+/// the last event that was read.
+pub fn run(
+    options: &Options,
+    classes: &Classes,
+    sink: &Sender<Vec<DataEntry>>,
+    cancel: &Receiver<()>,
+    mut since: Option<String>,
+) -> Result<(), FollowError> {
+    let retry = options.http_settings().retry;
+    let mut follower = Follower {
+        options,
+        classes,
+        sink,
+        client: reqwest::blocking::Client::builder()
+            .user_agent(USER_AGENT)
+            .build()?,
+        pending: BTreeSet::new(),
+        pending_since: None,
+        last_event_time: None,
+        events: 0,
+        items_applied: 0,
+        last_log: Instant::now(),
+    };
+
+    let mut try_count = 0;
+    loop {
+        let events_before = follower.events;
+        let result = follower.read_stream(since.as_deref(), cancel);
+        if follower.events > events_before {
+            try_count = 0;
+        }
+        match result {
+            Ok(()) => break,
+            Err(FollowError::Disconnected) => return Err(FollowError::Disconnected),
+            Err(e) => {
+                try_count += 1;
+                if try_count >= retry.tries {
+                    return Err(e);
+                }
+                let wait = retry.interval(try_count);
+                warn!(
+                    "Following recent changes failed: {}; retrying in {:.0?}",
+                    e, wait
+                );
+                std::thread::sleep(wait);
+                since = follower.last_event_time.clone().or(since);
+            }
+        }
+    }
+
+    follower.apply_pending()?;
+    info!("Applied {} changed items", follower.items_applied);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn classes() -> Classes {
+        let set = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect();
+        Classes {
+            territorial_entities: set(&["Q1221156"]),
+            human_settlements: set(&["Q515"]),
+            excluded: set(&[]),
+            excluded_settlements: set(&[]),
+            second_level_admin_div: set(&[]),
+            third_level_admin_div: set(&[]),
+            sovereign_states: set(&[]),
+            languages: set(&[]),
+            only_country: None,
+        }
+    }
+
+    fn follower<'a>(
+        options: &'a Options,
+        classes: &'a Classes,
+        sink: &'a Sender<Vec<DataEntry>>,
+    ) -> Follower<'a> {
+        Follower {
+            options,
+            classes,
+            sink,
+            client: reqwest::blocking::Client::new(),
+            pending: BTreeSet::new(),
+            pending_since: None,
+            last_event_time: None,
+            events: 0,
+            items_applied: 0,
+            last_log: Instant::now(),
+        }
+    }
+
+    /// Applies the entities as returned by the API and returns the batches of entries that were
+    /// sent, as their variants and IDs.
+    fn apply(entities: &[Value]) -> Vec<Vec<(String, String)>> {
+        let options = Options {
+            update: true,
+            ..Options::default()
+        };
+        let classes = classes();
+        let (send, recv) = crossbeam::channel::unbounded();
+        let follower = follower(&options, &classes, &send);
+        for entity in entities {
+            follower.apply(entity.clone()).unwrap();
+        }
+        drop(send);
+        recv.iter()
+            .map(|entries| {
+                entries
+                    .iter()
+                    .map(|entry| {
+                        let entry = serde_json::to_value(entry).unwrap();
+                        let (variant, fields) = entry.as_object().unwrap().iter().next().unwrap();
+                        (variant.clone(), fields["id"].as_str().unwrap().to_string())
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn entries(entries: &[(&str, &str)]) -> Vec<(String, String)> {
+        entries
+            .iter()
+            .map(|&(variant, id)| (variant.into(), id.into()))
+            .collect()
+    }
+
+    fn city(id: &str) -> Value {
+        let claim = |property: &str, value: u64| {
+            json!([{
+                "mainsnak": {
+                    "snaktype": "value",
+                    "property": property,
+                    "datavalue": {
+                        "value": {"entity-type": "item", "numeric-id": value, "id": format!("Q{}", value)},
+                        "type": "wikibase-entityid"
+                    }
+                },
+                "type": "statement",
+                "rank": "normal"
+            }])
+        };
+        json!({
+            "type": "item",
+            "id": id,
+            "claims": {"P31": claim("P31", 515), "P17": claim("P17", 183)}
+        })
+    }
+
+    #[test]
+    fn apply_edits() {
+        assert_eq!(
+            apply(&[city("Q64")]),
+            [entries(&[
+                ("RemoveEntity", "Q64"),
+                ("CityCountry", "Q64"),
+                ("City", "Q64")
+            ])]
+        );
+        // no longer a city
+        let mut edited = city("Q64");
+        edited["claims"] = json!({});
+        assert_eq!(apply(&[edited]), [entries(&[("RemoveEntity", "Q64")])]);
+    }
+
+    #[test]
+    fn apply_deletions() {
+        assert_eq!(
+            apply(&[json!({"id": "Q64", "missing": ""})]),
+            [entries(&[("RemoveEntity", "Q64")])]
+        );
+    }
+
+    #[test]
+    fn apply_merges() {
+        // Q2 was merged into Q64, so the API returns Q64 when asked for Q2
+        let mut merged = city("Q64");
+        merged["redirects"] = json!({"from": "Q2", "to": "Q64"});
+        assert_eq!(
+            apply(&[merged]),
+            [entries(&[
+                ("RemoveEntity", "Q2"),
+                ("RemoveEntity", "Q64"),
+                ("CityCountry", "Q64"),
+                ("City", "Q64")
+            ])]
+        );
+    }
+
+    #[test]
+    fn reject_entities_without_ids() {
+        let options = Options::default();
+        let classes = classes();
+        let (send, _recv) = crossbeam::channel::unbounded();
+        assert!(matches!(
+            follower(&options, &classes, &send).apply(json!({"missing": ""})),
+            Err(FollowError::Api(_))
+        ));
+    }
+
+    #[test]
+    fn collect_edited_items() {
+        let options = Options::default();
+        let classes = classes();
+        let (send, _recv) = crossbeam::channel::unbounded();
+        let mut follower = follower(&options, &classes, &send);
+        let event = |wiki: &str, namespace: i64, title: &str, dt: &str| {
+            json!({"wiki": wiki, "namespace": namespace, "title": title, "meta": {"dt": dt}})
+                .to_string()
+        };
+        follower.push_event(&event("wikidatawiki", 0, "Q64", "2024-01-01T00:00:00Z"));
+        follower.push_event(&event("wikidatawiki", 0, "Q64", "2024-01-01T00:00:01Z"));
+        // a property, and an edit on another wiki
+        follower.push_event(&event(
+            "wikidatawiki",
+            120,
+            "Property:P17",
+            "2024-01-01T00:00:02Z",
+        ));
+        follower.push_event(&event("dewiki", 0, "Berlin", "2024-01-01T00:00:03Z"));
+        follower.push_event("not an event");
+
+        assert_eq!(follower.pending.iter().collect::<Vec<_>>(), ["Q64"]);
+        assert!(follower.pending_since.is_some());
+        assert_eq!(follower.events, 4);
+        assert_eq!(
+            follower.last_event_time.as_deref(),
+            Some("2024-01-01T00:00:03Z")
+        );
+    }
+}
@@ -0,0 +1,248 @@
+//! Incremental dumps, for updating an existing database with the entities that changed.
+//!
+//! Wikimedia publishes a daily dump of the revisions made on Wikidata that day as MediaWiki XML,
+//! where the text of each revision is the JSON of the entity, in the same format as in the full
+//! dump (apart from `lastrevid`, which is taken from the revision instead). Pages are written one
+//! tag per line, so the XML is read line by line like the JSON dumps, and only the latest revision
+//! of each page is kept.
+
+/// Whether the first line of the input is the start of an XML dump rather than a JSON dump.
+pub fn is_xml(first_line: &str) -> bool {
+    first_line.trim_start().starts_with('<')
+}
+
+/// Collects the entity JSON of the latest revision of each page from the lines of an XML dump.
+#[derive(Default)]
+pub struct XmlRevisions {
+    in_revision: bool,
+    /// ID of the current revision.
+    revision: Option<u64>,
+    /// Content model of the current revision, e.g. wikibase-item.
+    model: Option<String>,
+    /// Entity JSON of the latest revision of the current page, with its revision ID.
+    latest: Option<(String, Option<u64>)>,
+    /// Whether the current page is a redirect, i.e. the entity was merged into another one.
+    redirect: bool,
+}
+
+impl XmlRevisions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads the next line of the dump. Returns the entity JSON of a page at its end, if the page
+    /// is an entity.
+    pub fn push_line(&mut self, line: &str) -> Option<String> {
+        let line = line.trim();
+        if line == "<revision>" {
+            self.in_revision = true;
+            self.revision = None;
+            self.model = None;
+        } else if line == "</revision>" {
+            self.in_revision = false;
+        } else if let Some(id) = tag_content(line, "id") {
+            // pages and contributors have IDs too
+            if self.in_revision && self.revision.is_none() {
+                self.revision = id.parse().ok();
+            }
+        } else if let Some(model) = tag_content(line, "model") {
+            self.model = Some(model.to_string());
+        } else if line.starts_with("<text") {
+            let text = line
+                .find('>')
+                .and_then(|start| line[start + 1..].strip_suffix("</text>"));
+            match text {
+                Some(text) if is_entity_model(self.model.as_deref()) => {
+                    self.latest = Some((unescape(text), self.revision));
+                }
+                Some(_) => (),
+                // empty or deleted (<text ... />) or, unexpectedly, spanning several lines
+                None => debug!("skipping revision {:?} without text", self.revision),
+            }
+        } else if line.starts_with("<redirect") {
+            self.redirect = true;
+        } else if line == "</page>" {
+            let redirect = std::mem::take(&mut self.redirect);
+            let (json, revision) = self.latest.take()?;
+            if redirect {
+                // the text is like {"entity":"Q1","redirect":"Q2"}; an entity without any
+                // statements takes its place so that its entries are removed
+                let entity: serde_json::Value = serde_json::from_str(&json).ok()?;
+                return Some(serde_json::json!({ "id": entity.get("entity")? }).to_string());
+            }
+            return Some(match revision {
+                Some(revision) if json.starts_with('{') && !json.contains("\"lastrevid\"") => {
+                    format!("{{\"lastrevid\":{},{}", revision, &json[1..])
+                }
+                _ => json,
+            });
+        }
+        None
+    }
+}
+
+fn is_entity_model(model: Option<&str>) -> bool {
+    matches!(model, Some(model) if model.starts_with("wikibase-"))
+}
+
+/// Returns the content of a line like `<tag>content</tag>`.
+fn tag_content<'a>(line: &'a str, tag: &str) -> Option<&'a str> {
+    line.strip_prefix('<')?
+        .strip_prefix(tag)?
+        .strip_prefix('>')?
+        .strip_suffix('>')?
+        .strip_suffix(tag)?
+        .strip_suffix("</")
+}
+
+/// Replaces the XML entities that MediaWiki uses in text.
+fn unescape(text: &str) -> String {
+    if !text.contains('&') {
+        return text.to_string();
+    }
+    text.replace("&quot;", "\"")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&#039;", "'")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Entity JSONs of the pages of the lines of a dump.
+    fn pages(xml: &str) -> Vec<String> {
+        let mut revisions = XmlRevisions::new();
+        xml.lines()
+            .filter_map(|line| revisions.push_line(line))
+            .collect()
+    }
+
+    #[test]
+    fn latest_revision() {
+        let xml = r#"  <page>
+    <title>Q64</title>
+    <ns>0</ns>
+    <id>190</id>
+    <revision>
+      <id>100</id>
+      <contributor>
+        <username>Example</username>
+        <id>5</id>
+      </contributor>
+      <model>wikibase-item</model>
+      <format>application/json</format>
+      <text bytes="23" xml:space="preserve">{"type":"item","id":"Q64","labels":{}}</text>
+    </revision>
+    <revision>
+      <id>101</id>
+      <parentid>100</parentid>
+      <model>wikibase-item</model>
+      <format>application/json</format>
+      <text bytes="23" xml:space="preserve">{"type":"item","id":"Q64","claims":{}}</text>
+    </revision>
+  </page>"#;
+        assert_eq!(
+            pages(xml),
+            [r#"{"lastrevid":101,"type":"item","id":"Q64","claims":{}}"#]
+        );
+    }
+
+    #[test]
+    fn revision_id_in_the_text_is_kept() {
+        let xml = r#"<page>
+<revision>
+<id>101</id>
+<model>wikibase-item</model>
+<text xml:space="preserve">{"type":"item","id":"Q64","lastrevid":99}</text>
+</revision>
+</page>"#;
+        assert_eq!(pages(xml), [r#"{"type":"item","id":"Q64","lastrevid":99}"#]);
+    }
+
+    #[test]
+    fn redirect() {
+        let xml = r#"<page>
+<title>Q1</title>
+<redirect title="Q2" />
+<revision>
+<id>101</id>
+<model>wikibase-item</model>
+<text xml:space="preserve">{&quot;entity&quot;:&quot;Q1&quot;,&quot;redirect&quot;:&quot;Q2&quot;}</text>
+</revision>
+</page>
+<page>
+<title>Q3</title>
+<revision>
+<id>102</id>
+<model>wikibase-item</model>
+<text xml:space="preserve">{"type":"item","id":"Q3"}</text>
+</revision>
+</page>"#;
+        assert_eq!(
+            pages(xml),
+            [
+                r#"{"id":"Q1"}"#,
+                r#"{"lastrevid":102,"type":"item","id":"Q3"}"#
+            ]
+        );
+    }
+
+    #[test]
+    fn revisions_without_text() {
+        let xml = r#"<page>
+<revision>
+<id>100</id>
+<model>wikibase-item</model>
+<text bytes="0" />
+</revision>
+</page>
+<page>
+<revision>
+<id>101</id>
+<model>wikibase-item</model>
+<text xml:space="preserve">{"type":"item","id":"Q64"}</text>
+</revision>
+<revision>
+<id>102</id>
+<model>wikibase-item</model>
+<text deleted="deleted" />
+</revision>
+</page>"#;
+        // the deleted revision leaves the one before it as the latest
+        assert_eq!(
+            pages(xml),
+            [r#"{"lastrevid":101,"type":"item","id":"Q64"}"#]
+        );
+    }
+
+    #[test]
+    fn pages_that_are_not_entities() {
+        let xml = r#"<page>
+<title>Wikidata:Main Page</title>
+<revision>
+<id>100</id>
+<model>wikitext</model>
+<text xml:space="preserve">Welcome to Wikidata</text>
+</revision>
+</page>"#;
+        assert!(pages(xml).is_empty());
+    }
+
+    #[test]
+    fn unescape_entities() {
+        assert_eq!(unescape("Berlin"), "Berlin");
+        assert_eq!(
+            unescape("{&quot;en&quot;:&quot;Rock &amp; Roll &lt;3&gt;&quot;}"),
+            r#"{"en":"Rock & Roll <3>"}"#
+        );
+        assert_eq!(
+            unescape("l&#039;Aquila, l&apos;Aquila"),
+            "l'Aquila, l'Aquila"
+        );
+        // escaped entities are only unescaped once
+        assert_eq!(unescape("&amp;quot;&amp;amp;"), "&quot;&amp;");
+    }
+}
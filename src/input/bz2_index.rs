@@ -0,0 +1,175 @@
+//! Seek points of a multi-stream bz2 dump, recorded while decompressing it so that a later run
+//! can start decompressing at a stream in the middle of the dump.
+//!
+//! The index is a text file with one seek point per line: the offset of a stream in the compressed
+//! data and the offset of its contents in the decompressed data, separated by a space. It is only
+//! valid for the dump it was recorded from.
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// The start of a bz2 stream.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SeekPoint {
+    pub compressed: u64,
+    pub decompressed: u64,
+}
+
+pub struct Bz2Index {
+    /// Ordered by offset.
+    points: Vec<SeekPoint>,
+    /// None after a write error.
+    file: Option<BufWriter<File>>,
+    /// Where decompressing started. Recorded offsets are relative to this.
+    start: SeekPoint,
+}
+
+/// Parses the seek points of an index file, ordered by offset.
+///
+/// Lines without a line break at the end were cut off by a crash while they were written, and
+/// are ignored even if they look valid, since a cut off number is still a number.
+fn parse(contents: &str) -> Vec<SeekPoint> {
+    let mut points = Vec::new();
+    for line in contents.split_inclusive('\n') {
+        let offsets = line.strip_suffix('\n').map(|line| {
+            line.split(' ')
+                .map(str::parse)
+                .collect::<Vec<Result<u64, _>>>()
+        });
+        match offsets.as_deref() {
+            Some(&[Ok(compressed), Ok(decompressed)]) => points.push(SeekPoint {
+                compressed,
+                decompressed,
+            }),
+            _ => debug!("ignoring invalid bz2 index line {:?}", line),
+        }
+    }
+    points.sort_by_key(|point| point.compressed);
+    points.dedup();
+    points
+}
+
+impl Bz2Index {
+    /// Loads the seek points in the file (if it exists) and opens it to record more.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let points = match fs::read_to_string(path) {
+            Ok(contents) => parse(&contents),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+
+        // rewritten so that a cut off line doesn't run into the next one
+        let mut file = BufWriter::new(File::create(path)?);
+        for point in &points {
+            writeln!(file, "{} {}", point.compressed, point.decompressed)?;
+        }
+        file.flush()?;
+        debug!(
+            "loaded {} bz2 seek points from {}",
+            points.len(),
+            path.display()
+        );
+
+        Ok(Bz2Index {
+            points,
+            file: Some(file),
+            start: SeekPoint::default(),
+        })
+    }
+
+    /// Returns the last seek point before the offset in the decompressed data, or the start of
+    /// the data for offset 0.
+    ///
+    /// A point at the offset itself isn't used, so that the byte before the offset is read again
+    /// and tells whether the offset is the start of a line.
+    pub fn seek_point(&self, decompressed: u64) -> SeekPoint {
+        self.points
+            .iter()
+            .take_while(|point| point.decompressed < decompressed)
+            .last()
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Sets where decompressing starts.
+    pub fn start_at(&mut self, point: SeekPoint) {
+        self.start = point;
+    }
+
+    pub fn start(&self) -> SeekPoint {
+        self.start
+    }
+
+    /// Records the start of a stream, with offsets relative to where decompressing started.
+    pub fn record(&mut self, compressed: u64, decompressed: u64) {
+        let point = SeekPoint {
+            compressed: self.start.compressed + compressed,
+            decompressed: self.start.decompressed + decompressed,
+        };
+        if matches!(self.points.last(), Some(last) if last.compressed >= point.compressed) {
+            // already known from an earlier run
+            return;
+        }
+        self.points.push(point);
+
+        if let Some(file) = &mut self.file {
+            let result = writeln!(file, "{} {}", point.compressed, point.decompressed)
+                .and_then(|_| file.flush());
+            if let Err(e) = result {
+                warn!("Stopped recording bz2 seek points: {}", e);
+                self.file = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(compressed: u64, decompressed: u64) -> SeekPoint {
+        SeekPoint {
+            compressed,
+            decompressed,
+        }
+    }
+
+    fn index(points: Vec<SeekPoint>) -> Bz2Index {
+        Bz2Index {
+            points,
+            file: None,
+            start: SeekPoint::default(),
+        }
+    }
+
+    #[test]
+    fn parse_cut_off_lines() {
+        assert_eq!(
+            parse("900 4000\n0 0\n900 4000\n1800 81"),
+            vec![point(0, 0), point(900, 4000)]
+        );
+        assert_eq!(
+            parse("0 0\n900\n900 4000 1\n9x0 4000\n\n"),
+            vec![point(0, 0)]
+        );
+        assert_eq!(parse(""), vec![]);
+    }
+
+    #[test]
+    fn seek_point_before_offset() {
+        let index = index(vec![point(0, 0), point(900, 4000), point(1800, 8000)]);
+        assert_eq!(index.seek_point(0), point(0, 0));
+        assert_eq!(index.seek_point(1), point(0, 0));
+        assert_eq!(index.seek_point(4000), point(0, 0));
+        assert_eq!(index.seek_point(4001), point(900, 4000));
+        assert_eq!(index.seek_point(8000), point(900, 4000));
+        assert_eq!(index.seek_point(u64::MAX), point(1800, 8000));
+    }
+
+    #[test]
+    fn seek_point_without_points() {
+        let index = index(vec![]);
+        assert_eq!(index.seek_point(4000), SeekPoint::default());
+    }
+}
@@ -0,0 +1,47 @@
+//! Keeps a copy of the downloaded dump for later runs.
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// The compressed data as downloaded. It is written to `<path>.part` and only renamed to `path`
+/// once the download is complete, so an existing `path` can always be used instead of the URL.
+pub struct DownloadCache {
+    path: PathBuf,
+    part_path: PathBuf,
+    file: BufWriter<File>,
+}
+
+impl DownloadCache {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let mut part_path = path.as_os_str().to_owned();
+        part_path.push(".part");
+        let part_path = PathBuf::from(part_path);
+        let file = BufWriter::new(File::create(&part_path)?);
+        Ok(DownloadCache {
+            path: path.into(),
+            part_path,
+            file,
+        })
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.file.write_all(data)
+    }
+
+    /// Completes the cache file.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.file.flush()?;
+        fs::rename(&self.part_path, &self.path)?;
+        info!("Saved the download to {}", self.path.display());
+        Ok(())
+    }
+
+    /// Removes the partial file, e.g. because the data is corrupt.
+    pub fn discard(self) {
+        drop(self.file);
+        if let Err(e) = fs::remove_file(&self.part_path) {
+            warn!("Failed to remove {}: {}", self.part_path.display(), e);
+        }
+    }
+}
@@ -0,0 +1,198 @@
+//! Verifies the dump against a published checksum file (e.g. `wikidata-20240101-md5sums.txt`).
+
+use crate::input::http::USER_AGENT;
+use std::fs;
+use std::io;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ChecksumError {
+    #[error("could not download checksums: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("could not read checksums: {0}")]
+    Io(#[from] io::Error),
+    #[error("{0} is not listed in the checksum file")]
+    NotListed(String),
+    #[error("unsupported digest {0:?} (expected MD5 or SHA-1)")]
+    UnknownDigest(String),
+    #[error("checksum mismatch: expected {expected}, got {actual}")]
+    Mismatch { expected: String, actual: String },
+}
+
+/// The expected digest of the dump.
+#[derive(Debug, Clone)]
+pub struct Checksum {
+    expected: String,
+    /// Whether a mismatch is only logged instead of failing the input.
+    warn_only: bool,
+}
+
+impl Checksum {
+    /// Loads the digest of `file_name` from a list of `<digest>  <file name>` lines (as written by
+    /// `md5sum` or `sha1sum`) at the given URL or path.
+    pub fn load(sums: &str, file_name: &str, warn_only: bool) -> Result<Self, ChecksumError> {
+        let sums = if sums.starts_with("http://") || sums.starts_with("https://") {
+            reqwest::blocking::Client::builder()
+                .user_agent(USER_AGENT)
+                .build()?
+                .get(sums)
+                .send()?
+                .error_for_status()?
+                .text()?
+        } else {
+            fs::read_to_string(sums)?
+        };
+
+        for line in sums.lines() {
+            let mut parts = line.split_whitespace();
+            if let (Some(digest), Some(name)) = (parts.next(), parts.next()) {
+                // binary mode is marked with a *
+                if name.trim_start_matches('*') == file_name {
+                    let expected = digest.to_ascii_lowercase();
+                    Hasher::for_digest(&expected)?;
+                    return Ok(Checksum {
+                        expected,
+                        warn_only,
+                    });
+                }
+            }
+        }
+        Err(ChecksumError::NotListed(file_name.into()))
+    }
+}
+
+enum Hasher {
+    Md5(md5::Context),
+    Sha1(sha1_smol::Sha1),
+}
+
+impl Hasher {
+    /// Picks the hash function by the length of the expected digest.
+    fn for_digest(digest: &str) -> Result<Self, ChecksumError> {
+        match digest.len() {
+            32 => Ok(Hasher::Md5(md5::Context::new())),
+            40 => Ok(Hasher::Sha1(sha1_smol::Sha1::new())),
+            _ => Err(ChecksumError::UnknownDigest(digest.into())),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Md5(context) => context.consume(data),
+            Hasher::Sha1(sha1) => sha1.update(data),
+        }
+    }
+
+    fn finish(self) -> String {
+        match self {
+            Hasher::Md5(context) => format!("{:x}", context.compute()),
+            Hasher::Sha1(sha1) => sha1.digest().to_string(),
+        }
+    }
+}
+
+/// Running digest of the data, compared to the checksum at the end.
+pub struct Digest {
+    hasher: Hasher,
+    checksum: Checksum,
+}
+
+impl Digest {
+    pub fn new(checksum: Checksum) -> Self {
+        Digest {
+            hasher: Hasher::for_digest(&checksum.expected).expect("checked when loading"),
+            checksum,
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+    }
+
+    /// Compares the digest of all data so far to the checksum.
+    pub fn verify(self) -> io::Result<()> {
+        let actual = self.hasher.finish();
+        if actual == self.checksum.expected {
+            info!("Checksum verified: {}", actual);
+            return Ok(());
+        }
+        let err = ChecksumError::Mismatch {
+            expected: self.checksum.expected,
+            actual,
+        };
+        if self.checksum.warn_only {
+            warn!("{}", err);
+            Ok(())
+        } else {
+            Err(io::Error::new(io::ErrorKind::InvalidData, err))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATA: &[u8] = b"The quick brown fox jumps over the lazy dog";
+    const MD5: &str = "9e107d9d372bb6826bd81d3542a419d6";
+    const SHA1: &str = "2fd4e1c67a2d28fced849ee1bb76e7391b93eb12";
+
+    fn load(sums: &str, file_name: &str, warn_only: bool) -> Result<Checksum, ChecksumError> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("md5sums.txt");
+        fs::write(&path, sums).unwrap();
+        Checksum::load(path.to_str().unwrap(), file_name, warn_only)
+    }
+
+    fn verify(checksum: Checksum, data: &[u8]) -> io::Result<()> {
+        let mut digest = Digest::new(checksum);
+        for chunk in data.chunks(7) {
+            digest.update(chunk);
+        }
+        digest.verify()
+    }
+
+    #[test]
+    fn matching_digests() {
+        for expected in [MD5, SHA1, &MD5.to_ascii_uppercase()] {
+            let sums = format!("0123  other.json.bz2\n{}  dump.json.bz2\n", expected);
+            verify(load(&sums, "dump.json.bz2", false).unwrap(), DATA).unwrap();
+        }
+        // binary mode
+        let sums = format!("{} *dump.json.bz2\n", SHA1);
+        verify(load(&sums, "dump.json.bz2", false).unwrap(), DATA).unwrap();
+    }
+
+    #[test]
+    fn mismatched_digests() {
+        for expected in [MD5, SHA1] {
+            let sums = format!("{}  dump.json.bz2\n", expected);
+            let err = verify(load(&sums, "dump.json.bz2", false).unwrap(), b"other").unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(matches!(
+                err.get_ref().and_then(|err| err.downcast_ref()),
+                Some(ChecksumError::Mismatch { expected: e, .. }) if e == expected
+            ));
+            // only logged
+            verify(load(&sums, "dump.json.bz2", true).unwrap(), b"other").unwrap();
+        }
+    }
+
+    #[test]
+    fn missing_entries() {
+        let sums = format!("{}  other.json.bz2\n", MD5);
+        assert!(matches!(
+            load(&sums, "dump.json.bz2", false),
+            Err(ChecksumError::NotListed(name)) if name == "dump.json.bz2"
+        ));
+        // a prefix of a listed name isn't listed
+        assert!(matches!(
+            load(&sums, "other.json", false),
+            Err(ChecksumError::NotListed(_))
+        ));
+        assert!(matches!(
+            load("0123abc  dump.json.bz2\n", "dump.json.bz2", false),
+            Err(ChecksumError::UnknownDigest(_))
+        ));
+    }
+}
@@ -1,13 +1,55 @@
+use crate::input::bz2_index::Bz2Index;
+use crate::input::checksum::ChecksumError;
+use crate::input::par_bz2::ParBzDecoder;
+use crate::input::InputError;
+use bzip2::bufread;
 use bzip2::read::BzDecoder;
-use bzip2_rs::decoder::ParallelDecoder;
-use bzip2_rs::RayonThreadPool;
-use std::io::{self, Read};
+use flate2::bufread::MultiGzDecoder;
+use std::io::{self, BufRead, Read};
+use thiserror::Error;
+
+/// Decoders report corrupt data as an IO error wrapping their own error type.
+impl InputError for io::Error {
+    fn is_decompress(&self) -> bool {
+        matches!(
+            self.get_ref(),
+            Some(inner) if inner.is::<bzip2::Error>()
+                || inner.is::<DecodeError>()
+                || inner.is::<FormatError>()
+                || inner.is::<ChecksumError>()
+        )
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum FormatError {
+    #[error("unrecognized compression format (data starts with {0:02x?})")]
+    Unknown(Vec<u8>),
+}
+
+/// flate2 and zstd report corrupt data as a plain IO error, so [`GzDecoder`] and [`ZstdDecoder`]
+/// wrap it in this instead.
+#[derive(Debug, Error)]
+#[error("{0}")]
+pub struct DecodeError(String);
 
 pub trait DecompressingReader<R>: Read {
     fn new(r: R) -> Self;
+    /// Creates a decoder that decompresses on up to `threads` threads, if the format can be
+    /// decompressed in parallel.
+    fn with_threads(r: R, _threads: usize) -> Self
+    where
+        Self: Sized,
+    {
+        Self::new(r)
+    }
     fn inner(&self) -> &R;
     fn inner_mut(&mut self) -> &mut R;
     fn total_in(&self) -> u64;
+    /// Records seek points to the index while decompressing, if the format has any.
+    fn set_index(&mut self, _index: Bz2Index) {
+        warn!("Seek points can only be recorded for bz2 data");
+    }
 }
 
 impl<R> DecompressingReader<R> for BzDecoder<R>
@@ -28,59 +70,267 @@ where
     }
 }
 
-/// Higher throughput Bzip2 decoder but it seems to occasionally decode things incorrectly?
-pub struct ParBzDecoder<R> {
-    decoder: ParallelDecoder<RayonThreadPool>,
+/// Like `BzDecoder`, but reads from a buffered reader chosen by the caller instead of wrapping the
+/// input in a fixed-size buffer.
+impl<R> DecompressingReader<R> for bufread::BzDecoder<R>
+where
+    R: BufRead,
+{
+    fn new(r: R) -> Self {
+        bufread::BzDecoder::new(r)
+    }
+    fn inner(&self) -> &R {
+        self.get_ref()
+    }
+    fn inner_mut(&mut self) -> &mut R {
+        self.get_mut()
+    }
+    fn total_in(&self) -> u64 {
+        self.total_in()
+    }
+}
+
+/// Counts the bytes consumed from a buffered reader.
+pub struct CountingReader<R> {
     inner: R,
-    total_in: u64,
+    count: u64,
+    /// Whether the last error came from the inner reader rather than the decoder.
+    inner_failed: bool,
 }
-impl<R> DecompressingReader<R> for ParBzDecoder<R>
+
+impl<R> CountingReader<R> {
+    fn new(inner: R) -> Self {
+        CountingReader {
+            inner,
+            count: 0,
+            inner_failed: false,
+        }
+    }
+
+    /// Marks errors returned by the decoder as corrupt data unless they came from reading.
+    fn decode_error(&mut self, err: io::Error) -> io::Error {
+        if std::mem::take(&mut self.inner_failed) {
+            err
+        } else {
+            io::Error::new(io::ErrorKind::InvalidData, DecodeError(err.to_string()))
+        }
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let result = self.inner.read(buf);
+        self.inner_failed = result.is_err();
+        let read = result?;
+        self.count += read as u64;
+        Ok(read)
+    }
+}
+
+impl<R: BufRead> BufRead for CountingReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        let result = self.inner.fill_buf();
+        self.inner_failed = result.is_err();
+        result
+    }
+    fn consume(&mut self, amt: usize) {
+        self.count += amt as u64;
+        self.inner.consume(amt)
+    }
+}
+
+/// Gzip decoder for (possibly multi-member) gzip streams, which is a lot faster than bzip2.
+///
+/// Like `bufread::BzDecoder`, it only consumes the input it has decompressed, so the inner reader
+/// can be replaced after a reconnect.
+pub struct GzDecoder<R> {
+    decoder: MultiGzDecoder<CountingReader<R>>,
+}
+impl<R> DecompressingReader<R> for GzDecoder<R>
 where
-    R: Read,
+    R: BufRead,
 {
     fn new(r: R) -> Self {
-        Self {
-            decoder: ParallelDecoder::new(RayonThreadPool, 99999999999),
-            inner: r,
-            total_in: 0,
+        GzDecoder {
+            decoder: MultiGzDecoder::new(CountingReader::new(r)),
         }
     }
     fn inner(&self) -> &R {
-        &self.inner
+        &self.decoder.get_ref().inner
     }
     fn inner_mut(&mut self) -> &mut R {
-        &mut self.inner
+        &mut self.decoder.get_mut().inner
     }
     fn total_in(&self) -> u64 {
-        self.total_in
+        self.decoder.get_ref().count
     }
 }
-impl<R> Read for ParBzDecoder<R>
+impl<R> Read for GzDecoder<R>
 where
-    R: Read,
+    R: BufRead,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.decoder.read(buf) {
+            Ok(read) => Ok(read),
+            Err(err) => Err(self.decoder.get_mut().decode_error(err)),
+        }
+    }
+}
+
+/// Decoder for (possibly multi-frame) zstd streams, e.g. a dump that was recompressed locally.
+///
+/// Like [`GzDecoder`], it only consumes the input it has decompressed.
+pub struct ZstdDecoder<R: BufRead> {
+    decoder: zstd::Decoder<'static, CountingReader<R>>,
+}
+impl<R> DecompressingReader<R> for ZstdDecoder<R>
+where
+    R: BufRead,
+{
+    fn new(r: R) -> Self {
+        ZstdDecoder {
+            decoder: zstd::Decoder::with_buffer(CountingReader::new(r))
+                .expect("failed to create zstd decoder"),
+        }
+    }
+    fn inner(&self) -> &R {
+        &self.decoder.get_ref().inner
+    }
+    fn inner_mut(&mut self) -> &mut R {
+        &mut self.decoder.get_mut().inner
+    }
+    fn total_in(&self) -> u64 {
+        self.decoder.get_ref().count
+    }
+}
+impl<R> Read for ZstdDecoder<R>
+where
+    R: BufRead,
 {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let mut read_zero = false;
-        let mut read_buf = [0; 16384];
-
-        loop {
-            match self.decoder.read(buf)? {
-                bzip2_rs::decoder::ReadState::NeedsWrite(space) => {
-                    let read = self.inner.read(&mut read_buf[..space.min(16384)])?;
-                    if read_zero {
-                        // FIXME: what is this exactly?
-                        return Err(io::Error::new(
-                            io::ErrorKind::UnexpectedEof,
-                            "unexpected EOF while reading compressed data",
-                        ));
-                    }
-                    self.total_in += read as u64;
-                    read_zero = read == 0;
-                    self.decoder.write(&read_buf[..read])?;
-                }
-                bzip2_rs::decoder::ReadState::Read(n) => return Ok(n),
-                bzip2_rs::decoder::ReadState::Eof => return Ok(0),
+        match self.decoder.read(buf) {
+            Ok(read) => Ok(read),
+            Err(err) => Err(self.decoder.get_mut().decode_error(err)),
+        }
+    }
+}
+
+/// Picks a decoder by the magic bytes at the start of the data: bz2, gzip, zstd, or uncompressed
+/// JSON.
+pub struct AutoDecoder<R: BufRead> {
+    format: Format<R>,
+    /// Passed to the decoder once the format is known.
+    index: Option<Bz2Index>,
+    /// Number of threads of the bz2 decoder.
+    threads: usize,
+}
+
+enum Format<R: BufRead> {
+    /// Nothing has been read yet. Only `None` while switching to the detected format.
+    Pending(Option<R>),
+    Bz2(ParBzDecoder<R>),
+    Gz(GzDecoder<R>),
+    Zstd(ZstdDecoder<R>),
+    Plain(CountingReader<R>),
+}
+
+impl<R> AutoDecoder<R>
+where
+    R: BufRead,
+{
+    fn detect(&mut self) -> io::Result<()> {
+        let inner = match &mut self.format {
+            Format::Pending(inner) => inner,
+            _ => return Ok(()),
+        };
+        // this may be less than the longest magic number if the first read was short, but
+        // buffered readers fill up way past that in practice
+        let start = inner.as_mut().unwrap().fill_buf()?;
+        let format_error = |err| io::Error::new(io::ErrorKind::InvalidData, err);
+        let detected = match start {
+            [b'B', b'Z', b'h', ..] => Format::Bz2(ParBzDecoder::with_threads(
+                inner.take().unwrap(),
+                self.threads,
+            )),
+            [0x1f, 0x8b, ..] => Format::Gz(GzDecoder::new(inner.take().unwrap())),
+            [0x28, 0xb5, 0x2f, 0xfd, ..] => Format::Zstd(ZstdDecoder::new(inner.take().unwrap())),
+            // the dump is a JSON array with one entity per line; empty data is passed through too
+            [] | [b'[', ..] | [b'{', ..] => {
+                Format::Plain(CountingReader::new(inner.take().unwrap()))
+            }
+            _ => {
+                let start = start[..start.len().min(4)].to_vec();
+                return Err(format_error(FormatError::Unknown(start)));
+            }
+        };
+        self.format = detected;
+        if let Some(index) = self.index.take() {
+            match &mut self.format {
+                Format::Bz2(decoder) => decoder.set_index(index),
+                _ => warn!("Seek points can only be recorded for bz2 data"),
             }
         }
+        Ok(())
+    }
+}
+
+impl<R> DecompressingReader<R> for AutoDecoder<R>
+where
+    R: BufRead,
+{
+    fn new(r: R) -> Self {
+        Self::with_threads(r, rayon_core::current_num_threads())
+    }
+    fn with_threads(r: R, threads: usize) -> Self {
+        AutoDecoder {
+            format: Format::Pending(Some(r)),
+            index: None,
+            threads,
+        }
+    }
+    fn inner(&self) -> &R {
+        match &self.format {
+            Format::Pending(inner) => inner.as_ref().unwrap(),
+            Format::Bz2(decoder) => decoder.inner(),
+            Format::Gz(decoder) => decoder.inner(),
+            Format::Zstd(decoder) => decoder.inner(),
+            Format::Plain(reader) => &reader.inner,
+        }
+    }
+    fn inner_mut(&mut self) -> &mut R {
+        match &mut self.format {
+            Format::Pending(inner) => inner.as_mut().unwrap(),
+            Format::Bz2(decoder) => decoder.inner_mut(),
+            Format::Gz(decoder) => decoder.inner_mut(),
+            Format::Zstd(decoder) => decoder.inner_mut(),
+            Format::Plain(reader) => &mut reader.inner,
+        }
+    }
+    fn total_in(&self) -> u64 {
+        match &self.format {
+            Format::Pending(_) => 0,
+            Format::Bz2(decoder) => decoder.total_in(),
+            Format::Gz(decoder) => decoder.total_in(),
+            Format::Zstd(decoder) => decoder.total_in(),
+            Format::Plain(reader) => reader.count,
+        }
+    }
+    fn set_index(&mut self, index: Bz2Index) {
+        self.index = Some(index);
+    }
+}
+impl<R> Read for AutoDecoder<R>
+where
+    R: BufRead,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.detect()?;
+        match &mut self.format {
+            Format::Pending(_) => unreachable!("format was detected"),
+            Format::Bz2(decoder) => decoder.read(buf),
+            Format::Gz(decoder) => decoder.read(buf),
+            Format::Zstd(decoder) => decoder.read(buf),
+            Format::Plain(reader) => reader.read(buf),
+        }
     }
 }
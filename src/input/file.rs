@@ -1,41 +1,78 @@
-use crate::input::compression::{DecompressingReader, ParBzDecoder};
+use crate::input::bz2_index::Bz2Index;
+use crate::input::checksum::Checksum;
+use crate::input::compression::{AutoDecoder, DecompressingReader, GzDecoder, ZstdDecoder};
+use crate::input::par_bz2::ParBzDecoder;
+use crate::input::source::SourceReader;
 use crate::input::DataInput;
-use bzip2::read::BzDecoder;
-use std::{fs, io};
+use bzip2::bufread::BzDecoder;
+use std::fs;
+use std::io::{self, BufReader, Seek};
 
 pub struct FileInput<B> {
     read: B,
     size: u64,
 }
 
+pub type Bz2FileInput = FileInput<BzDecoder<SourceReader<fs::File>>>;
 #[allow(dead_code)]
-pub type Bz2FileInput = FileInput<BzDecoder<fs::File>>;
+pub type ParBz2FileInput = FileInput<ParBzDecoder<SourceReader<fs::File>>>;
 #[allow(dead_code)]
-pub type ParBz2FileInput = FileInput<ParBzDecoder<fs::File>>;
+pub type GzFileInput = FileInput<GzDecoder<SourceReader<fs::File>>>;
+#[allow(dead_code)]
+pub type ZstdFileInput = FileInput<ZstdDecoder<SourceReader<fs::File>>>;
+/// Detects the compression format from the data.
+pub type AutoFileInput = FileInput<AutoDecoder<SourceReader<fs::File>>>;
 
 impl<B> FileInput<B>
 where
-    B: DecompressingReader<fs::File>,
+    B: DecompressingReader<SourceReader<fs::File>>,
 {
-    #[allow(dead_code)]
     pub fn new(file: fs::File) -> Self {
-        let size = file.metadata().unwrap().len();
+        Self::with_checksum(file, None, rayon_core::current_num_threads())
+    }
+
+    /// Creates an input that verifies the file against the checksum when reaching the end, and
+    /// decompresses it on up to `decoder_threads` threads.
+    ///
+    /// Reading starts at the current position of the file, and the size only counts the rest.
+    pub fn with_checksum(
+        mut file: fs::File,
+        checksum: Option<Checksum>,
+        decoder_threads: usize,
+    ) -> Self {
+        let position = file.stream_position().unwrap();
+        let size = file.metadata().unwrap().len() - position;
 
         FileInput {
-            read: B::new(file),
+            read: B::with_threads(
+                SourceReader::new(BufReader::new(file)).with_checksum(checksum),
+                decoder_threads,
+            ),
             size,
         }
     }
+
+    /// Records seek points to the index while reading.
+    pub fn with_index(mut self, index: Option<Bz2Index>) -> Self {
+        if let Some(index) = index {
+            self.read.set_index(index);
+        }
+        self
+    }
 }
 
 impl<B> DataInput for FileInput<B>
 where
-    B: DecompressingReader<fs::File>,
+    B: DecompressingReader<SourceReader<fs::File>>,
 {
     type Error = io::Error;
 
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
-        self.read.read(buf)
+        let read = self.read.read(buf)?;
+        if read == 0 {
+            self.read.inner_mut().finish()?;
+        }
+        Ok(read)
     }
 
     fn bytes_read(&self) -> u64 {
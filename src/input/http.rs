@@ -1,25 +1,142 @@
-use crate::input::compression::{DecompressingReader, ParBzDecoder};
-use crate::input::DataInput;
-use bzip2::read::BzDecoder;
+use crate::input::bz2_index::Bz2Index;
+use crate::input::cache::DownloadCache;
+use crate::input::checksum::Checksum;
+use crate::input::compression::{AutoDecoder, DecompressingReader, GzDecoder, ZstdDecoder};
+use crate::input::par_bz2::ParBzDecoder;
+use crate::input::s3;
+use crate::input::source::SourceReader;
+use crate::input::{DataInput, InputError};
+use bzip2::bufread::BzDecoder;
 use reqwest::blocking::Response;
 use reqwest::header::{self, HeaderMap, HeaderValue};
-use std::io::{self, Read};
+use std::io::{self, BufReader, Read};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use thiserror::Error;
 
+/// The latest full Wikidata dump.
+pub const DUMP_URL: &str = "https://dumps.wikimedia.org/wikidatawiki/entities/latest-all.json.bz2";
+
+/// The full Wikidata dump of a date like 20240101. Only the last few dumps are kept.
+pub fn dated_dump_url(date: &str) -> String {
+    format!(
+        "https://dumps.wikimedia.org/wikidatawiki/entities/{0}/wikidata-{0}-all.json.bz2",
+        date
+    )
+}
+
+/// The daily incremental dump of a date like 20240101, with the revisions made that day as XML.
+/// Only the last few weeks are kept.
+pub fn incr_dump_url(date: &str) -> String {
+    format!(
+        "https://dumps.wikimedia.org/other/incr/wikidatawiki/{0}/wikidatawiki-{0}-pages-meta-hist-incr.xml.bz2",
+        date
+    )
+}
 pub const USER_AGENT: &str = "AKSO geo-db (+https://akso.org)";
-const MAX_OPEN_TRIES: usize = 32;
-const OPEN_RETRY_INTERVAL_SECS: u64 = 8;
+/// Default size of the buffer between the socket and the decompressor.
+pub const DEFAULT_BUFFER_BYTES: usize = 1024 * 1024;
+/// Size of the download used to measure the speed of a mirror.
+const PROBE_BYTES: u64 = 4 * 1024 * 1024;
+const PROBE_TIMEOUT_SECS: u64 = 30;
+/// Longest wait before a retry, whatever the retry settings.
+const MAX_RETRY_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How often and how long to wait before giving up on a connection.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryConfig {
+    /// Connection attempts before giving up, including the first one.
+    pub tries: usize,
+    /// Wait before the first retry. This doubles with every try up to `max_interval_secs`.
+    pub interval_secs: f64,
+    pub max_interval_secs: f64,
+    /// Each wait is randomly shortened or lengthened by up to this fraction of it.
+    pub jitter: f64,
+}
+
+impl Default for RetryConfig {
+    /// Backs off so that outages of up to a couple of hours in total don't end the download.
+    fn default() -> Self {
+        RetryConfig {
+            tries: 32,
+            interval_secs: 8.,
+            max_interval_secs: 300.,
+            jitter: 0.2,
+        }
+    }
+}
+
+/// Settings for downloading the dump.
+#[derive(Debug, Clone)]
+pub struct HttpSettings {
+    /// Other URLs of the same file.
+    pub mirrors: Vec<String>,
+    /// Size of the buffer between the socket and the decompressor.
+    pub buffer_bytes: usize,
+    pub retry: RetryConfig,
+    /// Download rate limit in bytes per second.
+    pub max_rate: Option<f64>,
+    /// Where to save the downloaded data. If this file exists, it is read instead.
+    pub cache_path: Option<String>,
+}
+
+impl RetryConfig {
+    /// Time to wait after the given (failed) try.
+    pub fn interval(&self, try_count: usize) -> Duration {
+        let backoff = self.interval_secs * 2_f64.powi(try_count.saturating_sub(1).min(16) as i32);
+        let secs = backoff.min(self.max_interval_secs);
+        // there is no rand dependency, and this only needs to spread out clients a bit
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.subsec_nanos())
+            .unwrap_or(0);
+        let random = nanos as f64 / 1_000_000_000. * 2. - 1.;
+        // settings can be anything, so waits that are too long or infinite are clamped, and
+        // negative or NaN ones don't wait
+        let secs = (secs * (1. + self.jitter * random)).max(0.);
+        Duration::try_from_secs_f64(secs).map_or(MAX_RETRY_INTERVAL, |interval| {
+            interval.min(MAX_RETRY_INTERVAL)
+        })
+    }
+}
 
 /// Reentrant HTTP data input. If interrupted, will attempt to re-establish connection and seek
 /// to the appropriate location.
+///
+/// If the source can't be reached before any data was read, the fallback URLs are tried in order.
+/// Once the download has started, it only resumes from the same URL, since mirrors may serve a
+/// different version of the file.
 pub struct HttpDataInput<B> {
     src_url: String,
+    fallback_urls: Vec<String>,
+    buffer_bytes: usize,
+    retry: RetryConfig,
+    max_rate: Option<f64>,
+    /// Moved into the reader when the first connection is opened.
+    checksum: Option<Checksum>,
+    cache: Option<DownloadCache>,
+    /// Signs every request, e.g. for S3.
+    signer: Option<s3::Signer>,
+    /// Moved into the decoder when the first connection is opened.
+    index: Option<Bz2Index>,
+    /// Number of threads the download is decompressed on.
+    decoder_threads: usize,
+    /// Offset in the file where the download starts.
+    start_offset: u64,
+    /// When rate limiting started, and the bytes read at that point.
+    rate_start: Option<(Instant, u64)>,
     state: Option<HttpDataInputState<B>>,
 }
 
-pub type HttpBz2DataInput = HttpDataInput<BzDecoder<Response>>;
 #[allow(dead_code)]
-pub type HttpParBz2DataInput = HttpDataInput<ParBzDecoder<Response>>;
+pub type HttpBz2DataInput = HttpDataInput<BzDecoder<SourceReader<Response>>>;
+#[allow(dead_code)]
+pub type HttpParBz2DataInput = HttpDataInput<ParBzDecoder<SourceReader<Response>>>;
+#[allow(dead_code)]
+pub type HttpGzDataInput = HttpDataInput<GzDecoder<SourceReader<Response>>>;
+#[allow(dead_code)]
+pub type HttpZstdDataInput = HttpDataInput<ZstdDecoder<SourceReader<Response>>>;
+/// Detects the compression format from the data.
+pub type HttpAutoDataInput = HttpDataInput<AutoDecoder<SourceReader<Response>>>;
 
 /// HttpDataInput state. Exists during download.
 struct HttpDataInputState<B> {
@@ -36,15 +153,85 @@ struct HttpDataInputState<B> {
 
 impl<B> HttpDataInput<B>
 where
-    B: DecompressingReader<Response>,
+    B: DecompressingReader<SourceReader<Response>>,
 {
-    pub fn new(src_url: String) -> Self {
+    /// Creates a new HTTP input reading through a buffer of `buffer_bytes` bytes.
+    pub fn new(src_url: String, buffer_bytes: usize, retry: RetryConfig) -> Self {
         HttpDataInput {
             src_url,
+            fallback_urls: Vec::new(),
+            buffer_bytes,
+            retry,
+            max_rate: None,
+            checksum: None,
+            cache: None,
+            signer: None,
+            index: None,
+            decoder_threads: rayon_core::current_num_threads(),
+            start_offset: 0,
+            rate_start: None,
             state: None,
         }
     }
 
+    /// Sets URLs of the same file to try in order if the source can't be reached.
+    pub fn with_fallbacks(mut self, fallback_urls: Vec<String>) -> Self {
+        self.fallback_urls = fallback_urls;
+        self
+    }
+
+    /// Limits the average download rate to this many bytes per second.
+    pub fn with_max_rate(mut self, max_rate: Option<f64>) -> Self {
+        self.max_rate = max_rate;
+        self
+    }
+
+    /// Verifies the download against the checksum when reaching the end.
+    pub fn with_checksum(mut self, checksum: Option<Checksum>) -> Self {
+        self.checksum = checksum;
+        self
+    }
+
+    /// Saves the downloaded data to the cache file.
+    pub fn with_cache(mut self, cache: Option<DownloadCache>) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    pub fn with_signer(mut self, signer: Option<s3::Signer>) -> Self {
+        self.signer = signer;
+        self
+    }
+
+    /// Decompresses the download on up to this many threads.
+    pub fn with_decoder_threads(mut self, decoder_threads: usize) -> Self {
+        self.decoder_threads = decoder_threads;
+        self
+    }
+
+    /// Starts the download at the start point of the index, and records seek points to it.
+    pub fn with_index(mut self, index: Option<Bz2Index>) -> Self {
+        self.start_offset = index.as_ref().map_or(0, |index| index.start().compressed);
+        self.index = index;
+        self
+    }
+
+    /// Sleeps until the bytes read so far are within the rate limit.
+    fn throttle(&mut self) {
+        let max_rate = match self.max_rate {
+            Some(max_rate) => max_rate,
+            None => return,
+        };
+        let bytes_read = self.bytes_read();
+        let (start, start_bytes) = *self
+            .rate_start
+            .get_or_insert_with(|| (Instant::now(), bytes_read));
+        let allowed_time = Duration::from_secs_f64((bytes_read - start_bytes) as f64 / max_rate);
+        if let Some(wait) = allowed_time.checked_sub(start.elapsed()) {
+            std::thread::sleep(wait);
+        }
+    }
+
     pub fn open(&mut self) -> Result<(), HttpError> {
         let client = reqwest::blocking::Client::builder()
             .user_agent(USER_AGENT)
@@ -52,19 +239,31 @@ where
 
         debug!("opening new connection");
 
+        // offset of the next byte the decompressor (or the drain after it) needs
+        let cursor = self.start_offset
+            + self
+                .state
+                .as_ref()
+                .map_or(0, |s| s.read.total_in() + s.read.inner().drained());
         let mut headers = HeaderMap::new();
-        if let Some(state) = &self.state {
+        let range = if cursor > 0 {
             debug!(
-                "setting HTTP range header because we already read some data (cursor: {})",
-                state.read.total_in()
+                "setting HTTP range header because we don't start at the beginning (cursor: {})",
+                cursor
             );
+            let range = format!("bytes={}-", cursor);
             headers.append(
                 header::RANGE,
-                HeaderValue::from_str(&format!("bytes={}-", state.read.total_in()))
-                    .expect("failed to create range header"),
+                HeaderValue::from_str(&range).expect("failed to create range header"),
             );
-        }
+            Some(range)
+        } else {
+            None
+        };
 
+        if let Some(signer) = &self.signer {
+            headers.extend(signer.sign_get(&self.src_url, range.as_deref()));
+        }
         let mut response = client.get(&self.src_url).headers(headers).send()?;
 
         if !response.status().is_success() {
@@ -77,14 +276,15 @@ where
             .headers()
             .get(header::ETAG)
             .map(|s| s.to_str().unwrap_or(""))
-            .unwrap_or("");
-        let res_offset = if let Some(state) = &self.state {
-            // we sent a partial request, so we need to check the etag & range
-
+            .unwrap_or("")
+            .to_string();
+        if let Some(state) = &self.state {
             if state.etag != etag {
                 return Err(HttpError::EtagMismatch);
             }
-
+        }
+        let res_offset = if cursor > 0 {
+            // we sent a partial request, so we need to check the range
             let mut res_offset: u64 = 0;
             if let Some(content_range) = response.headers().get(header::CONTENT_RANGE) {
                 let content_range = content_range
@@ -108,7 +308,7 @@ where
                 }
             }
 
-            if res_offset > state.read.total_in() {
+            if res_offset > cursor {
                 return Err(HttpError::ContentRangeTooSmall);
             }
             res_offset
@@ -116,60 +316,85 @@ where
             0
         };
 
-        if let Some(state) = &mut self.state {
-            // seek until offset matches
-            let diff = (state.read.total_in() - res_offset) as usize;
-            if diff > 0 {
-                let mut buf = [0; 1024];
-                for _ in 0..(diff / 1024) {
-                    response.read_exact(&mut buf)?;
-                }
-                let remaining = diff - (diff / 1024) * 1024;
-                if remaining > 0 {
-                    response.read_exact(&mut buf[..remaining])?;
-                }
-
-                debug!(
-                    "response seeked from offset {} to offset {}",
-                    res_offset,
-                    state.read.total_in()
-                );
-            }
+        // seek until offset matches
+        let diff = cursor - res_offset;
+        if diff > 0 {
+            io::copy(&mut (&mut response).take(diff), &mut io::sink())?;
+            debug!(
+                "response seeked from offset {} to offset {}",
+                res_offset, cursor
+            );
+        }
 
-            *state.read.inner_mut() = response;
+        if let Some(state) = &mut self.state {
+            // anything left in the old buffer was read past the decompressor's cursor
+            state
+                .read
+                .inner_mut()
+                .replace_inner(BufReader::with_capacity(self.buffer_bytes, response));
         } else {
-            let etag = etag.to_string();
-            let len = response.content_length();
+            // the length of the rest of the file
+            let len = response
+                .content_length()
+                .map(|len| len.saturating_sub(diff));
+            let mut read = B::with_threads(
+                SourceReader::new(BufReader::with_capacity(self.buffer_bytes, response))
+                    .with_checksum(self.checksum.take())
+                    .with_cache(self.cache.take()),
+                self.decoder_threads,
+            );
+            if let Some(index) = self.index.take() {
+                read.set_index(index);
+            }
             // no state exists; create
-            self.state = Some(HttpDataInputState {
-                read: B::new(response),
-                etag,
-                len,
-            });
+            self.state = Some(HttpDataInputState { read, etag, len });
         }
 
         Ok(())
     }
 
     fn try_open(&mut self) -> Result<(), HttpError> {
+        if self.state.is_some() {
+            info!(
+                "Connection lost after {:.2} MB, resuming download with a range request",
+                self.bytes_read() as f64 / 1_000_000.
+            );
+        }
+
+        // don't make up for the time spent reconnecting with a burst
+        self.rate_start = None;
+
         let mut try_count = 1;
         loop {
-            let is_last_try = try_count == MAX_OPEN_TRIES;
+            let is_last_try = try_count >= self.retry.tries;
 
             match self.open() {
                 Ok(()) => break Ok(()),
                 Err(err) => {
-                    if is_last_try {
+                    if (is_last_try || err.is_permanent())
+                        && self.state.is_none()
+                        && !self.fallback_urls.is_empty()
+                    {
+                        let next_url = self.fallback_urls.remove(0);
+                        warn!(
+                            "Giving up on {} ({}), trying {} instead",
+                            self.src_url, err, next_url
+                        );
+                        self.src_url = next_url;
+                        try_count = 1;
+                    } else if is_last_try || err.is_permanent() {
                         break Err(err);
                     } else {
-                        std::thread::sleep(std::time::Duration::from_secs(
-                            OPEN_RETRY_INTERVAL_SECS,
-                        ));
-                        try_count += 1;
-                        debug!(
-                            "retrying connection because it failed (try {}/{}): {}",
-                            try_count, MAX_OPEN_TRIES, err
+                        let interval = self.retry.interval(try_count);
+                        warn!(
+                            "Connection failed (try {}/{}), retrying in {:.0}s: {}",
+                            try_count,
+                            self.retry.tries,
+                            interval.as_secs_f64(),
+                            err
                         );
+                        std::thread::sleep(interval);
+                        try_count += 1;
                     }
                 }
             }
@@ -186,13 +411,25 @@ where
 
 impl<B> DataInput for HttpDataInput<B>
 where
-    B: DecompressingReader<Response>,
+    B: DecompressingReader<SourceReader<Response>>,
 {
     type Error = HttpError;
 
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, HttpError> {
-        match self.read_raw(buf) {
-            Ok(bytes) => Ok(bytes),
+        // the rest of the data is read at the end, so it's retried like the data before it
+        let result = self.read_raw(buf).and_then(|bytes| {
+            if bytes == 0 {
+                if let Some(state) = &mut self.state {
+                    state.read.inner_mut().finish()?;
+                }
+            }
+            Ok(bytes)
+        });
+        match result {
+            Ok(bytes) => {
+                self.throttle();
+                Ok(bytes)
+            }
             Err(err) => match err.retry_policy() {
                 RetryPolicy::Retry => {
                     debug!("retrying read because of interrupt error: {}", err);
@@ -217,6 +454,67 @@ where
     }
 }
 
+/// Downloads the start of the file from each URL and returns the URLs ordered by speed, fastest
+/// first. URLs that fail are put last in their original order, since the error may be transient.
+pub fn rank_by_speed(urls: &[String]) -> Vec<String> {
+    let client = match reqwest::blocking::Client::builder()
+        .user_agent(USER_AGENT)
+        .timeout(Duration::from_secs(PROBE_TIMEOUT_SECS))
+        .build()
+    {
+        Ok(client) => client,
+        Err(err) => {
+            warn!("Could not create client to probe mirrors: {}", err);
+            return urls.to_vec();
+        }
+    };
+
+    let mut speeds = Vec::new();
+    let mut failed = Vec::new();
+    for url in urls {
+        match probe(&client, url) {
+            Ok(speed) => {
+                info!("{}: {:.2} MB/s", url, speed / 1_000_000.);
+                speeds.push((speed, url.clone()));
+            }
+            Err(err) => {
+                warn!("{}: probe failed: {}", url, err);
+                failed.push(url.clone());
+            }
+        }
+    }
+    speeds.sort_by(|(a, _), (b, _)| b.partial_cmp(a).unwrap_or(std::cmp::Ordering::Equal));
+    speeds
+        .into_iter()
+        .map(|(_, url)| url)
+        .chain(failed)
+        .collect()
+}
+
+/// Returns the download speed of the first `PROBE_BYTES` in bytes per second.
+fn probe(client: &reqwest::blocking::Client, url: &str) -> Result<f64, HttpError> {
+    let start = Instant::now();
+    let response = client
+        .get(url)
+        .header(header::RANGE, format!("bytes=0-{}", PROBE_BYTES - 1))
+        .send()?;
+    if !response.status().is_success() {
+        let status = response.status();
+        return Err(HttpError::Status(status, response.text()?));
+    }
+    // servers that ignore the range send the whole file, so stop reading after the probe size
+    let mut read = 0;
+    let mut buf = [0; 16384];
+    let mut response = response.take(PROBE_BYTES);
+    loop {
+        match response.read(&mut buf)? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(read as f64 / start.elapsed().as_secs_f64().max(0.001))
+}
+
 enum RetryPolicy {
     Fail,
     Reopen,
@@ -241,7 +539,29 @@ pub enum HttpError {
     Io(#[from] io::Error),
 }
 
+impl InputError for HttpError {
+    fn is_decompress(&self) -> bool {
+        match self {
+            HttpError::Io(err) => err.is_decompress(),
+            _ => false,
+        }
+    }
+}
+
 impl HttpError {
+    /// Whether retrying the request can't help, e.g. because the file is gone or has changed.
+    pub fn is_permanent(&self) -> bool {
+        match self {
+            HttpError::EtagMismatch => true,
+            HttpError::Status(status, _) => {
+                status.is_client_error()
+                    && *status != reqwest::StatusCode::REQUEST_TIMEOUT
+                    && *status != reqwest::StatusCode::TOO_MANY_REQUESTS
+            }
+            _ => false,
+        }
+    }
+
     fn retry_policy(&self) -> RetryPolicy {
         match self {
             HttpError::NoConnection => RetryPolicy::Reopen,
@@ -277,3 +597,60 @@ impl HttpError {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn retry(interval_secs: f64, max_interval_secs: f64) -> RetryConfig {
+        RetryConfig {
+            tries: 32,
+            interval_secs,
+            max_interval_secs,
+            jitter: 0.,
+        }
+    }
+
+    #[test]
+    fn backoff() {
+        let retry = retry(8., 300.);
+        let intervals: Vec<_> = (1..=7).map(|try_count| retry.interval(try_count)).collect();
+        let expected = [8, 16, 32, 64, 128, 256, 300].map(Duration::from_secs);
+        assert_eq!(intervals, expected);
+    }
+
+    #[test]
+    fn backoff_is_jittered() {
+        let retry = RetryConfig {
+            jitter: 0.5,
+            ..retry(8., 300.)
+        };
+        for _ in 0..10 {
+            let interval = retry.interval(1).as_secs_f64();
+            assert!((4. ..=12.).contains(&interval), "{}", interval);
+        }
+    }
+
+    #[test]
+    fn backoff_out_of_range() {
+        for (interval, max_interval) in [
+            (f64::MAX, f64::MAX),
+            (8., f64::INFINITY),
+            (f64::INFINITY, f64::INFINITY),
+        ] {
+            let retry = retry(interval, max_interval);
+            assert_eq!(retry.interval(32), MAX_RETRY_INTERVAL);
+        }
+        for (interval, max_interval) in [(-8., 300.), (8., -1.), (f64::NAN, f64::NAN)] {
+            assert_eq!(retry(interval, max_interval).interval(3), Duration::ZERO);
+        }
+        // the maximum is used if only the interval isn't a number
+        assert_eq!(retry(f64::NAN, 300.).interval(3), Duration::from_secs(300));
+        let retry = RetryConfig {
+            jitter: f64::INFINITY,
+            ..retry(8., 300.)
+        };
+        // the jitter is clamped by the options, but shouldn't panic either way
+        assert!(retry.interval(3) <= MAX_RETRY_INTERVAL);
+    }
+}
@@ -1,25 +1,228 @@
 use std::collections::VecDeque;
-use std::mem;
-use std::string::FromUtf8Error;
+use std::io::{Seek, SeekFrom};
+use std::path::Path;
+use std::str::{self, Utf8Error};
+use std::{fs, io};
 
 use thiserror::Error;
 
+pub mod bz2_index;
+mod cache;
+pub mod checksum;
 mod compression;
 pub mod file;
 pub mod http;
+pub mod par_bz2;
+pub mod s3;
+mod source;
+pub mod stdin;
+pub mod torrent;
 
 pub trait DataInput {
-    type Error;
+    type Error: InputError;
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error>;
     fn bytes_read(&self) -> u64;
     fn content_length(&self) -> Option<u64>;
 }
 
+pub trait InputError {
+    /// Whether the error was caused by corrupt compressed data rather than by reading it.
+    fn is_decompress(&self) -> bool;
+}
+
+/// Settings for opening the dump.
+#[derive(Debug, Clone)]
+pub struct InputSettings {
+    pub http: http::HttpSettings,
+    /// Where the download of a torrent is looked for, instead of next to the `.torrent` file.
+    pub torrent_dir: Option<String>,
+    /// Number of threads that bz2 data is decompressed on.
+    pub decoder_threads: usize,
+}
+
+/// The dump, either downloaded (if the source is an HTTP(S) or `s3://` URL), read from the download
+/// of a torrent client (if the source is a `.torrent` file), read from a local file, or read already
+/// decompressed from stdin (if the source is `-`).
+///
+/// The compression format of downloads and files is detected from the data.
+pub enum DumpInput {
+    Http(Box<http::HttpAutoDataInput>),
+    File(Box<file::AutoFileInput>),
+    Torrent(Box<torrent::TorrentInput>),
+    Stdin(stdin::StdinInput),
+}
+
+impl DumpInput {
+    /// Opens the source. For downloads, the mirrors are probed together with the source and the
+    /// fastest one is used, with the rest as fallbacks.
+    ///
+    /// Downloads and files are verified against the checksum (if any) at the end of the data.
+    ///
+    /// Downloads and files start at the start point of the bz2 index (if any), and seek points
+    /// are recorded to it.
+    pub fn open(
+        source: &str,
+        settings: &InputSettings,
+        mut checksum: Option<checksum::Checksum>,
+        index: Option<bz2_index::Bz2Index>,
+    ) -> io::Result<Self> {
+        let http = &settings.http;
+        let decoder_threads = settings.decoder_threads;
+        let start = index
+            .as_ref()
+            .map(|index| index.start())
+            .unwrap_or_default();
+        if start.compressed > 0 && checksum.take().is_some() {
+            warn!("Can't verify the checksum when starting in the middle of the dump");
+        }
+
+        if source == "-" {
+            if checksum.is_some() {
+                warn!("Can't verify the checksum of data read from stdin");
+            }
+            if index.is_some() {
+                warn!("Can't record or use bz2 seek points for data read from stdin");
+            }
+            Ok(DumpInput::Stdin(stdin::StdinInput::new()))
+        } else if source.starts_with("http://")
+            || source.starts_with("https://")
+            || source.starts_with("s3://")
+        {
+            if let Some(cache_path) = &http.cache_path {
+                if Path::new(cache_path).exists() {
+                    info!(
+                        "Reading the saved download {} instead of {}",
+                        cache_path, source
+                    );
+                    return Self::open_file(cache_path, checksum, index, decoder_threads);
+                }
+            }
+            let cache = match &http.cache_path {
+                Some(_) if start.compressed > 0 => {
+                    warn!("Can't save a download that starts in the middle of the dump");
+                    None
+                }
+                Some(cache_path) => Some(cache::DownloadCache::create(Path::new(cache_path))?),
+                None => None,
+            };
+
+            let (mut urls, signer) = if source.starts_with("s3://") {
+                let object = s3::S3Object::from_env(source)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                if !http.mirrors.is_empty() {
+                    warn!("Ignoring mirrors for S3 input");
+                }
+                (vec![object.url], object.signer)
+            } else {
+                let mut urls = vec![source.to_string()];
+                urls.extend(http.mirrors.iter().cloned());
+                (urls, None)
+            };
+            if urls.len() > 1 {
+                info!("Probing {} mirrors", urls.len());
+                urls = http::rank_by_speed(&urls);
+            }
+            let src_url = urls.remove(0);
+            Ok(DumpInput::Http(Box::new(
+                http::HttpAutoDataInput::new(src_url, http.buffer_bytes, http.retry)
+                    .with_fallbacks(urls)
+                    .with_max_rate(http.max_rate)
+                    .with_checksum(checksum)
+                    .with_cache(cache)
+                    .with_signer(signer)
+                    .with_decoder_threads(decoder_threads)
+                    .with_index(index),
+            )))
+        } else if source.ends_with(".torrent") {
+            let input = torrent::TorrentInput::open(
+                Path::new(source),
+                settings.torrent_dir.as_deref().map(Path::new),
+                checksum,
+                index,
+                decoder_threads,
+            )
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            Ok(DumpInput::Torrent(Box::new(input)))
+        } else {
+            Self::open_file(source, checksum, index, decoder_threads)
+        }
+    }
+
+    fn open_file(
+        path: &str,
+        checksum: Option<checksum::Checksum>,
+        index: Option<bz2_index::Bz2Index>,
+        decoder_threads: usize,
+    ) -> io::Result<Self> {
+        let mut file = fs::File::open(path)?;
+        if let Some(index) = &index {
+            file.seek(SeekFrom::Start(index.start().compressed))?;
+        }
+        Ok(DumpInput::File(Box::new(
+            file::AutoFileInput::with_checksum(file, checksum, decoder_threads).with_index(index),
+        )))
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum DumpInputError {
+    #[error("{0}")]
+    Http(#[from] http::HttpError),
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+}
+
+impl InputError for DumpInputError {
+    fn is_decompress(&self) -> bool {
+        match self {
+            DumpInputError::Http(err) => err.is_decompress(),
+            DumpInputError::Io(err) => err.is_decompress(),
+        }
+    }
+}
+
+impl DataInput for DumpInput {
+    type Error = DumpInputError;
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        Ok(match self {
+            DumpInput::Http(input) => input.read(buf)?,
+            DumpInput::File(input) => input.read(buf)?,
+            DumpInput::Torrent(input) => input.read(buf)?,
+            DumpInput::Stdin(input) => input.read(buf)?,
+        })
+    }
+
+    fn bytes_read(&self) -> u64 {
+        match self {
+            DumpInput::Http(input) => input.bytes_read(),
+            DumpInput::File(input) => input.bytes_read(),
+            DumpInput::Torrent(input) => input.bytes_read(),
+            DumpInput::Stdin(input) => input.bytes_read(),
+        }
+    }
+
+    fn content_length(&self) -> Option<u64> {
+        match self {
+            DumpInput::Http(input) => input.content_length(),
+            DumpInput::File(input) => input.content_length(),
+            DumpInput::Torrent(input) => input.content_length(),
+            DumpInput::Stdin(input) => input.content_length(),
+        }
+    }
+}
+
 pub struct InputLineIter<I> {
     pub input: I,
+    /// Bytes of decompressed data read into the buffer, which usually runs past the current line.
     pub bytes_read: u64,
+    read_buf: Box<[u8]>,
+    /// Reused for every line; lines are copied out into exactly-sized strings.
     line_buf: Vec<u8>,
-    pending_lines: VecDeque<String>,
+    /// Lines that were read, with the offset after the line break that ends them.
+    pending_lines: VecDeque<(String, u64)>,
+    /// Offset where the next line starts.
+    line_start: u64,
 }
 
 const ESTIMATED_LINE_SIZE: usize = 65536;
@@ -32,45 +235,120 @@ where
         InputLineIter {
             input,
             bytes_read: 0,
+            read_buf: vec![0; ESTIMATED_LINE_SIZE].into_boxed_slice(),
             line_buf: Vec::with_capacity(ESTIMATED_LINE_SIZE),
             pending_lines: VecDeque::new(),
+            line_start: 0,
         }
     }
 
-    fn push_line(&mut self) -> Result<(), LineIterError<I::Error>> {
-        self.pending_lines.push_back(
-            String::from_utf8(mem::replace(
-                &mut self.line_buf,
-                Vec::with_capacity(ESTIMATED_LINE_SIZE),
-            ))
-            .map_err(LineIterError::Utf8)?,
-        );
+    /// Sets the offset in the decompressed data where the input starts. Must be called before
+    /// reading any data.
+    pub fn start_at(&mut self, offset: u64) {
+        self.bytes_read = offset;
+        self.line_start = offset;
+    }
+
+    /// The offset in the decompressed data where the next line that is returned starts.
+    pub fn line_start(&self) -> u64 {
+        self.line_start
+    }
+
+    fn push_line(
+        line_buf: &mut Vec<u8>,
+        pending_lines: &mut VecDeque<(String, u64)>,
+        end: u64,
+    ) -> Result<(), LineIterError<I::Error>> {
+        let line = str::from_utf8(line_buf).map_err(LineIterError::Utf8)?;
+        pending_lines.push_back((line.to_string(), end));
+        line_buf.clear();
         Ok(())
     }
 
-    pub fn next(&mut self) -> Result<String, LineIterError<I::Error>> {
-        let mut buf = [0; ESTIMATED_LINE_SIZE];
+    /// Splits the read buffer from `from` to `to` into lines, the first of which continues the
+    /// line buffer. `buf_start` is the offset of the read buffer in the decompressed data.
+    fn push_lines(
+        &mut self,
+        buf_start: u64,
+        from: usize,
+        to: usize,
+    ) -> Result<(), LineIterError<I::Error>> {
+        let mut cursor = from;
+        for i in from..to {
+            if self.read_buf[i] == b'\n' {
+                self.line_buf.extend_from_slice(&self.read_buf[cursor..i]);
+                let end = buf_start + i as u64 + 1;
+                Self::push_line(&mut self.line_buf, &mut self.pending_lines, end)?;
+                cursor = i + 1;
+            }
+        }
+        self.line_buf.extend_from_slice(&self.read_buf[cursor..to]);
+        Ok(())
+    }
 
-        while self.pending_lines.is_empty() {
-            let bytes_read = self.input.read(&mut buf)?;
+    /// Reads and discards data up to the offset in the decompressed data, and then up to the
+    /// start of the next line if the offset is in the middle of one. Must be called before
+    /// reading any lines.
+    ///
+    /// Whether the offset is the start of a line is told by the byte before it, so if the input
+    /// starts at the offset, it's taken to be the start of a line.
+    pub fn skip_to(&mut self, offset: u64) -> Result<(), LineIterError<I::Error>> {
+        let mut at_line_start = true;
+        while self.bytes_read < offset {
+            let len = (offset - self.bytes_read).min(self.read_buf.len() as u64) as usize;
+            let bytes_read = self
+                .input
+                .read(&mut self.read_buf[..len])
+                .map_err(LineIterError::from_input)?;
+            if bytes_read == 0 {
+                return Err(LineIterError::Eof);
+            }
             self.bytes_read += bytes_read as u64;
+            at_line_start = self.read_buf[bytes_read - 1] == b'\n';
+        }
+        self.line_start = self.bytes_read;
 
-            let mut cursor = 0;
-            for i in 0..bytes_read {
-                let byte = buf[i];
-                if byte == b'\n' {
-                    self.line_buf.extend_from_slice(&buf[cursor..i]);
-                    self.push_line()?;
-                    cursor = i + 1;
-                }
+        while !at_line_start {
+            let buf_start = self.bytes_read;
+            let bytes_read = self
+                .input
+                .read(&mut self.read_buf)
+                .map_err(LineIterError::from_input)?;
+            if bytes_read == 0 {
+                return Err(LineIterError::Eof);
             }
-            if cursor < bytes_read {
-                self.line_buf.extend_from_slice(&buf[cursor..bytes_read]);
+            self.bytes_read += bytes_read as u64;
+            if let Some(i) = self.read_buf[..bytes_read].iter().position(|&b| b == b'\n') {
+                self.line_start = buf_start + i as u64 + 1;
+                self.push_lines(buf_start, i + 1, bytes_read)?;
+                at_line_start = true;
             }
+        }
+        if self.line_start > offset {
+            debug!(
+                "offset {} is in the middle of a line; starting at the next line at {}",
+                offset, self.line_start
+            );
+        }
+        Ok(())
+    }
+
+    /// Returns the next line and the offset in the decompressed data after its line break, where
+    /// the line after it starts.
+    pub fn next(&mut self) -> Result<(String, u64), LineIterError<I::Error>> {
+        while self.pending_lines.is_empty() {
+            let buf_start = self.bytes_read;
+            let bytes_read = self
+                .input
+                .read(&mut self.read_buf)
+                .map_err(LineIterError::from_input)?;
+            self.bytes_read += bytes_read as u64;
+            self.push_lines(buf_start, 0, bytes_read)?;
             if bytes_read == 0 {
                 // EOF
                 if !self.line_buf.is_empty() {
-                    self.push_line()?;
+                    let end = self.bytes_read;
+                    Self::push_line(&mut self.line_buf, &mut self.pending_lines, end)?;
                 } else {
                     // the end of the end
                     return Err(LineIterError::Eof);
@@ -78,7 +356,9 @@ where
             }
         }
 
-        Ok(self.pending_lines.pop_front().unwrap())
+        let (line, end) = self.pending_lines.pop_front().unwrap();
+        self.line_start = end;
+        Ok((line, end))
     }
 }
 
@@ -86,8 +366,129 @@ where
 pub enum LineIterError<I> {
     #[error("eof")]
     Eof,
+    /// The input data is corrupt. Retrying the read won't help.
+    #[error("decompression error: {0}")]
+    Decompress(#[source] I),
+    /// Reading the input failed (after any retries the input does by itself).
     #[error("{0}")]
-    Input(#[from] I),
+    Io(#[source] I),
     #[error("utf8 error: {0}")]
-    Utf8(FromUtf8Error),
+    Utf8(Utf8Error),
+}
+
+impl<I: InputError> LineIterError<I> {
+    fn from_input(err: I) -> Self {
+        if err.is_decompress() {
+            LineIterError::Decompress(err)
+        } else {
+            LineIterError::Io(err)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Data read a few bytes at a time, so lines span several reads.
+    struct ChunkedInput {
+        data: &'static [u8],
+        chunk: usize,
+        bytes_read: u64,
+    }
+
+    impl DataInput for ChunkedInput {
+        type Error = io::Error;
+
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            let start = self.bytes_read as usize;
+            let len = buf.len().min(self.chunk).min(self.data.len() - start);
+            buf[..len].copy_from_slice(&self.data[start..start + len]);
+            self.bytes_read += len as u64;
+            Ok(len)
+        }
+
+        fn bytes_read(&self) -> u64 {
+            self.bytes_read
+        }
+
+        fn content_length(&self) -> Option<u64> {
+            Some(self.data.len() as u64)
+        }
+    }
+
+    fn lines(data: &'static [u8], chunk: usize) -> InputLineIter<ChunkedInput> {
+        InputLineIter::new(ChunkedInput {
+            data,
+            chunk,
+            bytes_read: 0,
+        })
+    }
+
+    fn read_all(lines: &mut InputLineIter<ChunkedInput>) -> Vec<(String, u64)> {
+        let mut read = Vec::new();
+        loop {
+            match lines.next() {
+                Ok(line) => read.push(line),
+                Err(LineIterError::Eof) => return read,
+                Err(e) => panic!("{}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn line_ends() {
+        for chunk in [1, 3, 1024] {
+            let read = read_all(&mut lines(b"[\n{\"a\":1},\n\n{}\n]", chunk));
+            let expected = [
+                ("[", 2),
+                ("{\"a\":1},", 11),
+                ("", 12),
+                ("{}", 15),
+                ("]", 16),
+            ];
+            let expected: Vec<_> = expected
+                .iter()
+                .map(|&(line, end)| (line.to_string(), end))
+                .collect();
+            assert_eq!(read, expected, "chunks of {} bytes", chunk);
+        }
+    }
+
+    #[test]
+    fn skip_to_line_start() {
+        for chunk in [1, 3, 1024] {
+            let mut iter = lines(b"[\n{\"a\":1},\n\n{}\n]", chunk);
+            iter.skip_to(11).unwrap();
+            assert_eq!(iter.line_start(), 11);
+            assert_eq!(iter.next().unwrap(), ("".into(), 12));
+            assert_eq!(iter.line_start(), 12);
+        }
+    }
+
+    #[test]
+    fn skip_to_middle_of_line() {
+        for chunk in [1, 3, 1024] {
+            let mut iter = lines(b"[\n{\"a\":1},\n\n{}\n]", chunk);
+            iter.skip_to(4).unwrap();
+            // the rest of the line at the offset is skipped
+            assert_eq!(iter.line_start(), 11);
+            let read = read_all(&mut iter);
+            let expected = [("", 12), ("{}", 15), ("]", 16)];
+            let expected: Vec<_> = expected
+                .iter()
+                .map(|&(line, end)| (line.to_string(), end))
+                .collect();
+            assert_eq!(read, expected, "chunks of {} bytes", chunk);
+        }
+    }
+
+    #[test]
+    fn skip_to_last_line() {
+        let mut iter = lines(b"[\n]", 1024);
+        iter.skip_to(3).unwrap_err();
+        let mut iter = lines(b"[\n]", 1024);
+        iter.skip_to(1).unwrap();
+        assert_eq!(iter.next().unwrap(), ("]".into(), 3));
+    }
 }
@@ -0,0 +1,469 @@
+//! Parallel decompression of multi-stream bzip2 files.
+//!
+//! The dump consists of many concatenated bzip2 streams, each of which can be decompressed on its
+//! own. Streams start on a byte boundary with a recognizable header, so the compressed data is cut
+//! into chunks of whole streams, which are decompressed on a thread pool and returned in order.
+
+use crate::input::bz2_index::Bz2Index;
+use crate::input::compression::DecompressingReader;
+use bzip2::{Decompress, Status};
+use crossbeam::channel::{self, Receiver};
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Read};
+use std::mem;
+
+/// Chunks are cut at the first stream header after this many bytes.
+const CHUNK_BYTES: usize = 1024 * 1024;
+/// If there is no stream header within this many bytes, the stream is decompressed on the reading
+/// thread instead (e.g. in single-stream files).
+const MAX_CHUNK_BYTES: usize = 64 * 1024 * 1024;
+/// Length of a stream header: "BZh", the block size, and the magic number of the first block.
+const HEADER_LEN: usize = 10;
+const BLOCK_MAGIC: [u8; 6] = [0x31, 0x41, 0x59, 0x26, 0x53, 0x59];
+
+/// Returns the position of the first stream header at or after `from`.
+///
+/// The header may also turn up inside compressed data by chance. Such a chunk then ends in the
+/// middle of a stream, which is caught when decompressing it.
+fn find_stream_header(data: &[u8], from: usize) -> Option<usize> {
+    (from..data.len().saturating_sub(HEADER_LEN - 1)).find(|&i| {
+        data[i..i + 3] == *b"BZh"
+            && (b'1'..=b'9').contains(&data[i + 3])
+            && data[i + 4..i + HEADER_LEN] == BLOCK_MAGIC
+    })
+}
+
+enum ChunkError {
+    /// The chunk ends in the middle of a stream.
+    Incomplete,
+    Corrupt(bzip2::Error),
+}
+
+struct DecodedChunk {
+    /// The compressed data, which is needed again if the chunk turns out to be incomplete.
+    compressed: Vec<u8>,
+    decoded: Result<Vec<u8>, ChunkError>,
+}
+
+/// Decompresses a chunk of whole streams.
+fn decode_streams(compressed: &[u8]) -> Result<Vec<u8>, ChunkError> {
+    let mut out = Vec::with_capacity(compressed.len() * 4);
+    let mut input = compressed;
+    while !input.is_empty() {
+        let mut decompress = Decompress::new(false);
+        loop {
+            if out.len() == out.capacity() {
+                out.reserve(out.len());
+            }
+            let consumed = decompress.total_in() as usize;
+            let status = decompress
+                .decompress_vec(&input[consumed..], &mut out)
+                .map_err(ChunkError::Corrupt)?;
+            if status == Status::StreamEnd {
+                input = &input[decompress.total_in() as usize..];
+                break;
+            }
+            if decompress.total_in() as usize == input.len() && out.len() < out.capacity() {
+                return Err(ChunkError::Incomplete);
+            }
+        }
+    }
+    Ok(out)
+}
+
+fn corrupt(err: bzip2::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+/// Bzip2 decoder that decompresses streams on all cores, for when the single-threaded decoder
+/// can't keep up with the download.
+///
+/// All compressed data that has been read from the inner reader is kept until it is decompressed,
+/// so the inner reader can be replaced after a reconnect just like with the other decoders.
+pub struct ParBzDecoder<R> {
+    inner: R,
+    total_in: u64,
+    /// Decompressed bytes so far, including those in `output`.
+    total_out: u64,
+    eof: bool,
+    pool: rayon_core::ThreadPool,
+    max_in_flight: usize,
+    /// Compressed data that hasn't been sent to the pool yet. Starts at a stream header.
+    chunk: Vec<u8>,
+    /// Offset of `chunk` in the compressed data.
+    chunk_start: u64,
+    /// Where to continue looking for a stream header in `chunk`.
+    scan_from: usize,
+    /// Chunks being decompressed and their offsets, in order.
+    in_flight: VecDeque<(u64, Receiver<DecodedChunk>)>,
+    /// Decompressed data that hasn't been returned yet.
+    output: Vec<u8>,
+    output_pos: usize,
+    /// Decompresses the stream at the start of `chunk` on this thread if it couldn't be split off.
+    serial: Option<Decompress>,
+    /// How much of `chunk` has been decompressed serially.
+    serial_pos: usize,
+    /// Where to record the starts of streams.
+    index: Option<Bz2Index>,
+}
+
+impl<R: BufRead> ParBzDecoder<R> {
+    /// Appends the next buffer of compressed data to the chunk.
+    fn read_inner(&mut self) -> io::Result<()> {
+        let data = self.inner.fill_buf()?;
+        let len = data.len();
+        self.chunk.extend_from_slice(data);
+        self.inner.consume(len);
+        self.total_in += len as u64;
+        self.eof = len == 0;
+        Ok(())
+    }
+
+    /// Sends the chunk up to `end` to the pool.
+    fn dispatch(&mut self, end: usize) {
+        let rest = self.chunk.split_off(end);
+        let compressed = mem::replace(&mut self.chunk, rest);
+        let start = self.chunk_start;
+        self.chunk_start += end as u64;
+
+        let (send, recv) = channel::bounded(1);
+        self.pool.spawn(move || {
+            let decoded = decode_streams(&compressed);
+            // the decoder may have been dropped in the meantime
+            let _ = send.send(DecodedChunk {
+                compressed,
+                decoded,
+            });
+        });
+        self.in_flight.push_back((start, recv));
+    }
+
+    fn receive(recv: Receiver<DecodedChunk>) -> DecodedChunk {
+        recv.recv().expect("bz2 worker thread panicked")
+    }
+
+    /// Reads and dispatches chunks until enough are in flight. Stops early at a chunk without
+    /// stream headers, which has to be decompressed serially.
+    fn fill(&mut self) -> io::Result<()> {
+        while !self.eof && self.in_flight.len() < self.max_in_flight {
+            if let Some(end) = find_stream_header(&self.chunk, self.scan_from) {
+                self.dispatch(end);
+                self.scan_from = CHUNK_BYTES;
+                continue;
+            }
+            if self.chunk.len() >= MAX_CHUNK_BYTES {
+                break;
+            }
+            self.scan_from = CHUNK_BYTES.max(self.chunk.len().saturating_sub(HEADER_LEN - 1));
+            self.read_inner()?;
+        }
+        Ok(())
+    }
+
+    fn record_stream_start(&mut self, compressed: u64) {
+        if let Some(index) = &mut self.index {
+            index.record(compressed, self.total_out);
+        }
+    }
+
+    /// Starts decompressing the stream at the start of `chunk` on this thread.
+    fn start_serial(&mut self) {
+        self.record_stream_start(self.chunk_start);
+        self.serial = Some(Decompress::new(false));
+        self.serial_pos = 0;
+    }
+
+    /// Continues decompressing the current stream on this thread. Returns 0 if the stream ended.
+    fn read_serial(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.serial_pos == self.chunk.len() {
+                self.chunk_start += self.chunk.len() as u64;
+                self.chunk.clear();
+                self.serial_pos = 0;
+                if self.eof {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "bz2 stream ends before the end of the data",
+                    ));
+                }
+                self.read_inner()?;
+                continue;
+            }
+
+            let decompress = self.serial.as_mut().unwrap();
+            let before_in = decompress.total_in();
+            let before_out = decompress.total_out();
+            let status = decompress
+                .decompress(&self.chunk[self.serial_pos..], buf)
+                .map_err(corrupt)?;
+            self.serial_pos += (decompress.total_in() - before_in) as usize;
+            let read = (decompress.total_out() - before_out) as usize;
+            self.total_out += read as u64;
+
+            if status == Status::StreamEnd {
+                self.chunk.drain(..self.serial_pos);
+                self.chunk_start += self.serial_pos as u64;
+                self.serial_pos = 0;
+                self.scan_from = CHUNK_BYTES;
+                self.serial = None;
+                return Ok(read);
+            }
+            if read > 0 {
+                return Ok(read);
+            }
+        }
+    }
+}
+
+impl<R> DecompressingReader<R> for ParBzDecoder<R>
+where
+    R: BufRead,
+{
+    fn new(r: R) -> Self {
+        Self::with_threads(r, rayon_core::current_num_threads())
+    }
+    fn with_threads(r: R, threads: usize) -> Self {
+        ParBzDecoder {
+            inner: r,
+            total_in: 0,
+            total_out: 0,
+            eof: false,
+            pool: rayon_core::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .thread_name(|i| format!("bz2-{}", i))
+                .build()
+                .expect("failed to start bz2 decoder threads"),
+            max_in_flight: threads * 2,
+            chunk: Vec::new(),
+            chunk_start: 0,
+            scan_from: CHUNK_BYTES,
+            in_flight: VecDeque::new(),
+            output: Vec::new(),
+            output_pos: 0,
+            serial: None,
+            serial_pos: 0,
+            index: None,
+        }
+    }
+    fn inner(&self) -> &R {
+        &self.inner
+    }
+    fn inner_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+    fn total_in(&self) -> u64 {
+        self.total_in
+    }
+    fn set_index(&mut self, index: Bz2Index) {
+        self.index = Some(index);
+    }
+}
+
+impl<R> Read for ParBzDecoder<R>
+where
+    R: BufRead,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.output_pos < self.output.len() {
+                let read = buf.len().min(self.output.len() - self.output_pos);
+                buf[..read].copy_from_slice(&self.output[self.output_pos..self.output_pos + read]);
+                self.output_pos += read;
+                return Ok(read);
+            }
+
+            if self.serial.is_some() {
+                match self.read_serial(buf)? {
+                    0 => continue,
+                    read => return Ok(read),
+                }
+            }
+
+            self.fill()?;
+            if let Some((start, recv)) = self.in_flight.pop_front() {
+                let chunk = Self::receive(recv);
+                match chunk.decoded {
+                    Ok(decoded) => {
+                        self.record_stream_start(start);
+                        self.total_out += decoded.len() as u64;
+                        self.output = decoded;
+                        self.output_pos = 0;
+                    }
+                    Err(ChunkError::Incomplete) => {
+                        // a stream was split at a false header, so the rest is decompressed
+                        // serially up to the end of that stream
+                        debug!("bz2 chunk ends in the middle of a stream, decoding it serially");
+                        let mut compressed = chunk.compressed;
+                        for (_, recv) in self.in_flight.drain(..) {
+                            compressed.extend_from_slice(&Self::receive(recv).compressed);
+                        }
+                        compressed.extend_from_slice(&self.chunk);
+                        self.chunk = compressed;
+                        self.chunk_start = start;
+                        self.start_serial();
+                    }
+                    Err(ChunkError::Corrupt(err)) => return Err(corrupt(err)),
+                }
+            } else if self.eof {
+                if self.chunk.is_empty() {
+                    return Ok(0);
+                }
+                self.dispatch(self.chunk.len());
+            } else {
+                debug!(
+                    "no bz2 stream header in {} bytes, decoding serially",
+                    MAX_CHUNK_BYTES
+                );
+                self.start_serial();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bzip2::write::BzEncoder;
+    use bzip2::Compression;
+    use std::io::{BufReader, Write};
+    use std::time::Instant;
+
+    /// Bytes that barely compress, so that a few MiB of them are cut into several chunks.
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    /// Compresses each part as a stream of its own, like in the multi-stream dumps.
+    fn compress_streams(parts: &[&[u8]]) -> Vec<u8> {
+        let mut compressed = Vec::new();
+        for part in parts {
+            let mut encoder = BzEncoder::new(Vec::new(), Compression::fast());
+            encoder.write_all(part).unwrap();
+            compressed.extend(encoder.finish().unwrap());
+        }
+        compressed
+    }
+
+    fn decoder(compressed: &[u8]) -> ParBzDecoder<BufReader<&[u8]>> {
+        ParBzDecoder::new(BufReader::new(compressed))
+    }
+
+    fn read_all(decoder: &mut ParBzDecoder<BufReader<&[u8]>>) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        decoder.read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn single_stream() {
+        let data = noise(100_000, 1);
+        let compressed = compress_streams(&[&data]);
+        assert_eq!(read_all(&mut decoder(&compressed)).unwrap(), data);
+    }
+
+    #[test]
+    fn multi_stream() {
+        let data = noise(4 * CHUNK_BYTES, 2);
+        let parts: Vec<_> = data.chunks(300_000).collect();
+        let compressed = compress_streams(&parts);
+        let mut decoder = decoder(&compressed);
+        assert_eq!(read_all(&mut decoder).unwrap(), data);
+        assert_eq!(decoder.total_in(), compressed.len() as u64);
+    }
+
+    #[test]
+    fn multi_stream_on_threads() {
+        let data = noise(3 * CHUNK_BYTES, 4);
+        let parts: Vec<_> = data.chunks(300_000).collect();
+        let compressed = compress_streams(&parts);
+        for threads in [1, 3] {
+            let mut decoder = ParBzDecoder::with_threads(BufReader::new(&compressed[..]), threads);
+            assert_eq!(decoder.pool.current_num_threads(), threads);
+            assert_eq!(decoder.max_in_flight, threads * 2);
+            assert_eq!(read_all(&mut decoder).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn chunk_cut_at_false_header() {
+        let data = noise(300_000, 3);
+        let parts: Vec<_> = data.chunks(100_000).collect();
+        let compressed = compress_streams(&parts);
+        assert!(matches!(
+            decode_streams(&compressed[..1000]),
+            Err(ChunkError::Incomplete)
+        ));
+
+        // as if a header turned up by chance in the middle of the first stream
+        let mut decoder = decoder(&[]);
+        decoder.chunk = compressed.clone();
+        decoder.eof = true;
+        decoder.dispatch(1000);
+        decoder.dispatch(decoder.chunk.len() / 2);
+        assert_eq!(read_all(&mut decoder).unwrap(), data);
+    }
+
+    #[test]
+    fn truncated() {
+        let data = noise(300_000, 4);
+        let parts: Vec<_> = data.chunks(100_000).collect();
+        let compressed = compress_streams(&parts);
+        let err = read_all(&mut decoder(&compressed[..compressed.len() - 100])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn corrupt() {
+        let data = noise(300_000, 5);
+        let parts: Vec<_> = data.chunks(100_000).collect();
+        let mut compressed = compress_streams(&parts);
+        let middle = compressed.len() / 2;
+        for byte in &mut compressed[middle..middle + 16] {
+            *byte = !*byte;
+        }
+        assert!(read_all(&mut decoder(&compressed)).is_err());
+    }
+
+    /// Compares the speed with the single-threaded decoder:
+    /// `cargo test --release -- --ignored --nocapture par_bz2`.
+    #[test]
+    #[ignore]
+    fn benchmark() {
+        // text-like data that compresses about as well as the dump
+        let alphabet = b"abcdefghijklmnopqrstuvwxyz{}\":,\n";
+        let words = noise(1 << 16, 6);
+        let data: Vec<u8> = (0..64 << 20)
+            .map(|i: usize| alphabet[(words[i % words.len()] as usize + i / 7) % alphabet.len()])
+            .collect();
+        let parts: Vec<_> = data.chunks(900_000).collect();
+        let compressed = compress_streams(&parts);
+
+        let started = Instant::now();
+        let mut serial = Vec::new();
+        bzip2::read::MultiBzDecoder::new(&compressed[..])
+            .read_to_end(&mut serial)
+            .unwrap();
+        let serial_time = started.elapsed();
+
+        let started = Instant::now();
+        let parallel = read_all(&mut decoder(&compressed)).unwrap();
+        let parallel_time = started.elapsed();
+
+        assert_eq!(serial, parallel);
+        let mib = data.len() as f64 / (1 << 20) as f64;
+        println!(
+            "serial: {:.1} MiB/s, parallel on {} threads: {:.1} MiB/s",
+            mib / serial_time.as_secs_f64(),
+            rayon_core::current_num_threads(),
+            mib / parallel_time.as_secs_f64()
+        );
+    }
+}
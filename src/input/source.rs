@@ -0,0 +1,183 @@
+//! The compressed data on its way from the file or download to the decompressor.
+
+use crate::input::cache::DownloadCache;
+use crate::input::checksum::{Checksum, Digest};
+use std::io::{self, BufRead, BufReader, Read};
+
+/// Buffered reader for the compressed data. Consumed data is hashed (if there is a checksum) and
+/// copied to the download cache (if there is one).
+///
+/// Only consumed data is processed, so data that is read again after replacing the reader (e.g.
+/// when resuming a download) is never hashed or cached twice.
+pub struct SourceReader<R> {
+    inner: BufReader<R>,
+    digest: Option<Digest>,
+    cache: Option<DownloadCache>,
+    /// Bytes read by `finish` after the decoder stopped.
+    drained: u64,
+}
+
+impl<R: Read> SourceReader<R> {
+    pub fn new(inner: BufReader<R>) -> Self {
+        SourceReader {
+            inner,
+            digest: None,
+            cache: None,
+            drained: 0,
+        }
+    }
+
+    pub fn with_checksum(mut self, checksum: Option<Checksum>) -> Self {
+        self.digest = checksum.map(Digest::new);
+        self
+    }
+
+    pub fn with_cache(mut self, cache: Option<DownloadCache>) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// Replaces the underlying reader without resetting the digest or cache.
+    pub fn replace_inner(&mut self, inner: BufReader<R>) {
+        self.inner = inner;
+    }
+
+    /// Bytes read by `finish` after the decoder stopped, which follow the data the decoder
+    /// consumed.
+    pub fn drained(&self) -> u64 {
+        self.drained
+    }
+
+    /// Verifies the checksum and completes the cache file at the end of the data. Does nothing if
+    /// there is neither or this was already done.
+    ///
+    /// If reading the rest of the data fails, this can be called again after replacing the reader
+    /// with one that starts `drained` bytes after the data the decoder consumed.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.digest.is_none() && self.cache.is_none() {
+            return Ok(());
+        }
+        // decoders may stop at the end of the compressed stream, before the end of the file
+        let mut buf = [0; 8192];
+        loop {
+            match self.read(&mut buf) {
+                Ok(0) => break,
+                Ok(read) => self.drained += read as u64,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+
+        if let Some(digest) = self.digest.take() {
+            if let Err(e) = digest.verify() {
+                if let Some(cache) = self.cache.take() {
+                    cache.discard();
+                }
+                return Err(e);
+            }
+        }
+        if let Some(cache) = self.cache.take() {
+            // the data itself is fine, so this doesn't need to fail the run
+            if let Err(e) = cache.finish() {
+                warn!("Failed to save the download: {}", e);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Hashes and caches consumed data.
+fn process(digest: &mut Option<Digest>, cache: &mut Option<DownloadCache>, data: &[u8]) {
+    if let Some(digest) = digest {
+        digest.update(data);
+    }
+    if let Some(file) = cache {
+        if let Err(e) = file.write(data) {
+            warn!("Stopped saving the download: {}", e);
+            cache.take().unwrap().discard();
+        }
+    }
+}
+
+impl<R: Read> Read for SourceReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        process(&mut self.digest, &mut self.cache, &buf[..read]);
+        Ok(read)
+    }
+}
+
+impl<R: Read> BufRead for SourceReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+    fn consume(&mut self, amt: usize) {
+        process(
+            &mut self.digest,
+            &mut self.cache,
+            &self.inner.buffer()[..amt],
+        );
+        self.inner.consume(amt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    const DATA: &[u8] = b"The quick brown fox jumps over the lazy dog";
+
+    /// Reads the data and then fails like a dropped connection.
+    struct DroppedConnection(&'static [u8]);
+
+    impl Read for DroppedConnection {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() {
+                return Err(io::ErrorKind::ConnectionReset.into());
+            }
+            self.0.read(buf)
+        }
+    }
+
+    fn reader(data: &'static [u8]) -> BufReader<Box<dyn Read>> {
+        BufReader::with_capacity(4, Box::new(data))
+    }
+
+    fn checksum(expected: &str) -> Checksum {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sha1sums.txt");
+        fs::write(&path, format!("{}  dump.json.bz2\n", expected)).unwrap();
+        Checksum::load(path.to_str().unwrap(), "dump.json.bz2", false).unwrap()
+    }
+
+    #[test]
+    fn resume_finishing() {
+        let inner: BufReader<Box<dyn Read>> =
+            BufReader::with_capacity(4, Box::new(DroppedConnection(&DATA[..20])));
+        let mut source = SourceReader::new(inner)
+            .with_checksum(Some(checksum("2fd4e1c67a2d28fced849ee1bb76e7391b93eb12")));
+        // the decoder stops after the first bytes
+        let mut decoded = [0; 6];
+        source.read_exact(&mut decoded).unwrap();
+
+        let err = source.finish().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        assert_eq!(source.drained(), 14);
+        // reconnected where the drain stopped
+        source.replace_inner(reader(&DATA[6 + source.drained() as usize..]));
+        source.finish().unwrap();
+        source.finish().unwrap();
+    }
+
+    #[test]
+    fn verify_the_rest_of_the_data() {
+        let mut source = SourceReader::new(reader(DATA))
+            .with_checksum(Some(checksum("0000000000000000000000000000000000000000")));
+        let mut decoded = [0; 6];
+        source.read_exact(&mut decoded).unwrap();
+        let err = source.finish().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(source.drained(), DATA.len() as u64 - 6);
+    }
+}
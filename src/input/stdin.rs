@@ -0,0 +1,37 @@
+use crate::input::DataInput;
+use std::io::{self, Read};
+
+/// Reads the already decompressed dump from stdin, e.g. piped from `pbzip2 -dc`.
+///
+/// The length of the data is unknown, so there is no progress percentage or ETA.
+pub struct StdinInput {
+    stdin: io::Stdin,
+    bytes_read: u64,
+}
+
+impl StdinInput {
+    pub fn new() -> Self {
+        StdinInput {
+            stdin: io::stdin(),
+            bytes_read: 0,
+        }
+    }
+}
+
+impl DataInput for StdinInput {
+    type Error = io::Error;
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let bytes_read = self.stdin.lock().read(buf)?;
+        self.bytes_read += bytes_read as u64;
+        Ok(bytes_read)
+    }
+
+    fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    fn content_length(&self) -> Option<u64> {
+        None
+    }
+}
@@ -0,0 +1,625 @@
+//! Reading the dump while a BitTorrent client downloads it, e.g. from Academic Torrents.
+//!
+//! geo-db doesn't download torrents itself. Instead, it reads the file that the client is writing
+//! and uses the piece hashes in the `.torrent` file to tell which parts of it have arrived. Every
+//! piece is verified before it's used, and reading waits until the client has the next piece, so
+//! the client should be set to download pieces in sequential order. A piece that was written
+//! completely but doesn't match its hash and isn't written again fails the read.
+
+use crate::input::bz2_index::Bz2Index;
+use crate::input::checksum::Checksum;
+use crate::input::compression::{AutoDecoder, DecompressingReader};
+use crate::input::source::SourceReader;
+use crate::input::DataInput;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// How often to check whether the client has downloaded the next piece.
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+/// How often to log that reading is waiting for the client.
+const WAIT_LOG_INTERVAL: Duration = Duration::from_secs(60);
+/// Suffixes that clients add to incomplete files (Transmission and qBittorrent, if enabled).
+const INCOMPLETE_SUFFIXES: [&str; 2] = [".part", ".!qB"];
+/// Size of the blocks that clients request pieces in. A block of zeros hasn't been written yet.
+const BLOCK_SIZE: usize = 16384;
+/// How many polls a completely written piece may keep the same data that doesn't match its hash,
+/// to give the client time to notice and download it again.
+const MAX_MISMATCHED_POLLS: u32 = 30;
+/// How deeply lists and dictionaries of `.torrent` files may be nested.
+const MAX_DEPTH: usize = 32;
+
+#[derive(Debug, Error)]
+pub enum TorrentError {
+    #[error("could not read torrent file: {0}")]
+    Io(#[from] io::Error),
+    #[error("invalid torrent file: {0}")]
+    Invalid(&'static str),
+    #[error("torrents with multiple files are not supported")]
+    MultipleFiles,
+}
+
+/// A bencoded value.
+enum Value {
+    Int(i64),
+    Bytes(Vec<u8>),
+    /// The items of lists aren't needed, so they aren't kept.
+    List,
+    Dict(BTreeMap<Vec<u8>, Value>),
+}
+
+impl Value {
+    fn parse(data: &[u8], pos: &mut usize) -> Result<Value, TorrentError> {
+        Self::parse_nested(data, pos, 0)
+    }
+
+    /// Parses a value inside `depth` lists and dictionaries.
+    fn parse_nested(data: &[u8], pos: &mut usize, depth: usize) -> Result<Value, TorrentError> {
+        let truncated = TorrentError::Invalid("unexpected end of data");
+        let read_until = |pos: &mut usize, end: u8| -> Result<&str, TorrentError> {
+            let len = data[*pos..]
+                .iter()
+                .position(|b| *b == end)
+                .ok_or(TorrentError::Invalid("unexpected end of data"))?;
+            let s = std::str::from_utf8(&data[*pos..*pos + len])
+                .map_err(|_| TorrentError::Invalid("invalid number"))?;
+            *pos += len + 1;
+            Ok(s)
+        };
+
+        match data.get(*pos).ok_or(truncated)? {
+            b'i' => {
+                *pos += 1;
+                read_until(pos, b'e')?
+                    .parse()
+                    .map(Value::Int)
+                    .map_err(|_| TorrentError::Invalid("invalid integer"))
+            }
+            b'l' | b'd' => {
+                if depth >= MAX_DEPTH {
+                    return Err(TorrentError::Invalid("values nested too deeply"));
+                }
+                let is_dict = data[*pos] == b'd';
+                *pos += 1;
+                let mut items = Vec::new();
+                while data.get(*pos) != Some(&b'e') {
+                    if *pos >= data.len() {
+                        return Err(TorrentError::Invalid("unexpected end of data"));
+                    }
+                    items.push(Value::parse_nested(data, pos, depth + 1)?);
+                }
+                *pos += 1;
+                if !is_dict {
+                    return Ok(Value::List);
+                }
+                let mut dict = BTreeMap::new();
+                let mut items = items.into_iter();
+                while let Some(key) = items.next() {
+                    match (key, items.next()) {
+                        (Value::Bytes(key), Some(value)) => dict.insert(key, value),
+                        _ => return Err(TorrentError::Invalid("invalid dictionary")),
+                    };
+                }
+                Ok(Value::Dict(dict))
+            }
+            b'0'..=b'9' => {
+                let len: usize = read_until(pos, b':')?
+                    .parse()
+                    .map_err(|_| TorrentError::Invalid("invalid string length"))?;
+                let bytes = pos
+                    .checked_add(len)
+                    .and_then(|end| data.get(*pos..end))
+                    .ok_or(TorrentError::Invalid("unexpected end of data"))?;
+                *pos += len;
+                Ok(Value::Bytes(bytes.to_vec()))
+            }
+            _ => Err(TorrentError::Invalid("unknown value type")),
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Dict(dict) => dict.get(key.as_bytes()),
+            _ => None,
+        }
+    }
+
+    fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Int(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+}
+
+/// The parts of a single-file `.torrent` file that are needed to verify the download.
+pub struct TorrentMeta {
+    /// File name of the download.
+    name: String,
+    length: u64,
+    piece_length: u64,
+    /// SHA-1 of every piece.
+    hashes: Vec<[u8; 20]>,
+}
+
+impl TorrentMeta {
+    pub fn load(path: &Path) -> Result<Self, TorrentError> {
+        let data = fs::read(path)?;
+        let torrent = Value::parse(&data, &mut 0)?;
+        let info = torrent
+            .get("info")
+            .ok_or(TorrentError::Invalid("no info dictionary"))?;
+        if info.get("files").is_some() {
+            return Err(TorrentError::MultipleFiles);
+        }
+
+        let name = info
+            .get("name")
+            .and_then(Value::as_bytes)
+            .and_then(|name| std::str::from_utf8(name).ok())
+            // the name is used as a path
+            .filter(|name| !name.is_empty() && !name.contains('/') && !name.contains(".."))
+            .ok_or(TorrentError::Invalid("missing or invalid name"))?;
+        let length = info
+            .get("length")
+            .and_then(Value::as_int)
+            .filter(|length| *length >= 0)
+            .ok_or(TorrentError::Invalid("missing or invalid length"))? as u64;
+        let piece_length = info
+            .get("piece length")
+            .and_then(Value::as_int)
+            .filter(|length| *length > 0)
+            .ok_or(TorrentError::Invalid("missing or invalid piece length"))?
+            as u64;
+        let pieces = info
+            .get("pieces")
+            .and_then(Value::as_bytes)
+            .ok_or(TorrentError::Invalid("missing pieces"))?;
+        let piece_count = length.div_ceil(piece_length);
+        if piece_count.checked_mul(20) != Some(pieces.len() as u64) {
+            return Err(TorrentError::Invalid("piece hashes don't match the length"));
+        }
+        let hashes = pieces
+            .chunks(20)
+            .map(|hash| {
+                let mut bytes = [0; 20];
+                bytes.copy_from_slice(hash);
+                bytes
+            })
+            .collect();
+
+        Ok(TorrentMeta {
+            name: name.to_string(),
+            length,
+            piece_length,
+            hashes,
+        })
+    }
+}
+
+/// Reads the downloaded file, waiting for each piece to be downloaded and verified.
+pub struct PieceReader {
+    meta: TorrentMeta,
+    /// Where the client writes the file, without a suffix for incomplete files.
+    path: PathBuf,
+    /// None until the client has created the file.
+    file: Option<File>,
+    pos: u64,
+    /// The verified piece containing `pos`, if it was loaded.
+    piece: Option<(usize, Vec<u8>)>,
+    /// The piece that was last written completely with data that doesn't match its hash, the
+    /// hash of that data, and for how many polls it has stayed the same.
+    mismatch: Option<(usize, [u8; 20], u32)>,
+    poll_interval: Duration,
+    last_wait_log: Option<Instant>,
+}
+
+impl PieceReader {
+    fn new(meta: TorrentMeta, path: PathBuf, start: u64) -> Self {
+        PieceReader {
+            meta,
+            path,
+            file: None,
+            pos: start,
+            piece: None,
+            mismatch: None,
+            poll_interval: POLL_INTERVAL,
+            last_wait_log: None,
+        }
+    }
+
+    /// Opens the file under its final name or one of the names of incomplete files.
+    fn open_file(&mut self) -> io::Result<Option<&mut File>> {
+        if self.file.is_none() {
+            let mut candidates = vec![self.path.clone()];
+            for suffix in &INCOMPLETE_SUFFIXES {
+                let mut name = self.path.clone().into_os_string();
+                name.push(suffix);
+                candidates.push(name.into());
+            }
+            for path in candidates {
+                match File::open(&path) {
+                    Ok(file) => {
+                        debug!("reading torrent download from {}", path.display());
+                        self.file = Some(file);
+                        break;
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Ok(self.file.as_mut())
+    }
+
+    /// Reads the piece if the client has downloaded it completely. Fails if it was written
+    /// completely with the same wrong data for `MAX_MISMATCHED_POLLS` polls.
+    fn try_read_piece(&mut self, index: usize) -> io::Result<Option<Vec<u8>>> {
+        let start = index as u64 * self.meta.piece_length;
+        let len = self.meta.piece_length.min(self.meta.length - start) as usize;
+        let file = match self.open_file()? {
+            Some(file) => file,
+            None => return Ok(None),
+        };
+        file.seek(SeekFrom::Start(start))?;
+        let mut data = vec![0; len];
+        match file.read_exact(&mut data) {
+            Ok(()) => (),
+            // not allocated yet
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let hash = sha1_smol::Sha1::from(&data[..]).digest().bytes();
+        if hash == self.meta.hashes[index] {
+            self.mismatch = None;
+            return Ok(Some(data));
+        }
+        if data
+            .chunks(BLOCK_SIZE)
+            .any(|block| block.iter().all(|b| *b == 0))
+        {
+            // still being downloaded
+            return Ok(None);
+        }
+
+        let polls = match self.mismatch {
+            Some((i, last_hash, polls)) if i == index && last_hash == hash => polls + 1,
+            _ => 1,
+        };
+        if polls >= MAX_MISMATCHED_POLLS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "piece {} of {} doesn't match its hash",
+                    index + 1,
+                    self.path.display()
+                ),
+            ));
+        }
+        self.mismatch = Some((index, hash, polls));
+        Ok(None)
+    }
+
+    fn wait_for_piece(&mut self, index: usize) -> io::Result<Vec<u8>> {
+        loop {
+            if let Some(data) = self.try_read_piece(index)? {
+                return Ok(data);
+            }
+            let log_due = match self.last_wait_log {
+                Some(time) => time.elapsed() >= WAIT_LOG_INTERVAL,
+                None => true,
+            };
+            if log_due {
+                info!(
+                    "Waiting for the torrent client to download piece {} of {} of {}",
+                    index + 1,
+                    self.meta.hashes.len(),
+                    self.path.display()
+                );
+                self.last_wait_log = Some(Instant::now());
+            }
+            std::thread::sleep(self.poll_interval);
+        }
+    }
+}
+
+impl Read for PieceReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.meta.length {
+            return Ok(0);
+        }
+        let index = (self.pos / self.meta.piece_length) as usize;
+        if !matches!(&self.piece, Some((loaded, _)) if *loaded == index) {
+            let data = self.wait_for_piece(index)?;
+            self.piece = Some((index, data));
+        }
+
+        let data = &self.piece.as_ref().unwrap().1;
+        let offset = (self.pos - index as u64 * self.meta.piece_length) as usize;
+        let read = buf.len().min(data.len() - offset);
+        buf[..read].copy_from_slice(&data[offset..offset + read]);
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+/// The dump, read from the file a torrent client is downloading.
+pub struct TorrentInput {
+    read: AutoDecoder<SourceReader<PieceReader>>,
+    /// Length of the data from where reading started.
+    length: u64,
+}
+
+impl TorrentInput {
+    /// Reads the download of the `.torrent` file in `data_dir`, or in the directory of the
+    /// `.torrent` file if not given.
+    ///
+    /// Reading starts at the start point of the bz2 index (if any), and seek points are recorded
+    /// to it.
+    pub fn open(
+        torrent_path: &Path,
+        data_dir: Option<&Path>,
+        checksum: Option<Checksum>,
+        index: Option<Bz2Index>,
+        decoder_threads: usize,
+    ) -> Result<Self, TorrentError> {
+        let meta = TorrentMeta::load(torrent_path)?;
+        let dir = match data_dir {
+            Some(dir) => dir,
+            None => torrent_path.parent().unwrap_or_else(|| Path::new(".")),
+        };
+        let path = dir.join(&meta.name);
+        info!(
+            "Reading {} ({:.2} MB) as the torrent client downloads it",
+            path.display(),
+            meta.length as f64 / 1_000_000.
+        );
+
+        let start = index.as_ref().map_or(0, |index| index.start().compressed);
+        let length = meta.length.saturating_sub(start);
+        let mut read = AutoDecoder::with_threads(
+            SourceReader::new(BufReader::new(PieceReader::new(meta, path, start)))
+                .with_checksum(checksum),
+            decoder_threads,
+        );
+        if let Some(index) = index {
+            read.set_index(index);
+        }
+        Ok(TorrentInput { read, length })
+    }
+}
+
+impl DataInput for TorrentInput {
+    type Error = io::Error;
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let read = self.read.read(buf)?;
+        if read == 0 {
+            self.read.inner_mut().finish()?;
+        }
+        Ok(read)
+    }
+
+    fn bytes_read(&self) -> u64 {
+        self.read.total_in()
+    }
+
+    fn content_length(&self) -> Option<u64> {
+        Some(self.length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(data: &[u8]) -> Result<Value, TorrentError> {
+        let mut pos = 0;
+        let value = Value::parse(data, &mut pos)?;
+        assert_eq!(pos, data.len());
+        Ok(value)
+    }
+
+    fn invalid(data: &[u8]) -> &'static str {
+        match Value::parse(data, &mut 0) {
+            Err(TorrentError::Invalid(reason)) => reason,
+            Ok(_) => panic!("{:?} was parsed", String::from_utf8_lossy(data)),
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// A `.torrent` file of a single file with the info dictionary.
+    fn load(info: &[u8]) -> Result<TorrentMeta, TorrentError> {
+        let mut data = b"d8:announce9:udp://foo4:info".to_vec();
+        data.extend_from_slice(info);
+        data.push(b'e');
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dump.torrent");
+        fs::write(&path, data).unwrap();
+        TorrentMeta::load(&path)
+    }
+
+    #[test]
+    fn parse_values() {
+        assert_eq!(parse(b"i42e").unwrap().as_int(), Some(42));
+        assert_eq!(parse(b"i-3e").unwrap().as_int(), Some(-3));
+        assert_eq!(parse(b"4:spam").unwrap().as_bytes(), Some(&b"spam"[..]));
+        assert_eq!(parse(b"0:").unwrap().as_bytes(), Some(&b""[..]));
+        assert!(matches!(parse(b"l4:spami42ee").unwrap(), Value::List));
+        assert!(matches!(parse(b"le").unwrap(), Value::List));
+
+        let dict = parse(b"d3:cow3:moo4:listl1:ae4:dictd1:ki1eee").unwrap();
+        assert_eq!(dict.get("cow").and_then(Value::as_bytes), Some(&b"moo"[..]));
+        assert!(matches!(dict.get("list"), Some(Value::List)));
+        let inner = dict.get("dict").unwrap();
+        assert_eq!(inner.get("k").and_then(Value::as_int), Some(1));
+        assert!(dict.get("missing").is_none());
+    }
+
+    #[test]
+    fn parse_invalid_values() {
+        assert_eq!(invalid(b""), "unexpected end of data");
+        assert_eq!(invalid(b"i42"), "unexpected end of data");
+        assert_eq!(invalid(b"ie"), "invalid integer");
+        assert_eq!(invalid(b"i4x2e"), "invalid integer");
+        assert_eq!(invalid(b"5:spam"), "unexpected end of data");
+        assert_eq!(
+            invalid(b"18446744073709551615:spam"),
+            "unexpected end of data"
+        );
+        assert_eq!(
+            invalid(b"99999999999999999999:spam"),
+            "invalid string length"
+        );
+        assert_eq!(invalid(b"l4:spam"), "unexpected end of data");
+        assert_eq!(invalid(b"d3:cowe"), "invalid dictionary");
+        assert_eq!(invalid(b"di1e3:mooe"), "invalid dictionary");
+        assert_eq!(invalid(b"x"), "unknown value type");
+    }
+
+    #[test]
+    fn parse_nested_values() {
+        let nested = |depth| [b"l".repeat(depth), b"e".repeat(depth)].concat();
+        assert!(matches!(parse(&nested(MAX_DEPTH)).unwrap(), Value::List));
+        assert_eq!(invalid(&nested(MAX_DEPTH + 1)), "values nested too deeply");
+        // deeper than the stack would allow without the limit
+        assert_eq!(invalid(&b"l".repeat(1_000_000)), "values nested too deeply");
+    }
+
+    /// A reader of the download of `data` in pieces of 4 blocks, which doesn't wait between polls.
+    fn piece_reader(dir: &Path, data: &[u8]) -> PieceReader {
+        let piece_length = 4 * BLOCK_SIZE;
+        let meta = TorrentMeta {
+            name: "dump.bz2".into(),
+            length: data.len() as u64,
+            piece_length: piece_length as u64,
+            hashes: data
+                .chunks(piece_length)
+                .map(|piece| sha1_smol::Sha1::from(piece).digest().bytes())
+                .collect(),
+        };
+        let mut reader = PieceReader::new(meta, dir.join("dump.bz2"), 0);
+        reader.poll_interval = Duration::ZERO;
+        reader
+    }
+
+    /// Data without blocks of zeros.
+    fn download() -> Vec<u8> {
+        (0..10 * BLOCK_SIZE).map(|i| (i % 251 + 1) as u8).collect()
+    }
+
+    #[test]
+    fn read_pieces() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = download();
+        fs::write(dir.path().join("dump.bz2.part"), &data).unwrap();
+        let mut read = Vec::new();
+        piece_reader(dir.path(), &data)
+            .read_to_end(&mut read)
+            .unwrap();
+        assert!(read == data);
+    }
+
+    #[test]
+    fn wait_for_pieces() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dump.bz2");
+        let data = download();
+        let mut reader = piece_reader(dir.path(), &data);
+        // not created yet
+        assert!(reader.try_read_piece(0).unwrap().is_none());
+
+        // allocated, with a block of the second piece that isn't written yet
+        let mut written = data.clone();
+        written[5 * BLOCK_SIZE..6 * BLOCK_SIZE].fill(0);
+        fs::write(&path, &written).unwrap();
+        assert!(reader.try_read_piece(0).unwrap().is_some());
+        for _ in 0..2 * MAX_MISMATCHED_POLLS {
+            assert!(reader.try_read_piece(1).unwrap().is_none());
+        }
+
+        fs::write(&path, &data).unwrap();
+        assert!(reader.try_read_piece(1).unwrap().is_some());
+    }
+
+    #[test]
+    fn fail_on_mismatched_pieces() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dump.bz2");
+        let data = download();
+        let mut reader = piece_reader(dir.path(), &data);
+        let mut written = data.clone();
+        written[5 * BLOCK_SIZE] ^= 0xff;
+        fs::write(&path, &written).unwrap();
+
+        // the client may still download the piece again
+        for _ in 1..MAX_MISMATCHED_POLLS {
+            assert!(reader.try_read_piece(1).unwrap().is_none());
+        }
+        written[5 * BLOCK_SIZE + 1] ^= 0xff;
+        fs::write(&path, &written).unwrap();
+        for _ in 1..MAX_MISMATCHED_POLLS {
+            assert!(reader.try_read_piece(1).unwrap().is_none());
+        }
+
+        let mut read = Vec::new();
+        let err = reader.read_to_end(&mut read).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(read.len(), 4 * BLOCK_SIZE);
+    }
+
+    #[test]
+    fn load_torrent() {
+        let meta = load(
+            b"d6:lengthi5e4:name8:dump.bz212:piece lengthi4e6:pieces40:aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbbe",
+        )
+        .unwrap();
+        assert_eq!(meta.name, "dump.bz2");
+        assert_eq!(meta.length, 5);
+        assert_eq!(meta.piece_length, 4);
+        assert_eq!(meta.hashes, [[b'a'; 20], [b'b'; 20]]);
+    }
+
+    #[test]
+    fn load_invalid_torrents() {
+        assert!(matches!(
+            load(b"d5:filesle4:name4:dumpe"),
+            Err(TorrentError::MultipleFiles)
+        ));
+        let invalid = |info: &[u8]| match load(info) {
+            Err(TorrentError::Invalid(reason)) => reason,
+            _ => panic!("{:?} was loaded", String::from_utf8_lossy(info)),
+        };
+        assert_eq!(
+            invalid(b"d6:lengthi5e4:name5:../db12:piece lengthi4e6:pieces0:e"),
+            "missing or invalid name"
+        );
+        assert_eq!(
+            invalid(b"d6:lengthi-1e4:name4:dump12:piece lengthi4e6:pieces0:e"),
+            "missing or invalid length"
+        );
+        assert_eq!(
+            invalid(b"d6:lengthi5e4:name4:dump12:piece lengthi0e6:pieces0:e"),
+            "missing or invalid piece length"
+        );
+        assert_eq!(
+            invalid(b"d6:lengthi5e4:name4:dump12:piece lengthi4e6:pieces20:aaaaaaaaaaaaaaaaaaaae"),
+            "piece hashes don't match the length"
+        );
+        // too many pieces to have hashes
+        assert_eq!(
+            invalid(b"d6:lengthi9223372036854775807e4:name4:dump12:piece lengthi1e6:pieces0:e"),
+            "piece hashes don't match the length"
+        );
+    }
+}
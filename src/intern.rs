@@ -0,0 +1,159 @@
+//! Compact handles for the IDs and codes that repeat across entries.
+
+use rusqlite::types::{ToSql, ToSqlOutput};
+use serde::{Serialize, Serializer};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{OnceLock, RwLock};
+
+/// An entity ID (like `Q30`) or another short string that repeats across entries (like the
+/// language code `en`), so that entries don't carry a heap-allocated String for each of them.
+///
+/// Q-, P- and L-IDs are packed into the symbol itself. Other strings are interned in a global pool
+/// that lives until the end of the process, so this should only be used for values with few
+/// distinct strings.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Symbol(u64);
+
+/// The top two bits of a symbol: a pool index or the prefix of a packed entity ID.
+const TAG_SHIFT: u32 = 62;
+const TAG_POOL: u64 = 0;
+const ID_PREFIXES: [char; 3] = ['Q', 'P', 'L'];
+
+#[derive(Default)]
+struct Pool {
+    indices: HashMap<&'static str, u64>,
+    strings: Vec<&'static str>,
+}
+
+fn pool() -> &'static RwLock<Pool> {
+    static POOL: OnceLock<RwLock<Pool>> = OnceLock::new();
+    POOL.get_or_init(Default::default)
+}
+
+impl Symbol {
+    pub fn new(s: &str) -> Self {
+        Self::pack_id(s).unwrap_or_else(|| Self::intern(s))
+    }
+
+    /// Packs canonical IDs: the prefix and a number without leading zeros that fits.
+    fn pack_id(s: &str) -> Option<Self> {
+        let mut chars = s.chars();
+        let prefix = chars.next()?;
+        let tag = ID_PREFIXES.iter().position(|&p| p == prefix)? as u64 + 1;
+        let digits = chars.as_str();
+        if digits.is_empty()
+            || digits.len() > 18
+            || digits.starts_with('0')
+            || !digits.bytes().all(|b| b.is_ascii_digit())
+        {
+            return None;
+        }
+        Some(Symbol((tag << TAG_SHIFT) | digits.parse::<u64>().ok()?))
+    }
+
+    fn intern(s: &str) -> Self {
+        if let Some(&index) = pool().read().unwrap().indices.get(s) {
+            return Symbol(index);
+        }
+        let mut pool = pool().write().unwrap();
+        if let Some(&index) = pool.indices.get(s) {
+            return Symbol(index);
+        }
+        let index = pool.strings.len() as u64;
+        let s: &'static str = Box::leak(s.into());
+        pool.strings.push(s);
+        pool.indices.insert(s, index);
+        Symbol(index)
+    }
+
+    pub fn as_str(self) -> Cow<'static, str> {
+        let tag = self.0 >> TAG_SHIFT;
+        let value = self.0 & ((1 << TAG_SHIFT) - 1);
+        if tag == TAG_POOL {
+            Cow::Borrowed(pool().read().unwrap().strings[value as usize])
+        } else {
+            Cow::Owned(format!("{}{}", ID_PREFIXES[tag as usize - 1], value))
+        }
+    }
+}
+
+impl From<&str> for Symbol {
+    fn from(s: &str) -> Self {
+        Symbol::new(s)
+    }
+}
+
+impl From<Cow<'_, str>> for Symbol {
+    fn from(s: Cow<'_, str>) -> Self {
+        Symbol::new(&s)
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.as_str())
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&*self.as_str(), f)
+    }
+}
+
+impl Serialize for Symbol {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.as_str())
+    }
+}
+
+impl ToSql for Symbol {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str().into_owned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pack_canonical_ids() {
+        for id in ["Q64", "P17", "L1", "Q1", "Q999999999999999999"] {
+            let symbol = Symbol::pack_id(id).expect(id);
+            assert_ne!(symbol.0 >> TAG_SHIFT, TAG_POOL, "{}", id);
+            assert_eq!(symbol.as_str(), id);
+            assert_eq!(Symbol::new(id), symbol);
+        }
+        assert_ne!(Symbol::new("Q64"), Symbol::new("P64"));
+    }
+
+    #[test]
+    fn intern_other_strings() {
+        // these wouldn't be the same string after packing
+        let others = [
+            "Q",
+            "Q0",
+            "Q064",
+            "q64",
+            "Q+64",
+            "Q64a",
+            // the prefix is only the first character
+            "AP17",
+            "QL1",
+            "Q1000000000000000000",
+            "Q١",
+            "en",
+            "",
+        ];
+        for s in others {
+            assert!(Symbol::pack_id(s).is_none(), "{}", s);
+            let symbol = Symbol::new(s);
+            assert_eq!(symbol.0 >> TAG_SHIFT, TAG_POOL, "{}", s);
+            assert_eq!(symbol.as_str(), s);
+            assert_eq!(Symbol::new(s), symbol);
+        }
+    }
+}
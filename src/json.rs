@@ -1,42 +1,47 @@
-#[macro_export]
-macro_rules! json_get {
-    (($obj:expr) . $key:ident $($rest:tt)*) => {{
-        let value = $obj.get(stringify!($key));
-        json_get!(optval (value) $($rest)*)
-    }};
-    (($obj:expr) [ $key:expr ] $($rest:tt)*) => {{
-        let value = $obj.get($key);
-        json_get!(optval (value) $($rest)*)
-    }};
-    (value ($obj:expr) . $key:ident $($rest:tt)*) => {{
-        let value = $obj.as_object().map_or(None, |v| v.get(stringify!($key)));
-        json_get!(optval (value) $($rest)*)
-    }};
-    (value ($obj:expr) [ $key:expr ] $($rest:tt)*) => {{
-        let value = $obj.as_array().map_or(None, |v| v.get($key));
-        json_get!(optval (value) $($rest)*)
-    }};
-    (optval ($obj:expr) . $key:ident $($rest:tt)*) => {{
-        let value = $obj.map_or(None, |v| v.as_object()).map_or(None, |v| v.get(stringify!($key)));
-        json_get!(optval (value) $($rest)*)
-    }};
-    (optval ($obj:expr) [ $key:expr ] $($rest:tt)*) => {{
-        let value = $obj.map_or(None, |v| v.as_array()).map_or(None, |v| v.get($key));
-        json_get!(optval (value) $($rest)*)
-    }};
-    (optval ($obj:expr) : number) => {{
-        $obj.map_or(None, |v| v.as_f64())
-    }};
-    (optval ($obj:expr) : string) => {{
-        $obj.map_or(None, |v| v.as_str())
-    }};
-    (optval ($obj:expr) : object) => {{
-        $obj.map_or(None, |v| v.as_object())
-    }};
-    (optval ($obj:expr) : array) => {{
-        $obj.map_or(None, |v| v.as_array())
-    }};
-    (optval ($obj:expr) : u64) => {{
-        $obj.map_or(None, |v| v.as_u64())
-    }};
+// Lines of the dump are deserialized with serde_json by default, or with simd-json with the
+// `simd-json` feature, which is a lot faster on the dump.
+
+use serde::Deserialize;
+
+#[cfg(not(feature = "simd-json"))]
+pub type ParseError = serde_json::Error;
+
+#[cfg(feature = "simd-json")]
+pub type ParseError = simd_json::Error;
+
+/// Parses a line of the dump.
+///
+/// simd-json parses in place, so the line is copied to `buffer`, which the result may borrow from.
+#[cfg(not(feature = "simd-json"))]
+pub fn parse_line<'v, T: Deserialize<'v>>(
+    line: &'v str,
+    _buffer: &'v mut Vec<u8>,
+) -> Result<T, ParseError> {
+    serde_json::from_str(line)
+}
+
+/// Parses a line of the dump.
+///
+/// simd-json parses in place, so the line is copied to `buffer`, which the result may borrow from.
+#[cfg(feature = "simd-json")]
+pub fn parse_line<'v, T: Deserialize<'v>>(
+    line: &'v str,
+    buffer: &'v mut Vec<u8>,
+) -> Result<T, ParseError> {
+    buffer.clear();
+    buffer.extend_from_slice(line.as_bytes());
+    simd_json::serde::from_slice(buffer)
+}
+
+/// Byte offset of a parse error in the line.
+#[cfg(not(feature = "simd-json"))]
+pub fn error_offset(err: &ParseError) -> usize {
+    // entities are on a single line, so the column is the byte offset in the line
+    err.column().saturating_sub(1)
+}
+
+/// Byte offset of a parse error in the line.
+#[cfg(feature = "simd-json")]
+pub fn error_offset(err: &ParseError) -> usize {
+    err.index()
 }
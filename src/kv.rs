@@ -0,0 +1,64 @@
+//! Embedded key-value output for fast point lookups.
+//!
+//! Every entity is stored under its ID as newline-delimited JSON of all [`DataEntry`] values
+//! produced for it (the primary entry as well as labels, languages, parents, etc.).
+//!
+//! Since entries for the same entity arrive separately, they are combined with a sled merge
+//! operator that appends each new entry to the existing value. This is a read-modify-write of
+//! the whole record per entry, so entities with many labels cause more write amplification
+//! than the SQLite output, but no second pass over the data is needed. Duplicate entries are
+//! kept as-is.
+
+use crate::database::{DataEntry, DataSink};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum KvError {
+    #[error("sled error: {0}")]
+    Sled(#[from] sled::Error),
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+fn append_entry(_key: &[u8], old: Option<&[u8]>, entry: &[u8]) -> Option<Vec<u8>> {
+    let mut value = old.map(|old| old.to_vec()).unwrap_or_default();
+    value.extend_from_slice(entry);
+    value.push(b'\n');
+    Some(value)
+}
+
+/// Sled output, with one record per entity.
+pub struct KvSink {
+    db: sled::Db,
+}
+
+impl KvSink {
+    pub fn open(out_dir: &str) -> Result<Self, KvError> {
+        debug!("Opening KV store");
+        let db = sled::open(out_dir)?;
+        db.set_merge_operator(append_entry);
+        Ok(KvSink { db })
+    }
+}
+
+impl DataSink for KvSink {
+    type Error = KvError;
+
+    fn write(&mut self, entry: DataEntry) -> Result<(), KvError> {
+        if let DataEntry::RemoveEntity { id } = &entry {
+            self.db.remove(id.as_str().as_bytes())?;
+            return Ok(());
+        }
+        // checkpoints and metadata don't belong to an entity and aren't stored
+        if let Some(id) = entry.id() {
+            self.db
+                .merge(id.as_str().as_bytes(), serde_json::to_vec(&entry)?)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), KvError> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
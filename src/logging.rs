@@ -0,0 +1,138 @@
+//! Log output, either as colored lines of text or as one JSON object per record for log
+//! collectors (e.g. journald or Kubernetes).
+
+use crate::intern::Symbol;
+use log::kv::{Key, Value, VisitSource};
+use serde_json::Map;
+use std::cell::Cell;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl LogFormat {
+    pub const NAMES: [&'static str; 2] = ["text", "json"];
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            s => Err(format!("unknown log format {:?}", s)),
+        }
+    }
+}
+
+/// What the current thread is working on, added to JSON records.
+#[derive(Clone, Copy, Default)]
+struct Context {
+    line: Option<u64>,
+    entity: Option<Symbol>,
+}
+
+thread_local! {
+    static CONTEXT: Cell<Context> = Cell::new(Context::default());
+}
+
+/// Restores the previous context of the thread when dropped.
+pub struct ContextGuard(Context);
+
+impl Drop for ContextGuard {
+    fn drop(&mut self) {
+        CONTEXT.with(|context| context.set(self.0));
+    }
+}
+
+fn enter(update: impl FnOnce(&mut Context)) -> ContextGuard {
+    CONTEXT.with(|context| {
+        let previous = context.get();
+        let mut current = previous;
+        update(&mut current);
+        context.set(current);
+        ContextGuard(previous)
+    })
+}
+
+/// Attributes the records logged on this thread to a line of the dump, until the guard is dropped.
+pub fn enter_line(line_number: u64) -> ContextGuard {
+    enter(|context| {
+        context.line = Some(line_number);
+        context.entity = None;
+    })
+}
+
+/// Attributes the records logged on this thread to an entity, until the guard is dropped.
+pub fn enter_entity(id: Symbol) -> ContextGuard {
+    enter(|context| context.entity = Some(id))
+}
+
+/// Adds the key-value pairs of a record (like `skip_reason`) to a JSON object.
+struct JsonFields<'a>(&'a mut Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        let value = match value.to_u64() {
+            Some(n) => n.into(),
+            None => value.to_string().into(),
+        };
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+fn json_record(message: &std::fmt::Arguments, record: &log::Record) -> String {
+    let mut object = Map::new();
+    object.insert(
+        "time".into(),
+        chrono::Utc::now()
+            .to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+            .into(),
+    );
+    object.insert("level".into(), record.level().as_str().into());
+    object.insert("target".into(), record.target().into());
+    object.insert("message".into(), message.to_string().into());
+    let context = CONTEXT.with(Cell::get);
+    if let Some(line) = context.line {
+        object.insert("line".into(), line.into());
+    }
+    if let Some(entity) = context.entity {
+        object.insert("entity".into(), entity.to_string().into());
+    }
+    // the record was logged by this crate, so its fields can't fail to be visited
+    let _ = record.key_values().visit(&mut JsonFields(&mut object));
+    serde_json::Value::Object(object).to_string()
+}
+
+/// Sets up the global logger. Records are printed to stdout, above the progress bars if they're
+/// shown.
+pub fn init(format: LogFormat, verbose: bool) {
+    let colors = fern::colors::ColoredLevelConfig::new();
+    fern::Dispatch::new()
+        .format(move |out, msg, record| match format {
+            LogFormat::Text => out.finish(format_args!(
+                "{}\x1b[{}m[{} {}] {}\x1b[m",
+                chrono::Local::now().format("[%H:%M:%S]"),
+                colors.get_color(&record.level()).to_fg_str(),
+                record.level(),
+                record.target(),
+                msg
+            )),
+            LogFormat::Json => out.finish(format_args!("{}", json_record(msg, record))),
+        })
+        .level(if verbose {
+            log::LevelFilter::Debug
+        } else {
+            log::LevelFilter::Info
+        })
+        .chain(fern::Output::call(|record| {
+            crate::progress::print_log_line(record.args())
+        }))
+        .apply()
+        .unwrap();
+}
@@ -1,36 +1,594 @@
 #[macro_use]
 extern crate log;
 
+use crate::database::Backend;
+use crate::input::bz2_index::{Bz2Index, SeekPoint};
+use crate::input::checksum::Checksum;
 use crate::input::DataInput;
+use crate::logging::LogFormat;
+use crate::options::{DumpDate, Options};
 use clap::{App, Arg, SubCommand};
+use std::collections::BTreeMap;
+use std::path::Path;
 use std::process::exit;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
 
+mod config;
 mod database;
+mod diff;
+mod export;
+mod follow;
+mod incremental;
 mod input;
+mod intern;
 mod json;
+mod kv;
+mod logging;
+mod memory;
+mod metrics;
+mod migrate;
+mod options;
+mod output;
+#[cfg(feature = "postgres")]
+mod pg;
 mod post;
+mod progress;
+mod snapshot;
+mod timing;
 mod wiki_data_line;
+mod wiki_entity;
 mod wiki_sparql;
 mod wiki_time;
 
 fn main() {
     let matches = App::new("geo-db")
         .about("streams the latest WikiData dump and saves it to a file")
+        .arg(
+            Arg::with_name("head")
+                .long("head")
+                .value_name("N")
+                .help("Prints the first N entities of the dump as JSON and exits")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .value_name("FILE")
+                .help("Reads options from a TOML file; flags on the command line take precedence")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("input")
+                .short("i")
+                .long("input")
+                .value_name("URL_OR_FILE")
+                .help("Reads the dump (bz2, gzip, zstd or uncompressed) from this URL (http(s):// or s3://bucket/key), local file or .torrent file (default: the latest Wikidata dump)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("mirror")
+                .long("mirror")
+                .value_name("URL")
+                .help("Another URL of the same dump; the fastest of these and --input is used, and the others if it fails")
+                .takes_value(true)
+                .multiple(true)
+                .use_delimiter(true),
+        )
+        .arg(
+            Arg::with_name("file")
+                .long("file")
+                .value_name("PATH")
+                .help("Reads an already downloaded dump (e.g. latest-all.json.bz2)")
+                .takes_value(true)
+                .conflicts_with("input"),
+        )
+        .arg(
+            Arg::with_name("dump_date")
+                .long("dump-date")
+                .value_name("YYYYMMDD")
+                .help("Downloads the Wikidata dump of this date instead of the latest one (only recent dumps are kept)")
+                .takes_value(true)
+                .conflicts_with_all(&["input", "file"]),
+        )
+        .arg(
+            Arg::with_name("threads")
+                .short("j")
+                .long("threads")
+                .value_name("N")
+                .help("Sets the number of line handler threads and of bz2 decoder threads (default: one less than the number of CPUs)")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("out")
                 .short("o")
                 .long("output")
-                .help("Sets the output file")
+                .alias("out")
+                .help("Sets the output file (or directory for the sled backend)")
                 .takes_value(true)
                 .default_value("geo.db"),
         )
+        .arg(
+            Arg::with_name("backend")
+                .long("backend")
+                .help("Sets the output backend")
+                .takes_value(true)
+                .possible_values(&Backend::NAMES)
+                .default_value("sqlite"),
+        )
+        .arg(
+            Arg::with_name("db")
+                .long("db")
+                .value_name("URL")
+                .help("Writes to a PostgreSQL database (postgres://…) instead of the output file")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("verbose")
                 .short("v")
                 .long("verbose")
                 .help("Prints debug info"),
         )
+        .arg(
+            Arg::with_name("log_format")
+                .long("log-format")
+                .help("Sets the log format (json: one object per record, with the line number and entity ID it's about)")
+                .takes_value(true)
+                .possible_values(&LogFormat::NAMES)
+                .default_value("text"),
+        )
+        .arg(
+            Arg::with_name("no_languages")
+                .long("no-languages")
+                .help("Skips language entities (object languages are still extracted, but the languages table stays empty)"),
+        )
+        .arg(
+            Arg::with_name("fail_fast")
+                .long("fail-fast")
+                .help("Aborts on the first entity that fails to parse"),
+        )
+        .arg(
+            Arg::with_name("resilient")
+                .long("resilient")
+                .conflicts_with("fail_fast")
+                .help("Logs entities that fail to parse and continues (default)"),
+        )
+        .arg(
+            Arg::with_name("no_progress")
+                .long("no-progress")
+                .help("Logs the progress every few seconds instead of showing progress bars (the default if stderr is not a terminal)"),
+        )
+        .arg(
+            Arg::with_name("metrics_addr")
+                .long("metrics-addr")
+                .value_name("ADDR")
+                .help("Serves Prometheus metrics of the run over HTTP on this address (e.g. 0.0.0.0:9100)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max_memory")
+                .long("max-memory")
+                .value_name("MB")
+                .help("Pauses reading the dump while the process uses more memory than this, until the line handlers and DB writer have caught up (Linux only)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("bulk_load")
+                .long("bulk-load")
+                .help("Writes the SQLite output with faster, less safe settings: an exclusive lock, a large cache and transactions that are only committed at checkpoints"),
+        )
+        .arg(
+            Arg::with_name("rows_per_commit")
+                .long("rows-per-commit")
+                .value_name("N")
+                .help("Sets the number of entries per SQLite transaction (default: 127, or 100000 with --bulk-load)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("pretty_errors")
+                .long("pretty-errors")
+                .help("Shows the part of the line around JSON parse errors"),
+        )
+        .arg(
+            Arg::with_name("coord_crs")
+                .long("coord-crs")
+                .help("Sets the CRS of extracted coordinates (4326: WGS84 degrees, 3857: Web Mercator meters)")
+                .takes_value(true)
+                .possible_values(&["4326", "3857"])
+                .default_value("4326"),
+        )
+        .arg(
+            Arg::with_name("validate_coords")
+                .long("validate-coords")
+                .help("Sets what happens to out-of-range coordinates (swap: swap lat/lon if that makes them valid, otherwise skip)")
+                .takes_value(true)
+                .possible_values(&["off", "skip", "clamp", "swap"])
+                .default_value("skip"),
+        )
+        .arg(
+            Arg::with_name("aliases")
+                .long("aliases")
+                .help("Extracts aliases that differ from the entity's labels"),
+        )
+        .arg(
+            Arg::with_name("label_languages")
+                .long("label-languages")
+                .value_name("LANGS")
+                .help("Only extracts labels, aliases and short names in these languages (e.g. en,eo); native names are always extracted")
+                .takes_value(true)
+                .multiple(true)
+                .use_delimiter(true),
+        )
+        .arg(
+            Arg::with_name("short_name_labels")
+                .long("short-name-labels")
+                .help("Uses short names (P1813) as labels in languages that have no label"),
+        )
+        .arg(
+            Arg::with_name("alias_dedup_scope")
+                .long("alias-dedup-scope")
+                .help("Drops aliases matching a label in the same language or in any language")
+                .takes_value(true)
+                .possible_values(&["language", "entity"])
+                .default_value("language"),
+        )
+        .arg(
+            Arg::with_name("quality_events")
+                .long("quality-events")
+                .help("Also stores data quality warnings in the data_quality table"),
+        )
+        .arg(
+            Arg::with_name("http_buffer_bytes")
+                .long("http-buffer-bytes")
+                .help("Sets the size of the buffer between the download and the decompressor (default: 1 MiB)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("http_retries")
+                .long("http-retries")
+                .value_name("N")
+                .help("Gives up the download after N failed connection attempts in a row (default: 32)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("http_retry_interval")
+                .long("http-retry-interval")
+                .value_name("SECS")
+                .help("Waits this long before the first retry, doubling with every further try (default: 8)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("http_retry_max_interval")
+                .long("http-retry-max-interval")
+                .value_name("SECS")
+                .help("Waits at most this long between retries (default: 300)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("http_retry_jitter")
+                .long("http-retry-jitter")
+                .value_name("FRACTION")
+                .help("Randomly varies each wait by up to this fraction of it (default: 0.2)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("cache_download")
+                .long("cache-download")
+                .value_name("PATH")
+                .help("Saves the downloaded dump to this file, or reads it from there if it already exists")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("torrent_dir")
+                .long("torrent-dir")
+                .value_name("DIR")
+                .help("Reads the download of a .torrent input from this directory, where a torrent client with sequential downloading saves it (default: next to the .torrent file)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("checksums")
+                .long("checksums")
+                .value_name("URL_OR_FILE")
+                .help("Verifies the dump against the MD5 or SHA-1 listed for it in this file (e.g. wikidata-20240101-md5sums.txt)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("checksum_warn_only")
+                .long("checksum-warn-only")
+                .help("Only warns if the dump doesn't match the checksum instead of failing"),
+        )
+        .arg(
+            Arg::with_name("max_download_rate")
+                .long("max-download-rate")
+                .value_name("MB_PER_SEC")
+                .help("Limits the average download speed (e.g. 5 for 5 MB/s)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("entity_types")
+                .long("entity-types")
+                .help("Only extracts these kinds of entities (default: all)")
+                .takes_value(true)
+                .use_delimiter(true)
+                .possible_values(&options::EntityType::NAMES),
+        )
+        .arg(
+            Arg::with_name("statement_coords")
+                .long("statement-coords")
+                .help("Extracts coordinate qualifiers on located-in and country statements"),
+        )
+        .arg(
+            Arg::with_name("replaces")
+                .long("replaces")
+                .help("Extracts which entities each entity replaces (succession chains)"),
+        )
+        .arg(
+            Arg::with_name("part_of")
+                .long("part-of")
+                .help("Extracts P361 (part of) and uses it to find the country of settlements without P17"),
+        )
+        .arg(
+            Arg::with_name("include_historical")
+                .long("include-historical")
+                .help("Also processes replaced and dissolved entities instead of skipping them"),
+        )
+        .arg(
+            Arg::with_name("max_line_bytes")
+                .long("max-line-bytes")
+                .help("Lines larger than this are handled one at a time instead of in parallel (default: 64 MiB)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max_error_rate")
+                .long("max-error-rate")
+                .value_name("RATE")
+                .help("Exits with an error if more than this fraction of lines failed (e.g. 0.01)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("limit")
+                .long("limit")
+                .value_name("N")
+                .help("Stops after the first N lines of the dump, e.g. to try out changes quickly")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("sample_rate")
+                .long("sample-rate")
+                .value_name("RATE")
+                .help("Only processes this fraction of the lines (e.g. 0.01), picked at random but the same in every run")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("deterministic")
+                .long("deterministic")
+                .help("Writes entries in the order of the dump lines, so that builds of the same dump are identical"),
+        )
+        .arg(
+            Arg::with_name("classes_file")
+                .long("classes")
+                .value_name("PATH")
+                .help("Loads the class sets from this file instead of the SPARQL endpoint, and saves them to it if it doesn't exist")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("dump_matched")
+                .long("dump-matched")
+                .value_name("PATH")
+                .help("Also writes the raw JSON line of every matched entity to this file")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("min_population")
+                .long("min-population")
+                .value_name("N")
+                .help("Skips settlements whose largest population (P1082) is below N")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("require_property")
+                .long("require-property")
+                .value_name("PROPERTY")
+                .help("Skips places without a claim for these properties (e.g. P625)")
+                .takes_value(true)
+                .multiple(true)
+                .use_delimiter(true),
+        )
+        .arg(
+            Arg::with_name("country")
+                .long("country")
+                .value_name("QID")
+                .help("Skips places that aren't in one of these countries (P17)")
+                .takes_value(true)
+                .multiple(true)
+                .use_delimiter(true),
+        )
+        .arg(
+            Arg::with_name("class")
+                .long("class")
+                .value_name("QID")
+                .help("Skips places that aren't directly an instance (P31) of one of these classes")
+                .takes_value(true)
+                .multiple(true)
+                .use_delimiter(true),
+        )
+        .arg(
+            Arg::with_name("only_country")
+                .long("only-country")
+                .value_name("COUNTRY")
+                .help("Skips places that aren't in this country (QID or ISO code) by P17 or their P131 chain")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("output_compression")
+                .long("output-compression")
+                .help("Sets the compression for file outputs (--dump-matched and the ndjson backend)")
+                .takes_value(true)
+                .possible_values(&["none", "gz", "zst"])
+                .default_value("none"),
+        )
+        .arg(
+            Arg::with_name("resume_from_id")
+                .long("resume-from-id")
+                .value_name("QID")
+                .help("Skips entities until this one (or a numerically greater one) is reached")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("bz2_index")
+                .long("bz2-index")
+                .value_name("PATH")
+                .help("Records the positions of bz2 streams in the dump to this file, for seeking with --resume-offset")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("resume_offset")
+                .long("resume-offset")
+                .value_name("BYTES")
+                .help("Starts at this offset in the decompressed dump (as logged), seeking to the closest stream in --bz2-index")
+                .takes_value(true),
+        )
+        .subcommand(
+            SubCommand::with_name("build")
+                .about("builds the database from the dump (the default if no subcommand is given)")
+                .arg(
+                    Arg::with_name("input")
+                        .help("the dump to read instead of --input, or - for decompressed JSON on stdin")
+                        .index(1)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("resume")
+                .about("builds the database, skipping the dump up to an entity (like --resume-from-id) or an offset (like --resume-offset), or continues an interrupted build of the SQLite output")
+                .arg(
+                    Arg::with_name("entity")
+                        .help("the entity id (including Q) to resume from")
+                        .index(1)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("offset")
+                        .long("offset")
+                        .value_name("BYTES")
+                        .help("the offset in the decompressed dump to resume from")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("update")
+                .about("updates an existing database (sqlite or sled) with the entities in an incremental dump")
+                .arg(
+                    Arg::with_name("input")
+                        .help("the daily incremental dump (XML), or a JSON dump of the changed entities")
+                        .index(1)
+                        .takes_value(true)
+                        .required_unless("date"),
+                )
+                .arg(
+                    Arg::with_name("date")
+                        .long("date")
+                        .value_name("YYYYMMDD")
+                        .help("downloads the daily incremental dump of this date")
+                        .takes_value(true)
+                        .conflicts_with("input"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("follow")
+                .about("keeps an existing database (sqlite or sled) up to date with the edits made on Wikidata")
+                .arg(
+                    Arg::with_name("since")
+                        .long("since")
+                        .value_name("TIME")
+                        .help("applies the edits since this time (ISO 8601, at most a few days ago) instead of since the last run stopped")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("writes a table of a SQLite database as NDJSON, Parquet, CSV, GeoJSON or GeoPackage")
+                .arg(
+                    Arg::with_name("database")
+                        .help("the database file")
+                        .index(1)
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("file")
+                        .help("the file to write (a directory with --all-tables)")
+                        .index(2)
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("table")
+                        .help("the table to export")
+                        .long("table")
+                        .takes_value(true)
+                        .default_value("cities"),
+                )
+                .arg(
+                    Arg::with_name("all_tables")
+                        .help("exports every table to a file named after it in the directory")
+                        .long("all-tables")
+                        .conflicts_with("table"),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .help("the file format (parquet requires --features parquet)")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(&export::ExportFormat::NAMES)
+                        .default_value("ndjson"),
+                )
+                .arg(
+                    Arg::with_name("delimiter")
+                        .help("the field delimiter of CSV files (default: a comma, or a tab for tsv)")
+                        .long("delimiter")
+                        .value_name("CHAR")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("country")
+                        .help("only exports places in this country to GeoJSON or GeoPackage (an ISO code or ID)")
+                        .long("country")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("min_population")
+                        .help("only exports cities with at least this population to GeoJSON or GeoPackage")
+                        .long("min-population")
+                        .value_name("N")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("lang")
+                        .help("the language of the names of places in GeoJSON or GeoPackage")
+                        .long("lang")
+                        .takes_value(true)
+                        .default_value("en"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("query")
+                .about("prints all rows of a SQLite database that belong to an entity")
+                .arg(
+                    Arg::with_name("database")
+                        .help("the database file")
+                        .index(1)
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("entity")
+                        .help("the entity id (including Q)")
+                        .index(2)
+                        .takes_value(true)
+                        .required(true),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("entity")
                 .about("loads a single entity and prints generated database entries")
@@ -43,6 +601,18 @@ fn main() {
                         .required(true),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("fetch")
+                .about("loads single entities, prints generated database entries and stores them in an existing database (sqlite or sled), replacing their old entries")
+                .arg(
+                    Arg::with_name("entity")
+                        .help("the entity id(s) (including Q)")
+                        .index(1)
+                        .takes_value(true)
+                        .multiple(true)
+                        .required(true),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("post")
                 .about("performs post-processing on the database")
@@ -62,162 +632,1228 @@ fn main() {
                     Arg::with_name("skip_cleanup")
                         .help("skips the cleanup step")
                         .long("no-cleanup"),
+                )
+                .arg(
+                    Arg::with_name("emit_place_table")
+                        .help("also builds a denormalized table with one row per place")
+                        .long("emit-place-table")
+                        .conflicts_with("only_cleanup"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("snapshot")
+                .about("runs the parser over a dump slice and compares the output to a golden file")
+                .arg(
+                    Arg::with_name("input")
+                        .help("the bz2-compressed dump slice")
+                        .index(1)
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("golden")
+                        .help("the golden NDJSON file")
+                        .index(2)
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("classes")
+                        .help("class sets cache file (fetched and saved if it doesn’t exist)")
+                        .long("classes")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("update")
+                        .help("overwrites the golden file instead of comparing")
+                        .long("update"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("merge")
+                .about("combines SQLite outputs into a new database, taking each entity from the input with its latest revision")
+                .arg(
+                    Arg::with_name("output")
+                        .help("the database file to create")
+                        .index(1)
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("inputs")
+                        .help("the databases to merge, in order of preference")
+                        .index(2)
+                        .takes_value(true)
+                        .multiple(true)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("migrate")
+                .about("upgrades a SQLite output of an older geo-db to the current schema in place")
+                .arg(
+                    Arg::with_name("database")
+                        .help("the database file")
+                        .index(1)
+                        .takes_value(true)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("diff")
+                .about("compares two NDJSON exports, databases or dumps and writes added, removed and changed entries")
+                .arg(
+                    Arg::with_name("old")
+                        .help("the previous export, database or dump")
+                        .index(1)
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("new")
+                        .help("the current export, database or dump")
+                        .index(2)
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("changes")
+                        .help("the NDJSON file to write changes to")
+                        .index(3)
+                        .takes_value(true)
+                        .default_value("changes.ndjson"),
+                )
+                .arg(
+                    Arg::with_name("entities")
+                        .help("writes one record per added, removed or changed entity instead of per entry")
+                        .long("entities"),
                 ),
         )
         .get_matches();
 
-    let colors = fern::colors::ColoredLevelConfig::new();
-    fern::Dispatch::new()
-        .format(move |out, msg, record| {
-            out.finish(format_args!(
-                "{}\x1b[{}m[{} {}] {}\x1b[m",
-                chrono::Local::now().format("[%H:%M:%S]"),
-                colors.get_color(&record.level()).to_fg_str(),
-                record.level(),
-                record.target(),
-                msg
-            ))
-        })
-        .level(if matches.is_present("verbose") {
-            log::LevelFilter::Debug
-        } else {
-            log::LevelFilter::Info
-        })
-        .chain(std::io::stdout())
-        .apply()
-        .unwrap();
+    let log_format: LogFormat = matches
+        .value_of("log_format")
+        .unwrap_or("text")
+        .parse()
+        .expect("invalid log format");
+    logging::init(log_format, matches.is_present("verbose"));
+
+    let base_options = match matches.value_of("config") {
+        Some(path) => match config::load(path) {
+            Ok(options) => options,
+            Err(e) => {
+                error!("Failed to load config file {}: {}", path, e);
+                exit(-1);
+            }
+        },
+        None => Options::default(),
+    };
+    let mut options = match Options::from_matches(&matches, base_options) {
+        Ok(options) => options,
+        Err(e) => {
+            error!("{}", e);
+            exit(-1);
+        }
+    };
 
     match matches.subcommand() {
         ("entity", Some(args)) => {
             let ids = args.values_of("entity").expect("no entity id");
-            match debug_entities(ids) {
+            match debug_entities(ids, &options, None) {
                 Ok(()) => {}
                 Err(e) => error!("{}", e),
             }
         }
-        ("post", Some(args)) => {
+        ("fetch", Some(args)) => {
+            if options.backend == Backend::Ndjson {
+                error!("NDJSON outputs can’t be updated");
+                exit(-1);
+            }
+            options.update = true;
+            let ids = args.values_of("entity").expect("no entity id");
+            let (send, recv) = crossbeam::channel::unbounded();
+            let db_writer = spawn_db_writer(&options, recv, &Arc::new(AtomicU64::new(0)));
+            let result = debug_entities(ids, &options, Some(&send));
+            drop(send);
+            db_writer.join().unwrap();
+            if let Err(e) = result {
+                error!("{}", e);
+                exit(-1);
+            }
+        }
+        ("post", Some(args)) => {
+            let db_file = args.value_of("database").expect("no database file");
+            let only_cleanup = args.is_present("only_cleanup");
+            let skip_cleanup = args.is_present("skip_cleanup");
+            let (do_post, do_cleanup) = match (only_cleanup, skip_cleanup) {
+                (true, true) => {
+                    error!("Can’t both do cleanup and not do cleanup");
+                    exit(-1);
+                }
+                (true, false) => (false, true),
+                (false, true) => (true, false),
+                (false, false) => (true, true),
+            };
+            let emit_place_table = args.is_present("emit_place_table");
+            match post::run(db_file, do_post, do_cleanup, emit_place_table) {
+                Ok(()) => {}
+                Err(e) => error!("{}", e),
+            }
+        }
+        ("snapshot", Some(args)) => {
+            let input = args.value_of("input").expect("no input file");
+            let golden = args.value_of("golden").expect("no golden file");
+            let classes = args.value_of("classes").or(options.classes_file.as_deref());
+            let update = args.is_present("update");
+            match snapshot::run(input, golden, classes, &options, update) {
+                Ok(true) => {}
+                Ok(false) => exit(1),
+                Err(e) => {
+                    error!("{}", e);
+                    exit(-1);
+                }
+            }
+        }
+        ("build", Some(args)) => {
+            if let Some(input) = args.value_of("input") {
+                options.input = input.into();
+                options.dump_date = None;
+            }
+            run(options);
+        }
+        ("resume", Some(args)) => {
+            if let Some(id) = args.value_of("entity") {
+                options.resume_from_id = match id.parse() {
+                    Ok(id) => Some(id),
+                    Err(e) => {
+                        error!("{}", e);
+                        exit(-1);
+                    }
+                };
+            }
+            if let Some(offset) = args.value_of("offset") {
+                options.resume_offset = match offset.parse() {
+                    Ok(offset) => Some(offset),
+                    Err(e) => {
+                        error!("invalid offset {:?}: {}", offset, e);
+                        exit(-1);
+                    }
+                };
+            }
+            if !args.is_present("entity") && !args.is_present("offset") {
+                continue_build(&mut options);
+            }
+            run(options);
+        }
+        ("update", Some(args)) => {
+            match args.value_of("date") {
+                Some(date) => match date.parse::<DumpDate>() {
+                    Ok(date) => {
+                        options.input = date.incr_url();
+                        options.dump_date = Some(date);
+                    }
+                    Err(e) => {
+                        error!("{}", e);
+                        exit(-1);
+                    }
+                },
+                None => {
+                    options.input = args.value_of("input").expect("no input").into();
+                    options.dump_date = None;
+                }
+            }
+            if options.backend == Backend::Ndjson {
+                error!("NDJSON outputs can’t be updated");
+                exit(-1);
+            }
+            options.update = true;
+            run(options);
+        }
+        ("follow", Some(args)) => {
+            if options.backend == Backend::Ndjson {
+                error!("NDJSON outputs can’t be updated");
+                exit(-1);
+            }
+            let since = match args.value_of("since") {
+                Some(since) => Some(since.to_string()),
+                // continues where the last run stopped
+                None if options.backend == Backend::Sqlite => {
+                    match database::read_metadata(&options.output, "follow_since") {
+                        Ok(since) => since,
+                        Err(e) => {
+                            error!("Failed to open {}: {}", options.output, e);
+                            exit(-1);
+                        }
+                    }
+                }
+                None => None,
+            };
+            options.update = true;
+            follow_changes(options, since);
+        }
+        ("export", Some(args)) => {
+            let db_file = args.value_of("database").expect("no database file");
+            let file = args.value_of("file").expect("no output file");
+            let table = args.value_of("table").expect("no table");
+            let format = args
+                .value_of("format")
+                .unwrap_or("ndjson")
+                .parse()
+                .expect("invalid export format");
+            let delimiter = match args.value_of("delimiter") {
+                Some(delimiter) if delimiter.len() == 1 => Some(delimiter.as_bytes()[0]),
+                Some("\\t") => Some(b'\t'),
+                Some(delimiter) => {
+                    error!(
+                        "The delimiter must be a single ASCII character, not {:?}",
+                        delimiter
+                    );
+                    exit(-1);
+                }
+                None => None,
+            };
+            let min_population = match args.value_of("min_population") {
+                Some(population) => match population.parse() {
+                    Ok(population) => Some(population),
+                    Err(e) => {
+                        error!("invalid minimum population {:?}: {}", population, e);
+                        exit(-1);
+                    }
+                },
+                None => None,
+            };
+            let settings = export::ExportSettings {
+                format,
+                compression: options.output_compression,
+                delimiter,
+                country: args.value_of("country").map(Into::into),
+                min_population,
+                name_language: args.value_of("lang").unwrap_or("en").into(),
+            };
+            let result = if args.is_present("all_tables") {
+                export::export_all(db_file, file, &settings)
+            } else {
+                export::export(db_file, table, file, &settings)
+                    .map(|count| info!("Exported {} rows of {}", count, table))
+            };
+            if let Err(e) = result {
+                error!("{}", e);
+                exit(-1);
+            }
+        }
+        ("query", Some(args)) => {
+            let db_file = args.value_of("database").expect("no database file");
+            let id = args.value_of("entity").expect("no entity id");
+            if let Err(e) = export::query(db_file, id) {
+                error!("{}", e);
+                exit(-1);
+            }
+        }
+        ("merge", Some(args)) => {
+            let out_file = args.value_of("output").expect("no output file");
+            let inputs: Vec<_> = args.values_of("inputs").expect("no inputs").collect();
+            if Path::new(out_file).exists() {
+                error!("{} already exists", out_file);
+                exit(-1);
+            }
+            match database::merge(out_file, &inputs) {
+                Ok(stats) => info!(
+                    "Merged {} entities, {} of which were in more than one input",
+                    stats.entities, stats.conflicts
+                ),
+                Err(e) => {
+                    error!("{}", e);
+                    exit(-1);
+                }
+            }
+        }
+        ("migrate", Some(args)) => {
             let db_file = args.value_of("database").expect("no database file");
-            let only_cleanup = args.is_present("only_cleanup");
-            let skip_cleanup = args.is_present("skip_cleanup");
-            let (do_post, do_cleanup) = match (only_cleanup, skip_cleanup) {
-                (true, true) => {
-                    error!("Can’t both do cleanup and not do cleanup");
+            match migrate::migrate(db_file) {
+                Ok(migrate::SCHEMA_VERSION) => info!(
+                    "{} already has schema version {}",
+                    db_file,
+                    migrate::SCHEMA_VERSION
+                ),
+                Ok(_) => (),
+                Err(e) => {
+                    error!("{}", e);
                     exit(-1);
                 }
-                (true, false) => (false, true),
-                (false, true) => (true, false),
-                (false, false) => (true, true),
+            }
+        }
+        ("diff", Some(args)) => {
+            let paths = [
+                args.value_of("old").expect("no old export"),
+                args.value_of("new").expect("no new export"),
+            ];
+            let changes = args.value_of("changes").expect("no changes file");
+            let detected: Vec<_> = paths
+                .iter()
+                .map(|path| match diff::DiffSource::detect(path) {
+                    Ok(source) => source,
+                    Err(e) => {
+                        error!("Failed to open {}: {}", path, e);
+                        exit(-1);
+                    }
+                })
+                .collect();
+
+            // dumps are extracted into the same kind of output as the other side
+            let backend = if detected
+                .iter()
+                .any(|source| matches!(source, Some(diff::DiffSource::Database(_))))
+            {
+                Backend::Sqlite
+            } else {
+                Backend::Ndjson
             };
-            match post::run(db_file, do_post, do_cleanup) {
-                Ok(()) => {}
-                Err(e) => error!("{}", e),
+            let mut extracted = Vec::new();
+            let mut sources = Vec::new();
+            for ((path, source), name) in paths.iter().zip(detected).zip(&["old", "new"]) {
+                if let Some(source) = source {
+                    sources.push(source);
+                    continue;
+                }
+                let extension = if backend == Backend::Sqlite {
+                    "db"
+                } else {
+                    "ndjson"
+                };
+                let file = std::env::temp_dir()
+                    .join(format!(
+                        "geo-db-diff-{}-{}.{}",
+                        std::process::id(),
+                        name,
+                        extension
+                    ))
+                    .to_string_lossy()
+                    .into_owned();
+                let mut dump_options = options.clone();
+                dump_options.input = path.to_string();
+                dump_options.output = file.clone();
+                dump_options.backend = backend;
+                dump_options.output_compression = output::OutputCompression::None;
+                info!("Extracting {}", path);
+                let finished = run(dump_options);
+                extracted.push(file.clone());
+                if !finished {
+                    remove_files(&extracted);
+                    exit(-1);
+                }
+                sources.push(if backend == Backend::Sqlite {
+                    diff::DiffSource::Database(file)
+                } else {
+                    diff::DiffSource::Export(file)
+                });
+            }
+
+            let by_entity = args.is_present("entities");
+            let result = diff::run(
+                &sources[0],
+                &sources[1],
+                changes,
+                options.output_compression,
+                by_entity,
+            );
+            remove_files(&extracted);
+            match result {
+                Ok(stats) => info!(
+                    "{} {} added, {} removed, {} changed",
+                    stats.added,
+                    if by_entity { "entities" } else { "entries" },
+                    stats.removed,
+                    stats.changed
+                ),
+                Err(e) => {
+                    error!("{}", e);
+                    exit(-1);
+                }
+            }
+        }
+        _ => match matches.value_of("head") {
+            Some(count) => {
+                let count = count.parse().expect("invalid line count");
+                if let Err(e) = print_head(count, &options) {
+                    error!("{}", e);
+                    exit(-1);
+                }
+            }
+            None => {
+                run(options);
+            }
+        },
+    }
+}
+
+/// Number of finished lines between checkpoints sent to the DB writer.
+const CHECKPOINT_INTERVAL: u64 = 1000;
+
+/// Weight of the newest sample in the smoothed input rate.
+const RATE_SMOOTHING: f64 = 0.1;
+
+/// Time between updates of the progress bars and metrics.
+const COUNTS_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// Time between reports of the slowest lines.
+const TIMING_REPORT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(600);
+
+/// Number of queued entry batches (about one per entity) above which the DB writer is considered
+/// to be falling behind.
+const WRITER_BACKLOG_BATCHES: usize = 10_000;
+
+/// Keeps track of the handled lines to send checkpoints.
+///
+/// Line handlers finish out of order, so finished line numbers are collected until all lines up
+/// to some point are done. A checkpoint for that point is then sent after their entries, and the
+/// DB writer reports it back once it's committed. In deterministic builds, the handlers also
+/// collect the entries of their line, which are written here in line order.
+struct LineTracker {
+    deterministic: bool,
+    /// Finished lines after `done_through`, with their entries in deterministic builds.
+    done_lines: BTreeMap<u64, Vec<database::DataEntry>>,
+    /// Last line such that it and all lines before it are finished.
+    done_through: u64,
+    last_checkpoint: u64,
+    /// Offset in the decompressed dump after each line that isn't part of a checkpoint yet.
+    line_ends: BTreeMap<u64, u64>,
+}
+
+impl LineTracker {
+    fn new(start: u64, deterministic: bool) -> Self {
+        LineTracker {
+            deterministic,
+            done_lines: BTreeMap::new(),
+            done_through: start,
+            last_checkpoint: start,
+            line_ends: BTreeMap::new(),
+        }
+    }
+
+    /// Records where a line that was read ends in the decompressed dump.
+    fn read(&mut self, line_number: u64, end_offset: u64) {
+        self.line_ends.insert(line_number, end_offset);
+    }
+
+    fn finish(&mut self, line_number: u64, entries: Vec<database::DataEntry>) {
+        self.done_lines.insert(line_number, entries);
+    }
+
+    /// Writes the entries of the lines that are now done, and sends a checkpoint when one is due
+    /// or, if `last` is set, for all of them.
+    fn advance(&mut self, send: &crossbeam::channel::Sender<Vec<database::DataEntry>>, last: bool) {
+        while let Some(entries) = self.done_lines.remove(&(self.done_through + 1)) {
+            self.done_through += 1;
+            if !entries.is_empty() {
+                send.send(entries).unwrap();
+            }
+            // at fixed lines, so that the writer's transactions are the same in every build
+            if self.deterministic && self.done_through.is_multiple_of(CHECKPOINT_INTERVAL) {
+                self.checkpoint(send);
             }
         }
-        _ => {
-            let out_file = matches.value_of("out").expect("no output file");
-            run(out_file.into());
+        let is_due =
+            !self.deterministic && self.done_through >= self.last_checkpoint + CHECKPOINT_INTERVAL;
+        if is_due || (last && self.done_through > self.last_checkpoint) {
+            self.checkpoint(send);
         }
     }
+
+    fn checkpoint(&mut self, send: &crossbeam::channel::Sender<Vec<database::DataEntry>>) {
+        send.send(vec![database::DataEntry::Checkpoint {
+            line_number: self.done_through,
+            offset: self.line_ends.get(&self.done_through).copied(),
+        }])
+        .unwrap();
+        self.line_ends = self.line_ends.split_off(&(self.done_through + 1));
+        self.last_checkpoint = self.done_through;
+    }
 }
 
-fn run(out_file: String) {
-    let url = "https://dumps.wikimedia.org/wikidatawiki/entities/latest-all.json.bz2";
-    let db_writer = {
-        let data_input = input::http::HttpBz2DataInput::new(url.into());
-        // let data_input = input::file::Bz2FileInput::new(std::fs::File::open(file).unwrap());
-        let mut lines = input::InputLineIter::new(data_input);
+/// What currently limits the speed of a run, guessed from the queue depths.
+enum Bottleneck {
+    /// Entries pile up faster than the DB writer can store them.
+    Writer,
+    /// Line handlers can't keep up with the input.
+    Cpu,
+    /// Handlers and writer are waiting for the download or decompression.
+    Io,
+}
 
-        info!("Loading classes");
-        let classes = Arc::new(match wiki_sparql::Classes::new_from_http() {
-            Ok(classes) => classes,
+impl Bottleneck {
+    fn classify(
+        queued_batches: usize,
+        last_queued_batches: usize,
+        tasks_in_flight: usize,
+        workers: usize,
+    ) -> Self {
+        if queued_batches > WRITER_BACKLOG_BATCHES && queued_batches >= last_queued_batches {
+            Bottleneck::Writer
+        } else if tasks_in_flight > workers {
+            // more lines are waiting than there are workers to handle them
+            Bottleneck::Cpu
+        } else {
+            Bottleneck::Io
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            Bottleneck::Writer => "[writer-bound]",
+            Bottleneck::Cpu => "[cpu-bound]",
+            Bottleneck::Io => "[io-bound]",
+        }
+    }
+}
+
+/// Prints the first `count` entities of the dump as pretty-printed JSON.
+fn print_head(count: usize, options: &Options) -> Result<(), String> {
+    let data_input = input::DumpInput::open(&options.input, &options.input_settings(), None, None)
+        .map_err(|e| format!("failed to open {}: {}", options.input, e))?;
+    let mut lines = input::InputLineIter::new(data_input);
+
+    let mut printed = 0;
+    while printed < count {
+        let (line, _) = match lines.next() {
+            Ok(line) => line,
+            Err(input::LineIterError::Eof) => break,
+            Err(e) => return Err(format!("line iterator error: {}", e)),
+        };
+        if line.len() <= 1 {
+            // one of the [ or ] array boundary lines
+            continue;
+        }
+
+        let line = line.strip_suffix(',').unwrap_or(&line);
+        match serde_json::from_str::<serde_json::Value>(line) {
+            Ok(entity) => println!("{}", serde_json::to_string_pretty(&entity).unwrap()),
+            Err(e) => {
+                warn!("line is not valid JSON ({}); printing it as-is", e);
+                println!("{}", line);
+            }
+        }
+        printed += 1;
+    }
+    Ok(())
+}
+
+/// Sets up the options to continue the interrupted build of the SQLite output after its last
+/// checkpoint.
+fn continue_build(options: &mut Options) {
+    if options.backend != Backend::Sqlite {
+        error!("Only builds of sqlite databases can be continued; pass an entity or an offset");
+        exit(-1);
+    }
+    let (line_number, offset) = match database::read_build_state(&options.output) {
+        Ok(Some((line_number, Some(offset)))) => (line_number, offset),
+        Ok(_) => {
+            error!("{} has no checkpoint to continue from", options.output);
+            exit(-1);
+        }
+        Err(e) => {
+            error!(
+                "Failed to read the build state of {}: {}",
+                options.output, e
+            );
+            exit(-1);
+        }
+    };
+    info!(
+        "Continuing the build of {} after line {}",
+        options.output, line_number
+    );
+    options.resume_offset = Some(offset);
+    options.resume_line_number = Some(line_number);
+    match database::roll_back_to_checkpoint(&options.output) {
+        Ok(Some(count)) => info!(
+            "Removed the entries of {} entities that were committed after the checkpoint",
+            count
+        ),
+        Ok(None) => {
+            // entries of the lines after the checkpoint may have been stored already, so the
+            // existing database is updated, which replaces them
+            options.update = true;
+        }
+        Err(e) => {
+            error!(
+                "Failed to roll {} back to its checkpoint: {}",
+                options.output, e
+            );
+            exit(-1);
+        }
+    }
+    // the dump date was stored by the interrupted run
+    options.dump_date = None;
+}
+
+/// Applies the edits made on Wikidata to an existing database until interrupted.
+fn follow_changes(options: Options, since: Option<String>) {
+    info!("Loading classes");
+    let classes = match wiki_sparql::Classes::load(options.classes_file.as_deref(), &options) {
+        Ok(classes) => classes,
+        Err(e) => {
+            error!("Failed to load classes: {}", e);
+            exit(-1);
+        }
+    };
+
+    let committed_line = Arc::new(AtomicU64::new(0));
+    let (send, recv) = crossbeam::channel::unbounded();
+    let db_writer = spawn_db_writer(&options, recv, &committed_line);
+
+    let cancel_recv = cancel_signals();
+    let result = follow::run(&options, &classes, &send, &cancel_recv, since);
+    drop(send);
+    debug!("Waiting for DB writer to join");
+    db_writer.join().unwrap();
+    if let Err(e) = result {
+        error!("{}", e);
+        exit(-1);
+    }
+}
+
+/// Starts the thread that writes the entries from the channel to the output.
+fn spawn_db_writer(
+    options: &Options,
+    recv: crossbeam::channel::Receiver<Vec<database::DataEntry>>,
+    committed_line: &Arc<AtomicU64>,
+) -> std::thread::JoinHandle<()> {
+    let out_file = options.output.clone();
+    let backend = options.backend;
+    let output_compression = options.output_compression;
+    // continued builds write to the database of the interrupted one
+    let update = options.update || options.resume_line_number.is_some();
+    let sqlite = options.sqlite_settings();
+    // outputs of older versions are upgraded before they're updated
+    if backend == Backend::Sqlite && update {
+        match database::is_cleaned_up(&out_file) {
+            Ok(false) => (),
+            Ok(true) => {
+                error!(
+                    "The post-processing of {} was cleaned up, which dropped the tables that are updated; build it again instead",
+                    out_file
+                );
+                exit(-1);
+            }
+            Err(e) => {
+                error!("Failed to open {}: {}", out_file, e);
+                exit(-1);
+            }
+        }
+        if let Err(e) = migrate::migrate(&out_file) {
+            error!("Failed to upgrade {}: {}", out_file, e);
+            exit(-1);
+        }
+        // coordinates in another CRS can't be mixed in
+        match database::read_metadata(&out_file, "coord_crs") {
+            Ok(Some(crs)) if crs != options.coord_crs.as_str() => {
+                error!(
+                    "{} has coordinates in EPSG:{}; pass --coord-crs {} to update it",
+                    out_file, crs, crs
+                );
+                exit(-1);
+            }
+            Ok(_) => (),
+            Err(e) => {
+                error!("Failed to read the metadata of {}: {}", out_file, e);
+                exit(-1);
+            }
+        }
+    }
+    let committed_line = Arc::clone(committed_line);
+    std::thread::spawn(move || {
+        let result = match backend {
+            Backend::Sqlite if update => database::SqliteSink::open_existing(&out_file, sqlite)
+                .and_then(|sink| database::db_writer(sink, recv, &committed_line))
+                .map_err(|e| e.to_string()),
+            Backend::Sqlite => database::SqliteSink::open(&out_file, sqlite)
+                .and_then(|sink| database::db_writer(sink, recv, &committed_line))
+                .map_err(|e| e.to_string()),
+            Backend::Sled => kv::KvSink::open(&out_file)
+                .and_then(|sink| database::db_writer(sink, recv, &committed_line))
+                .map_err(|e| e.to_string()),
+            Backend::Ndjson => output::OutputWriter::create(&out_file, output_compression)
+                .map(output::NdjsonSink::new)
+                .and_then(|sink| database::db_writer(sink, recv, &committed_line))
+                .map_err(|e| e.to_string()),
+            #[cfg(feature = "postgres")]
+            Backend::Postgres if update => pg::PgSink::open_existing(&out_file)
+                .and_then(|sink| database::db_writer(sink, recv, &committed_line))
+                .map_err(|e| e.to_string()),
+            #[cfg(feature = "postgres")]
+            Backend::Postgres => pg::PgSink::create(&out_file)
+                .and_then(|sink| database::db_writer(sink, recv, &committed_line))
+                .map_err(|e| e.to_string()),
+            #[cfg(not(feature = "postgres"))]
+            Backend::Postgres => Err("geo-db was built without the postgres feature".to_string()),
+        };
+        if let Err(e) = result {
+            error!("database writer exited with error: {}", e);
+            exit(-1);
+        }
+    })
+}
+
+/// Returns a receiver for interrupt and termination signals: SIGINT, SIGTERM and SIGHUP (only
+/// Ctrl-C on platforms without them). The handler is installed once and shared by all runs, and
+/// further signals after the first few are ignored while shutting down.
+fn cancel_signals() -> crossbeam::channel::Receiver<()> {
+    static SIGNALS: OnceLock<crossbeam::channel::Receiver<()>> = OnceLock::new();
+    SIGNALS
+        .get_or_init(|| {
+            let (cancel_send, cancel_recv) = crossbeam::channel::bounded(3);
+            ctrlc::set_handler(move || {
+                let _ = cancel_send.try_send(());
+            })
+            .expect("could not set interrupt handler");
+            cancel_recv
+        })
+        .clone()
+}
+
+/// Extracts the dump into the output. Returns false if it was stopped by a signal.
+fn run(options: Options) -> bool {
+    let out_file = options.output.clone();
+    let backend = options.backend;
+    let max_error_rate = options.max_error_rate;
+    // last line whose entries have all been committed by the DB writer
+    let committed_line = Arc::new(AtomicU64::new(0));
+    let (db_writer, failed_lines, lines_handled, cancelled) = {
+        let checksum = match &options.checksums {
+            Some(sums) => {
+                // torrents of dumps are named after the dump file
+                let file_name = options.input.rsplit('/').next().unwrap_or("");
+                let file_name = file_name.strip_suffix(".torrent").unwrap_or(file_name);
+                match Checksum::load(sums, file_name, options.checksum_warn_only) {
+                    Ok(checksum) => Some(checksum),
+                    Err(e) if options.checksum_warn_only => {
+                        warn!("Not verifying the dump: {}", e);
+                        None
+                    }
+                    Err(e) => {
+                        error!("Failed to load checksums from {}: {}", sums, e);
+                        exit(-1);
+                    }
+                }
+            }
+            None => None,
+        };
+        let mut index = match &options.bz2_index {
+            Some(path) => match Bz2Index::open(Path::new(path)) {
+                Ok(index) => Some(index),
+                Err(e) => {
+                    error!("Failed to open bz2 index {}: {}", path, e);
+                    exit(-1);
+                }
+            },
+            None => None,
+        };
+        let start = match (options.resume_offset, &mut index) {
+            (Some(offset), Some(index)) => {
+                let start = index.seek_point(offset);
+                index.start_at(start);
+                start
+            }
+            _ => SeekPoint::default(),
+        };
+        let data_input = match input::DumpInput::open(
+            &options.input,
+            &options.input_settings(),
+            checksum,
+            index,
+        ) {
+            Ok(data_input) => data_input,
             Err(e) => {
-                error!("Failed to fetch classes: {}", e);
+                error!("Failed to open {}: {}", options.input, e);
+                exit(-1);
+            }
+        };
+        let mut lines = input::InputLineIter::new(data_input);
+        lines.start_at(start.decompressed);
+        if let Some(offset) = options.resume_offset {
+            info!(
+                "Resuming at byte {} of the decompressed dump, starting at byte {} of the input",
+                offset, start.compressed
+            );
+            if let Err(e) = lines.skip_to(offset) {
+                error!("Failed to skip to offset {}: {}", offset, e);
                 exit(-1);
             }
-        });
+        }
 
-        info!("Streaming data from {} to {}", url, out_file);
+        info!("Loading classes");
+        let classes = Arc::new(
+            match wiki_sparql::Classes::load(options.classes_file.as_deref(), &options) {
+                Ok(classes) => classes,
+                Err(e) => {
+                    error!("Failed to load classes: {}", e);
+                    exit(-1);
+                }
+            },
+        );
+
+        let options = Arc::new(options);
+        // updates also remove the entries of entities that are no longer extracted, so every
+        // line has to be parsed
+        let prefilter = if options.update {
+            None
+        } else {
+            Some(Arc::new(wiki_data_line::Prefilter::new(&classes, &options)))
+        };
+
+        info!("Streaming data from {} to {}", options.input, out_file);
 
         let (send, recv) = crossbeam::channel::unbounded();
+        // so that exports know how to read the coordinates
+        send.send(vec![database::DataEntry::Metadata {
+            key: "coord_crs".into(),
+            value: options.coord_crs.as_str().into(),
+        }])
+        .unwrap();
+        if let Some(date) = &options.dump_date {
+            send.send(vec![database::DataEntry::Metadata {
+                key: if options.update {
+                    "update_date".into()
+                } else {
+                    "dump_date".into()
+                },
+                value: date.as_str().into(),
+            }])
+            .unwrap();
+        }
+
+        let db_writer = spawn_db_writer(&options, recv, &committed_line);
+
+        let (matched_send, matched_writer) = match &options.dump_matched {
+            Some(path) => {
+                let (send, recv) = crossbeam::channel::unbounded();
+                let path = path.clone();
+                let compression = options.output_compression;
+                let writer = std::thread::spawn(move || {
+                    if let Err(e) = output::line_writer(&path, compression, recv) {
+                        error!("matched entity writer exited with error: {}", e);
+                        exit(-1);
+                    }
+                });
+                (Some(send), Some(writer))
+            }
+            None => (None, None),
+        };
 
-        let db_writer = std::thread::spawn(move || match database::db_writer(&out_file, recv) {
-            Ok(()) => (),
+        let worker_count = options.worker_threads();
+        info!("Handling lines on {} worker threads", worker_count);
+        let workers = match rayon_core::ThreadPoolBuilder::new()
+            .num_threads(worker_count)
+            .thread_name(|i| format!("line-{}", i))
+            .build()
+        {
+            Ok(workers) => workers,
             Err(e) => {
-                error!("database writer exited with error: {}", e);
+                error!("Failed to set up {} worker threads: {}", worker_count, e);
                 exit(-1);
             }
-        });
+        };
 
-        let (cancel_send, cancel_recv) = crossbeam::channel::bounded(3);
-        ctrlc::set_handler(move || cancel_send.send(()).unwrap())
-            .expect("could not set interrupt handler");
+        // number of spawned line handlers that haven't finished yet
+        let pending_tasks = Arc::new(AtomicUsize::new(0));
+        // number of lines that failed to be handled
+        let failed_lines = Arc::new(AtomicUsize::new(0));
+        let mut lines_handled = 0;
+
+        let (done_send, done_recv) = crossbeam::channel::unbounded();
+        let mut tracker = LineTracker::new(
+            options.resume_line_number.unwrap_or(0),
+            options.deterministic,
+        );
+
+        let cancel_recv = cancel_signals();
+
+        if let Some(addr) = &options.metrics_addr {
+            if let Err(e) = metrics::serve(addr, lines.input.content_length()) {
+                error!("Failed to serve metrics on {}: {}", addr, e);
+                exit(-1);
+            }
+            info!("Serving metrics on http://{}/metrics", addr);
+        }
+
+        let mut memory_budget = match options.max_memory {
+            Some(max_mb) => {
+                let budget = memory::MemoryBudget::new(max_mb * 1_000_000);
+                if budget.is_none() {
+                    warn!("Memory use can't be measured on this platform; ignoring --max-memory");
+                }
+                budget
+            }
+            None => None,
+        };
 
+        // progress bars replace the periodic progress log line
+        let mut progress = if options.no_progress {
+            None
+        } else {
+            progress::Progress::show(lines.input.content_length())
+        };
+        let mut last_counts_update = std::time::Instant::now();
+        let mut last_timing_report = std::time::Instant::now();
         let mut last_time = std::time::Instant::now();
+        // exponential moving average of the input byte rate, used for the ETA
+        let mut smoothed_bytes_read: Option<f64> = None;
         let mut last_bytes = 0;
         let mut last_dec_bytes = 0;
-        let mut line_number = 0;
+        let mut last_queued_batches = 0;
+        let mut line_number = tracker.done_through;
+        let mut xml_revisions = None;
+        let mut cancelled = false;
         loop {
             match cancel_recv.try_recv() {
                 Ok(()) => {
-                    debug!("received interrupt signal");
+                    info!("Received interrupt or termination signal, stopping");
+                    cancelled = true;
                     break;
                 }
                 Err(crossbeam::channel::TryRecvError::Empty) => (),
                 Err(e) => panic!("unexpected error {}", e),
             }
 
-            let line_offset = lines.bytes_read;
-            line_number += 1;
-            let line = match lines.next() {
+            if matches!(options.limit, Some(limit) if line_number >= limit) {
+                info!("Stopping after {} lines", line_number);
+                break;
+            }
+
+            if let Some(budget) = &mut memory_budget {
+                if let Some(rss) = budget.exceeded() {
+                    // the queues of lines and entries are what grows when the writer falls behind.
+                    // The memory they free is usually kept by the allocator for reuse, so the input
+                    // is only paused until they've drained, not until the resident size shrinks.
+                    if budget.pauses == 0 {
+                        info!(
+                            "Memory use of {} MB is over the budget of {} MB; pausing the input until queued lines and entries are handled",
+                            rss / 1_000_000,
+                            budget.max_bytes() / 1_000_000
+                        );
+                    }
+                    let paused = std::time::Instant::now();
+                    while (!send.is_empty() || pending_tasks.load(Ordering::SeqCst) > worker_count)
+                        && cancel_recv.is_empty()
+                    {
+                        for (done_line, entries) in done_recv.try_iter() {
+                            tracker.finish(done_line, entries);
+                        }
+                        tracker.advance(&send, false);
+                        std::thread::sleep(std::time::Duration::from_millis(50));
+                    }
+                    budget.record_pause(paused.elapsed());
+                }
+            }
+
+            let line_offset = lines.line_start();
+            let (line, line_end) = match lines.next() {
                 Ok(line) => line,
                 Err(input::LineIterError::Eof) => break,
+                Err(e @ input::LineIterError::Decompress(_)) => {
+                    error!("line iterator error: {}", e);
+                    error!("the dump appears to be corrupt; restarting the download may help");
+                    exit(-1);
+                }
                 Err(e) => {
                     error!("line iterator error: {}", e);
                     exit(-1);
                 }
             };
+            if options.update
+                && line_number == 0
+                && xml_revisions.is_none()
+                && incremental::is_xml(&line)
+            {
+                info!("Reading the input as an XML dump of revisions");
+                xml_revisions = Some(incremental::XmlRevisions::new());
+            }
+            // the entities of XML dumps are handled as if they were lines of a JSON dump
+            let line = match &mut xml_revisions {
+                Some(revisions) => match revisions.push_line(&line) {
+                    Some(entity) => entity,
+                    None => continue,
+                },
+                None => line,
+            };
+            line_number += 1;
+            tracker.read(line_number, line_end);
+            if !is_sampled(line_number, options.sample_rate) {
+                tracker.finish(line_number, Vec::new());
+                continue;
+            }
 
             let sink = send.clone();
+            let matched_sink = matched_send.clone();
             let classes2 = Arc::clone(&classes);
-            rayon_core::spawn(move || {
-                match wiki_data_line::handle_line(&line, &classes2, &sink, false) {
-                    Ok(()) => (),
-                    Err(e) => error!(
-                        "error handling line {} at offset {}:{}\n\n",
-                        line_number, line_offset, e
+            let options2 = Arc::clone(&options);
+            let prefilter2 = prefilter.clone();
+            let pending_tasks2 = Arc::clone(&pending_tasks);
+            let failed_lines2 = Arc::clone(&failed_lines);
+            let done_send2 = done_send.clone();
+            lines_handled += 1;
+            let is_oversized = line.len() > options.max_line_bytes;
+            pending_tasks.fetch_add(1, Ordering::SeqCst);
+            let task = move || {
+                let _log_context = logging::enter_line(line_number);
+                let mut entries = Vec::new();
+                let started = std::time::Instant::now();
+                let result = match &prefilter2 {
+                    Some(prefilter) if !prefilter.may_match(&line) => {
+                        wiki_data_line::SKIPPED_BY_PREFILTER.fetch_add(1, Ordering::Relaxed);
+                        Ok(())
+                    }
+                    _ => wiki_data_line::handle_line(
+                        &line,
+                        &classes2,
+                        &options2,
+                        &mut entries,
+                        matched_sink.as_ref(),
+                        false,
                     ),
+                };
+                timing::record(line_number, &line, started.elapsed());
+                match result {
+                    Ok(()) => (),
+                    Err(e) => {
+                        failed_lines2.fetch_add(1, Ordering::SeqCst);
+                        let fragment = match e.json_fragment(&line) {
+                            Some(fragment) if options2.pretty_errors => fragment,
+                            _ => String::new(),
+                        };
+                        if options2.fail_fast {
+                            error!(
+                                "error handling line {} at offset {}:{}\n{}\n{}",
+                                line_number, line_offset, e, fragment, line
+                            );
+                            exit(-1);
+                        }
+                        error!(
+                            "error handling line {} at offset {}:{}\n{}\n",
+                            line_number, line_offset, e, fragment
+                        );
+                    }
+                }
+                if !entries.is_empty() {
+                    // for the build journal, as entries of later lines can be written before the
+                    // checkpoint of this one
+                    entries.insert(0, database::DataEntry::Line { line_number });
+                }
+                // entries of deterministic builds are written in line order by the tracker
+                if !options2.deterministic && !entries.is_empty() {
+                    sink.send(std::mem::take(&mut entries)).unwrap();
+                }
+                done_send2.send((line_number, entries)).unwrap();
+                pending_tasks2.fetch_sub(1, Ordering::SeqCst);
+            };
+            if is_oversized {
+                // handled on this thread so that no more than one huge line is in flight at a time
+                warn!(
+                    "line {} at offset {} exceeds the maximum line size; handling it on the reader thread",
+                    line_number, line_offset
+                );
+                task();
+            } else {
+                workers.spawn(task);
+            }
+
+            for (done_line, entries) in done_recv.try_iter() {
+                tracker.finish(done_line, entries);
+            }
+            tracker.advance(&send, false);
+
+            if (progress.is_some() || options.metrics_addr.is_some())
+                && last_counts_update.elapsed() >= COUNTS_INTERVAL
+            {
+                let counts = progress::Counts {
+                    downloaded: lines.input.bytes_read(),
+                    decompressed: lines.bytes_read,
+                    lines: line_number,
+                    failed_lines: failed_lines.load(Ordering::SeqCst),
+                    matched: wiki_data_line::MATCHED_ENTITIES.load(Ordering::Relaxed) as u64,
+                    tasks_in_flight: pending_tasks.load(Ordering::SeqCst),
+                    queued_batches: send.len(),
+                    written: database::ENTRIES_WRITTEN.load(Ordering::Relaxed),
+                    committed_line: committed_line.load(Ordering::SeqCst),
+                };
+                if let Some(progress) = &progress {
+                    progress.update(&counts);
                 }
-            });
+                metrics::record(counts);
+                last_counts_update = std::time::Instant::now();
+            }
+
+            if last_timing_report.elapsed() >= TIMING_REPORT_INTERVAL {
+                timing::report();
+                last_timing_report = std::time::Instant::now();
+            }
 
             let elapsed = last_time.elapsed();
             if elapsed.as_secs() > 10 {
+                let queued_batches = send.len();
+                let tasks_in_flight = pending_tasks.load(Ordering::SeqCst);
+                let bottleneck = Bottleneck::classify(
+                    queued_batches,
+                    last_queued_batches,
+                    tasks_in_flight,
+                    workers.current_num_threads(),
+                );
+                last_queued_batches = queued_batches;
+                if let Some(progress) = &mut progress {
+                    // the bars show everything else of the log line
+                    progress.set_bottleneck(bottleneck.label());
+                    last_time = std::time::Instant::now();
+                    continue;
+                }
+
                 let bytes_read =
                     (lines.input.bytes_read() - last_bytes) as f64 / elapsed.as_secs_f64();
                 let dec_bytes_read =
                     (lines.bytes_read - last_dec_bytes) as f64 / elapsed.as_secs_f64();
-                let total_bytes = lines.input.content_length().unwrap_or(0);
-                let percent_complete = lines.input.bytes_read() as f64 / total_bytes as f64;
-                let mut eta = (total_bytes - lines.input.bytes_read()) as f64 / bytes_read / 60.;
-                let mut eta_unit = "m";
-                if eta > 60. {
-                    eta /= 60.;
-                    eta_unit = "h";
-
-                    if eta > 24. {
-                        eta /= 24.;
-                        eta_unit = "d 😔";
+                let rate = match smoothed_bytes_read {
+                    Some(rate) => rate + RATE_SMOOTHING * (bytes_read - rate),
+                    None => bytes_read,
+                };
+                smoothed_bytes_read = Some(rate);
+
+                // the length is unknown when reading from stdin
+                let input_progress = match lines.input.content_length() {
+                    Some(total_bytes) => {
+                        let percent_complete = lines.input.bytes_read() as f64 / total_bytes as f64;
+                        let mut eta = total_bytes.saturating_sub(lines.input.bytes_read()) as f64
+                            / rate
+                            / 60.;
+                        let mut eta_unit = "m";
+                        if eta > 60. {
+                            eta /= 60.;
+                            eta_unit = "h";
+
+                            if eta > 24. {
+                                eta /= 24.;
+                                eta_unit = "d 😔";
+                            }
+                        }
+                        format!(
+                            "{:02.2}% (ETA: {:.1}{}) | {:.2} MB of {:.2} MB",
+                            percent_complete * 100.,
+                            eta,
+                            eta_unit,
+                            lines.input.bytes_read() as f64 / 1000_000.,
+                            total_bytes as f64 / 1000_000.,
+                        )
                     }
-                }
+                    None => format!("{:.2} MB", lines.input.bytes_read() as f64 / 1_000_000.),
+                };
 
                 info!(
-                    "{:02.2}% (ETA: {:.1}{}) | {:.2} MB of {:.2} MB at {:.2} MB/s ({:.2} MB/s data)",
-                    percent_complete * 100.,
-                    eta,
-                    eta_unit,
-                    lines.input.bytes_read() as f64 / 1000_000.,
-                    total_bytes as f64 / 1000_000.,
+                    "{} {} at {:.2} MB/s ({:.2} MB/s data) | {} lines in flight, {} batches queued, committed through line {}",
+                    bottleneck.label(),
+                    input_progress,
                     bytes_read / 1000_000.,
                     dec_bytes_read / 1000_000.,
+                    tasks_in_flight,
+                    queued_batches,
+                    committed_line.load(Ordering::SeqCst),
                 );
                 last_bytes = lines.input.bytes_read();
                 last_dec_bytes = lines.bytes_read;
@@ -225,17 +1861,139 @@ fn run(out_file: String) {
             }
         }
 
-        db_writer
+        drop(progress);
+        if let Some(budget) = &memory_budget {
+            if budget.pauses > 0 {
+                info!(
+                    "Paused the input {} times for {:.1} s in total to stay within the memory budget",
+                    budget.pauses,
+                    budget.paused_for.as_secs_f64()
+                );
+            }
+        }
+
+        // wait for in-flight line handlers so we don't close the channel on them
+        if cancelled {
+            // draining can take a while if the DB writer is behind
+            info!(
+                "Waiting for {} lines in flight and {} queued batches of entries to be committed",
+                pending_tasks.load(Ordering::SeqCst),
+                send.len()
+            );
+        } else {
+            debug!(
+                "Waiting for {} in-flight tasks",
+                pending_tasks.load(Ordering::SeqCst)
+            );
+        }
+        while pending_tasks.load(Ordering::SeqCst) > 0 {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        }
+        for (done_line, entries) in done_recv.try_iter() {
+            tracker.finish(done_line, entries);
+        }
+        tracker.advance(&send, true);
+        drop(send);
+        drop(matched_send);
+        timing::report();
+
+        if let Some(matched_writer) = matched_writer {
+            debug!("Waiting for matched entity writer to join");
+            matched_writer.join().unwrap();
+        }
+
+        (
+            db_writer,
+            failed_lines.load(Ordering::SeqCst),
+            lines_handled,
+            cancelled,
+        )
     };
 
     debug!("Waiting for DB writer to join");
     db_writer.join().unwrap();
+    info!(
+        "Committed entries of all lines up to line {}",
+        committed_line.load(Ordering::SeqCst)
+    );
+    if cancelled && backend == Backend::Sqlite {
+        info!("`geo-db resume` continues the build from there");
+    }
+
+    let error_rate = if lines_handled > 0 {
+        failed_lines as f64 / lines_handled as f64
+    } else {
+        0.
+    };
+    info!(
+        "{} of {} lines failed ({:.3}%), {} lines skipped by the prefilter, {} non-item entities skipped, {} places skipped by filters",
+        failed_lines,
+        lines_handled,
+        error_rate * 100.,
+        wiki_data_line::SKIPPED_BY_PREFILTER.load(Ordering::SeqCst),
+        wiki_data_line::SKIPPED_BY_TYPE.load(Ordering::SeqCst),
+        wiki_data_line::SKIPPED_BY_FILTER.load(Ordering::SeqCst)
+    );
+    if let Some(max_error_rate) = max_error_rate {
+        if error_rate > max_error_rate {
+            error!(
+                "error rate {:.3}% exceeds the maximum of {:.3}%",
+                error_rate * 100.,
+                max_error_rate * 100.
+            );
+            exit(-1);
+        }
+    }
     info!("Done!");
+    !cancelled
+}
+
+/// Whether the line is in the sample of the given fraction of lines.
+///
+/// Lines are picked by a hash of their number, so a sample is spread over the whole dump and
+/// the same in every run.
+fn is_sampled(line_number: u64, sample_rate: Option<f64>) -> bool {
+    let rate = match sample_rate {
+        Some(rate) => rate,
+        None => return true,
+    };
+    // splitmix64 finalizer
+    let mut hash = line_number.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^= hash >> 31;
+    (hash as f64 / u64::MAX as f64) < rate
+}
+
+/// Removes temporary files, logging failures.
+fn remove_files(paths: &[String]) {
+    for path in paths {
+        if let Err(e) = std::fs::remove_file(path) {
+            warn!("Failed to remove {}: {}", path, e);
+        }
+    }
+}
+
+/// Finds an entity in a response of Special:EntityData, and returns its ID and JSON. An entity that
+/// was merged into another one is redirected to it, so the response has the other one instead.
+fn fetched_entity(json: &serde_json::Value, id: &str) -> Option<(String, String)> {
+    let entities = json.get("entities")?.as_object()?;
+    let (id, entity) = match entities.get(id) {
+        Some(entity) => (id, entity),
+        None if entities.len() == 1 => entities.iter().next().map(|(id, e)| (id.as_str(), e))?,
+        None => return None,
+    };
+    Some((id.to_string(), serde_json::to_string(entity).ok()?))
 }
 
-fn debug_entities<'a>(ids: impl Iterator<Item = &'a str>) -> reqwest::Result<()> {
+/// Loads entities from Wikidata and prints their entries, which are also sent to `store` if given.
+fn debug_entities<'a>(
+    ids: impl Iterator<Item = &'a str>,
+    options: &Options,
+    store: Option<&crossbeam::channel::Sender<Vec<database::DataEntry>>>,
+) -> Result<(), wiki_sparql::ClassesError> {
     info!("Loading classes");
-    let classes = wiki_sparql::Classes::new_from_http()?;
+    let classes = wiki_sparql::Classes::load(options.classes_file.as_deref(), options)?;
 
     for id in ids {
         let url = format!("https://wikidata.org/wiki/Special:EntityData/{}.json", id);
@@ -246,17 +2004,18 @@ fn debug_entities<'a>(ids: impl Iterator<Item = &'a str>) -> reqwest::Result<()>
                 continue;
             }
         };
-        if let Some(entity) = json
-            .as_object()
-            .and_then(|root| root.get("entities"))
-            .and_then(|entities| entities.as_object())
-            .and_then(|entities| entities.get(id))
-            .and_then(|entity| serde_json::to_string(entity).ok())
-        {
+        if let Some((entity_id, entity)) = fetched_entity(&json, id) {
             info!("Entity {}", id);
 
-            let (send, recv) = crossbeam::channel::unbounded();
-            match wiki_data_line::handle_line(&entity, &classes, &send, false) {
+            let mut entries = Vec::new();
+            if entity_id != id {
+                info!("{} was merged into {}", id, entity_id);
+                if options.update {
+                    entries.push(database::DataEntry::RemoveEntity { id: id.into() });
+                }
+            }
+            match wiki_data_line::handle_line(&entity, &classes, options, &mut entries, None, false)
+            {
                 Ok(()) => {}
                 Err(e) => {
                     error!("{}", e);
@@ -264,18 +2023,31 @@ fn debug_entities<'a>(ids: impl Iterator<Item = &'a str>) -> reqwest::Result<()>
             }
 
             let mut was_empty = true;
-            while let Ok(entry) = recv.try_recv() {
-                was_empty = false;
-                if let database::DataEntry::ObjectLabel { .. } = &entry {
-                    info!("{}: {:?}", id, entry);
-                } else {
-                    info!("{}: {:#?}", id, entry);
+            for entry in &entries {
+                match entry {
+                    database::DataEntry::ObjectLabel { .. } => info!("{}: {:?}", id, entry),
+                    // sent for every item when updating
+                    database::DataEntry::RemoveEntity { .. } => debug!("{}: {:?}", id, entry),
+                    _ => info!("{}: {:#?}", id, entry),
                 }
+                if !matches!(entry, database::DataEntry::RemoveEntity { .. }) {
+                    was_empty = false;
+                }
+            }
+            if let Some(store) = store {
+                store.send(entries).unwrap();
             }
 
             if was_empty {
                 info!("empty output - debug:");
-                if let Err(e) = wiki_data_line::handle_line(&entity, &classes, &send, true) {
+                if let Err(e) = wiki_data_line::handle_line(
+                    &entity,
+                    &classes,
+                    options,
+                    &mut Vec::new(),
+                    None,
+                    true,
+                ) {
                     error!("{}", e);
                 }
             }
@@ -287,3 +2059,44 @@ fn debug_entities<'a>(ids: impl Iterator<Item = &'a str>) -> reqwest::Result<()>
     info!("Done!");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn sample_lines() {
+        assert!((1..1000).all(|line| is_sampled(line, None)));
+        assert!((1..1000).all(|line| is_sampled(line, Some(1.))));
+        assert!((1..1000).all(|line| !is_sampled(line, Some(0.))));
+
+        let sampled: Vec<u64> = (1..=100_000)
+            .filter(|&line| is_sampled(line, Some(0.01)))
+            .collect();
+        assert!((900..1100).contains(&sampled.len()), "{}", sampled.len());
+        // spread over the lines rather than bunched up
+        assert!(sampled.iter().any(|&line| line <= 10_000));
+        assert!(sampled.iter().any(|&line| line > 90_000));
+        // the same lines in every run, and the lines of a smaller sample are in a larger one
+        assert!(sampled.iter().all(|&line| is_sampled(line, Some(0.01))));
+        assert!(sampled.iter().all(|&line| is_sampled(line, Some(0.1))));
+    }
+
+    #[test]
+    fn find_fetched_entities() {
+        let response = json!({"entities": {"Q64": {"type": "item", "id": "Q64"}}});
+        let (id, entity) = fetched_entity(&response, "Q64").unwrap();
+        assert_eq!(id, "Q64");
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&entity).unwrap(),
+            response["entities"]["Q64"]
+        );
+        // Q2 was merged into Q64
+        assert_eq!(fetched_entity(&response, "Q2").unwrap().0, "Q64");
+
+        let response = json!({"entities": {"Q64": {}, "Q90": {}}});
+        assert!(fetched_entity(&response, "Q2").is_none());
+        assert!(fetched_entity(&json!({"error": "no such entity"}), "Q64").is_none());
+    }
+}
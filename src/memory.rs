@@ -0,0 +1,79 @@
+//! Memory budget of a run (`--max-memory`).
+
+use std::time::{Duration, Instant};
+
+/// Time between reads of the resident set size.
+const CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Resident set size of the process, if the platform reports it (only Linux for now).
+pub fn resident_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let rss = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?;
+    let kb: u64 = rss.trim().strip_suffix("kB")?.trim().parse().ok()?;
+    Some(kb * 1024)
+}
+
+/// Tells the reader thread when to stop reading lines so that the queues in front of the line
+/// handlers and the DB writer can drain.
+pub struct MemoryBudget {
+    max_bytes: u64,
+    last_check: Instant,
+    /// Number of times the input was paused, and for how long in total.
+    pub pauses: u64,
+    pub paused_for: Duration,
+}
+
+impl MemoryBudget {
+    /// Returns None if the resident set size can't be read on this platform.
+    pub fn new(max_bytes: u64) -> Option<Self> {
+        resident_bytes()?;
+        Some(MemoryBudget {
+            max_bytes,
+            last_check: Instant::now(),
+            pauses: 0,
+            paused_for: Duration::ZERO,
+        })
+    }
+
+    pub fn max_bytes(&self) -> u64 {
+        self.max_bytes
+    }
+
+    /// Returns the resident set size if it exceeds the budget. It's only read every 100 ms, so
+    /// this is cheap enough to call for every line.
+    pub fn exceeded(&mut self) -> Option<u64> {
+        if self.last_check.elapsed() < CHECK_INTERVAL {
+            return None;
+        }
+        self.last_check = Instant::now();
+        resident_bytes().filter(|rss| *rss > self.max_bytes)
+    }
+
+    pub fn record_pause(&mut self, duration: Duration) {
+        self.pauses += 1;
+        self.paused_for += duration;
+        self.last_check = Instant::now();
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_the_budget_every_interval() {
+        let mut budget = MemoryBudget::new(1).unwrap();
+        assert!(resident_bytes().unwrap() > 1);
+        // the first check waits for the interval
+        assert_eq!(budget.exceeded(), None);
+        std::thread::sleep(CHECK_INTERVAL);
+        assert!(budget.exceeded().is_some());
+        assert_eq!(budget.exceeded(), None);
+
+        let mut budget = MemoryBudget::new(u64::MAX).unwrap();
+        std::thread::sleep(CHECK_INTERVAL);
+        assert_eq!(budget.exceeded(), None);
+    }
+}
@@ -0,0 +1,201 @@
+//! Prometheus metrics of a run, served over HTTP with `--metrics-addr`.
+
+use crate::database::{BATCHES_WRITTEN, BATCH_WRITE_NANOS};
+use crate::options::EntityType;
+use crate::progress::Counts;
+use crate::wiki_data_line::{
+    MATCHED_BY_TYPE, SKIPPED_BY_FILTER, SKIPPED_BY_PREFILTER, SKIPPED_BY_TYPE,
+};
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Counters of the run, as of the last update from the reader thread.
+static LATEST: Mutex<Option<Counts>> = Mutex::new(None);
+
+/// Size of the input, if it's known.
+static INPUT_SIZE: Mutex<Option<u64>> = Mutex::new(None);
+
+pub fn record(counts: Counts) {
+    *LATEST.lock().unwrap() = Some(counts);
+}
+
+/// Starts serving the metrics on `addr` (like `0.0.0.0:9100`) on a background thread.
+pub fn serve(addr: &str, input_size: Option<u64>) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    *INPUT_SIZE.lock().unwrap() = input_size;
+    std::thread::Builder::new()
+        .name("metrics".into())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let result = stream.and_then(respond);
+                if let Err(e) = result {
+                    debug!("failed to serve metrics: {}", e);
+                }
+            }
+        })?;
+    Ok(())
+}
+
+/// Answers one request. Every path gets the metrics, since scrapers only ask for those.
+fn respond(mut stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    // the request itself doesn't matter, but it has to be read before the connection is closed
+    let mut reader = BufReader::new(&stream);
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 && line != "\r\n" && line != "\n" {
+        line.clear();
+    }
+
+    let body = render();
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+/// Writes a metric in the Prometheus text format. Samples are appended to the name, so they start
+/// with their labels (like `{type="country"}`) or a suffix (like `_sum`).
+fn metric<S: AsRef<str>>(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    samples: &[(S, f64)],
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (suffix, value) in samples {
+        let _ = writeln!(out, "{}{} {}", name, suffix.as_ref(), value);
+    }
+}
+
+fn render() -> String {
+    let mut out = String::new();
+    let counts = *LATEST.lock().unwrap();
+    if let Some(counts) = counts {
+        metric(
+            &mut out,
+            "geodb_input_bytes_total",
+            "counter",
+            "Bytes of the input read, compressed if the dump is.",
+            &[("", counts.downloaded as f64)],
+        );
+        if let Some(size) = *INPUT_SIZE.lock().unwrap() {
+            metric(
+                &mut out,
+                "geodb_input_size_bytes",
+                "gauge",
+                "Size of the input.",
+                &[("", size as f64)],
+            );
+        }
+        metric(
+            &mut out,
+            "geodb_decompressed_bytes_total",
+            "counter",
+            "Bytes of the decompressed dump read.",
+            &[("", counts.decompressed as f64)],
+        );
+        metric(
+            &mut out,
+            "geodb_lines_read_total",
+            "counter",
+            "Lines of the dump read.",
+            &[("", counts.lines as f64)],
+        );
+        metric(
+            &mut out,
+            "geodb_lines_failed_total",
+            "counter",
+            "Lines that failed to be handled.",
+            &[("", counts.failed_lines as f64)],
+        );
+        metric(
+            &mut out,
+            "geodb_lines_in_flight",
+            "gauge",
+            "Lines read but not handled yet.",
+            &[("", counts.tasks_in_flight as f64)],
+        );
+        metric(
+            &mut out,
+            "geodb_writer_queued_batches",
+            "gauge",
+            "Batches of entries waiting for the DB writer.",
+            &[("", counts.queued_batches as f64)],
+        );
+        metric(
+            &mut out,
+            "geodb_entries_written_total",
+            "counter",
+            "Entries passed to the DB writer's sink.",
+            &[("", counts.written as f64)],
+        );
+        metric(
+            &mut out,
+            "geodb_committed_line",
+            "gauge",
+            "Last line whose entries have all been committed.",
+            &[("", counts.committed_line as f64)],
+        );
+    }
+
+    let by_type: Vec<_> = EntityType::NAMES
+        .iter()
+        .zip(&MATCHED_BY_TYPE)
+        .map(|(name, count)| {
+            (
+                format!("{{type=\"{}\"}}", name),
+                count.load(Ordering::Relaxed) as f64,
+            )
+        })
+        .collect();
+    metric(
+        &mut out,
+        "geodb_entities_matched_total",
+        "counter",
+        "Entities extracted, by type.",
+        &by_type,
+    );
+    metric(
+        &mut out,
+        "geodb_entities_skipped_total",
+        "counter",
+        "Lines or entities skipped, by reason.",
+        &[
+            (
+                "{reason=\"prefilter\"}",
+                SKIPPED_BY_PREFILTER.load(Ordering::Relaxed) as f64,
+            ),
+            (
+                "{reason=\"not_item\"}",
+                SKIPPED_BY_TYPE.load(Ordering::Relaxed) as f64,
+            ),
+            (
+                "{reason=\"filters\"}",
+                SKIPPED_BY_FILTER.load(Ordering::Relaxed) as f64,
+            ),
+        ],
+    );
+    metric(
+        &mut out,
+        "geodb_writer_batch_seconds",
+        "summary",
+        "Time the DB writer took to write a batch of entries.",
+        &[
+            (
+                "_sum",
+                BATCH_WRITE_NANOS.load(Ordering::Relaxed) as f64 / 1e9,
+            ),
+            ("_count", BATCHES_WRITTEN.load(Ordering::Relaxed) as f64),
+        ],
+    );
+    out
+}
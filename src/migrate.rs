@@ -0,0 +1,389 @@
+//! Versions of the schema of SQLite outputs, and upgrading outputs of older versions of geo-db in
+//! place (`geo-db migrate`, and before outputs are updated).
+//!
+//! The version is stored as the `user_version` of the database. Outputs are upgraded by comparing
+//! them with the schema of setup.sql: missing tables and indexes are created, missing nullable
+//! columns are added, and tables that lack columns which can't be null or whose columns changed
+//! are rebuilt with their rows copied over.
+//!
+//! Outputs whose post-processing was cleaned up don't have the tables that the cleanup drops or
+//! replaces, so those are left out rather than created again.
+
+use rusqlite::{params, Connection, OpenFlags, Transaction};
+use thiserror::Error;
+
+/// Version of the schema of setup.sql, which has to be increased when it changes. Outputs
+/// built before the version was stored have version 0.
+pub const SCHEMA_VERSION: i64 = 2;
+
+/// Values of the columns that can't be null in rows of older outputs that don't have them, as
+/// SQL expressions over the columns they do have.
+pub const FILLED_COLUMNS: [(&str, &str, &str); 4] = [
+    ("countries", "conflicting", "0"),
+    // dependent territories weren't told apart from sovereign states yet
+    ("countries", "sovereign", "1"),
+    // languages used (P2936) were only stored for objects without official languages
+    ("object_languages", "kind", "'official'"),
+    (
+        "object_labels",
+        "source",
+        "case when native_order is null then 'label' else 'native_name' end",
+    ),
+];
+
+/// Tables of a build that the cleanup of post-processing drops, or replaces with tables of its own
+/// (`object_labels` and `object_languages` become `cities_labels` and `cities_languages`).
+const CLEANED_UP_TABLES: [&str; 9] = [
+    "countries",
+    "object_languages",
+    "languages",
+    "territorial_entities",
+    "territorial_entities_parents",
+    "admin_levels",
+    "object_labels",
+    "missing_p17",
+    "part_of",
+];
+
+#[derive(Debug, Error)]
+pub enum MigrateError {
+    #[error("database error: {0}")]
+    Db(#[from] rusqlite::Error),
+    #[error(
+        "the database has schema version {0}, but this geo-db only knows versions up to {}",
+        SCHEMA_VERSION
+    )]
+    Newer(i64),
+}
+
+/// A column as described by `PRAGMA table_info`.
+#[derive(Debug, PartialEq)]
+struct Column {
+    name: String,
+    declared_type: String,
+    not_null: bool,
+}
+
+fn schema_version(conn: &Connection) -> rusqlite::Result<i64> {
+    conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+}
+
+fn columns(conn: &Connection, table: &str) -> rusqlite::Result<Vec<Column>> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info(\"{}\")", table))?;
+    let columns = stmt.query_map([], |row| {
+        Ok(Column {
+            name: row.get(1)?,
+            declared_type: row.get(2)?,
+            not_null: row.get(3)?,
+        })
+    })?;
+    columns.collect()
+}
+
+/// Names, tables and SQL of the tables, indexes or triggers of a database.
+fn schema_objects(
+    conn: &Connection,
+    kind: &str,
+) -> rusqlite::Result<Vec<(String, String, String)>> {
+    let mut stmt = conn.prepare(
+        "select name, tbl_name, sql from sqlite_master where type = ?1 and sql is not null
+        order by rowid",
+    )?;
+    let objects = stmt.query_map(params![kind], |row| {
+        Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+    })?;
+    objects.collect()
+}
+
+fn has_object(tx: &Transaction, name: &str) -> rusqlite::Result<bool> {
+    tx.query_row(
+        "select count(*) > 0 from sqlite_master where name = ?1",
+        params![name],
+        |row| row.get(0),
+    )
+}
+
+/// Replaces a table with one created from the SQL of the current schema, and copies its rows.
+/// Columns that aren't in the schema, like those added by post-processing, are kept. Its indexes
+/// are dropped with it, and its triggers (like those of the R*Tree of the cities) are created
+/// again.
+fn rebuild_table(
+    tx: &Transaction,
+    table: &str,
+    sql: &str,
+    target: &[Column],
+    existing: &[Column],
+) -> rusqlite::Result<()> {
+    let temp = format!("geodb_migrate_{}", table);
+    let definition = &sql[sql.find('(').unwrap_or(0)..];
+    tx.execute_batch(&format!("create table \"{}\" {}", temp, definition))?;
+    let extra: Vec<_> = existing
+        .iter()
+        .filter(|old| !target.iter().any(|new| new.name == old.name))
+        .collect();
+    for column in &extra {
+        tx.execute_batch(&format!(
+            "alter table \"{}\" add column \"{}\" {}",
+            temp, column.name, column.declared_type
+        ))?;
+    }
+    let triggers: Vec<_> = schema_objects(tx, "trigger")?
+        .into_iter()
+        .filter(|(_, trigger_table, _)| trigger_table == table)
+        .collect();
+
+    let mut names: Vec<_> = target
+        .iter()
+        .map(|column| format!("\"{}\"", column.name))
+        .collect();
+    let mut values: Vec<_> = target
+        .iter()
+        .map(|column| {
+            if existing.iter().any(|old| old.name == column.name) {
+                format!("\"{}\"", column.name)
+            } else {
+                FILLED_COLUMNS
+                    .iter()
+                    .find(|(t, c, _)| *t == table && *c == column.name)
+                    .map(|(_, _, value)| value.to_string())
+                    .unwrap_or_else(|| "null".into())
+            }
+        })
+        .collect();
+    for column in &extra {
+        names.push(format!("\"{}\"", column.name));
+        values.push(format!("\"{}\"", column.name));
+    }
+    tx.execute_batch(&format!(
+        "insert into \"{0}\" ({1}) select {2} from \"{3}\";
+        drop table \"{3}\";
+        alter table \"{0}\" rename to \"{3}\";",
+        temp,
+        names.join(", "),
+        values.join(", "),
+        table
+    ))?;
+    for (trigger, _, sql) in triggers {
+        tx.execute_batch(&sql)?;
+        debug!("Recreated trigger {}", trigger);
+    }
+    Ok(())
+}
+
+/// Upgrades a SQLite output to the current schema version in one transaction, logging the
+/// changes, and returns the version it had.
+pub fn migrate(out_file: &str) -> Result<i64, MigrateError> {
+    let mut conn = Connection::open_with_flags(out_file, OpenFlags::SQLITE_OPEN_READ_WRITE)?;
+    let version = schema_version(&conn)?;
+    if version > SCHEMA_VERSION {
+        return Err(MigrateError::Newer(version));
+    }
+    if version == SCHEMA_VERSION {
+        return Ok(version);
+    }
+
+    let schema = Connection::open_in_memory()?;
+    schema.execute_batch(include_str!("setup.sql"))?;
+
+    let tx = conn.transaction()?;
+    let cleaned_up = has_object(&tx, "cities_labels")?;
+    if cleaned_up {
+        info!("The output was cleaned up after post-processing, so its tables of the build are left out");
+    }
+    let is_left_out = |table: &str| cleaned_up && CLEANED_UP_TABLES.contains(&table);
+    for (table, _, sql) in schema_objects(&schema, "table")? {
+        if is_left_out(&table) {
+            continue;
+        }
+        if !has_object(&tx, &table)? {
+            tx.execute_batch(&sql)?;
+            info!("Created table {}", table);
+            continue;
+        }
+        let target = columns(&schema, &table)?;
+        let existing = columns(&tx, &table)?;
+        let missing: Vec<_> = target
+            .iter()
+            .filter(|column| !existing.iter().any(|old| old.name == column.name))
+            .collect();
+        let changed = existing
+            .iter()
+            .any(|old| target.iter().any(|new| new.name == old.name && new != old));
+        if missing.is_empty() && !changed {
+            continue;
+        }
+        if changed || missing.iter().any(|column| column.not_null) {
+            rebuild_table(&tx, &table, &sql, &target, &existing)?;
+            info!("Rebuilt table {}", table);
+        } else {
+            for column in missing {
+                tx.execute_batch(&format!(
+                    "alter table \"{}\" add column \"{}\" {}",
+                    table, column.name, column.declared_type
+                ))?;
+                info!("Added column {}.{}", table, column.name);
+            }
+        }
+    }
+    // also recreates the indexes of rebuilt tables
+    for (index, table, sql) in schema_objects(&schema, "index")? {
+        if !is_left_out(&table) && !has_object(&tx, &index)? {
+            tx.execute_batch(&sql)?;
+            info!("Created index {}", index);
+        }
+    }
+    tx.execute_batch(&format!("PRAGMA user_version = {}", SCHEMA_VERSION))?;
+    tx.commit()?;
+    info!(
+        "Upgraded {} from schema version {} to {}",
+        out_file, version, SCHEMA_VERSION
+    );
+    Ok(version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// An output file created from the SQL of a fixture.
+    fn fixture(sql: &str) -> (TempDir, String) {
+        let dir = tempfile::tempdir().unwrap();
+        let out_file = dir.path().join("out.db").to_str().unwrap().to_string();
+        Connection::open(&out_file)
+            .unwrap()
+            .execute_batch(sql)
+            .unwrap();
+        (dir, out_file)
+    }
+
+    fn has_table(conn: &Connection, table: &str) -> bool {
+        conn.query_row(
+            "select count(*) > 0 from sqlite_master where type = 'table' and name = ?1",
+            params![table],
+            |row| row.get(0),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn migrate_version_0() {
+        let (_dir, out_file) = fixture(include_str!("../tests/fixtures/output_v0.sql"));
+        assert_eq!(migrate(&out_file).unwrap(), 0);
+
+        let conn = Connection::open(&out_file).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), SCHEMA_VERSION);
+        let schema = Connection::open_in_memory().unwrap();
+        schema.execute_batch(include_str!("setup.sql")).unwrap();
+        // added columns come after the others
+        for (table, _, _) in schema_objects(&schema, "table").unwrap() {
+            let existing = columns(&conn, &table).unwrap();
+            let target = columns(&schema, &table).unwrap();
+            assert_eq!(existing.len(), target.len(), "{}", table);
+            for column in target {
+                assert!(existing.contains(&column), "{}.{}", table, column.name);
+            }
+        }
+        let country: (String, bool, bool) = conn
+            .query_row(
+                "select iso, conflicting, sovereign from countries where id = 'Q183'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(country, ("DE".into(), false, true));
+        let sources: Vec<String> = conn
+            .prepare("select source from object_labels order by lang")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(sources, ["native_name", "label"]);
+        let kind: String = conn
+            .query_row("select kind from object_languages", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(kind, "official");
+
+        // the second time there's nothing to do
+        assert_eq!(migrate(&out_file).unwrap(), SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn migrate_cleaned_up_version_0() {
+        let (_dir, out_file) = fixture(include_str!("../tests/fixtures/cleaned_output_v0.sql"));
+        assert_eq!(migrate(&out_file).unwrap(), 0);
+
+        let conn = Connection::open(&out_file).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), SCHEMA_VERSION);
+        for table in &CLEANED_UP_TABLES {
+            assert!(!has_table(&conn, table), "{}", table);
+        }
+        assert!(has_table(&conn, "sister_cities"));
+        let city: (String, String, Option<i64>) = conn
+            .query_row(
+                "select country, native_label, revision from cities where id = 'Q64'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(city, ("DE".into(), "Berlin".into(), None));
+    }
+
+    #[test]
+    fn rebuilt_tables_keep_triggers_and_columns() {
+        let (_dir, out_file) = fixture(include_str!("../tests/fixtures/output_v0.sql"));
+        let mut conn = Connection::open(&out_file).unwrap();
+        conn.execute_batch(include_str!("city_rtree.sql")).unwrap();
+        conn.execute_batch("alter table cities add column native_label string")
+            .unwrap();
+
+        let schema = Connection::open_in_memory().unwrap();
+        schema.execute_batch(include_str!("setup.sql")).unwrap();
+        let (_, _, sql) = schema_objects(&schema, "table")
+            .unwrap()
+            .into_iter()
+            .find(|(table, _, _)| table == "cities")
+            .unwrap();
+        let tx = conn.transaction().unwrap();
+        let target = columns(&schema, "cities").unwrap();
+        let existing = columns(&tx, "cities").unwrap();
+        rebuild_table(&tx, "cities", &sql, &target, &existing).unwrap();
+        tx.commit().unwrap();
+
+        let triggers: Vec<String> = schema_objects(&conn, "trigger")
+            .unwrap()
+            .into_iter()
+            .map(|(name, _, _)| name)
+            .collect();
+        assert_eq!(
+            triggers,
+            [
+                "cities_rtree_insert",
+                "cities_rtree_update",
+                "cities_rtree_delete"
+            ]
+        );
+        conn.execute(
+            "insert into cities (id, lat, lon, native_label) values ('Q90', 48.857, 2.352, 'Paris')",
+            [],
+        )
+        .unwrap();
+        let indexed: bool = conn
+            .query_row(
+                "select count(*) > 0 from cities_rtree where id = 90",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert!(indexed);
+        let label: String = conn
+            .query_row(
+                "select native_label from cities where id = 'Q90'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(label, "Paris");
+    }
+}
@@ -0,0 +1,810 @@
+use crate::database::{self, Backend, SqliteSettings};
+use crate::input::{http, InputSettings};
+use crate::output::OutputCompression;
+use clap::ArgMatches;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use thiserror::Error;
+
+/// The primary kinds of entities that can be extracted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EntityType {
+    Country,
+    TerritorialEntity,
+    Settlement,
+    Language,
+}
+
+impl EntityType {
+    pub const ALL: [EntityType; 4] = [
+        EntityType::Country,
+        EntityType::TerritorialEntity,
+        EntityType::Settlement,
+        EntityType::Language,
+    ];
+    pub const NAMES: [&'static str; 4] =
+        ["country", "territorial-entity", "settlement", "language"];
+}
+
+impl FromStr for EntityType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "country" => Ok(EntityType::Country),
+            "territorial-entity" => Ok(EntityType::TerritorialEntity),
+            "settlement" => Ok(EntityType::Settlement),
+            "language" => Ok(EntityType::Language),
+            s => Err(format!("unknown entity type {:?}", s)),
+        }
+    }
+}
+
+/// Which names of an entity an alias must differ from to be kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AliasDedupScope {
+    /// Labels and aliases in the same language.
+    Language,
+    /// Labels and aliases in any language.
+    Entity,
+}
+
+impl FromStr for AliasDedupScope {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "language" => Ok(AliasDedupScope::Language),
+            "entity" => Ok(AliasDedupScope::Entity),
+            s => Err(format!("unknown alias dedup scope {:?}", s)),
+        }
+    }
+}
+
+/// Coordinate reference system of extracted coordinates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum CoordCrs {
+    /// EPSG:4326, WGS84 latitude/longitude in degrees, as in Wikidata.
+    #[serde(rename = "4326")]
+    Wgs84,
+    /// EPSG:3857, Web Mercator meters. Y is stored as `lat` and X as `lon`.
+    #[serde(rename = "3857")]
+    WebMercator,
+}
+
+impl CoordCrs {
+    /// Web Mercator is undefined at the poles, so latitudes are clamped to its square extent.
+    const MAX_MERCATOR_LAT: f64 = 85.051_128_78;
+    const EARTH_RADIUS: f64 = 6_378_137.;
+
+    /// The EPSG code, as in `--coord-crs` and the `coord_crs` metadata of outputs.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CoordCrs::Wgs84 => "4326",
+            CoordCrs::WebMercator => "3857",
+        }
+    }
+
+    /// Projects WGS84 coordinates, returning (lat, lon) or (y, x).
+    pub fn project(self, lat: f64, lon: f64) -> (f64, f64) {
+        match self {
+            CoordCrs::Wgs84 => (lat, lon),
+            CoordCrs::WebMercator => {
+                let lat = lat
+                    .clamp(-Self::MAX_MERCATOR_LAT, Self::MAX_MERCATOR_LAT)
+                    .to_radians();
+                let x = Self::EARTH_RADIUS * lon.to_radians();
+                let y = Self::EARTH_RADIUS * (std::f64::consts::FRAC_PI_4 + lat / 2.).tan().ln();
+                (y, x)
+            }
+        }
+    }
+
+    /// Converts (lat, lon) or (y, x) in this CRS back to WGS84 (lat, lon). Latitudes that were
+    /// clamped by the projection stay clamped.
+    pub fn unproject(self, a: f64, b: f64) -> (f64, f64) {
+        match self {
+            CoordCrs::Wgs84 => (a, b),
+            CoordCrs::WebMercator => {
+                let lat = 2. * (a / Self::EARTH_RADIUS).exp().atan() - std::f64::consts::FRAC_PI_2;
+                let lon = b / Self::EARTH_RADIUS;
+                (lat.to_degrees(), lon.to_degrees())
+            }
+        }
+    }
+}
+
+impl FromStr for CoordCrs {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "4326" => Ok(CoordCrs::Wgs84),
+            "3857" => Ok(CoordCrs::WebMercator),
+            s => Err(format!("unsupported coordinate CRS {:?}", s)),
+        }
+    }
+}
+
+/// What happens to WGS84 coordinates outside of lat ∈ [-90, 90] and lon ∈ [-180, 180].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CoordValidation {
+    /// Coordinates are stored as they are.
+    Off,
+    /// Out-of-range coordinates are dropped.
+    Skip,
+    /// Out-of-range coordinates are clamped to the valid range.
+    Clamp,
+    /// Coordinates that are only in range with lat/lon swapped are swapped; others are dropped.
+    Swap,
+}
+
+impl CoordValidation {
+    pub fn in_range(lat: f64, lon: f64) -> bool {
+        (-90. ..=90.).contains(&lat) && (-180. ..=180.).contains(&lon)
+    }
+
+    /// Returns the coordinates to store in place of out-of-range (lat, lon), if any.
+    pub fn fix(self, lat: f64, lon: f64) -> Option<(f64, f64)> {
+        match self {
+            CoordValidation::Off => Some((lat, lon)),
+            CoordValidation::Skip => None,
+            CoordValidation::Clamp => Some((lat.clamp(-90., 90.), lon.clamp(-180., 180.))),
+            CoordValidation::Swap if Self::in_range(lon, lat) => Some((lon, lat)),
+            CoordValidation::Swap => None,
+        }
+    }
+}
+
+impl FromStr for CoordValidation {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(CoordValidation::Off),
+            "skip" => Ok(CoordValidation::Skip),
+            "clamp" => Ok(CoordValidation::Clamp),
+            "swap" => Ok(CoordValidation::Swap),
+            s => Err(format!("unknown coordinate validation {:?}", s)),
+        }
+    }
+}
+
+/// Root classes of each kind of entity. Subclasses are looked up over SPARQL.
+///
+/// These can only be changed in a config file, e.g.
+///
+/// ```toml
+/// [class-roots]
+/// settlement = ["Q486972", "Q3957"]
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct ClassRoots {
+    pub territorial_entity: Vec<String>,
+    pub settlement: Vec<String>,
+    pub language: Vec<String>,
+    /// Places of these classes are not extracted.
+    pub excluded: Vec<String>,
+    /// Settlements of these classes are not extracted as settlements.
+    pub excluded_settlement: Vec<String>,
+}
+
+impl Default for ClassRoots {
+    fn default() -> Self {
+        let ids = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect();
+        ClassRoots {
+            // territorial entity
+            territorial_entity: ids(&["Q56061"]),
+            // human settlement
+            settlement: ids(&["Q486972"]),
+            // language
+            language: ids(&["Q34770"]),
+            excluded: ids(&[
+                "Q2974842", // lost cities
+                "Q123705",  // neighborhoods, including stuff like shipyards
+                "Q131596",  // farm
+                "Q40357",   // prisons
+                "Q695850",  // airbase
+                            // not excluded because this causes moscow (->Q4442912) and others to be excluded
+                            // "Q19953632", // former administrative territorial entities
+            ]),
+            excluded_settlement: ids(&[
+                "Q941150",    // Plusregio (administrative unit in NL)
+                "Q159313",    // urban agglomeration
+                "Q106505045", // linear pottery culture
+                "Q22969563",  // bodendenkmal
+            ]),
+        }
+    }
+}
+
+/// Larger than any entity in the dump so far (the largest are a few megabytes).
+const DEFAULT_MAX_LINE_BYTES: usize = 64 * 1024 * 1024;
+
+/// Options that control what gets extracted from the dump and where it goes.
+///
+/// These can also be loaded from a config file, where keys are named like the command line
+/// flags (e.g. `entity-types = ["country", "settlement"]`).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Options {
+    /// URL or local path of the dump (bz2, gzip, zstd or uncompressed).
+    pub input: String,
+    /// Date of the Wikidata dump to download instead of the input.
+    pub dump_date: Option<DumpDate>,
+    /// Other URLs of the same dump, if the input is downloaded.
+    #[serde(rename = "mirror")]
+    pub mirrors: Vec<String>,
+    /// Number of line handler threads, and of bz2 decompression threads (default: one less than
+    /// the number of CPUs).
+    pub threads: Option<usize>,
+    /// Output file (or directory for the sled backend, or URL for the postgres backend).
+    pub output: String,
+    /// Output backend.
+    pub backend: Backend,
+    /// Whether the SQLite output is written with the faster, less safe settings of a bulk load.
+    pub bulk_load: bool,
+    /// Entries per SQLite transaction (default: depends on `bulk_load`).
+    pub rows_per_commit: Option<usize>,
+    /// Kinds of entities to extract. Language classes are only fetched if languages are included.
+    pub entity_types: HashSet<EntityType>,
+    /// Whether P625 qualifiers on P131/P17 statements are extracted.
+    pub statement_coords: bool,
+    /// Whether P1365 (replaces) relationships are extracted.
+    pub replaces: bool,
+    /// Whether P361 (part of) relationships are extracted and used to find missing countries.
+    pub part_of: bool,
+    /// Whether aliases are extracted.
+    pub aliases: bool,
+    /// Whether P1813 (short name) is used as the label in languages without one.
+    pub short_name_labels: bool,
+    /// Languages whose labels, aliases and short names are extracted (all if empty). Native
+    /// names are always extracted because post-processing picks city names from them.
+    pub label_languages: HashSet<String>,
+    /// Classes whose instances (including those of subclasses) are extracted.
+    pub class_roots: ClassRoots,
+    /// Which labels an alias is compared against to drop duplicates.
+    pub alias_dedup_scope: AliasDedupScope,
+    /// CRS that coordinates are stored in. Coordinate precisions stay in degrees.
+    pub coord_crs: CoordCrs,
+    /// How out-of-range coordinates are handled.
+    pub validate_coords: CoordValidation,
+    /// Whether replaced (P1366) and dissolved (P576) entities are processed instead of skipped.
+    pub include_historical: bool,
+    /// Whether the first line handling error aborts the run instead of being logged.
+    pub fail_fast: bool,
+    /// Fraction of failed lines above which the run exits with an error.
+    pub max_error_rate: Option<f64>,
+    /// Whether JSON errors are logged with the surrounding part of the line.
+    pub pretty_errors: bool,
+    /// Whether the progress is logged periodically instead of shown as progress bars.
+    pub no_progress: bool,
+    /// Address to serve Prometheus metrics of the run on.
+    pub metrics_addr: Option<String>,
+    /// Whether data quality warnings are also stored as entries.
+    pub quality_events: bool,
+    /// Size of the buffer between the dump download and the decompressor.
+    pub http_buffer_bytes: usize,
+    /// Connection attempts before the download fails.
+    pub http_retries: usize,
+    /// Seconds to wait before the first retry, doubling with every try.
+    pub http_retry_interval: f64,
+    /// Upper limit of the wait between retries in seconds.
+    pub http_retry_max_interval: f64,
+    /// Random variation of each wait, as a fraction of it.
+    pub http_retry_jitter: f64,
+    /// Download rate limit in MB/s.
+    pub max_download_rate: Option<f64>,
+    /// Where to save the downloaded dump, which is read instead of downloading if it exists.
+    pub cache_download: Option<String>,
+    /// Directory a torrent client downloads the dump to, if the input is a `.torrent` file.
+    pub torrent_dir: Option<String>,
+    /// URL or path of a checksum list to verify the dump against.
+    pub checksums: Option<String>,
+    /// Whether a checksum mismatch is only logged.
+    pub checksum_warn_only: bool,
+    /// Memory use in MB above which the reader thread waits for the queues to drain.
+    pub max_memory: Option<u64>,
+    /// Lines larger than this are handled on the reader thread to limit memory use.
+    pub max_line_bytes: usize,
+    /// Entity to resume from, skipping everything before it.
+    pub resume_from_id: Option<ResumeFromId>,
+    /// File to record the positions of bz2 streams in, and to look them up in for resuming.
+    pub bz2_index: Option<String>,
+    /// Offset in the decompressed dump to resume from.
+    pub resume_offset: Option<u64>,
+    /// Number of the last line before the resume offset, so that line numbers continue from the
+    /// interrupted run. Set by `resume` from the build state of the output.
+    #[serde(skip)]
+    pub resume_line_number: Option<u64>,
+    /// Number of dump lines after which the run stops.
+    pub limit: Option<u64>,
+    /// Fraction of the dump lines that are processed. The same lines are picked in every run.
+    pub sample_rate: Option<f64>,
+    /// Whether entries are written in the order of the dump lines, so that builds of the same
+    /// dump produce identical outputs.
+    pub deterministic: bool,
+    /// File the class sets are loaded from instead of the SPARQL endpoint, and saved to if it
+    /// doesn't exist yet.
+    pub classes_file: Option<String>,
+    /// File to write the raw JSON of every matched entity to.
+    pub dump_matched: Option<String>,
+    /// Compression for file outputs.
+    pub output_compression: OutputCompression,
+    /// Settlements whose largest P1082 (population) is below this are skipped.
+    pub min_population: Option<u64>,
+    /// Places without claims for all of these properties are skipped.
+    #[serde(rename = "require-property")]
+    pub require_properties: Vec<String>,
+    /// If not empty, places without a P17 (country) in this set are skipped.
+    #[serde(rename = "country")]
+    pub countries: HashSet<String>,
+    /// If not empty, places without a P31 (instance of) in this set are skipped.
+    #[serde(rename = "class")]
+    pub classes: HashSet<String>,
+    /// Country (QID or ISO 3166-1 alpha-2 code) that places must be in, by P17 (country) or by
+    /// their P131 (located in) chain.
+    pub only_country: Option<String>,
+    /// Whether the input updates an existing database, replacing the stored entries of every
+    /// item in it. Set by the `update` subcommand.
+    #[serde(skip)]
+    pub update: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            input: http::DUMP_URL.into(),
+            dump_date: None,
+            mirrors: Vec::new(),
+            threads: None,
+            output: "geo.db".into(),
+            backend: Backend::Sqlite,
+            bulk_load: false,
+            rows_per_commit: None,
+            entity_types: EntityType::ALL.iter().copied().collect(),
+            statement_coords: false,
+            replaces: false,
+            part_of: false,
+            aliases: false,
+            short_name_labels: false,
+            label_languages: HashSet::new(),
+            class_roots: ClassRoots::default(),
+            alias_dedup_scope: AliasDedupScope::Language,
+            coord_crs: CoordCrs::Wgs84,
+            validate_coords: CoordValidation::Skip,
+            include_historical: false,
+            fail_fast: false,
+            max_error_rate: None,
+            pretty_errors: false,
+            no_progress: false,
+            metrics_addr: None,
+            quality_events: false,
+            http_buffer_bytes: http::DEFAULT_BUFFER_BYTES,
+            http_retries: http::RetryConfig::default().tries,
+            http_retry_interval: http::RetryConfig::default().interval_secs,
+            http_retry_max_interval: http::RetryConfig::default().max_interval_secs,
+            http_retry_jitter: http::RetryConfig::default().jitter,
+            max_download_rate: None,
+            cache_download: None,
+            torrent_dir: None,
+            checksums: None,
+            checksum_warn_only: false,
+            max_memory: None,
+            max_line_bytes: DEFAULT_MAX_LINE_BYTES,
+            resume_from_id: None,
+            bz2_index: None,
+            resume_offset: None,
+            resume_line_number: None,
+            limit: None,
+            sample_rate: None,
+            deterministic: false,
+            classes_file: None,
+            dump_matched: None,
+            output_compression: OutputCompression::None,
+            min_population: None,
+            require_properties: Vec::new(),
+            countries: HashSet::new(),
+            classes: HashSet::new(),
+            only_country: None,
+            update: false,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum OptionsError {
+    #[error("invalid value {value:?} for {name}: {message}")]
+    InvalidValue {
+        name: String,
+        value: String,
+        message: String,
+    },
+    #[error("unsupported database URL {0:?}")]
+    UnsupportedUrl(String),
+}
+
+/// Parses the value of an argument.
+fn parse_arg<T>(name: &str, value: &str) -> Result<T, OptionsError>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|e: T::Err| OptionsError::InvalidValue {
+            name: name.to_string(),
+            value: value.to_string(),
+            message: e.to_string(),
+        })
+}
+
+/// Parses the value of an argument if it was passed explicitly (i.e. not a clap default value).
+fn explicit_arg<T>(matches: &ArgMatches, name: &str) -> Result<Option<T>, OptionsError>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    if matches.occurrences_of(name) == 0 {
+        return Ok(None);
+    }
+    matches
+        .value_of(name)
+        .map(|value| parse_arg(name, value))
+        .transpose()
+}
+
+impl Options {
+    pub fn sqlite_settings(&self) -> SqliteSettings {
+        let default_rows = if self.bulk_load {
+            database::BULK_LOAD_ROWS_PER_COMMIT
+        } else {
+            database::DEFAULT_ROWS_PER_COMMIT
+        };
+        SqliteSettings {
+            bulk_load: self.bulk_load,
+            rows_per_commit: self.rows_per_commit.unwrap_or(default_rows).max(1),
+        }
+    }
+
+    /// Number of line handler threads: `--threads`, or one less than the number of CPUs so that
+    /// the reader (which also decompresses single-stream dumps) and the DB writer aren't starved.
+    /// Multi-stream bz2 dumps are decompressed on a pool of the same size.
+    pub fn worker_threads(&self) -> usize {
+        self.threads.unwrap_or_else(|| {
+            let cpus = std::thread::available_parallelism().map_or(1, |cpus| cpus.get());
+            cpus.saturating_sub(1).max(1)
+        })
+    }
+
+    pub fn input_settings(&self) -> InputSettings {
+        InputSettings {
+            http: self.http_settings(),
+            torrent_dir: self.torrent_dir.clone(),
+            decoder_threads: self.worker_threads(),
+        }
+    }
+
+    pub fn http_settings(&self) -> http::HttpSettings {
+        http::HttpSettings {
+            mirrors: self.mirrors.clone(),
+            buffer_bytes: self.http_buffer_bytes,
+            retry: http::RetryConfig {
+                tries: self.http_retries.max(1),
+                interval_secs: self.http_retry_interval,
+                max_interval_secs: self.http_retry_max_interval,
+                jitter: self.http_retry_jitter.clamp(0., 1.),
+            },
+            max_rate: self
+                .max_download_rate
+                .filter(|rate| *rate > 0.)
+                .map(|rate| rate * 1_000_000.),
+            cache_path: self.cache_download.clone(),
+        }
+    }
+
+    /// Reads options from the command line. Options that weren't passed are taken from `base`,
+    /// which is usually the config file or the defaults.
+    pub fn from_matches(matches: &ArgMatches, base: Options) -> Result<Self, OptionsError> {
+        let mut entity_types: HashSet<EntityType> = match matches.values_of("entity_types") {
+            Some(types) => types
+                .map(|t| parse_arg("entity_types", t))
+                .collect::<Result<_, _>>()?,
+            None => base.entity_types,
+        };
+        if matches.is_present("no_languages") {
+            entity_types.remove(&EntityType::Language);
+        }
+
+        let input = match explicit_arg(matches, "file")? {
+            Some(file) => Some(file),
+            None => explicit_arg(matches, "input")?,
+        };
+        // an input on the command line replaces a dump date from the config file
+        let dump_date = match input {
+            Some(_) => None,
+            None => explicit_arg(matches, "dump_date")?.or(base.dump_date),
+        };
+        // `--db URL` is short for `--backend postgres --output URL`
+        let db: Option<String> = explicit_arg(matches, "db")?;
+
+        Ok(Options {
+            input: input
+                .or_else(|| dump_date.as_ref().map(DumpDate::url))
+                .unwrap_or(base.input),
+            dump_date,
+            mirrors: match matches.values_of("mirror") {
+                Some(mirrors) => mirrors.map(|m| m.to_string()).collect(),
+                None => base.mirrors,
+            },
+            threads: explicit_arg(matches, "threads")?.or(base.threads),
+            output: match &db {
+                Some(url) => url.clone(),
+                None => explicit_arg(matches, "out")?.unwrap_or(base.output),
+            },
+            backend: match &db {
+                Some(url) => Backend::for_url(url)
+                    .ok_or_else(|| OptionsError::UnsupportedUrl(url.clone()))?,
+                None => explicit_arg(matches, "backend")?.unwrap_or(base.backend),
+            },
+            bulk_load: base.bulk_load || matches.is_present("bulk_load"),
+            rows_per_commit: explicit_arg(matches, "rows_per_commit")?.or(base.rows_per_commit),
+            entity_types,
+            statement_coords: base.statement_coords || matches.is_present("statement_coords"),
+            replaces: base.replaces || matches.is_present("replaces"),
+            part_of: base.part_of || matches.is_present("part_of"),
+            aliases: base.aliases || matches.is_present("aliases"),
+            short_name_labels: base.short_name_labels || matches.is_present("short_name_labels"),
+            label_languages: match matches.values_of("label_languages") {
+                Some(languages) => languages.map(|l| l.to_string()).collect(),
+                None => base.label_languages,
+            },
+            class_roots: base.class_roots,
+            alias_dedup_scope: explicit_arg(matches, "alias_dedup_scope")?
+                .unwrap_or(base.alias_dedup_scope),
+            coord_crs: explicit_arg(matches, "coord_crs")?.unwrap_or(base.coord_crs),
+            validate_coords: explicit_arg(matches, "validate_coords")?
+                .unwrap_or(base.validate_coords),
+            include_historical: base.include_historical || matches.is_present("include_historical"),
+            fail_fast: if matches.is_present("resilient") {
+                false
+            } else {
+                base.fail_fast || matches.is_present("fail_fast")
+            },
+            max_error_rate: explicit_arg(matches, "max_error_rate")?.or(base.max_error_rate),
+            pretty_errors: base.pretty_errors || matches.is_present("pretty_errors"),
+            no_progress: base.no_progress || matches.is_present("no_progress"),
+            metrics_addr: explicit_arg(matches, "metrics_addr")?.or(base.metrics_addr),
+            quality_events: base.quality_events || matches.is_present("quality_events"),
+            http_buffer_bytes: explicit_arg(matches, "http_buffer_bytes")?
+                .unwrap_or(base.http_buffer_bytes),
+            http_retries: explicit_arg(matches, "http_retries")?.unwrap_or(base.http_retries),
+            http_retry_interval: explicit_arg(matches, "http_retry_interval")?
+                .unwrap_or(base.http_retry_interval),
+            http_retry_max_interval: explicit_arg(matches, "http_retry_max_interval")?
+                .unwrap_or(base.http_retry_max_interval),
+            http_retry_jitter: explicit_arg(matches, "http_retry_jitter")?
+                .unwrap_or(base.http_retry_jitter),
+            max_download_rate: explicit_arg(matches, "max_download_rate")?
+                .or(base.max_download_rate),
+            cache_download: explicit_arg(matches, "cache_download")?.or(base.cache_download),
+            torrent_dir: explicit_arg(matches, "torrent_dir")?.or(base.torrent_dir),
+            checksums: explicit_arg(matches, "checksums")?.or(base.checksums),
+            checksum_warn_only: base.checksum_warn_only || matches.is_present("checksum_warn_only"),
+            max_memory: explicit_arg(matches, "max_memory")?.or(base.max_memory),
+            max_line_bytes: explicit_arg(matches, "max_line_bytes")?.unwrap_or(base.max_line_bytes),
+            resume_from_id: explicit_arg(matches, "resume_from_id")?.or(base.resume_from_id),
+            bz2_index: explicit_arg(matches, "bz2_index")?.or(base.bz2_index),
+            resume_offset: explicit_arg(matches, "resume_offset")?.or(base.resume_offset),
+            resume_line_number: base.resume_line_number,
+            limit: explicit_arg(matches, "limit")?.or(base.limit),
+            sample_rate: explicit_arg(matches, "sample_rate")?.or(base.sample_rate),
+            deterministic: base.deterministic || matches.is_present("deterministic"),
+            classes_file: explicit_arg(matches, "classes_file")?.or(base.classes_file),
+            dump_matched: explicit_arg(matches, "dump_matched")?.or(base.dump_matched),
+            output_compression: explicit_arg(matches, "output_compression")?
+                .unwrap_or(base.output_compression),
+            min_population: explicit_arg(matches, "min_population")?.or(base.min_population),
+            require_properties: match matches.values_of("require_property") {
+                Some(properties) => properties.map(|p| p.to_string()).collect(),
+                None => base.require_properties,
+            },
+            countries: match matches.values_of("country") {
+                Some(countries) => countries.map(|c| c.to_string()).collect(),
+                None => base.countries,
+            },
+            classes: match matches.values_of("class") {
+                Some(classes) => classes.map(|c| c.to_string()).collect(),
+                None => base.classes,
+            },
+            only_country: explicit_arg(matches, "only_country")?.or(base.only_country),
+            update: base.update,
+        })
+    }
+
+    pub fn extracts(&self, entity_type: EntityType) -> bool {
+        self.entity_types.contains(&entity_type)
+    }
+}
+
+/// Date of a Wikidata dump, like 20240101.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct DumpDate(String);
+
+impl DumpDate {
+    pub fn url(&self) -> String {
+        http::dated_dump_url(&self.0)
+    }
+
+    pub fn incr_url(&self) -> String {
+        http::incr_dump_url(&self.0)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for DumpDate {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() == 8 && s.bytes().all(|b| b.is_ascii_digit()) {
+            Ok(DumpDate(s.to_string()))
+        } else {
+            Err(format!("invalid dump date {:?} (expected YYYYMMDD)", s))
+        }
+    }
+}
+
+impl TryFrom<String> for DumpDate {
+    type Error = String;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Skips entities until one with the given ID (or a numerically greater one) has been seen.
+///
+/// The dump is only roughly ordered by ID and lines are handled in parallel, so a few entities
+/// around the resume point may be processed again.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct ResumeFromId {
+    id: u64,
+    reached: Arc<AtomicBool>,
+}
+
+impl ResumeFromId {
+    /// Returns true if the entity comes before the resume point.
+    pub fn skips(&self, entity_id: &str) -> bool {
+        if self.reached.load(Ordering::Relaxed) {
+            return false;
+        }
+        match numeric_id(entity_id) {
+            Some(id) if id >= self.id => {
+                info!("Reached {}; resuming", entity_id);
+                self.reached.store(true, Ordering::Relaxed);
+                false
+            }
+            _ => true,
+        }
+    }
+}
+
+impl FromStr for ResumeFromId {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match numeric_id(s) {
+            Some(id) => Ok(ResumeFromId {
+                id,
+                reached: Arc::new(AtomicBool::new(false)),
+            }),
+            None => Err(format!("invalid entity ID {:?}", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for ResumeFromId {
+    type Error = String;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Parses the number in an entity ID like Q12345.
+fn numeric_id(id: &str) -> Option<u64> {
+    id.strip_prefix('Q')?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fix_out_of_range_coordinates() {
+        // latitude out of range, but in range as a longitude
+        let (lat, lon) = (120., 45.);
+        assert_eq!(CoordValidation::Off.fix(lat, lon), Some((120., 45.)));
+        assert_eq!(CoordValidation::Skip.fix(lat, lon), None);
+        assert_eq!(CoordValidation::Clamp.fix(lat, lon), Some((90., 45.)));
+        assert_eq!(CoordValidation::Swap.fix(lat, lon), Some((45., 120.)));
+
+        // out of range either way
+        let (lat, lon) = (-100., 200.);
+        assert_eq!(CoordValidation::Off.fix(lat, lon), Some((-100., 200.)));
+        assert_eq!(CoordValidation::Skip.fix(lat, lon), None);
+        assert_eq!(CoordValidation::Clamp.fix(lat, lon), Some((-90., 180.)));
+        assert_eq!(CoordValidation::Swap.fix(lat, lon), None);
+    }
+
+    #[test]
+    fn unproject_web_mercator() {
+        let crs = CoordCrs::WebMercator;
+        for &(lat, lon) in &[
+            (0., 0.),
+            (52.516_667, 13.383_333),
+            (-33.9, -70.6),
+            (85., 180.),
+        ] {
+            let (y, x) = crs.project(lat, lon);
+            let (new_lat, new_lon) = crs.unproject(y, x);
+            assert!((new_lat - lat).abs() < 1e-9, "{} != {}", new_lat, lat);
+            assert!((new_lon - lon).abs() < 1e-9, "{} != {}", new_lon, lon);
+        }
+        let (lat, _) = crs.unproject(crs.project(90., 0.).0, 0.);
+        assert!((lat - CoordCrs::MAX_MERCATOR_LAT).abs() < 1e-9);
+        assert_eq!(CoordCrs::Wgs84.unproject(52.5, 13.4), (52.5, 13.4));
+    }
+
+    fn options_from(args: &[&str]) -> Result<Options, OptionsError> {
+        let app = clap::App::new("geo-db").args(&[
+            clap::Arg::with_name("threads")
+                .long("threads")
+                .takes_value(true),
+            clap::Arg::with_name("db").long("db").takes_value(true),
+            clap::Arg::with_name("entity_types")
+                .long("entity-types")
+                .takes_value(true)
+                .multiple(true)
+                .use_delimiter(true),
+        ]);
+        let matches = app.get_matches_from(std::iter::once("geo-db").chain(args.iter().copied()));
+        Options::from_matches(&matches, Options::default())
+    }
+
+    #[test]
+    fn report_invalid_arguments() {
+        let options = options_from(&["--threads", "4", "--entity-types", "country"]).unwrap();
+        assert_eq!(options.threads, Some(4));
+        assert_eq!(options.entity_types.len(), 1);
+        assert_eq!(options_from(&[]).unwrap().threads, None);
+
+        assert!(matches!(
+            options_from(&["--threads", "four"]),
+            Err(OptionsError::InvalidValue { name, value, .. }) if name == "threads" && value == "four"
+        ));
+        assert!(matches!(
+            options_from(&["--entity-types", "country,city"]),
+            Err(OptionsError::InvalidValue { value, .. }) if value == "city"
+        ));
+        assert!(matches!(
+            options_from(&["--db", "mysql://localhost/geo"]),
+            Err(OptionsError::UnsupportedUrl(_))
+        ));
+    }
+
+    #[test]
+    fn coordinates_in_range() {
+        assert!(CoordValidation::in_range(90., -180.));
+        assert!(CoordValidation::in_range(-90., 180.));
+        assert!(!CoordValidation::in_range(90.5, 0.));
+        assert!(!CoordValidation::in_range(0., -180.5));
+        assert!(!CoordValidation::in_range(f64::NAN, 0.));
+    }
+}
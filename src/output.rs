@@ -0,0 +1,149 @@
+use crate::database::{DataEntry, DataSink};
+use crossbeam::channel::Receiver;
+use flate2::write::GzEncoder;
+use serde::Deserialize;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::str::FromStr;
+
+/// Compression applied to file outputs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputCompression {
+    None,
+    Gz,
+    Zst,
+}
+
+impl OutputCompression {
+    /// File extension appended to output paths for this compression.
+    pub fn extension(&self) -> Option<&'static str> {
+        match self {
+            OutputCompression::None => None,
+            OutputCompression::Gz => Some("gz"),
+            OutputCompression::Zst => Some("zst"),
+        }
+    }
+}
+
+impl FromStr for OutputCompression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(OutputCompression::None),
+            "gz" => Ok(OutputCompression::Gz),
+            "zst" => Ok(OutputCompression::Zst),
+            s => Err(format!("unknown output compression {:?}", s)),
+        }
+    }
+}
+
+/// A file writer that (optionally) compresses everything written to it.
+///
+/// Compressed streams must be finalized with [`OutputWriter::finish`], otherwise the file will
+/// be truncated.
+pub enum OutputWriter {
+    Plain(BufWriter<File>),
+    Gz(GzEncoder<BufWriter<File>>),
+    Zst(zstd::Encoder<'static, BufWriter<File>>),
+}
+
+impl OutputWriter {
+    /// Creates the output file, appending the compression extension to the path if needed.
+    pub fn create(path: &str, compression: OutputCompression) -> io::Result<Self> {
+        let path = match compression.extension() {
+            Some(ext) if !path.ends_with(&format!(".{}", ext)) => format!("{}.{}", path, ext),
+            _ => path.to_string(),
+        };
+        debug!("Creating output file {}", path);
+        let file = BufWriter::new(File::create(path)?);
+
+        Ok(match compression {
+            OutputCompression::None => OutputWriter::Plain(file),
+            OutputCompression::Gz => {
+                OutputWriter::Gz(GzEncoder::new(file, flate2::Compression::default()))
+            }
+            OutputCompression::Zst => OutputWriter::Zst(zstd::Encoder::new(file, 0)?),
+        })
+    }
+
+    /// Writes the compressed stream trailer (if any) and flushes the file.
+    pub fn finish(self) -> io::Result<()> {
+        let mut file = match self {
+            OutputWriter::Plain(file) => file,
+            OutputWriter::Gz(encoder) => encoder.finish()?,
+            OutputWriter::Zst(encoder) => encoder.finish()?,
+        };
+        file.flush()
+    }
+}
+
+impl Write for OutputWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            OutputWriter::Plain(file) => file.write(buf),
+            OutputWriter::Gz(encoder) => encoder.write(buf),
+            OutputWriter::Zst(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            OutputWriter::Plain(file) => file.flush(),
+            OutputWriter::Gz(encoder) => encoder.flush(),
+            OutputWriter::Zst(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Writes every received line to the output file as newline-delimited text until the channel
+/// closes.
+pub fn line_writer(
+    path: &str,
+    compression: OutputCompression,
+    recv: Receiver<String>,
+) -> io::Result<()> {
+    let mut out = OutputWriter::create(path, compression)?;
+    for line in recv {
+        out.write_all(line.as_bytes())?;
+        out.write_all(b"\n")?;
+    }
+    out.finish()
+}
+
+/// Newline-delimited JSON output with one entry per line.
+///
+/// This is the simplest possible [`DataSink`] and can serve as a starting point for others.
+pub struct NdjsonSink {
+    out: OutputWriter,
+}
+
+impl NdjsonSink {
+    pub fn new(out: OutputWriter) -> Self {
+        NdjsonSink { out }
+    }
+}
+
+impl DataSink for NdjsonSink {
+    type Error = io::Error;
+
+    fn write(&mut self, entry: DataEntry) -> io::Result<()> {
+        // only entity entries, so that exports can be diffed
+        if let DataEntry::Line { .. } | DataEntry::Checkpoint { .. } | DataEntry::Metadata { .. } =
+            entry
+        {
+            return Ok(());
+        }
+        serde_json::to_writer(&mut self.out, &entry)?;
+        self.out.write_all(b"\n")
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    fn finish(self) -> io::Result<()> {
+        self.out.finish()
+    }
+}
@@ -0,0 +1,402 @@
+//! PostgreSQL output (`--db postgres://…`), built with `--features postgres`.
+//!
+//! Entries are buffered per table and loaded with `COPY` when a checkpoint is committed. `COPY`
+//! can't skip rows that are already stored, so the rows are copied into temporary tables first
+//! and inserted from there with `on conflict do nothing`, which skips duplicates like the SQLite
+//! output does for parents or aliases.
+
+use crate::database::{DataEntry, DataSink, ENTITY_TABLES};
+use crate::intern::Symbol;
+use postgres::{Client, NoTls};
+use std::collections::HashSet;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum PgError {
+    #[error("PostgreSQL error: {0}")]
+    Postgres(#[from] postgres::Error),
+    #[error("failed to copy rows: {0}")]
+    Copy(#[from] io::Error),
+}
+
+/// Tables that entries are copied into, and the columns in the order the rows are written in.
+/// The first column is always the entity ID.
+const COPY_TABLES: [(&str, &str); 17] = [
+    ("countries", "id, iso, conflicting, sovereign, revision"),
+    ("object_languages", "id, lang_id, lang_index, kind"),
+    ("languages", "id, code, revision"),
+    (
+        "territorial_entities",
+        "id, is_2nd, iso, lat, lon, coord_precision, revision",
+    ),
+    ("territorial_entities_parents", "id, parent"),
+    ("admin_levels", "id, level"),
+    (
+        "cities",
+        "id, population, population_time, population_precision, lat, lon, revision",
+    ),
+    ("cities_countries", "city, country, priority"),
+    ("object_labels", "id, lang, label, native_order, source"),
+    ("missing_p17", "id"),
+    ("sister_cities", "id, other_id"),
+    ("statement_coords", "id, property, lat, lon"),
+    ("replaces", "id, predecessor_id"),
+    ("part_of", "id, parent_id"),
+    ("aliases", "id, lang, alias"),
+    ("data_quality", "id, property, reason"),
+    ("borders_with", "id, neighbor_id"),
+];
+
+/// Buffered rows after which a checkpoint is committed.
+const ROWS_PER_COMMIT: usize = 50_000;
+/// Time after which a checkpoint is committed, even with fewer rows (e.g. when following edits).
+const COMMIT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A value in the text format of `COPY`.
+trait CopyValue {
+    fn write_to(&self, out: &mut String);
+}
+
+impl CopyValue for str {
+    fn write_to(&self, out: &mut String) {
+        for c in self.chars() {
+            match c {
+                '\\' => out.push_str("\\\\"),
+                '\t' => out.push_str("\\t"),
+                '\n' => out.push_str("\\n"),
+                '\r' => out.push_str("\\r"),
+                c => out.push(c),
+            }
+        }
+    }
+}
+
+impl CopyValue for String {
+    fn write_to(&self, out: &mut String) {
+        self.as_str().write_to(out);
+    }
+}
+
+impl CopyValue for Symbol {
+    fn write_to(&self, out: &mut String) {
+        self.as_str().write_to(out);
+    }
+}
+
+impl CopyValue for bool {
+    fn write_to(&self, out: &mut String) {
+        out.push(if *self { 't' } else { 'f' });
+    }
+}
+
+macro_rules! display_copy_value {
+    ($($ty:ty),*) => {
+        $(impl CopyValue for $ty {
+            fn write_to(&self, out: &mut String) {
+                let _ = write!(out, "{}", self);
+            }
+        })*
+    };
+}
+
+display_copy_value!(u8, u32, u64, f64);
+
+impl<T: CopyValue> CopyValue for Option<T> {
+    fn write_to(&self, out: &mut String) {
+        match self {
+            Some(value) => value.write_to(out),
+            None => out.push_str("\\N"),
+        }
+    }
+}
+
+/// A row that is being added to the rows of a table.
+struct Row<'a>(&'a mut String);
+
+impl Row<'_> {
+    fn field<T: CopyValue + ?Sized>(self, value: &T) -> Self {
+        self.0.push('\t');
+        value.write_to(self.0);
+        self
+    }
+
+    fn end(self) {
+        self.0.push('\n');
+    }
+}
+
+/// PostgreSQL output. Entries are committed at checkpoints, once there are enough of them.
+pub struct PgSink {
+    client: Client,
+    /// Rows in the text format of `COPY`, per table of [`COPY_TABLES`].
+    rows: [String; 17],
+    row_count: usize,
+    /// Entities with buffered rows.
+    buffered: HashSet<Symbol>,
+    /// Entities whose stored entries are removed before the buffered rows are copied.
+    removed: Vec<Symbol>,
+    checkpoint: Option<(u64, Option<u64>)>,
+    metadata: Vec<(String, String)>,
+    last_commit: Instant,
+}
+
+impl PgSink {
+    /// Connects to the database and creates the tables, which must not exist yet.
+    pub fn create(url: &str) -> Result<Self, PgError> {
+        let mut client = Client::connect(url, NoTls)?;
+        debug!("Setting up database");
+        let mut tx = client.transaction()?;
+        tx.batch_execute(include_str!("postgres_setup.sql"))?;
+        tx.commit()?;
+        debug!("Database set up");
+
+        Self::new(client)
+    }
+
+    /// Connects to a database built earlier to update it.
+    pub fn open_existing(url: &str) -> Result<Self, PgError> {
+        Self::new(Client::connect(url, NoTls)?)
+    }
+
+    fn new(mut client: Client) -> Result<Self, PgError> {
+        for (table, _) in &COPY_TABLES {
+            client.batch_execute(&format!(
+                "create temporary table geodb_copy_{0} (like {0}) on commit delete rows",
+                table
+            ))?;
+        }
+
+        Ok(PgSink {
+            client,
+            rows: Default::default(),
+            row_count: 0,
+            buffered: HashSet::new(),
+            removed: Vec::new(),
+            checkpoint: None,
+            metadata: Vec::new(),
+            last_commit: Instant::now(),
+        })
+    }
+
+    /// Starts a row of a table of [`COPY_TABLES`] with the ID of its entity.
+    fn row(&mut self, table: &str, id: Symbol) -> Row<'_> {
+        let index = COPY_TABLES
+            .iter()
+            .position(|(name, _)| *name == table)
+            .expect("not a copied table");
+        self.buffered.insert(id);
+        self.row_count += 1;
+        let rows = &mut self.rows[index];
+        id.write_to(rows);
+        Row(rows)
+    }
+}
+
+impl DataSink for PgSink {
+    type Error = PgError;
+
+    fn write(&mut self, entry: DataEntry) -> Result<(), PgError> {
+        match entry {
+            DataEntry::TerritorialEntity {
+                id,
+                is_2nd,
+                iso,
+                lat,
+                lon,
+                coord_precision,
+                revision,
+            } => self
+                .row("territorial_entities", id)
+                .field(&is_2nd)
+                .field(&iso)
+                .field(&lat)
+                .field(&lon)
+                .field(&coord_precision)
+                .field(&revision)
+                .end(),
+            DataEntry::TerritorialEntityParent { id, parent } => self
+                .row("territorial_entities_parents", id)
+                .field(&parent)
+                .end(),
+            DataEntry::ObjectLanguage {
+                id,
+                lang_id,
+                index,
+                kind,
+            } => self
+                .row("object_languages", id)
+                .field(&lang_id)
+                .field(&index)
+                .field(kind.as_str())
+                .end(),
+            DataEntry::Language { id, code, revision } => self
+                .row("languages", id)
+                .field(&code)
+                .field(&revision)
+                .end(),
+            DataEntry::City {
+                id,
+                population,
+                population_time,
+                population_precision,
+                lat,
+                lon,
+                revision,
+            } => self
+                .row("cities", id)
+                .field(&population)
+                .field(&population_time)
+                .field(&population_precision)
+                .field(&lat)
+                .field(&lon)
+                .field(&revision)
+                .end(),
+            DataEntry::CityCountry {
+                id,
+                country,
+                priority,
+            } => self
+                .row("cities_countries", id)
+                .field(&country)
+                .field(&priority)
+                .end(),
+            DataEntry::ObjectLabel {
+                id,
+                lang,
+                label,
+                native_order,
+                source,
+            } => self
+                .row("object_labels", id)
+                .field(&lang)
+                .field(&label)
+                .field(&native_order)
+                .field(source.as_str())
+                .end(),
+            DataEntry::Country {
+                id,
+                iso,
+                conflicting,
+                sovereign,
+                revision,
+            } => self
+                .row("countries", id)
+                .field(&iso)
+                .field(&conflicting)
+                .field(&sovereign)
+                .field(&revision)
+                .end(),
+            DataEntry::MissingP17 { id } => self.row("missing_p17", id).end(),
+            DataEntry::AdminLevel { id, level } => self.row("admin_levels", id).field(&level).end(),
+            DataEntry::StatementCoord {
+                id,
+                property,
+                lat,
+                lon,
+            } => self
+                .row("statement_coords", id)
+                .field(&property)
+                .field(&lat)
+                .field(&lon)
+                .end(),
+            DataEntry::Replaces { id, predecessor_id } => {
+                self.row("replaces", id).field(&predecessor_id).end()
+            }
+            DataEntry::PartOf { id, parent_id } => self.row("part_of", id).field(&parent_id).end(),
+            DataEntry::Alias { id, lang, alias } => {
+                self.row("aliases", id).field(&lang).field(&alias).end()
+            }
+            DataEntry::QualityEvent {
+                id,
+                property,
+                reason,
+            } => self
+                .row("data_quality", id)
+                .field(&property)
+                .field(&reason)
+                .end(),
+            DataEntry::BordersWith { id, neighbor_id } => {
+                self.row("borders_with", id).field(&neighbor_id).end()
+            }
+            DataEntry::SisterCity { id, other_id } => {
+                self.row("sister_cities", id).field(&other_id).end()
+            }
+            DataEntry::RemoveEntity { id } => {
+                // entries are removed before the buffered rows are copied, so rows of the entity
+                // from before the removal have to be copied first
+                if self.buffered.contains(&id) {
+                    self.flush()?;
+                }
+                self.removed.push(id);
+            }
+            // only SQLite outputs keep a build journal
+            DataEntry::Line { .. } => (),
+            DataEntry::Checkpoint {
+                line_number,
+                offset,
+            } => self.checkpoint = Some((line_number, offset)),
+            DataEntry::Metadata { key, value } => self.metadata.push((key, value)),
+        }
+        Ok(())
+    }
+
+    fn checkpoint(&mut self) -> Result<bool, PgError> {
+        if self.row_count < ROWS_PER_COMMIT && self.last_commit.elapsed() < COMMIT_INTERVAL {
+            return Ok(false);
+        }
+        self.flush()?;
+        Ok(true)
+    }
+
+    fn flush(&mut self) -> Result<(), PgError> {
+        let mut tx = self.client.transaction()?;
+        if !self.removed.is_empty() {
+            let ids: Vec<String> = self
+                .removed
+                .drain(..)
+                .map(|id| id.as_str().into_owned())
+                .collect();
+            for (table, column) in &ENTITY_TABLES {
+                tx.execute(
+                    format!("delete from {} where {} = any($1)", table, column).as_str(),
+                    &[&ids],
+                )?;
+            }
+        }
+        for ((table, columns), rows) in COPY_TABLES.iter().zip(&mut self.rows) {
+            if rows.is_empty() {
+                continue;
+            }
+            let mut copy =
+                tx.copy_in(format!("copy geodb_copy_{} ({}) from stdin", table, columns).as_str())?;
+            copy.write_all(rows.as_bytes())?;
+            copy.finish()?;
+            rows.clear();
+            tx.batch_execute(&format!(
+                "insert into {0} select * from geodb_copy_{0} on conflict do nothing",
+                table
+            ))?;
+        }
+        if let Some((line_number, offset)) = self.checkpoint.take() {
+            tx.execute(
+                "insert into build_state (id, line_number, byte_offset) values (0, $1, $2) on conflict (id) do update set line_number = excluded.line_number, byte_offset = excluded.byte_offset",
+                &[&(line_number as i64), &offset.map(|offset| offset as i64)],
+            )?;
+        }
+        for (key, value) in self.metadata.drain(..) {
+            tx.execute(
+                "insert into metadata (key, value) values ($1, $2) on conflict (key) do update set value = excluded.value",
+                &[&key, &value],
+            )?;
+        }
+        tx.commit()?;
+
+        self.row_count = 0;
+        self.buffered.clear();
+        self.last_commit = Instant::now();
+        Ok(())
+    }
+}
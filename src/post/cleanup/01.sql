@@ -1,2 +1,19 @@
+-- the direct parents of the cities and their admin levels (see parent_levels.sql) are the only
+-- part of the hierarchy that is kept
+CREATE TABLE cities_parents (
+  "id"	string NOT NULL,
+  "parent"	string NOT NULL,
+  "parent_level"	integer,
+  PRIMARY KEY("id","parent")
+);
+CREATE INDEX cities_parents_parent_index ON cities_parents (parent);
+
+INSERT INTO cities_parents
+SELECT territorial_entities_parents.id, parent, parent_level
+FROM territorial_entities_parents
+JOIN cities
+ON cities.id = territorial_entities_parents.id;
+
 DROP TABLE territorial_entities;
 DROP TABLE territorial_entities_parents;
+DROP TABLE admin_levels;
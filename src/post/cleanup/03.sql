@@ -2,15 +2,21 @@ CREATE TABLE object_labels_tmp (
 	"id"	string NOT NULL,
 	"lang"	string NOT NULL,
 	"label"	string NOT NULL,
+	"source"	string NOT NULL,
 	PRIMARY KEY("id","lang")
 );
 CREATE INDEX object_labels_new_lang_index ON object_labels_tmp (lang);
 CREATE INDEX object_labels_new_label_index ON object_labels_tmp (label);
 
+-- one label per language is kept: the entity's label over its native names (the first of
+-- them), and those over short names
 INSERT OR IGNORE
 INTO object_labels_tmp
-SELECT id, lang, label
-FROM object_labels;
+SELECT id, lang, label, source
+FROM object_labels
+ORDER BY
+  CASE source WHEN 'label' THEN 0 WHEN 'native_name' THEN 1 ELSE 2 END,
+  native_order;
 
 DROP TABLE object_labels;
 ALTER TABLE object_labels_tmp RENAME TO object_labels;
@@ -1,2 +1,3 @@
 DROP TABLE languages;
 DROP TABLE missing_p17;
+DROP TABLE part_of;
@@ -1,8 +1,10 @@
+-- the country column is replaced with the ISO code, so countries without one can't be used
 DELETE FROM cities
 WHERE NOT EXISTS(
   SELECT 1
   FROM countries
   WHERE countries.id = cities.country
+  AND countries.iso IS NOT NULL
 );
 
 UPDATE cities
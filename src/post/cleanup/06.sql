@@ -2,3 +2,10 @@ DELETE FROM cities
 WHERE
   native_label IS NULL
   AND eo_label IS NULL;
+
+DELETE FROM cities_parents
+WHERE NOT EXISTS(
+  SELECT 1
+  FROM cities
+  WHERE cities.id = cities_parents.id
+);
@@ -1,7 +1,12 @@
 use rusqlite::{params, Connection};
 use std::time::Instant;
 
-pub fn run(db_file: &str, do_post: bool, do_cleanup: bool) -> rusqlite::Result<()> {
+pub fn run(
+    db_file: &str,
+    do_post: bool,
+    do_cleanup: bool,
+    emit_place_table: bool,
+) -> rusqlite::Result<()> {
     info!(
         "Opening database at {} (SQLite {})",
         db_file,
@@ -111,9 +116,18 @@ pub fn run(db_file: &str, do_post: bool, do_cleanup: bool) -> rusqlite::Result<(
             Ok(())
         }
 
+        info!("Picking object languages");
+        conn.execute_batch(include_str!("object_languages.sql"))?;
+
+        info!("Resolving countries through P361");
+        conn.execute_batch(include_str!("part_of_countries.sql"))?;
+
         info!("Picking most relevant countries");
         conn.execute_batch(include_str!("city_countries.sql"))?;
 
+        info!("Resolving parent admin levels");
+        conn.execute_batch(include_str!("parent_levels.sql"))?;
+
         info!("Finding subdivisions");
         conn.execute_batch(include_str!("find_subdivision.sql"))?;
 
@@ -153,13 +167,18 @@ pub fn run(db_file: &str, do_post: bool, do_cleanup: bool) -> rusqlite::Result<(
 
         info!("Updating cities by subdivision ISO");
         conn.execute_batch(include_str!("subdivision_iso.sql"))?;
+
+        if emit_place_table {
+            info!("Building place table");
+            conn.execute_batch(include_str!("place_table.sql"))?;
+        }
     }
 
     if do_cleanup {
         const SCRIPTS: &[(&str, &str)] = &[
             (
                 include_str!("cleanup/01.sql"),
-                "deleting territorial entities",
+                "keeping the parents of cities and deleting territorial entities",
             ),
             (
                 include_str!("cleanup/02.sql"),
@@ -196,3 +215,134 @@ pub fn run(db_file: &str, do_post: bool, do_cleanup: bool) -> rusqlite::Result<(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cleanup_keeps_labels_over_native_names() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(include_str!("../setup.sql")).unwrap();
+        conn.execute_batch(
+            "insert into object_labels (id, lang, native_order, label, source) values
+                ('Q64', 'de', 1, 'Berlin (Stadt)', 'native_name'),
+                ('Q64', 'de', 0, 'Land Berlin', 'native_name'),
+                ('Q64', 'de', null, 'Berlin', 'label'),
+                ('Q64', 'en', null, 'BER', 'short_name_fallback'),
+                ('Q64', 'fr', 1, 'Berlin (ville)', 'native_name'),
+                ('Q64', 'fr', 0, 'Berlin', 'native_name');",
+        )
+        .unwrap();
+        conn.execute_batch(include_str!("cleanup/03.sql")).unwrap();
+
+        let mut stmt = conn
+            .prepare("select lang, label, source from object_labels order by lang")
+            .unwrap();
+        let labels = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<(String, String, String)>>>()
+            .unwrap();
+        let expected = [
+            ("de", "Berlin", "label"),
+            ("en", "BER", "short_name_fallback"),
+            ("fr", "Berlin", "native_name"),
+        ];
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|&(lang, label, source)| (lang.into(), label.into(), source.into()))
+            .collect();
+        assert_eq!(labels, expected);
+    }
+
+    #[test]
+    fn cleanup_keeps_parent_levels_of_cities() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_file = dir.path().join("out.db");
+        let db_file = db_file.to_str().unwrap();
+        let conn = Connection::open(db_file).unwrap();
+        conn.execute_batch(include_str!("../setup.sql")).unwrap();
+        // Q2 wasn't extracted as a territorial entity, so its level is unknown
+        conn.execute_batch(
+            "insert into countries (id, iso, conflicting, sovereign) values ('Q183', 'DE', 0, 1);
+            insert into territorial_entities (id, is_2nd) values ('Q1208', 0);
+            insert into admin_levels (id, level) values ('Q1208', 1);
+            insert into territorial_entities_parents (id, parent) values
+                ('Q1208', 'Q183'),
+                ('Q64', 'Q1208'),
+                ('Q64', 'Q2');
+            insert into cities (id, country) values ('Q64', 'Q183');
+            insert into cities_countries (city, priority, country) values ('Q64', 0, 'Q183');
+            insert into object_labels (id, lang, native_order, label, source)
+                values ('Q64', 'de', 0, 'Berlin', 'native_name');",
+        )
+        .unwrap();
+        drop(conn);
+        run(db_file, true, true, false).unwrap();
+
+        let conn = Connection::open(db_file).unwrap();
+        let mut stmt = conn
+            .prepare("select id, parent, parent_level from cities_parents order by parent")
+            .unwrap();
+        let parents = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<(String, String, Option<u8>)>>>()
+            .unwrap();
+        assert_eq!(
+            parents,
+            [
+                ("Q64".into(), "Q1208".into(), Some(1)),
+                ("Q64".into(), "Q2".into(), None)
+            ]
+        );
+    }
+
+    #[test]
+    fn languages_used_only_count_without_official_languages() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_file = dir.path().join("out.db");
+        let db_file = db_file.to_str().unwrap();
+        let conn = Connection::open(db_file).unwrap();
+        conn.execute_batch(include_str!("../setup.sql")).unwrap();
+        conn.execute_batch(
+            "insert into countries (id, iso, conflicting, sovereign) values ('Q183', 'DE', 0, 1);
+            insert into languages (id, code) values ('Q188', 'de'), ('Q1860', 'en');
+            insert into object_languages (id, lang_id, lang_index, kind) values
+                ('Q64', 'Q188', 0, 'official'),
+                ('Q64', 'Q1860', 0, 'used');
+            insert into territorial_entities_parents (id, parent) values ('Q64', 'Q183');
+            insert into cities (id, country) values ('Q64', 'Q183');
+            insert into cities_countries (city, priority, country) values ('Q64', 0, 'Q183');
+            insert into object_labels (id, lang, native_order, label, source) values
+                ('Q64', 'de', null, 'Berlin', 'label'),
+                ('Q64', 'en', null, 'Berlin (en)', 'label');",
+        )
+        .unwrap();
+        drop(conn);
+        run(db_file, true, true, false).unwrap();
+
+        let conn = Connection::open(db_file).unwrap();
+        let native_label: String = conn
+            .query_row("select native_label from cities", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(native_label, "Berlin");
+        // the cleanup keeps both kinds of languages
+        let mut stmt = conn
+            .prepare("select id, lang, kind from cities_languages order by lang")
+            .unwrap();
+        let languages = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<(String, String, String)>>>()
+            .unwrap();
+        assert_eq!(
+            languages,
+            [
+                ("Q64".into(), "de".into(), "official".into()),
+                ("Q64".into(), "en".into(), "used".into())
+            ]
+        );
+    }
+}
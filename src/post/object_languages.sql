@@ -0,0 +1,13 @@
+-- Languages used (P2936) only count for objects that have no official language (P37). Both kinds
+-- are kept in object_languages, and the post-processing reads the languages that count from here.
+DROP VIEW IF EXISTS preferred_object_languages;
+CREATE VIEW preferred_object_languages AS
+SELECT *
+FROM object_languages
+WHERE kind = 'official'
+OR NOT EXISTS(
+	SELECT 1
+	FROM object_languages official
+	WHERE official.id = object_languages.id
+	AND official.kind = 'official'
+);
@@ -0,0 +1,8 @@
+-- Label hierarchy edges with the admin level of the parent.
+-- Parents that were not extracted as territorial entities keep a NULL (unknown) level.
+ALTER TABLE territorial_entities_parents ADD COLUMN parent_level integer;
+
+UPDATE territorial_entities_parents
+SET parent_level = admin_levels.level
+FROM admin_levels
+WHERE admin_levels.id = territorial_entities_parents.parent;
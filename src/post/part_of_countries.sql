@@ -0,0 +1,33 @@
+-- Cities without an active P17 country inherit the countries of their nearest P361 (part of)
+-- ancestor that is a country or has countries. part_of is only filled with --part-of.
+WITH RECURSIVE ancestors(city, step, id) AS (
+	SELECT id, 0, id
+	FROM cities
+	WHERE NOT EXISTS(
+		SELECT 1
+		FROM cities_countries
+		WHERE cities_countries.city = cities.id
+	)
+	UNION ALL
+	SELECT ancestors.city, step + 1, part_of.parent_id
+	FROM part_of, ancestors
+	WHERE part_of.id = ancestors.id
+	AND step < 10
+),
+candidates(city, step, priority, country) AS (
+	SELECT ancestors.city, step, 0, ancestors.id
+	FROM ancestors
+	INNER JOIN countries ON countries.id = ancestors.id
+	UNION ALL
+	SELECT ancestors.city, step, cities_countries.priority, cities_countries.country
+	FROM ancestors
+	INNER JOIN cities_countries ON cities_countries.city = ancestors.id
+)
+INSERT OR IGNORE INTO cities_countries (city, priority, country)
+SELECT city, 2000 + priority, country
+FROM candidates
+WHERE step = (
+	SELECT MIN(step)
+	FROM candidates nearest
+	WHERE nearest.city = candidates.city
+);
@@ -22,11 +22,11 @@ FROM (
       object_labels.label
     FROM parents
 
-    LEFT JOIN object_languages
-    ON parents.id = object_languages.id
+    LEFT JOIN preferred_object_languages
+    ON parents.id = preferred_object_languages.id
 
     INNER JOIN languages
-    ON object_languages.lang_id = languages.id
+    ON preferred_object_languages.lang_id = languages.id
 
     INNER JOIN object_labels ON
       object_labels.id = ?1
@@ -35,7 +35,7 @@ FROM (
         OR object_labels.lang LIKE iif(instr(languages.code,"-") = 0, languages.code, substring(languages.code, 0, instr(languages.code,"-"))) || "-%"
       )
 
-    GROUP BY step, parents.id, object_languages.lang_id
+    GROUP BY step, parents.id, preferred_object_languages.lang_id
     ORDER BY step ASC, lang_index ASC
     LIMIT 2
   )
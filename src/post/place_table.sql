@@ -0,0 +1,64 @@
+-- One denormalized row per city or territorial entity
+CREATE TABLE places (
+	id string NOT NULL PRIMARY KEY,
+	label string,
+	country_iso char(2),
+	lat decimal(5, 3),
+	lon decimal(6, 3),
+	population integer,
+	admin_level integer
+);
+CREATE INDEX places_country_iso_index ON places (country_iso);
+
+INSERT OR IGNORE
+INTO places (id, country_iso, lat, lon, population)
+SELECT cities.id, countries.iso, cities.lat, cities.lon, cities.population
+FROM cities
+LEFT JOIN countries
+ON countries.id = cities.country;
+
+-- Territorial entities use their own ISO code if they are a country, or that of a direct parent
+INSERT OR IGNORE
+INTO places (id, country_iso, lat, lon)
+SELECT
+	territorial_entities.id,
+	COALESCE(countries.iso, parent_countries.iso),
+	territorial_entities.lat,
+	territorial_entities.lon
+FROM territorial_entities
+LEFT JOIN countries
+ON countries.id = territorial_entities.id
+LEFT JOIN (
+	SELECT territorial_entities_parents.id, MIN(countries.iso) AS iso
+	FROM territorial_entities_parents
+	INNER JOIN countries
+	ON countries.id = territorial_entities_parents.parent
+	GROUP BY territorial_entities_parents.id
+) AS parent_countries
+ON parent_countries.id = territorial_entities.id;
+
+UPDATE places
+SET admin_level = admin_levels.level
+FROM admin_levels
+WHERE admin_levels.id = places.id;
+
+-- English label, falling back to the same languages as the Esperanto labels
+UPDATE places
+SET label = (
+	SELECT label
+	FROM object_labels
+	WHERE
+		object_labels.id = places.id
+		AND object_labels.lang IN ('en','eo','fr','es','de','nl')
+	ORDER BY
+		CASE object_labels.lang
+			WHEN 'en' THEN 0
+			WHEN 'eo' THEN 1
+			WHEN 'fr' THEN 2
+			WHEN 'es' THEN 3
+			WHEN 'de' THEN 4
+			ELSE 5
+		END,
+		object_labels.native_order IS NOT NULL
+	LIMIT 1
+);
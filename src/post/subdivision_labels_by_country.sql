@@ -22,7 +22,7 @@ FROM (
   INNER JOIN countries
   ON countries.id = cities.country
 
-  INNER JOIN object_languages ol1
+  INNER JOIN preferred_object_languages ol1
   ON
     ol1.id = countries.id
     AND ol1.lang_index = 0
@@ -30,7 +30,7 @@ FROM (
   INNER JOIN languages l1
   ON l1.id = ol1.lang_id
 
-  LEFT JOIN object_languages ol2
+  LEFT JOIN preferred_object_languages ol2
   ON
     ol2.id = countries.id
     AND ol2.lang_index = 1
@@ -0,0 +1,157 @@
+-- the tables of setup.sql, with PostgreSQL types.
+-- coordinates are double precision rather than decimal, which PostgreSQL would round.
+
+create table countries (
+    id text not null primary key,
+    iso char(2),
+    conflicting boolean not null,
+    sovereign boolean not null,
+    revision bigint
+);
+create index countries_iso_index on countries (iso);
+
+create table object_languages (
+    id text not null,
+    lang_id text not null,
+    lang_index integer not null,
+    kind text not null,
+    primary key (id, lang_id)
+);
+create index object_languages_lang_id_index on object_languages (lang_id);
+
+create table languages (
+    id text not null primary key,
+    code text not null,
+    revision bigint
+);
+create index languages_code_index on languages (code);
+
+create table territorial_entities (
+    id text not null primary key,
+    is_2nd boolean not null,
+    iso text,
+    lat double precision,
+    lon double precision,
+    coord_precision double precision,
+    revision bigint
+);
+create index territorial_entities_is_2nd on territorial_entities (is_2nd);
+
+create table territorial_entities_parents (
+    id text not null,
+    parent text not null,
+    primary key (id, parent)
+);
+create index territorial_entities_parents_parent_index on territorial_entities_parents (parent);
+
+create table admin_levels (
+    id text not null primary key,
+    level integer not null
+);
+
+create table cities (
+    id text not null primary key,
+    country text,
+    population bigint,
+    population_time text,
+    population_precision integer,
+    lat double precision,
+    lon double precision,
+    revision bigint
+);
+create index cities_country_index on cities (country);
+create index cities_population_index on cities (population);
+create index cities_lat_index on cities (lat);
+create index cities_lon_index on cities (lon);
+
+create table cities_countries (
+    city text not null,
+    priority integer not null,
+    country text not null,
+    primary key (city, priority, country)
+);
+create index cities_countries_priority_index on cities_countries (priority);
+create index cities_countries_country_index on cities_countries (country);
+create unique index cities_countries_city_country_index on cities_countries (city, country);
+create unique index cities_countries_city_priority_index on cities_countries (city, priority);
+
+-- native_order is null for most labels, which a primary key doesn't allow
+create table object_labels (
+    id text not null,
+    lang text not null,
+    native_order bigint,
+    label text not null,
+    source text not null,
+    unique (id, lang, native_order)
+);
+create index object_labels_label_index on object_labels (label);
+create index object_labels_lang_index on object_labels (lang);
+create index object_labels_native_order_index on object_labels (native_order);
+
+create table missing_p17 (id text not null primary key);
+
+create table sister_cities (
+    id text not null,
+    other_id text not null,
+    primary key (id, other_id)
+);
+create index sister_cities_other_id_index on sister_cities (other_id);
+
+create table statement_coords (
+    id text not null,
+    property text not null,
+    lat double precision not null,
+    lon double precision not null
+);
+create index statement_coords_id_index on statement_coords (id);
+
+create table replaces (
+    id text not null,
+    predecessor_id text not null,
+    primary key (id, predecessor_id)
+);
+create index replaces_predecessor_id_index on replaces (predecessor_id);
+
+create table part_of (
+    id text not null,
+    parent_id text not null,
+    primary key (id, parent_id)
+);
+create index part_of_parent_id_index on part_of (parent_id);
+
+create table aliases (
+    id text not null,
+    lang text not null,
+    alias text not null,
+    primary key (id, lang, alias)
+);
+create index aliases_alias_index on aliases (alias);
+
+create table data_quality (
+    id text not null,
+    property text not null,
+    reason text not null
+);
+create index data_quality_id_index on data_quality (id);
+create index data_quality_property_index on data_quality (property);
+
+create table borders_with (
+    id text not null,
+    neighbor_id text not null,
+    primary key (id, neighbor_id)
+);
+create index borders_with_neighbor_id_index on borders_with (neighbor_id);
+
+-- single row with the last input line whose entries have all been committed,
+-- and the offset in the decompressed dump after it
+create table build_state (
+    id integer not null primary key,
+    line_number bigint not null,
+    byte_offset bigint
+);
+
+-- information about the run, e.g. the dump date
+create table metadata (
+    key text not null primary key,
+    value text not null
+);
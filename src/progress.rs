@@ -0,0 +1,125 @@
+//! Progress bars of a run, shown on stderr if it's a terminal.
+
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressState, ProgressStyle};
+use std::fmt;
+use std::sync::Mutex;
+
+/// Bars that are currently shown, so that log lines can be printed above them.
+static SHOWN: Mutex<Option<MultiProgress>> = Mutex::new(None);
+
+/// Prints a log line, above the progress bars if they're shown.
+pub fn print_log_line(line: &fmt::Arguments) {
+    match &*SHOWN.lock().unwrap() {
+        Some(bars) => bars.suspend(|| println!("{}", line)),
+        None => println!("{}", line),
+    }
+}
+
+/// Counters of a run at one point in time.
+#[derive(Clone, Copy)]
+pub struct Counts {
+    /// Bytes of the input read so far (compressed, if the dump is).
+    pub downloaded: u64,
+    pub decompressed: u64,
+    pub lines: u64,
+    pub failed_lines: usize,
+    pub matched: u64,
+    pub tasks_in_flight: usize,
+    pub queued_batches: usize,
+    pub written: u64,
+    pub committed_line: u64,
+}
+
+/// One bar per stage of the run, from the download to the DB writer.
+pub struct Progress {
+    bars: MultiProgress,
+    download: ProgressBar,
+    decompressed: ProgressBar,
+    lines: ProgressBar,
+    matched: ProgressBar,
+    backlog: ProgressBar,
+    written: ProgressBar,
+    bottleneck: &'static str,
+}
+
+fn style(template: &str) -> ProgressStyle {
+    ProgressStyle::with_template(template)
+        .expect("invalid progress template")
+        .progress_chars("=> ")
+        // `per_sec` has four decimals
+        .with_key("rate", |state: &ProgressState, w: &mut dyn fmt::Write| {
+            let _ = write!(w, "{:.0}/s", state.per_sec());
+        })
+}
+
+impl Progress {
+    /// Shows the bars, unless stderr is not a terminal. `content_length` is the size of the input,
+    /// if it's known.
+    pub fn show(content_length: Option<u64>) -> Option<Self> {
+        let target = ProgressDrawTarget::stderr();
+        if target.is_hidden() {
+            return None;
+        }
+        let bars = MultiProgress::with_draw_target(target);
+
+        let stage = |name: &'static str, template: &str| {
+            let bar = bars.add(ProgressBar::new_spinner());
+            bar.set_style(style(template));
+            bar.set_prefix(name);
+            bar
+        };
+        let download = match content_length {
+            Some(length) => {
+                let bar = stage(
+                    "input",
+                    "{prefix:>12} [{bar:30}] {percent:>3}% {bytes}/{total_bytes} at {bytes_per_sec} (ETA {eta})",
+                );
+                bar.set_length(length);
+                bar
+            }
+            // the length is unknown when reading from stdin
+            None => stage("input", "{prefix:>12} {bytes} at {bytes_per_sec}"),
+        };
+
+        let progress = Progress {
+            download,
+            decompressed: stage("decompressed", "{prefix:>12} {bytes} at {bytes_per_sec}"),
+            lines: stage("parsed", "{prefix:>12} {human_pos} lines at {rate}"),
+            matched: stage("matched", "{prefix:>12} {human_pos} entities"),
+            backlog: stage("in flight", "{prefix:>12} {msg}"),
+            written: stage("written", "{prefix:>12} {human_pos} entries, {msg}"),
+            bars: bars.clone(),
+            bottleneck: "",
+        };
+        *SHOWN.lock().unwrap() = Some(bars);
+        Some(progress)
+    }
+
+    pub fn update(&self, counts: &Counts) {
+        self.download.set_position(counts.downloaded);
+        self.decompressed.set_position(counts.decompressed);
+        self.lines.set_position(counts.lines);
+        self.matched.set_position(counts.matched);
+        self.backlog.set_message(format!(
+            "{} lines, {} batches queued for the writer {}",
+            counts.tasks_in_flight, counts.queued_batches, self.bottleneck
+        ));
+        self.written.set_position(counts.written);
+        self.written
+            .set_message(format!("committed through line {}", counts.committed_line));
+    }
+
+    /// Sets the label of what currently limits the speed of the run.
+    pub fn set_bottleneck(&mut self, label: &'static str) {
+        self.bottleneck = label;
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        *SHOWN.lock().unwrap() = None;
+        if let Err(e) = self.bars.clear() {
+            debug!("failed to clear progress bars: {}", e);
+        }
+    }
+}
@@ -1,33 +1,47 @@
 -- since we are just writing to a blank database,
--- we can tune sqlite for speed at the expense of safety
+-- we can tune sqlite for speed at the expense of safety.
+-- the journal is a WAL file rather than in memory though, so that a killed build leaves a
+-- consistent database to continue (the writer switches back to a rollback journal at the end)
 PRAGMA synchronous = OFF;
 PRAGMA cache_size = 100000;
-PRAGMA journal_mode = MEMORY;
+PRAGMA journal_mode = WAL;
 PRAGMA temp_store = MEMORY;
 
 create table countries (
         id string not null primary key,
-        iso char(2) not null
+        iso char(2),
+        conflicting boolean not null,
+        sovereign boolean not null,
+        revision integer
 );
 create index countries_iso_index on countries (iso);
 
+-- official languages (P37) and languages used (P2936). A language that is both is stored once,
+-- as official: those are written first and the first row is kept. Post-processing only counts the
+-- languages used of objects without official languages (see post/object_languages.sql).
 create table object_languages (
         id string not null,
         lang_id string not null,
         lang_index integer not null,
+        kind string not null,
         primary key (id, lang_id)
 );
 create index object_languages_lang_id_index on object_languages (lang_id);
 
 create table languages (
         id string not null primary key,
-        code string not null);
+        code string not null,
+        revision integer);
 create index languages_code_index on languages (code);
 
 create table territorial_entities (
     id string not null primary key,
     is_2nd boolean not null,
-    iso string
+    iso string,
+    lat decimal(5, 3),
+    lon decimal(6, 3),
+    coord_precision double,
+    revision integer
 );
 create index territorial_entities_is_2nd on territorial_entities (is_2nd);
 
@@ -38,12 +52,20 @@ create table territorial_entities_parents (
 );
 create index territorial_entities_parents_parent_index on territorial_entities_parents (parent);
 
+create table admin_levels (
+    id string not null primary key,
+    level integer not null
+);
+
 create table cities (
     id string not null primary key,
     country string,
     population integer,
+    population_time string,
+    population_precision integer,
     lat decimal(5, 3),
-    lon decimal(6, 3)
+    lon decimal(6, 3),
+    revision integer
 );
 create index cities_country_index on cities (country);
 create index cities_population_index on cities (population);
@@ -66,6 +88,7 @@ create table object_labels (
     lang string not null,
     native_order integer,
     label string not null,
+    source string not null,
     primary key (id, lang, native_order)
 );
 create index object_labels_label_index on object_labels (label);
@@ -73,3 +96,78 @@ create index object_labels_lang_index on object_labels (lang);
 create index object_labels_native_order_index on object_labels (native_order);
 
 create table missing_p17 (id string not null primary key);
+
+create table sister_cities (
+    id string not null,
+    other_id string not null,
+    primary key (id, other_id)
+);
+create index sister_cities_other_id_index on sister_cities (other_id);
+
+create table statement_coords (
+    id string not null,
+    property string not null,
+    lat decimal(5, 3) not null,
+    lon decimal(6, 3) not null
+);
+create index statement_coords_id_index on statement_coords (id);
+
+create table replaces (
+    id string not null,
+    predecessor_id string not null,
+    primary key (id, predecessor_id)
+);
+create index replaces_predecessor_id_index on replaces (predecessor_id);
+
+create table part_of (
+    id string not null,
+    parent_id string not null,
+    primary key (id, parent_id)
+);
+create index part_of_parent_id_index on part_of (parent_id);
+
+create table aliases (
+    id string not null,
+    lang string not null,
+    alias string not null,
+    primary key (id, lang, alias)
+);
+create index aliases_alias_index on aliases (alias);
+
+create table data_quality (
+    id string not null,
+    property string not null,
+    reason string not null
+);
+create index data_quality_id_index on data_quality (id);
+create index data_quality_property_index on data_quality (property);
+
+create table borders_with (
+    id string not null,
+    neighbor_id string not null,
+    primary key (id, neighbor_id)
+);
+create index borders_with_neighbor_id_index on borders_with (neighbor_id);
+
+-- single row with the last input line whose entries have all been committed,
+-- and the offset in the decompressed dump after it for resuming the build
+create table build_state (
+    id integer not null primary key,
+    line_number integer not null,
+    byte_offset integer
+);
+
+-- entities with entries committed after the last checkpoint, which are removed
+-- before the build continues from the checkpoint, and the input line they're from
+-- (entries of later lines can be committed before the checkpoint of a line)
+create table build_journal (
+    id string not null primary key,
+    line_number integer
+);
+
+-- information about the run, e.g. the dump date
+-- (text rather than string, which has numeric affinity and would turn dates into integers)
+create table metadata (
+    key text not null primary key,
+    value text not null
+);
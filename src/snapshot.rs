@@ -0,0 +1,134 @@
+use crate::input::file::Bz2FileInput;
+use crate::input::{InputLineIter, LineIterError};
+use crate::options::Options;
+use crate::wiki_data_line::{self, HandleLineError};
+use crate::wiki_sparql::{Classes, ClassesError};
+use std::cmp::Ordering;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum SnapshotError {
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("failed to load classes: {0}")]
+    Classes(#[from] ClassesError),
+    #[error("line iterator error: {0}")]
+    Lines(#[from] LineIterError<io::Error>),
+    #[error("error handling line {0}: {1}")]
+    HandleLine(usize, Box<HandleLineError>),
+}
+
+/// Runs every line of the dump slice through the parser (sequentially) and returns the sorted
+/// NDJSON lines of all produced entries.
+fn extract(
+    input: &str,
+    classes: &Classes,
+    options: &Options,
+) -> Result<Vec<String>, SnapshotError> {
+    let mut lines = InputLineIter::new(Bz2FileInput::new(fs::File::open(input)?));
+    let mut entries = Vec::new();
+    let mut line_number = 0;
+    loop {
+        line_number += 1;
+        let (line, _) = match lines.next() {
+            Ok(line) => line,
+            Err(LineIterError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut line_entries = Vec::new();
+        wiki_data_line::handle_line(&line, classes, options, &mut line_entries, None, false)
+            .map_err(|e| SnapshotError::HandleLine(line_number, Box::new(e)))?;
+
+        for entry in line_entries {
+            entries.push(serde_json::to_string(&entry)?);
+        }
+    }
+
+    entries.sort();
+    Ok(entries)
+}
+
+/// Compares the extracted output of a dump slice against a golden NDJSON file, printing a diff.
+/// If `update` is set, the golden file is overwritten instead.
+///
+/// Returns true if the output matches.
+pub fn run(
+    input: &str,
+    golden: &str,
+    classes: Option<&str>,
+    options: &Options,
+    update: bool,
+) -> Result<bool, SnapshotError> {
+    info!("Loading classes");
+    let classes = Classes::load(classes, options)?;
+
+    info!("Extracting entries from {}", input);
+    let entries = extract(input, &classes, options)?;
+
+    if update {
+        let mut file = io::BufWriter::new(fs::File::create(golden)?);
+        for entry in &entries {
+            writeln!(file, "{}", entry)?;
+        }
+        file.flush()?;
+        info!("Wrote {} entries to {}", entries.len(), golden);
+        return Ok(true);
+    }
+
+    let expected = BufReader::new(fs::File::open(golden)?)
+        .lines()
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // both lists are sorted, so we can diff them in one pass
+    let mut removed = 0;
+    let mut added = 0;
+    let mut expected_iter = expected.iter().peekable();
+    let mut entries_iter = entries.iter().peekable();
+    loop {
+        match (expected_iter.peek(), entries_iter.peek()) {
+            (Some(a), Some(b)) => match a.cmp(b) {
+                Ordering::Equal => {
+                    expected_iter.next();
+                    entries_iter.next();
+                }
+                Ordering::Less => {
+                    println!("- {}", a);
+                    removed += 1;
+                    expected_iter.next();
+                }
+                Ordering::Greater => {
+                    println!("+ {}", b);
+                    added += 1;
+                    entries_iter.next();
+                }
+            },
+            (Some(a), None) => {
+                println!("- {}", a);
+                removed += 1;
+                expected_iter.next();
+            }
+            (None, Some(b)) => {
+                println!("+ {}", b);
+                added += 1;
+                entries_iter.next();
+            }
+            (None, None) => break,
+        }
+    }
+
+    if removed == 0 && added == 0 {
+        info!("Snapshot matches ({} entries)", entries.len());
+        Ok(true)
+    } else {
+        error!(
+            "Snapshot mismatch: {} entries removed, {} entries added",
+            removed, added
+        );
+        Ok(false)
+    }
+}
@@ -0,0 +1,151 @@
+//! How long the line handlers take per line, to find entities that stall them (like countries
+//! with thousands of population statements).
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Number of slowest lines kept between reports.
+const SLOWEST_KEPT: usize = 10;
+
+/// Upper bounds of the histogram buckets in microseconds; the last bucket has no bound.
+const BUCKET_BOUNDS_US: [u64; 6] = [100, 1_000, 10_000, 100_000, 1_000_000, 10_000_000];
+const BUCKET_LABELS: [&str; 7] = ["<100µs", "<1ms", "<10ms", "<100ms", "<1s", "<10s", "≥10s"];
+
+/// Number of lines per bucket, since the start of the run.
+static HISTOGRAM: [AtomicU64; 7] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+
+/// Lines that took at least this long may be among the slowest, so that faster lines don't have
+/// to lock [`SLOWEST`].
+static SLOWEST_THRESHOLD_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// The slowest lines since the last report, slowest first.
+static SLOWEST: Mutex<Vec<SlowLine>> = Mutex::new(Vec::new());
+
+struct SlowLine {
+    entity: String,
+    line_number: u64,
+    bytes: usize,
+    duration: Duration,
+}
+
+/// Records how long it took to handle a line.
+pub fn record(line_number: u64, line: &str, duration: Duration) {
+    let micros = duration.as_micros() as u64;
+    let bucket = BUCKET_BOUNDS_US
+        .iter()
+        .position(|bound| micros < *bound)
+        .unwrap_or(BUCKET_BOUNDS_US.len());
+    HISTOGRAM[bucket].fetch_add(1, Ordering::Relaxed);
+
+    let nanos = duration.as_nanos() as u64;
+    if nanos < SLOWEST_THRESHOLD_NANOS.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut slowest = SLOWEST.lock().unwrap();
+    let index = slowest.partition_point(|slow| slow.duration >= duration);
+    if index >= SLOWEST_KEPT {
+        return;
+    }
+    slowest.insert(
+        index,
+        SlowLine {
+            entity: entity_id(line).to_string(),
+            line_number,
+            bytes: line.len(),
+            duration,
+        },
+    );
+    slowest.truncate(SLOWEST_KEPT);
+    if slowest.len() == SLOWEST_KEPT {
+        let fastest = slowest[SLOWEST_KEPT - 1].duration.as_nanos() as u64;
+        SLOWEST_THRESHOLD_NANOS.store(fastest, Ordering::Relaxed);
+    }
+}
+
+/// ID of the entity on a line of a JSON dump, without parsing it. Entities start with their type
+/// and ID, so the first `"id"` is the entity's.
+fn entity_id(line: &str) -> &str {
+    const KEY: &str = "\"id\":\"";
+    match line.find(KEY) {
+        Some(start) => {
+            let id = &line[start + KEY.len()..];
+            &id[..id.find('"').unwrap_or(id.len())]
+        }
+        None => "?",
+    }
+}
+
+/// Logs the slowest lines since the last report and the histogram of all lines so far.
+pub fn report() {
+    let slowest = std::mem::take(&mut *SLOWEST.lock().unwrap());
+    SLOWEST_THRESHOLD_NANOS.store(0, Ordering::Relaxed);
+    if slowest.is_empty() {
+        return;
+    }
+
+    let histogram = BUCKET_LABELS
+        .iter()
+        .zip(&HISTOGRAM)
+        .map(|(label, count)| format!("{} {}", label, count.load(Ordering::Relaxed)))
+        .collect::<Vec<_>>();
+    info!("Line handling times: {}", histogram.join(", "));
+    for slow in slowest {
+        info!(
+            entity = slow.entity.as_str(),
+            line = slow.line_number,
+            bytes = slow.bytes as u64,
+            duration_ms = slow.duration.as_millis() as u64;
+            "Slow line {} ({}): {:.1} KB in {:.3} s",
+            slow.line_number,
+            slow.entity,
+            slow.bytes as f64 / 1000.,
+            slow.duration.as_secs_f64()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_entity_ids() {
+        assert_eq!(
+            entity_id(r#"{"type":"item","id":"Q64","labels":{}},"#),
+            "Q64"
+        );
+        assert_eq!(entity_id(r#"{"type":"item","id":"Q64"#), "Q64");
+        assert_eq!(entity_id("["), "?");
+    }
+
+    #[test]
+    fn keep_the_slowest_lines() {
+        for i in 0..SLOWEST_KEPT as u64 + 5 {
+            let line = format!(r#"{{"type":"item","id":"Q{}"}},"#, i);
+            record(i, &line, Duration::from_millis(i));
+        }
+        let slowest = std::mem::take(&mut *SLOWEST.lock().unwrap());
+        let lines = slowest
+            .iter()
+            .map(|slow| slow.line_number)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            (5..SLOWEST_KEPT as u64 + 5).rev().collect::<Vec<_>>()
+        );
+        assert_eq!(slowest[0].entity, "Q14");
+        assert_eq!(
+            SLOWEST_THRESHOLD_NANOS.load(Ordering::Relaxed),
+            Duration::from_millis(5).as_nanos() as u64
+        );
+    }
+}
@@ -1,139 +1,555 @@
-use crate::database::DataEntry;
-use crate::json_get;
+use crate::database::{DataEntry, LabelSource, LanguageKind};
+use crate::json;
+use crate::logging;
+use crate::options::{AliasDedupScope, CoordCrs, CoordValidation, EntityType, Options};
+use crate::wiki_entity::{Entity, Rank, Snak, Statement};
 use crate::wiki_sparql::Classes;
 use crate::wiki_time::{
     is_object_active, is_object_end_active, is_object_start_active, parse_wikidata_time,
 };
 use crossbeam::channel::Sender;
-use serde_json::Value;
 use std::collections::HashSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use thiserror::Error;
 
+/// Number of entities skipped because they are not items.
+pub static SKIPPED_BY_TYPE: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of places skipped because they didn't pass the filters in the options.
+pub static SKIPPED_BY_FILTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of lines skipped by the [`Prefilter`] without being parsed.
+pub static SKIPPED_BY_PREFILTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of entities extracted as a country, place or language.
+pub static MATCHED_ENTITIES: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of entities extracted as each [`EntityType`], indexed like [`EntityType::ALL`]. An
+/// entity can be of several types.
+pub static MATCHED_BY_TYPE: [AtomicUsize; 4] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+
+/// Records a data quality problem with a property of an entity if `quality_events` is enabled
+/// (`--quality-events`). The caller logs the details.
+fn quality_event(
+    quality_events: bool,
+    sink: &mut Vec<DataEntry>,
+    id: &str,
+    property: &str,
+    reason: &str,
+) -> Result<(), HandleLineError> {
+    if quality_events {
+        sink.push(DataEntry::QualityEvent {
+            id: id.into(),
+            property: property.into(),
+            reason: reason.into(),
+        });
+    }
+    Ok(())
+}
+
 /// both human settlements and territorial entities
-fn handle_place(obj: &Value, sink: &Sender<DataEntry>) -> Result<(), HandleLineError> {
-    let obj_id = json_get!(value(obj).id: string).unwrap();
-    if let Some(parents) = json_get!(value(obj).claims.P131: array) {
+fn handle_place(
+    obj: &Entity,
+    quality_events: bool,
+    sink: &mut Vec<DataEntry>,
+) -> Result<(), HandleLineError> {
+    let obj_id: &str = &obj.id;
+    if let Some(parents) = obj.claims.get("P131") {
         for parent in parents {
-            if !is_object_active(json_get!(value(parent).qualifiers: object)) {
+            if !is_object_active(&parent.qualifiers) {
                 continue;
             }
 
-            if let Some(parent) = json_get!(value(parent).mainsnak.datavalue.value.id: string) {
-                sink.send(DataEntry::TerritorialEntityParent {
+            if let Some(parent) = parent.value_id() {
+                sink.push(DataEntry::TerritorialEntityParent {
                     id: obj_id.into(),
                     parent: parent.into(),
-                })?;
+                });
             } else {
                 warn!(
                     "skipping TE {} P131 parent because it has no datavalue ID",
                     obj_id
                 );
+                quality_event(quality_events, sink, obj_id, "P131", "no datavalue ID")?;
+            }
+        }
+    }
+
+    // P190: twinned administrative body
+    if let Some(twins) = obj.claims.get("P190") {
+        for twin in twins {
+            if !twin.mainsnak.has_value() {
+                continue;
+            }
+            if !is_object_active(&twin.qualifiers) {
+                continue;
+            }
+
+            if let Some(other_id) = twin.value_id() {
+                sink.push(DataEntry::SisterCity {
+                    id: obj_id.into(),
+                    other_id: other_id.into(),
+                });
+            } else {
+                warn!(
+                    "skipping {} P190 twin because it has no datavalue ID",
+                    obj_id
+                );
+                quality_event(quality_events, sink, obj_id, "P190", "no datavalue ID")?;
             }
         }
     }
     Ok(())
 }
 
-fn handle_territorial_entity(
-    obj: &Value,
-    is_2nd: bool,
-    sink: &Sender<DataEntry>,
+/// Reads lat/lon from a globe-coordinate snak (a mainsnak or a qualifier), validated and
+/// projected to `crs`.
+fn snak_coordinates(
+    obj_id: &str,
+    snak: &Snak,
+    crs: CoordCrs,
+    validation: CoordValidation,
+    quality_events: bool,
+    sink: &mut Vec<DataEntry>,
+) -> Result<Option<(f64, f64)>, HandleLineError> {
+    if !snak.has_value() {
+        return Ok(None);
+    }
+    let value = snak.value_object();
+    if let (Some(lat), Some(lon)) = (
+        value.and_then(|value| value.latitude),
+        value.and_then(|value| value.longitude),
+    ) {
+        if validation == CoordValidation::Off || CoordValidation::in_range(lat, lon) {
+            return Ok(Some(crs.project(lat, lon)));
+        }
+        let fixed = validation.fix(lat, lon);
+        match fixed {
+            Some((new_lat, new_lon)) => warn!(
+                "replacing {} lat/lon {}, {} with {}, {} because they are out of range",
+                obj_id, lat, lon, new_lat, new_lon
+            ),
+            None => warn!(
+                "skipping {} lat/lon {}, {} because they are out of range",
+                obj_id, lat, lon
+            ),
+        }
+        quality_event(quality_events, sink, obj_id, "P625", "out of range")?;
+        Ok(fixed.map(|(lat, lon)| crs.project(lat, lon)))
+    } else {
+        warn!(
+            "skipping {} lat/lon because lat/lon are invalid types",
+            obj_id
+        );
+        Ok(None)
+    }
+}
+
+/// P625 (coordinate location) qualifiers on P131 (located in) and P17 (country) statements,
+/// including inactive ones, since these are mostly used for historical locations.
+fn handle_statement_coords(
+    obj: &Entity,
+    crs: CoordCrs,
+    validation: CoordValidation,
+    quality_events: bool,
+    sink: &mut Vec<DataEntry>,
+) -> Result<(), HandleLineError> {
+    let obj_id: &str = &obj.id;
+    for property in &["P131", "P17"] {
+        for statement in obj.claims.statements(property) {
+            for coords in &statement.qualifiers.coordinates {
+                if let Some((lat, lon)) =
+                    snak_coordinates(obj_id, coords, crs, validation, quality_events, sink)?
+                {
+                    sink.push(DataEntry::StatementCoord {
+                        id: obj_id.into(),
+                        property: (*property).into(),
+                        lat,
+                        lon,
+                    });
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// P1365: replaces
+fn handle_replaces(
+    obj: &Entity,
+    quality_events: bool,
+    sink: &mut Vec<DataEntry>,
 ) -> Result<(), HandleLineError> {
-    let obj_id = json_get!(value(obj).id: string).unwrap();
+    let obj_id: &str = &obj.id;
+    if let Some(predecessors) = obj.claims.get("P1365") {
+        for predecessor in predecessors {
+            if !predecessor.mainsnak.has_value() {
+                continue;
+            }
+            if !is_object_active(&predecessor.qualifiers) {
+                continue;
+            }
 
-    let iso = if is_2nd {
-        json_get!(value(obj).claims.P300[0].mainsnak.datavalue.value: string).map(|s| s.to_string())
-    } else {
-        None
+            if let Some(predecessor_id) = predecessor.value_id() {
+                sink.push(DataEntry::Replaces {
+                    id: obj_id.into(),
+                    predecessor_id: predecessor_id.into(),
+                });
+            } else {
+                warn!(
+                    "skipping {} P1365 predecessor because it has no datavalue ID",
+                    obj_id
+                );
+                quality_event(quality_events, sink, obj_id, "P1365", "no datavalue ID")?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// P361: part of
+fn handle_part_of(
+    obj: &Entity,
+    quality_events: bool,
+    sink: &mut Vec<DataEntry>,
+) -> Result<(), HandleLineError> {
+    let obj_id: &str = &obj.id;
+    if let Some(parents) = obj.claims.get("P361") {
+        for parent in parents {
+            if !parent.mainsnak.has_value() {
+                continue;
+            }
+            if !is_object_active(&parent.qualifiers) {
+                continue;
+            }
+
+            if let Some(parent_id) = parent.value_id() {
+                sink.push(DataEntry::PartOf {
+                    id: obj_id.into(),
+                    parent_id: parent_id.into(),
+                });
+            } else {
+                warn!(
+                    "skipping {} P361 parent because it has no datavalue ID",
+                    obj_id
+                );
+                quality_event(quality_events, sink, obj_id, "P361", "no datavalue ID")?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// P47: shares border with
+fn handle_borders(
+    obj: &Entity,
+    quality_events: bool,
+    sink: &mut Vec<DataEntry>,
+) -> Result<(), HandleLineError> {
+    let obj_id: &str = &obj.id;
+    if let Some(neighbors) = obj.claims.get("P47") {
+        for neighbor in neighbors {
+            if !neighbor.mainsnak.has_value() {
+                continue;
+            }
+            if !is_object_active(&neighbor.qualifiers) {
+                continue;
+            }
+
+            if let Some(neighbor_id) = neighbor.value_id() {
+                sink.push(DataEntry::BordersWith {
+                    id: obj_id.into(),
+                    neighbor_id: neighbor_id.into(),
+                });
+            } else {
+                warn!(
+                    "skipping {} P47 neighbor because it has no datavalue ID",
+                    obj_id
+                );
+                quality_event(quality_events, sink, obj_id, "P47", "no datavalue ID")?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether names in a language are extracted, given the configured label languages (all if empty).
+fn is_label_language(languages: &HashSet<String>, lang: &str) -> bool {
+    languages.is_empty() || languages.contains(lang)
+}
+
+/// The entity's labels in the given languages.
+fn handle_labels(
+    obj: &Entity,
+    languages: &HashSet<String>,
+    sink: &mut Vec<DataEntry>,
+) -> Result<(), HandleLineError> {
+    let obj_id: &str = &obj.id;
+    for label in &obj.labels {
+        if !is_label_language(languages, &label.language) {
+            continue;
+        }
+        sink.push(DataEntry::ObjectLabel {
+            id: obj_id.into(),
+            lang: (*label.language).into(),
+            label: label.value.to_string(),
+            native_order: None,
+            source: LabelSource::Label,
+        });
+    }
+    Ok(())
+}
+
+/// P1813 (short name) as labels in languages that have no label, at most one per language.
+fn handle_short_names(
+    obj: &Entity,
+    languages: &HashSet<String>,
+    quality_events: bool,
+    sink: &mut Vec<DataEntry>,
+) -> Result<(), HandleLineError> {
+    let obj_id: &str = &obj.id;
+    let short_names = match obj.claims.get("P1813") {
+        Some(short_names) => short_names,
+        None => return Ok(()),
     };
 
-    sink.send(DataEntry::TerritorialEntity {
-        id: obj_id.into(),
-        is_2nd,
-        iso,
-    })?;
+    let mut langs: HashSet<&str> = obj.labels.iter().map(|label| &*label.language).collect();
+
+    for claim in short_names {
+        if !is_object_active(&claim.qualifiers) {
+            continue;
+        }
+        let text = claim.mainsnak.value_object();
+        if let (Some(lang), Some(label)) = (
+            text.and_then(|text| text.language.as_deref()),
+            text.and_then(|text| text.text.as_deref()),
+        ) {
+            if !is_label_language(languages, lang) || !langs.insert(lang) {
+                continue;
+            }
+            sink.push(DataEntry::ObjectLabel {
+                id: obj_id.into(),
+                lang: lang.into(),
+                label: label.into(),
+                native_order: None,
+                source: LabelSource::ShortNameFallback,
+            });
+        } else {
+            warn!(
+                "skipping {} P1813 short name because it has invalid type",
+                obj_id
+            );
+            quality_event(quality_events, sink, obj_id, "P1813", "invalid type")?;
+        }
+    }
+    Ok(())
+}
 
-    handle_place(obj, sink)?;
+/// Aliases, minus those that case-insensitively match a label or an earlier alias within the
+/// dedup scope.
+fn handle_aliases(
+    obj: &Entity,
+    scope: AliasDedupScope,
+    languages: &HashSet<String>,
+    sink: &mut Vec<DataEntry>,
+) -> Result<(), HandleLineError> {
+    let obj_id: &str = &obj.id;
 
-    // P37: official language
-    // P2936: language used
-    if let Some(langs) =
-        json_get!(value(obj).claims.P37: array).or(json_get!(value(obj).claims.P2936: array))
-    {
+    // (language, lowercase name); the language is empty for the entity scope
+    let scoped_key = |lang: &str, name: &str| match scope {
+        AliasDedupScope::Language => (lang.to_string(), name.to_lowercase()),
+        AliasDedupScope::Entity => (String::new(), name.to_lowercase()),
+    };
+
+    let mut seen: HashSet<_> = obj
+        .labels
+        .iter()
+        .map(|label| scoped_key(&label.language, &label.value))
+        .collect();
+
+    for alias in obj.aliases.iter().flatten() {
+        let lang = &*alias.language;
+        if !is_label_language(languages, lang) || !seen.insert(scoped_key(lang, &alias.value)) {
+            continue;
+        }
+        sink.push(DataEntry::Alias {
+            id: obj_id.into(),
+            lang: lang.into(),
+            alias: alias.value.to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// P37: official language
+/// P2936: language used
+///
+/// Languages are indexed in claim order, separately for each kind.
+fn handle_object_languages(
+    obj: &Entity,
+    property: &str,
+    kind: LanguageKind,
+    quality_events: bool,
+    sink: &mut Vec<DataEntry>,
+) -> Result<(), HandleLineError> {
+    let obj_id: &str = &obj.id;
+    if let Some(langs) = obj.claims.get(property) {
         let mut lang_index = 0;
         for lang in langs {
-            if json_get!(value(lang).mainsnak.snaktype: string) != Some("value") {
+            if !lang.mainsnak.has_value() {
                 continue;
             }
-            if !is_object_active(json_get!(value(lang).qualifiers: object)) {
+            if !is_object_active(&lang.qualifiers) {
                 continue;
             }
-            if let Some(lang_id) = json_get!(value(lang).mainsnak.datavalue.value.id: string) {
-                sink.send(DataEntry::ObjectLanguage {
+            if let Some(lang_id) = lang.value_id() {
+                sink.push(DataEntry::ObjectLanguage {
                     id: obj_id.into(),
                     lang_id: lang_id.into(),
                     index: lang_index,
-                })?;
+                    kind,
+                });
                 lang_index += 1;
             } else {
                 warn!(
-                    "skipping TE {} P37 lang because it has no datavalue ID",
-                    obj_id
+                    "skipping {} {} lang because it has no datavalue ID",
+                    obj_id, property
                 );
+                quality_event(quality_events, sink, obj_id, property, "no datavalue ID")?;
             }
         }
     }
+    Ok(())
+}
 
-    if let Some(labels) = json_get!(value(obj).labels: object) {
-        for label in labels.values() {
-            if let (Some(lang), Some(label)) = (
-                json_get!(value(label).language: string),
-                json_get!(value(label).value: string),
-            ) {
-                sink.send(DataEntry::ObjectLabel {
-                    id: obj_id.into(),
-                    lang: lang.into(),
-                    label: label.into(),
-                    native_order: None,
-                })?;
-            } else {
-                warn!("skipping {} label because it has invalid type", obj_id);
-            }
-        }
+fn handle_territorial_entity(
+    obj: &Entity,
+    is_2nd: bool,
+    admin_level: Option<u8>,
+    options: &Options,
+    sink: &mut Vec<DataEntry>,
+) -> Result<(), HandleLineError> {
+    let obj_id: &str = &obj.id;
+    let (crs, validation) = (options.coord_crs, options.validate_coords);
+    let quality_events = options.quality_events;
+
+    let iso = if is_2nd {
+        obj.claims
+            .statements("P300")
+            .first()
+            .and_then(|statement| statement.mainsnak.value_str())
+            .map(|s| s.to_string())
+    } else {
+        None
+    };
+
+    let mut lat_lon = None;
+    let mut coord_precision = None;
+    if let Some(coords) = obj.claims.statements("P625").first() {
+        lat_lon = snak_coordinates(
+            obj_id,
+            &coords.mainsnak,
+            crs,
+            validation,
+            quality_events,
+            sink,
+        )?;
+        coord_precision = coords
+            .mainsnak
+            .value_object()
+            .and_then(|value| value.precision);
+    }
+
+    sink.push(DataEntry::TerritorialEntity {
+        id: obj_id.into(),
+        is_2nd,
+        iso,
+        lat: lat_lon.map(|(lat, _)| lat),
+        lon: lat_lon.map(|(_, lon)| lon),
+        coord_precision,
+        revision: entity_revision(obj),
+    });
+
+    if let Some(level) = admin_level {
+        sink.push(DataEntry::AdminLevel {
+            id: obj_id.into(),
+            level,
+        });
     }
 
+    handle_place(obj, quality_events, sink)?;
+    handle_borders(obj, quality_events, sink)?;
+
+    // official languages are sent first, so they win if a language is also listed as used
+    handle_object_languages(obj, "P37", LanguageKind::Official, quality_events, sink)?;
+    handle_object_languages(obj, "P2936", LanguageKind::Used, quality_events, sink)?;
+
+    handle_labels(obj, &options.label_languages, sink)?;
+
     Ok(())
 }
 
-fn handle_language(obj: &Value, sink: &Sender<DataEntry>) -> Result<(), HandleLineError> {
-    let obj_id = json_get!(value(obj).id: string).unwrap();
-    if let Some(wikimedia_code) =
-        json_get!(value(obj).claims.P424[0].mainsnak.datavalue.value: string)
+fn handle_language(obj: &Entity, sink: &mut Vec<DataEntry>) -> Result<(), HandleLineError> {
+    let obj_id: &str = &obj.id;
+    if let Some(wikimedia_code) = obj
+        .claims
+        .statements("P424")
+        .first()
+        .and_then(|statement| statement.mainsnak.value_str())
     {
-        sink.send(DataEntry::Language {
+        sink.push(DataEntry::Language {
             id: obj_id.into(),
             code: wikimedia_code.into(),
-        })?;
+            revision: entity_revision(obj),
+        });
     } else {
         // warn!("skipping lang {} because it has no wikimedia language code", obj_id);
     }
     Ok(())
 }
 
-fn handle_human_settlement(obj: &Value, sink: &Sender<DataEntry>) -> Result<(), HandleLineError> {
-    let obj_id = json_get!(value(obj).id: string).unwrap();
-    let country_entries = match json_get!(value(obj).claims.P17: array) {
+/// Units that a P1082 population can be given in. It is usually unitless ("1"), and sometimes
+/// counted in humans or persons, which is the same. Other units, e.g. households or per square
+/// kilometre (a density), aren't a number of inhabitants and are skipped.
+const POPULATION_UNITS: &[&str] = &[
+    "1",
+    // human
+    "http://www.wikidata.org/entity/Q5",
+    // person
+    "http://www.wikidata.org/entity/Q215627",
+];
+
+/// If `keep_without_country` is set, settlements without P17 are still extracted so that
+/// post-processing can look for their country through P361 (part of).
+fn handle_human_settlement(
+    obj: &Entity,
+    keep_without_country: bool,
+    options: &Options,
+    sink: &mut Vec<DataEntry>,
+) -> Result<(), HandleLineError> {
+    let obj_id: &str = &obj.id;
+    let (crs, validation) = (options.coord_crs, options.validate_coords);
+    let quality_events = options.quality_events;
+    let country_entries = match obj.claims.get("P17") {
         Some(country_entries) => country_entries,
         None => {
-            sink.send(DataEntry::MissingP17 { id: obj_id.into() })?;
-            return Ok(()); // we cannot use the entry without its country
+            sink.push(DataEntry::MissingP17 { id: obj_id.into() });
+            if !keep_without_country {
+                return Ok(()); // we cannot use the entry without its country
+            }
+            &[]
         }
     };
 
-    handle_place(obj, sink)?;
+    handle_place(obj, quality_events, sink)?;
 
     for (i, country_entry) in country_entries.iter().enumerate() {
-        let qualifiers = json_get!(value(country_entry).qualifiers: object);
+        let qualifiers = &country_entry.qualifiers;
         let start_active = is_object_start_active(qualifiers);
         let end_active = is_object_end_active(qualifiers);
         if end_active == Some(false) || start_active == Some(false) {
@@ -147,65 +563,65 @@ fn handle_human_settlement(obj: &Value, sink: &Sender<DataEntry>) -> Result<(),
             1000 + i as u32
         };
 
-        if let Some(id) = json_get!(value(country_entry).mainsnak.datavalue.value.id: string) {
-            sink.send(DataEntry::CityCountry {
+        if let Some(id) = country_entry.value_id() {
+            sink.push(DataEntry::CityCountry {
                 id: obj_id.into(),
                 country: id.into(),
                 priority,
-            })
-            .unwrap();
+            });
         } else {
             warn!(
                 "skipping HS {} P17 country entry because it has no datavalue id",
                 obj_id
             );
+            quality_event(quality_events, sink, obj_id, "P17", "no datavalue ID")?;
         }
     }
 
     let mut population = None;
     let mut population_time = None;
-    if let Some(population_entries) = json_get!(value(obj).claims.P1082: array) {
+    if let Some(population_entries) = obj.claims.get("P1082") {
         for population_entry in population_entries {
+            let qualifiers = &population_entry.qualifiers;
             let mut new_population_time = None;
-            if let Some(population_time) =
-                json_get!(value(population_entry).qualifiers.P585[0]: object)
-            {
-                if json_get!((population_time).snaktype: string) != Some("value") {
+            if let Some(population_time) = qualifiers.point_in_time.first() {
+                if !population_time.has_value() {
                     continue;
                 }
-                if let Some(time_obj) = json_get!((population_time).datavalue.value: object) {
-                    if let (Some(time), Some(zone)) = (
-                        json_get!((time_obj).time: string),
-                        json_get!((time_obj).timezone: number),
-                    ) {
-                        if let Ok(time) = parse_wikidata_time(time, zone) {
-                            new_population_time = Some(time);
+                if let Some(time_obj) = population_time.value_object() {
+                    if let (Some(time), Some(zone)) = (time_obj.time.as_deref(), time_obj.timezone)
+                    {
+                        if let Ok(parsed_time) = parse_wikidata_time(time, zone) {
+                            let precision = time_obj.precision.map(|p| p as u8);
+                            new_population_time = Some((parsed_time, time, precision));
                         }
                     } else {
                         warn!(
                             "skipping {} P1082/P585 population entry because it has invalid time",
                             obj_id
                         );
+                        quality_event(quality_events, sink, obj_id, "P585", "invalid time")?;
                     }
                 } else {
                     warn!(
                         "skipping {} P1082/P585 population entry because it has no time value",
                         obj_id
                     );
+                    quality_event(quality_events, sink, obj_id, "P585", "no time value")?;
                 }
             } else {
                 // warn!("skipping {} P1082 population entry because it has no P585 entry", obj_id);
             }
 
-            if let Some(_) = json_get!(value(population_entry).qualifiers.P518[0]: object) {
+            if !qualifiers.applies_to_part.is_empty() {
                 // "applies to part" - but we want the entire population
                 new_population_time = None; // reset to none
             }
-            if let Some(_) = json_get!(value(population_entry).qualifiers.P1539[0]: object) {
+            if !qualifiers.female_population.is_empty() {
                 // this is only the female population
                 new_population_time = None; // reset to none
             }
-            if let Some(_) = json_get!(value(population_entry).qualifiers.P1540[0]: object) {
+            if !qualifiers.male_population.is_empty() {
                 // this is only the male population
                 new_population_time = None; // reset to none
             }
@@ -213,26 +629,40 @@ fn handle_human_settlement(obj: &Value, sink: &Sender<DataEntry>) -> Result<(),
             if let Some(new_time) = new_population_time {
                 if population_time
                     .as_ref()
-                    .map_or(true, |old| new_time >= *old)
+                    .map_or(true, |(old, _, _)| new_time.0 >= *old)
                 {
+                    let quantity = population_entry.mainsnak.value_object();
                     if let (Some(value), Some(unit)) = (
-                        json_get!(value(population_entry).mainsnak.datavalue.value.amount: string),
-                        json_get!(value(population_entry).mainsnak.datavalue.value.unit: string),
+                        quantity.and_then(|quantity| quantity.amount.as_deref()),
+                        quantity.and_then(|quantity| quantity.unit.as_deref()),
                     ) {
                         // wikidata population is stored as "value" and "unit" strings
-                        if unit != "1" {
-                            // population is unitless!
+                        if !POPULATION_UNITS.contains(&unit) {
+                            // e.g. population density
+                            warn!(
+                                "skipping {} P1082 population entry because it has unit {}",
+                                obj_id, unit
+                            );
+                            quality_event(quality_events, sink, obj_id, "P1082", "unknown unit")?;
                             continue;
                         }
 
-                        if let Some(value) = parse_quantity(value) {
+                        if let Some(value) = parse_quantity(value, &QuantityFormat::WIKIDATA) {
                             population = Some(value);
                             population_time = Some(new_time);
                         } else {
                             warn!("skipping {} P1082 population entry because its amount value could not be parsed as a number", obj_id);
+                            quality_event(
+                                quality_events,
+                                sink,
+                                obj_id,
+                                "P1082",
+                                "unparseable amount",
+                            )?;
                         }
                     } else {
                         warn!("skipping {} P1082 population entry because its amount value either does not exist or is an unexpected type", obj_id);
+                        quality_event(quality_events, sink, obj_id, "P1082", "missing amount")?;
                     }
                 }
             }
@@ -240,92 +670,61 @@ fn handle_human_settlement(obj: &Value, sink: &Sender<DataEntry>) -> Result<(),
     }
 
     let mut lat_lon = None;
-    if let Some(coords) = json_get!(value(obj).claims.P625[0].mainsnak: object) {
-        if json_get!((coords).snaktype: string) == Some("value") {
-            if let (Some(lat), Some(lon)) = (
-                json_get!((coords).datavalue.value.latitude: number),
-                json_get!((coords).datavalue.value.longitude: number),
-            ) {
-                lat_lon = Some((lat, lon));
-            } else {
-                warn!(
-                    "skipping {} lat/lon because lat/lon are invalid types",
-                    obj_id
-                );
-            }
-        }
+    if let Some(coords) = obj.claims.statements("P625").first() {
+        lat_lon = snak_coordinates(
+            obj_id,
+            &coords.mainsnak,
+            crs,
+            validation,
+            quality_events,
+            sink,
+        )?;
     } else {
         // warn!("skipping {} lat/lon because it has no P625 entry", obj_id);
     }
 
-    sink.send(DataEntry::City {
+    sink.push(DataEntry::City {
         id: obj_id.into(),
         population,
+        population_time: population_time.map(|(_, time, _)| time.to_string()),
+        population_precision: population_time.and_then(|(_, _, precision)| precision),
         lat: lat_lon.map(|(lat, _)| lat),
         lon: lat_lon.map(|(_, lon)| lon),
-    })?;
+        revision: entity_revision(obj),
+    });
 
-    if let Some(labels) = json_get!(value(obj).labels: object) {
-        for label in labels.values() {
-            if let (Some(lang), Some(label)) = (
-                json_get!(value(label).language: string),
-                json_get!(value(label).value: string),
-            ) {
-                sink.send(DataEntry::ObjectLabel {
-                    id: obj_id.into(),
-                    lang: lang.into(),
-                    label: label.into(),
-                    native_order: None,
-                })?;
-            } else {
-                warn!("skipping {} label because it has invalid type", obj_id);
-            }
-        }
-    }
+    handle_labels(obj, &options.label_languages, sink)?;
 
-    // Insert native labels
+    // Insert native labels, followed by any official names that are not also native labels
     let mut native_order_index = 0;
-    if let Some(native_labels) = json_get!(value(obj).claims.P1705: array) {
-        for claim in native_labels {
-            if let (Some(lang), Some(label)) = (
-                json_get!(value(claim).mainsnak.datavalue.value.language: string),
-                json_get!(value(claim).mainsnak.datavalue.value.text: string),
-            ) {
-                sink.send(DataEntry::ObjectLabel {
-                    id: obj_id.into(),
-                    lang: lang.into(),
-                    label: label.into(),
-                    native_order: Some(native_order_index),
-                })?;
-                native_order_index += 1;
-            } else {
-                warn!(
-                    "skipping {} P1705 native label because it has invalid type",
-                    obj_id
-                );
-            }
-        }
-    } else if let Some(official_names) = json_get!(value(obj).claims.P1448: array) {
-        for claim in official_names {
-            if !is_object_active(json_get!(value(claim).qualifiers: object)) {
+    let mut native_labels = HashSet::new();
+    for property in &["P1705", "P1448"] {
+        for claim in obj.claims.statements(property) {
+            if *property == "P1448" && !is_object_active(&claim.qualifiers) {
                 continue;
             }
+            let text = claim.mainsnak.value_object();
             if let (Some(lang), Some(label)) = (
-                json_get!(value(claim).mainsnak.datavalue.value.language: string),
-                json_get!(value(claim).mainsnak.datavalue.value.text: string),
+                text.and_then(|text| text.language.as_deref()),
+                text.and_then(|text| text.text.as_deref()),
             ) {
-                sink.send(DataEntry::ObjectLabel {
+                if !native_labels.insert((lang, label)) {
+                    continue;
+                }
+                sink.push(DataEntry::ObjectLabel {
                     id: obj_id.into(),
                     lang: lang.into(),
                     label: label.into(),
                     native_order: Some(native_order_index),
-                })?;
+                    source: LabelSource::NativeName,
+                });
                 native_order_index += 1;
             } else {
                 warn!(
-                    "skipping {} P1448 native label because it has invalid type",
-                    obj_id
+                    "skipping {} {} native label because it has invalid type",
+                    obj_id, property
                 );
+                quality_event(quality_events, sink, obj_id, property, "invalid type")?;
             }
         }
     }
@@ -333,10 +732,83 @@ fn handle_human_settlement(obj: &Value, sink: &Sender<DataEntry>) -> Result<(),
     Ok(())
 }
 
+/// A cheap check of raw lines, which rules out most entities of the dump (e.g. scholarly articles
+/// and paintings) without parsing their JSON.
+///
+/// Only instances (P31) of the extracted classes and, for countries, entities with an ISO code
+/// (P297) produce entries, so lines that mention neither any of those classes nor P297 can be
+/// skipped. Mentions anywhere in the line count, so this never skips an entity that
+/// [`handle_line`] would extract.
+pub struct Prefilter {
+    /// Numeric IDs of the extracted classes.
+    classes: HashSet<u64>,
+    countries: bool,
+}
+
+impl Prefilter {
+    pub fn new(classes: &Classes, options: &Options) -> Self {
+        let mut class_sets = Vec::new();
+        if options.extracts(EntityType::Country) {
+            class_sets.push(&classes.sovereign_states);
+        }
+        if options.extracts(EntityType::TerritorialEntity) {
+            class_sets.push(&classes.territorial_entities);
+        }
+        if options.extracts(EntityType::Settlement) {
+            class_sets.push(&classes.human_settlements);
+        }
+        if options.extracts(EntityType::Language) {
+            class_sets.push(&classes.languages);
+        }
+        Prefilter {
+            classes: class_sets
+                .into_iter()
+                .flatten()
+                .filter_map(|id| id.strip_prefix('Q')?.parse().ok())
+                .collect(),
+            countries: options.extracts(EntityType::Country),
+        }
+    }
+
+    /// Whether the entity on the line may produce entries.
+    pub fn may_match(&self, line: &str) -> bool {
+        (self.countries && line.contains("\"P297\""))
+            || self.mentions_class(line, "\"Q")
+            // values of non-canonical data may only have a numeric ID
+            || self.mentions_class(line, "\"numeric-id\":")
+    }
+
+    /// Whether the number after any occurrence of `prefix` is the ID of one of the classes.
+    fn mentions_class(&self, line: &str, prefix: &str) -> bool {
+        line.match_indices(prefix).any(|(start, _)| {
+            let rest = &line[start + prefix.len()..];
+            let end = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            matches!(rest[..end].parse::<u64>(), Ok(id) if self.classes.contains(&id))
+        })
+    }
+}
+
+/// Logs why an entity is skipped, with a `skip_reason` field for structured logs. Skips are only
+/// shown with `--verbose`, unless entities are being debugged.
+fn log_skip(debug: bool, reason: &str, message: &str) {
+    let level = if debug {
+        log::Level::Info
+    } else {
+        log::Level::Debug
+    };
+    log!(level, skip_reason = reason; "{}", message);
+}
+
+/// Extracts the entries of the entity on a line of the dump and appends them to `sink`, so that
+/// they can be sent to the writer as one batch.
 pub fn handle_line(
     mut line: &str,
     classes: &Classes,
-    sink: &Sender<DataEntry>,
+    options: &Options,
+    sink: &mut Vec<DataEntry>,
+    matched: Option<&Sender<String>>,
     debug: bool,
 ) -> Result<(), HandleLineError> {
     if line.len() <= 1 {
@@ -347,15 +819,39 @@ pub fn handle_line(
     if line.ends_with(',') {
         line = &line[..line.len() - 1];
     }
-    let obj: Value = serde_json::from_str(line)?;
-    let obj_id = json_get!(value(obj).id: string).expect("object has no id!");
+    let mut buffer = Vec::new();
+    let obj: Entity = json::parse_line(line, &mut buffer)?;
+    let obj_id: &str = &obj.id;
+    let _log_context = logging::enter_entity(obj_id.into());
+
+    // properties (P…) and lexemes (L…) are in the dump too, but only items (Q…) are of interest
+    let is_item = match obj.entity_type.as_deref() {
+        Some(entity_type) => entity_type == "item",
+        None => obj_id.starts_with('Q'),
+    };
+    if !is_item {
+        SKIPPED_BY_TYPE.fetch_add(1, Ordering::Relaxed);
+        log_skip(debug, "not_item", "entity is not an item; skipping");
+        return Ok(());
+    }
+
+    if let Some(resume_from_id) = &options.resume_from_id {
+        if resume_from_id.skips(obj_id) {
+            return Ok(());
+        }
+    }
+
+    if options.update {
+        // also removes the entries of items that are no longer extracted
+        sink.push(DataEntry::RemoveEntity { id: obj_id.into() });
+    }
 
     // P1366 replaced by but not P518 applies to part
-    let replaced_by = json_get!(value(obj).claims.P1366: array).map_or(false, |a| {
+    let replaced_by = obj.claims.get("P1366").is_some_and(|a| {
         let replaced_by = !a.is_empty();
         let mut applies_to_part = false;
         for item in a {
-            if json_get!(value(item).qualifiers.P518: array).map_or(false, |a| !a.is_empty()) {
+            if !item.qualifiers.applies_to_part.is_empty() {
                 applies_to_part = true;
                 break;
             }
@@ -363,60 +859,99 @@ pub fn handle_line(
         replaced_by && !applies_to_part
     });
 
-    if replaced_by || json_get!(value(obj).claims.P576: array).map_or(false, |a| !a.is_empty()) {
+    if !options.include_historical && (replaced_by || !obj.claims.statements("P576").is_empty()) {
         // P1366: "replaced by"
         // P576: "dissolved date"
         // -> don't care about this object
-        if debug {
-            println!("entity has P1366 (replaced by) or P576 (dissolved date); skipping");
-        }
+        log_skip(
+            debug,
+            "historical",
+            "entity has P1366 (replaced by) or P576 (dissolved date); skipping",
+        );
         return Ok(());
     }
 
-    if let Some(code_entries) = json_get!(value(obj).claims.P297: array) {
-        let mut code_entry = None;
-        for entry in code_entries {
-            if is_object_active(json_get!(value(entry).qualifiers: object)) {
-                code_entry = Some(entry);
-                break;
+    let code_entries = if options.extracts(EntityType::Country) {
+        obj.claims.get("P297")
+    } else {
+        None
+    };
+    // sovereign states are countries even if they have no ISO code
+    let is_sovereign = options.extracts(EntityType::Country)
+        && is_subclass_of(&obj, &classes.sovereign_states, debug, "sovereign state");
+    let is_country = code_entries.is_some() || is_sovereign;
+    if is_country {
+        let mut codes = Vec::new();
+        for entry in code_entries.into_iter().flatten() {
+            if !is_object_active(&entry.qualifiers) {
+                continue;
+            }
+            let rank = match statement_rank(entry) {
+                Some(rank) => rank,
+                None => continue,
+            };
+            if let Some(iso) = entry.mainsnak.value_str() {
+                codes.push((rank, iso.to_ascii_lowercase()));
             }
         }
 
-        if let Some(iso) = json_get!(optval(code_entry).mainsnak.datavalue.value: string) {
-            sink.send(DataEntry::Country {
-                id: obj_id.into(),
-                iso: iso.to_ascii_lowercase(),
-            })?;
+        // best rank wins; the sort is stable, so claim order breaks ties
+        codes.sort_by_key(|(rank, _)| std::cmp::Reverse(*rank));
+        let iso = codes.first().map(|(_, iso)| iso.clone());
+        let conflicting = match &iso {
+            Some(iso) => codes.iter().any(|(_, other)| other != iso),
+            None => false,
+        };
+        if conflicting {
+            warn!(
+                "{} has conflicting P297 ISO codes {:?}; using {}",
+                obj_id,
+                codes
+                    .iter()
+                    .map(|(_, iso)| iso.as_str())
+                    .collect::<Vec<_>>(),
+                iso.as_deref().unwrap_or_default()
+            );
+            quality_event(
+                options.quality_events,
+                sink,
+                obj_id,
+                "P297",
+                "conflicting ISO codes",
+            )?;
         }
 
-        let mut lang_index = 0;
-        if let Some(langs) = json_get!(value(obj).claims.P37: array) {
-            for lang in langs {
-                if !is_object_active(json_get!(value(lang).qualifiers: object)) {
-                    continue;
-                }
-                if let Some(lang_id) = json_get!(value(lang).mainsnak.datavalue.value.id: string) {
-                    sink.send(DataEntry::ObjectLanguage {
-                        id: obj_id.into(),
-                        lang_id: lang_id.into(),
-                        index: lang_index,
-                    })?;
-                    lang_index += 1;
-                }
-            }
+        if iso.is_some() || is_sovereign {
+            sink.push(DataEntry::Country {
+                id: obj_id.into(),
+                iso,
+                conflicting,
+                sovereign: is_sovereign,
+                revision: entity_revision(&obj),
+            });
         }
+
+        handle_object_languages(
+            &obj,
+            "P37",
+            LanguageKind::Official,
+            options.quality_events,
+            sink,
+        )?;
     }
 
-    let is_territorial_entity = is_subclass_of(
-        &obj,
-        &classes.territorial_entities,
-        debug,
-        "territorial entity",
-    );
-    let is_human_settlement =
-        is_subclass_of(&obj, &classes.human_settlements, debug, "human settlement");
+    let is_territorial_entity = options.extracts(EntityType::TerritorialEntity)
+        && is_subclass_of(
+            &obj,
+            &classes.territorial_entities,
+            debug,
+            "territorial entity",
+        );
+    let is_human_settlement = options.extracts(EntityType::Settlement)
+        && is_subclass_of(&obj, &classes.human_settlements, debug, "human settlement");
     let is_excluded = is_subclass_of(&obj, &classes.excluded, debug, "excluded");
-    let is_language = is_subclass_of(&obj, &classes.languages, debug, "languages");
+    let is_language = options.extracts(EntityType::Language)
+        && is_subclass_of(&obj, &classes.languages, debug, "languages");
 
     if debug {
         info!("is territorial entity: {is_territorial_entity}");
@@ -425,7 +960,31 @@ pub fn handle_line(
         info!("is language: {is_language}");
     }
 
-    if is_territorial_entity && !is_excluded {
+    let mut is_place = (is_territorial_entity || is_human_settlement) && !is_excluded;
+    if is_place && !passes_filters(&obj, is_human_settlement, options, classes) {
+        SKIPPED_BY_FILTER.fetch_add(1, Ordering::Relaxed);
+        log_skip(debug, "filters", "place doesn't pass the filters; skipping");
+        is_place = false;
+    }
+    let matched_types = [
+        is_country,
+        is_territorial_entity && is_place,
+        is_human_settlement && is_place,
+        is_language,
+    ];
+    for (count, matched) in MATCHED_BY_TYPE.iter().zip(matched_types) {
+        if matched {
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    if is_country || is_place || is_language {
+        MATCHED_ENTITIES.fetch_add(1, Ordering::Relaxed);
+        if let Some(matched) = matched {
+            matched.send(line.to_string())?;
+        }
+    }
+
+    if is_territorial_entity && is_place {
         let is_2nd = is_subclass_of(
             &obj,
             &classes.second_level_admin_div,
@@ -433,14 +992,28 @@ pub fn handle_line(
             "second level admin div",
         );
 
+        // subdivision level of the country (1 = first-level subdivision)
+        let admin_level = if is_2nd {
+            Some(1)
+        } else if is_subclass_of(
+            &obj,
+            &classes.third_level_admin_div,
+            debug,
+            "third level admin div",
+        ) {
+            Some(2)
+        } else {
+            None
+        };
+
         if debug {
             info!("is a non-excluded territorial entity - calling handler");
         }
 
-        handle_territorial_entity(&obj, is_2nd, sink)?;
+        handle_territorial_entity(&obj, is_2nd, admin_level, options, sink)?;
     }
     if is_human_settlement
-        && !is_excluded
+        && is_place
         && !is_subclass_of(
             &obj,
             &classes.excluded_settlements,
@@ -452,30 +1025,129 @@ pub fn handle_line(
             info!("is a non-excluded human settlement - calling handler");
         }
 
-        handle_human_settlement(&obj, sink)?;
+        handle_human_settlement(&obj, options.part_of, options, sink)?;
     }
     if is_language {
         handle_language(&obj, sink)?;
     }
 
+    if options.statement_coords && is_place {
+        handle_statement_coords(
+            &obj,
+            options.coord_crs,
+            options.validate_coords,
+            options.quality_events,
+            sink,
+        )?;
+    }
+    if options.replaces && is_place {
+        handle_replaces(&obj, options.quality_events, sink)?;
+    }
+    if options.part_of && is_place {
+        handle_part_of(&obj, options.quality_events, sink)?;
+    }
+    if options.aliases && is_place {
+        handle_aliases(
+            &obj,
+            options.alias_dedup_scope,
+            &options.label_languages,
+            sink,
+        )?;
+    }
+    if options.short_name_labels && is_place {
+        handle_short_names(&obj, &options.label_languages, options.quality_events, sink)?;
+    }
+
     Ok(())
 }
 
-fn is_subclass_of(obj: &Value, classes: &HashSet<String>, debug: bool, debug_label: &str) -> bool {
+/// The revision of the entity in the dump (`lastrevid`), if it is a number.
+fn entity_revision(obj: &Entity) -> Option<u64> {
+    obj.lastrevid
+}
+
+/// Whether a place passes the filters in the options. All of them must match. The minimum
+/// population only applies to settlements, since territorial entities often have no P1082.
+fn passes_filters(obj: &Entity, is_settlement: bool, options: &Options, classes: &Classes) -> bool {
+    let statements = |property: &str| obj.claims.statements(property);
+    let any_value_in = |property: &str, ids: &HashSet<String>| {
+        statements(property)
+            .iter()
+            .filter_map(Statement::value_id)
+            .any(|id| ids.contains(id.as_ref()))
+    };
+
+    let min_population = options.min_population.filter(|_| is_settlement);
+    if let Some(min_population) = min_population {
+        let population = statements("P1082")
+            .iter()
+            .filter(|statement| statement_rank(statement).is_some())
+            .filter_map(|statement| statement.mainsnak.value_object())
+            .filter(|quantity| {
+                quantity
+                    .unit
+                    .as_deref()
+                    .is_some_and(|unit| POPULATION_UNITS.contains(&unit))
+            })
+            .filter_map(|quantity| quantity.amount.as_deref())
+            .filter_map(|amount| parse_quantity(amount, &QuantityFormat::WIKIDATA))
+            .max()
+            .unwrap_or(0);
+        if population < min_population {
+            return false;
+        }
+    }
+    if options
+        .require_properties
+        .iter()
+        .any(|property| statements(property).is_empty())
+    {
+        return false;
+    }
+    if !options.countries.is_empty() && !any_value_in("P17", &options.countries) {
+        return false;
+    }
+    if !options.classes.is_empty() && !any_value_in("P31", &options.classes) {
+        return false;
+    }
+    if let Some(region) = &classes.only_country {
+        let in_country = statements("P17")
+            .iter()
+            .filter_map(Statement::value_id)
+            .any(|id| id == region.country.as_str())
+            || any_value_in("P131", &region.entities);
+        if !in_country {
+            return false;
+        }
+    }
+    true
+}
+
+/// Statement rank for picking between claims: 2 for preferred, 1 for normal.
+/// Returns None for deprecated statements.
+fn statement_rank(statement: &Statement) -> Option<u8> {
+    match statement.rank {
+        Rank::Preferred => Some(2),
+        Rank::Normal => Some(1),
+        Rank::Deprecated => None,
+    }
+}
+
+fn is_subclass_of(obj: &Entity, classes: &HashSet<String>, debug: bool, debug_label: &str) -> bool {
     if debug {
         info!("checking - is object subclass of {debug_label}?");
     }
 
-    if let Some(parents) = json_get!(value(obj).claims.P31: array) {
+    if let Some(parents) = obj.claims.get("P31") {
         for parent in parents {
-            if let Some(id) = json_get!(value(parent).mainsnak.datavalue.value.id: string) {
-                if classes.contains(id) {
+            if let Some(id) = parent.value_id() {
+                if classes.contains(id.as_ref()) {
                     // check if this relation is expired
-                    if !is_object_active(json_get!(value(parent).qualifiers: object)) {
+                    if !is_object_active(&parent.qualifiers) {
                         continue;
                     }
 
-                    if json_get!(value(parent).qualifiers.P1366[0]: object).is_some() {
+                    if !parent.qualifiers.replaced_by.is_empty() {
                         // P1366: "replaced by"
                         continue;
                     }
@@ -499,25 +1171,574 @@ fn is_subclass_of(obj: &Value, classes: &HashSet<String>, debug: bool, debug_lab
 #[derive(Debug, Error)]
 pub enum HandleLineError {
     #[error("JSON error: {0}")]
-    Json(#[from] serde_json::Error),
-    #[error("crossbeam channel send error: {0}")]
-    Sink(#[from] crossbeam::channel::SendError<DataEntry>),
+    Json(#[from] json::ParseError),
+    #[error("crossbeam channel send error for matched entity: {0}")]
+    Matched(#[from] crossbeam::channel::SendError<String>),
+}
+
+impl HandleLineError {
+    /// For JSON errors, returns the part of the line around the error position, with a marker
+    /// below the error position.
+    pub fn json_fragment(&self, line: &str) -> Option<String> {
+        const CONTEXT: usize = 60;
+
+        let err = match self {
+            HandleLineError::Json(err) => err,
+            _ => return None,
+        };
+
+        let mut pos = json::error_offset(err).min(line.len());
+        while !line.is_char_boundary(pos) {
+            pos -= 1;
+        }
+        let mut start = pos.saturating_sub(CONTEXT);
+        while !line.is_char_boundary(start) {
+            start -= 1;
+        }
+        let mut end = (pos + CONTEXT).min(line.len());
+        while !line.is_char_boundary(end) {
+            end += 1;
+        }
+
+        let prefix = if start > 0 { "…" } else { "" };
+        let suffix = if end < line.len() { "…" } else { "" };
+        let marker_offset = prefix.chars().count() + line[start..pos].chars().count();
+        Some(format!(
+            "{}{}{}\n{}^",
+            prefix,
+            &line[start..end],
+            suffix,
+            " ".repeat(marker_offset)
+        ))
+    }
+}
+
+/// Number format of quantity amounts.
+///
+/// Wikidata normalizes amounts to a signed decimal with `.` as the decimal separator and no
+/// digit grouping (e.g. `+1234.5`), which is what [`QuantityFormat::WIKIDATA`] describes.
+pub struct QuantityFormat {
+    pub decimal_separator: char,
+    pub thousands_separator: Option<char>,
 }
 
-fn parse_quantity(n: &str) -> Option<u64> {
-    let should_keep_char = |c: &char| match c {
-        c if c.is_whitespace() => false,
-        ',' | '.' | '+' => false, // thousands separators and leading +
-        _ => true,
+impl QuantityFormat {
+    pub const WIKIDATA: QuantityFormat = QuantityFormat {
+        decimal_separator: '.',
+        thousands_separator: None,
+    };
+
+    fn parse_f64(&self, n: &str) -> Option<f64> {
+        if self.decimal_separator == '.' {
+            n.parse().ok()
+        } else {
+            n.replace(self.decimal_separator, ".").parse().ok()
+        }
+    }
+}
+
+/// Parses a quantity amount as a non-negative integer. Fractional amounts (also in exponent
+/// notation, e.g. `1.5e3`) are rounded to the nearest integer, with halves rounded up. Negative
+/// amounts, which aren't counts, and amounts too large for a u64 are rejected.
+fn parse_quantity(n: &str, format: &QuantityFormat) -> Option<u64> {
+    let n = n.strip_prefix('+').unwrap_or(n);
+
+    let value = match n.parse::<u64>() {
+        Ok(value) => return Some(value),
+        Err(_) => match format.parse_f64(n) {
+            Some(value) => value,
+            None => {
+                // malformed amount: try again without whitespace and digit grouping
+                let cleaned = n
+                    .chars()
+                    .filter(|c| !c.is_whitespace() && Some(*c) != format.thousands_separator)
+                    .collect::<String>();
+                format.parse_f64(cleaned.strip_prefix('+').unwrap_or(&cleaned))?
+            }
+        },
     };
 
-    if n.contains(|c| !should_keep_char(&c)) {
-        n.chars()
-            .filter(should_keep_char)
-            .collect::<String>()
-            .parse()
-            .ok()
+    let value = value.round();
+    // -0.5 rounds to -0, which is fine
+    if value >= 0. && value < u64::MAX as f64 {
+        Some(value as u64)
     } else {
-        n.parse().ok()
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wiki_sparql::CountryRegion;
+
+    fn classes() -> Classes {
+        let set = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect();
+        Classes {
+            territorial_entities: set(&["Q1221156"]),
+            human_settlements: set(&["Q515"]),
+            excluded: set(&[]),
+            excluded_settlements: set(&[]),
+            second_level_admin_div: set(&["Q1221156"]),
+            third_level_admin_div: set(&[]),
+            sovereign_states: set(&["Q3624078"]),
+            languages: set(&[]),
+            only_country: None,
+        }
+    }
+
+    fn entries(line: &str) -> Vec<DataEntry> {
+        entries_with(line, &Options::default())
+    }
+
+    fn entries_with(line: &str, options: &Options) -> Vec<DataEntry> {
+        let mut entries = Vec::new();
+        handle_line(line, &classes(), options, &mut entries, None, false).unwrap();
+        entries
+    }
+
+    /// A city with a population in the unit.
+    fn city_with_population(unit: &str) -> String {
+        format!(
+            r#"{{"type":"item","id":"Q1055","claims":{{"P31":[{{"mainsnak":{{"snaktype":"value","property":"P31","datavalue":{{"value":{{"entity-type":"item","numeric-id":515,"id":"Q515"}},"type":"wikibase-entityid"}}}},"type":"statement","rank":"normal"}}],"P17":[{{"mainsnak":{{"snaktype":"value","property":"P17","datavalue":{{"value":{{"entity-type":"item","numeric-id":183,"id":"Q183"}},"type":"wikibase-entityid"}}}},"type":"statement","rank":"normal"}}],"P1082":[{{"mainsnak":{{"snaktype":"value","property":"P1082","datavalue":{{"value":{{"amount":"+1945532","unit":"{}"}},"type":"quantity"}}}},"type":"statement","rank":"normal","qualifiers":{{"P585":[{{"snaktype":"value","property":"P585","datavalue":{{"value":{{"time":"+2022-12-31T00:00:00Z","timezone":0,"before":0,"after":0,"precision":11,"calendarmodel":"http://www.wikidata.org/entity/Q1985727"}},"type":"time"}}}}]}}}}]}}}},"#,
+            unit
+        )
+    }
+
+    fn population(entries: &[DataEntry]) -> Option<u64> {
+        entries
+            .iter()
+            .find_map(|entry| match entry {
+                DataEntry::City { population, .. } => Some(*population),
+                _ => None,
+            })
+            .expect("no city")
+    }
+
+    #[test]
+    fn population_units() {
+        let line = city_with_population("1");
+        assert_eq!(population(&entries(&line)), Some(1945532));
+        // person
+        let line = city_with_population("http://www.wikidata.org/entity/Q215627");
+        assert_eq!(population(&entries(&line)), Some(1945532));
+        // human
+        let line = city_with_population("http://www.wikidata.org/entity/Q5");
+        assert_eq!(population(&entries(&line)), Some(1945532));
+        // square kilometre
+        let line = city_with_population("http://www.wikidata.org/entity/Q712226");
+        assert_eq!(population(&entries(&line)), None);
+    }
+
+    /// Statements of the property with the items as values.
+    fn item_claims(property: &str, ids: &[&str]) -> String {
+        let statements: Vec<_> = ids
+            .iter()
+            .map(|id| {
+                format!(
+                    r#"{{"mainsnak":{{"snaktype":"value","property":"{}","datavalue":{{"value":{{"entity-type":"item","numeric-id":{},"id":"{}"}},"type":"wikibase-entityid"}}}},"type":"statement","rank":"normal"}}"#,
+                    property,
+                    &id[1..],
+                    id
+                )
+            })
+            .collect();
+        format!(r#""{}":[{}]"#, property, statements.join(","))
+    }
+
+    /// P1082 statements with the amounts, units and ranks.
+    fn population_claims(populations: &[(u64, &str, &str)]) -> String {
+        let statements: Vec<_> = populations
+            .iter()
+            .map(|(amount, unit, rank)| {
+                format!(
+                    r#"{{"mainsnak":{{"snaktype":"value","property":"P1082","datavalue":{{"value":{{"amount":"+{}","unit":"{}"}},"type":"quantity"}}}},"type":"statement","rank":"{}"}}"#,
+                    amount, unit, rank
+                )
+            })
+            .collect();
+        format!(r#""P1082":[{}]"#, statements.join(","))
+    }
+
+    /// Whether a settlement with the claims passes the filters.
+    fn passes(claims: &[String], options: &Options, classes: &Classes) -> bool {
+        place_passes(claims, true, options, classes)
+    }
+
+    fn place_passes(
+        claims: &[String],
+        is_settlement: bool,
+        options: &Options,
+        classes: &Classes,
+    ) -> bool {
+        let line = format!(
+            r#"{{"type":"item","id":"Q1","claims":{{{}}}}}"#,
+            claims.join(",")
+        );
+        let mut buffer = Vec::new();
+        let obj: Entity = json::parse_line(&line, &mut buffer).unwrap();
+        passes_filters(&obj, is_settlement, options, classes)
+    }
+
+    #[test]
+    fn filter_by_min_population() {
+        let options = Options {
+            min_population: Some(100_000),
+            ..Options::default()
+        };
+        let passes = |populations| passes(&[population_claims(populations)], &options, &classes());
+        assert!(passes(&[(150_000, "1", "normal")]));
+        assert!(passes(&[
+            (90_000, "1", "normal"),
+            (120_000, "1", "preferred")
+        ]));
+        assert!(!passes(&[(90_000, "1", "normal")]));
+        assert!(!passes(&[]));
+        // deprecated statements and densities don't count
+        assert!(!passes(&[
+            (90_000, "1", "normal"),
+            (150_000, "1", "deprecated")
+        ]));
+        assert!(!passes(&[(
+            150_000,
+            "http://www.wikidata.org/entity/Q712226",
+            "normal"
+        )]));
+        assert!(passes(&[(
+            150_000,
+            "http://www.wikidata.org/entity/Q215627",
+            "normal"
+        )]));
+
+        // territorial entities aren't filtered by population
+        assert!(place_passes(&[], false, &options, &classes()));
+        assert!(place_passes(
+            &[population_claims(&[(90_000, "1", "normal")])],
+            false,
+            &options,
+            &classes()
+        ));
+    }
+
+    #[test]
+    fn filter_by_properties_countries_and_classes() {
+        let set = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect();
+        let city = [item_claims("P31", &["Q515"]), item_claims("P17", &["Q183"])];
+
+        let options = Options {
+            require_properties: vec!["P31".into(), "P17".into()],
+            ..Options::default()
+        };
+        assert!(passes(&city, &options, &classes()));
+        assert!(!passes(&city[..1], &options, &classes()));
+
+        let options = Options {
+            countries: set(&["Q183", "Q40"]),
+            ..Options::default()
+        };
+        assert!(passes(&city, &options, &classes()));
+        assert!(!passes(&city[..1], &options, &classes()));
+        assert!(!passes(
+            &[item_claims("P17", &["Q142"])],
+            &options,
+            &classes()
+        ));
+
+        let options = Options {
+            classes: set(&["Q515"]),
+            ..Options::default()
+        };
+        assert!(passes(&city, &options, &classes()));
+        // subclasses are not followed
+        assert!(!passes(
+            &[item_claims("P31", &["Q1549591"])],
+            &options,
+            &classes()
+        ));
+
+        // all filters must match
+        let options = Options {
+            countries: set(&["Q142"]),
+            classes: set(&["Q515"]),
+            ..Options::default()
+        };
+        assert!(!passes(&city, &options, &classes()));
+    }
+
+    #[test]
+    fn filter_by_country_region() {
+        let set = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect();
+        let in_germany = Classes {
+            only_country: Some(CountryRegion {
+                country: "Q183".into(),
+                entities: set(&["Q183", "Q1208"]),
+            }),
+            ..classes()
+        };
+        let options = Options::default();
+        assert!(passes(
+            &[item_claims("P17", &["Q183"])],
+            &options,
+            &in_germany
+        ));
+        // located in an entity in the country, without a country of its own
+        assert!(passes(
+            &[item_claims("P131", &["Q1208"])],
+            &options,
+            &in_germany
+        ));
+        assert!(passes(
+            &[item_claims("P131", &["Q90", "Q1208"])],
+            &options,
+            &in_germany
+        ));
+        assert!(!passes(
+            &[item_claims("P17", &["Q142"]), item_claims("P131", &["Q90"])],
+            &options,
+            &in_germany
+        ));
+        assert!(!passes(&[], &options, &in_germany));
+        assert!(passes(&[], &options, &classes()));
+    }
+
+    /// Reads the coordinates of a P625 snak with the validation.
+    fn coordinates(lat: f64, lon: f64, validation: CoordValidation) -> Option<(f64, f64)> {
+        let snak = format!(
+            r#"{{"snaktype":"value","property":"P625","datavalue":{{"value":{{"latitude":{},"longitude":{},"precision":0.0001,"globe":"http://www.wikidata.org/entity/Q2"}},"type":"globecoordinate"}}}}"#,
+            lat, lon
+        );
+        let mut buffer = Vec::new();
+        let snak: Snak = json::parse_line(&snak, &mut buffer).unwrap();
+        snak_coordinates(
+            "Q1",
+            &snak,
+            CoordCrs::Wgs84,
+            validation,
+            false,
+            &mut Vec::new(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn skip_lexemes_and_properties() {
+        // instances of a city, which would be extracted if they were items
+        let p31 = r#""claims":{"P31":[{"mainsnak":{"snaktype":"value","property":"P31","datavalue":{"value":{"entity-type":"item","numeric-id":515,"id":"Q515"},"type":"wikibase-entityid"}},"type":"statement","rank":"normal"}]}"#;
+        let lines = [
+            format!(
+                r#"{{"type":"lexeme","id":"L1","lemmas":{{"en":{{"language":"en","value":"city"}}}},{}}},"#,
+                p31
+            ),
+            format!(
+                r#"{{"type":"property","id":"P17","datatype":"wikibase-item",{}}},"#,
+                p31
+            ),
+            // without a type, the ID tells them apart
+            format!(r#"{{"id":"L1",{}}},"#, p31),
+            format!(r#"{{"id":"P17",{}}},"#, p31),
+        ];
+        let skipped = SKIPPED_BY_TYPE.load(Ordering::SeqCst);
+        for line in &lines {
+            assert!(entries(line).is_empty(), "{}", line);
+        }
+        assert_eq!(
+            SKIPPED_BY_TYPE.load(Ordering::SeqCst) - skipped,
+            lines.len()
+        );
+
+        let item = format!(r#"{{"type":"item","id":"Q1055",{}}},"#, p31);
+        assert!(!entries(&item).is_empty());
+        assert_eq!(
+            SKIPPED_BY_TYPE.load(Ordering::SeqCst) - skipped,
+            lines.len()
+        );
+    }
+
+    #[test]
+    fn prefilter_lines() {
+        let prefilter = Prefilter::new(&classes(), &Options::default());
+        // cities are extracted
+        assert!(prefilter.may_match(&city_with_population("1")));
+        assert!(!entries(&city_with_population("1")).is_empty());
+        // an instance of a class that isn't extracted, whose ID starts like one that is
+        assert!(!prefilter.may_match(
+            r#"{"type":"item","id":"Q1","claims":{"P31":[{"mainsnak":{"datavalue":{"value":{"entity-type":"item","numeric-id":5150,"id":"Q5150"}}}}]}},"#
+        ));
+        // non-canonical data without the string ID
+        assert!(prefilter.may_match(
+            r#"{"type":"item","id":"Q1","claims":{"P31":[{"mainsnak":{"datavalue":{"value":{"entity-type":"item","numeric-id":515}}}}]}},"#
+        ));
+        let iso = r#"{"type":"item","id":"Q183","claims":{"P297":[{"mainsnak":{"datavalue":{"value":"DE"}}}]}},"#;
+        assert!(prefilter.may_match(iso));
+
+        // without countries, only the classes count
+        let options = Options {
+            entity_types: [EntityType::Settlement].iter().copied().collect(),
+            ..Options::default()
+        };
+        let prefilter = Prefilter::new(&classes(), &options);
+        assert!(!prefilter.may_match(iso));
+        assert!(prefilter.may_match(&city_with_population("1")));
+        // Q1221156 is a territorial entity
+        assert!(!prefilter.may_match(
+            r#"{"type":"item","id":"Q1208","claims":{"P31":[{"mainsnak":{"datavalue":{"value":{"entity-type":"item","numeric-id":1221156,"id":"Q1221156"}}}}]}},"#
+        ));
+    }
+
+    #[test]
+    fn quality_events() {
+        let is_event = |entry: &DataEntry| matches!(entry, DataEntry::QualityEvent { .. });
+        // square kilometre
+        let line = city_with_population("http://www.wikidata.org/entity/Q712226");
+        assert!(!entries(&line).iter().any(is_event));
+        let options = Options {
+            quality_events: true,
+            ..Options::default()
+        };
+        let events: Vec<_> = entries_with(&line, &options)
+            .into_iter()
+            .filter_map(|entry| match entry {
+                DataEntry::QualityEvent {
+                    id,
+                    property,
+                    reason,
+                } => Some((id, property, reason)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            events,
+            [("Q1055".into(), "P1082".into(), "unknown unit".into())]
+        );
+    }
+
+    #[test]
+    fn official_languages_first() {
+        let language = |property: &str, id: u64| {
+            format!(
+                r#""{0}":[{{"mainsnak":{{"snaktype":"value","property":"{0}","datavalue":{{"value":{{"entity-type":"item","numeric-id":{1},"id":"Q{1}"}},"type":"wikibase-entityid"}}}},"type":"statement","rank":"normal"}}]"#,
+                property, id
+            )
+        };
+        let line = format!(
+            r#"{{"type":"item","id":"Q1208","claims":{{"P31":[{{"mainsnak":{{"snaktype":"value","property":"P31","datavalue":{{"value":{{"entity-type":"item","numeric-id":1221156,"id":"Q1221156"}},"type":"wikibase-entityid"}}}},"type":"statement","rank":"normal"}}],{},{}}}}},"#,
+            language("P2936", 188),
+            language("P37", 188)
+        );
+        let kinds: Vec<_> = entries(&line)
+            .into_iter()
+            .filter_map(|entry| match entry {
+                DataEntry::ObjectLanguage { lang_id, kind, .. } => Some((lang_id, kind)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                ("Q188".into(), LanguageKind::Official),
+                ("Q188".into(), LanguageKind::Used)
+            ]
+        );
+    }
+
+    #[test]
+    fn validate_coordinates() {
+        for validation in [
+            CoordValidation::Off,
+            CoordValidation::Skip,
+            CoordValidation::Clamp,
+            CoordValidation::Swap,
+        ] {
+            assert_eq!(
+                coordinates(52.5, 13.4, validation),
+                Some((52.5, 13.4)),
+                "{:?}",
+                validation
+            );
+        }
+
+        assert_eq!(
+            coordinates(120., 45., CoordValidation::Off),
+            Some((120., 45.))
+        );
+        assert_eq!(coordinates(120., 45., CoordValidation::Skip), None);
+        assert_eq!(
+            coordinates(120., 45., CoordValidation::Clamp),
+            Some((90., 45.))
+        );
+        assert_eq!(
+            coordinates(120., 45., CoordValidation::Swap),
+            Some((45., 120.))
+        );
+        assert_eq!(coordinates(-100., 200., CoordValidation::Swap), None);
+    }
+
+    #[test]
+    fn project_validated_coordinates() {
+        let snak = r#"{"snaktype":"value","property":"P625","datavalue":{"value":{"latitude":0,"longitude":200},"type":"globecoordinate"}}"#;
+        let mut buffer = Vec::new();
+        let snak: Snak = json::parse_line(snak, &mut buffer).unwrap();
+        // clamped to the antimeridian before the projection
+        let (y, x) = snak_coordinates(
+            "Q1",
+            &snak,
+            CoordCrs::WebMercator,
+            CoordValidation::Clamp,
+            false,
+            &mut Vec::new(),
+        )
+        .unwrap()
+        .unwrap();
+        assert!(y.abs() < 1e-6);
+        assert!((x - 20037508.342789244).abs() < 1e-3, "{}", x);
+    }
+
+    #[test]
+    fn skip_snak_without_value() {
+        let snak = r#"{"snaktype":"novalue","property":"P625"}"#;
+        let mut buffer = Vec::new();
+        let snak: Snak = json::parse_line(snak, &mut buffer).unwrap();
+        let coordinates = snak_coordinates(
+            "Q1",
+            &snak,
+            CoordCrs::Wgs84,
+            CoordValidation::Off,
+            false,
+            &mut Vec::new(),
+        );
+        assert_eq!(coordinates.unwrap(), None);
+    }
+
+    #[test]
+    fn parse_wikidata_quantities() {
+        let parse = |n| parse_quantity(n, &QuantityFormat::WIKIDATA);
+        assert_eq!(parse("+1000000"), Some(1_000_000));
+        assert_eq!(parse("1000000"), Some(1_000_000));
+        assert_eq!(parse("+0"), Some(0));
+        assert_eq!(parse("1.0e6"), Some(1_000_000));
+        assert_eq!(parse("+1.5E3"), Some(1500));
+        // fractional amounts are rounded
+        assert_eq!(parse("+5.5"), Some(6));
+        assert_eq!(parse("+5.49"), Some(5));
+        assert_eq!(parse("-0.4"), Some(0));
+        // negative amounts aren't counts
+        assert_eq!(parse("-5.5"), None);
+        assert_eq!(parse("-1"), None);
+        assert_eq!(parse("+1e30"), None);
+        assert_eq!(parse("NaN"), None);
+        assert_eq!(parse("+inf"), None);
+        assert_eq!(parse(""), None);
+        // malformed amounts with whitespace
+        assert_eq!(parse("+1 000"), Some(1000));
+    }
+
+    #[test]
+    fn parse_grouped_quantities() {
+        let format = QuantityFormat {
+            decimal_separator: ',',
+            thousands_separator: Some('.'),
+        };
+        assert_eq!(parse_quantity("1.234.567", &format), Some(1_234_567));
+        assert_eq!(parse_quantity("+12,5", &format), Some(13));
     }
 }
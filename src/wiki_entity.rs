@@ -0,0 +1,433 @@
+//! Typed views of the parts of Wikidata entity JSON that are extracted.
+//!
+//! Lines are deserialized directly into these structs instead of a full JSON value tree. Fields
+//! that aren't read are skipped, and strings borrow from the line where possible.
+
+use serde::de::value::MapAccessDeserializer;
+use serde::de::{self, Deserializer, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde::Deserialize;
+use std::borrow::{Borrow, Cow};
+use std::collections::HashMap;
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::Deref;
+
+/// A string that borrows from the line unless it had to be unescaped.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Str<'a>(pub Cow<'a, str>);
+
+impl Deref for Str<'_> {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Borrow<str> for Str<'_> {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl<'de: 'a, 'a> Deserialize<'de> for Str<'a> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct StrVisitor;
+
+        impl<'de> Visitor<'de> for StrVisitor {
+            type Value = Str<'de>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a string")
+            }
+
+            fn visit_borrowed_str<E: de::Error>(self, v: &'de str) -> Result<Self::Value, E> {
+                Ok(Str(Cow::Borrowed(v)))
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                Ok(Str(Cow::Owned(v.to_string())))
+            }
+
+            fn visit_string<E: de::Error>(self, v: String) -> Result<Self::Value, E> {
+                Ok(Str(Cow::Owned(v)))
+            }
+        }
+
+        deserializer.deserialize_str(StrVisitor)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Entity<'a> {
+    #[serde(borrow)]
+    pub id: Str<'a>,
+    /// `item`, `property`, `lexeme`, …
+    #[serde(rename = "type", default, borrow)]
+    pub entity_type: Option<Str<'a>>,
+    /// The revision of the entity in the dump.
+    #[serde(default)]
+    pub lastrevid: Option<u64>,
+    #[serde(default, borrow, deserialize_with = "map_values")]
+    pub labels: Vec<Term<'a>>,
+    /// Aliases of each language.
+    #[serde(default, borrow, deserialize_with = "map_values")]
+    pub aliases: Vec<Vec<Term<'a>>>,
+    #[serde(default, borrow, deserialize_with = "empty_array_as_default")]
+    pub claims: Claims<'a>,
+}
+
+/// A label or an alias.
+#[derive(Deserialize)]
+pub struct Term<'a> {
+    #[serde(borrow)]
+    pub language: Str<'a>,
+    #[serde(borrow)]
+    pub value: Str<'a>,
+}
+
+/// Statements by property.
+#[derive(Default, Deserialize)]
+#[serde(transparent)]
+pub struct Claims<'a>(#[serde(borrow)] HashMap<Str<'a>, Vec<Statement<'a>>>);
+
+impl<'a> Claims<'a> {
+    pub fn get(&self, property: &str) -> Option<&[Statement<'a>]> {
+        self.0.get(property).map(Vec::as_slice)
+    }
+
+    /// The statements of a property, which are empty if there are none.
+    pub fn statements(&self, property: &str) -> &[Statement<'a>] {
+        self.get(property).unwrap_or_default()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Statement<'a> {
+    #[serde(borrow)]
+    pub mainsnak: Snak<'a>,
+    #[serde(default)]
+    pub rank: Rank,
+    #[serde(default, borrow, deserialize_with = "empty_array_as_default")]
+    pub qualifiers: Qualifiers<'a>,
+}
+
+impl<'a> Statement<'a> {
+    /// The ID of the entity the statement refers to.
+    pub fn value_id(&self) -> Option<Cow<'_, str>> {
+        self.mainsnak.value_object()?.entity_id()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Rank {
+    Preferred,
+    #[default]
+    Normal,
+    Deprecated,
+}
+
+/// The qualifiers that are read.
+#[derive(Default, Deserialize)]
+pub struct Qualifiers<'a> {
+    /// P580: start time
+    #[serde(rename = "P580", default, borrow)]
+    pub start_time: Vec<Snak<'a>>,
+    /// P582: end time
+    #[serde(rename = "P582", default, borrow)]
+    pub end_time: Vec<Snak<'a>>,
+    /// P585: point in time
+    #[serde(rename = "P585", default, borrow)]
+    pub point_in_time: Vec<Snak<'a>>,
+    /// P518: applies to part
+    #[serde(rename = "P518", default, borrow)]
+    pub applies_to_part: Vec<Snak<'a>>,
+    /// P1539: female population
+    #[serde(rename = "P1539", default, borrow)]
+    pub female_population: Vec<Snak<'a>>,
+    /// P1540: male population
+    #[serde(rename = "P1540", default, borrow)]
+    pub male_population: Vec<Snak<'a>>,
+    /// P1366: replaced by
+    #[serde(rename = "P1366", default, borrow)]
+    pub replaced_by: Vec<Snak<'a>>,
+    /// P625: coordinate location
+    #[serde(rename = "P625", default, borrow)]
+    pub coordinates: Vec<Snak<'a>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SnakType {
+    Value,
+    SomeValue,
+    NoValue,
+}
+
+#[derive(Deserialize)]
+pub struct Snak<'a> {
+    pub snaktype: SnakType,
+    #[serde(default, borrow)]
+    pub datavalue: Option<DataValue<'a>>,
+}
+
+impl<'a> Snak<'a> {
+    pub fn has_value(&self) -> bool {
+        self.snaktype == SnakType::Value
+    }
+
+    pub fn value(&self) -> Option<&Value<'a>> {
+        self.datavalue.as_ref().map(|datavalue| &datavalue.value)
+    }
+
+    /// The value of string-like types (string, external ID, URL, …).
+    pub fn value_str(&self) -> Option<&str> {
+        match self.value()? {
+            Value::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn value_object(&self) -> Option<&ValueObject<'a>> {
+        match self.value()? {
+            Value::Object(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct DataValue<'a> {
+    #[serde(borrow)]
+    pub value: Value<'a>,
+}
+
+/// `datavalue.value` of a snak.
+///
+/// The `type` of the data value comes after the value in the dump, so the fields of all object
+/// values that are read are in one [`ValueObject`].
+pub enum Value<'a> {
+    String(Str<'a>),
+    Object(Box<ValueObject<'a>>),
+    Other,
+}
+
+impl<'de: 'a, 'a> Deserialize<'de> for Value<'a> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ValueVisitor;
+
+        impl<'de> Visitor<'de> for ValueVisitor {
+            type Value = Value<'de>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a data value")
+            }
+
+            fn visit_borrowed_str<E: de::Error>(self, v: &'de str) -> Result<Self::Value, E> {
+                Ok(Value::String(Str(Cow::Borrowed(v))))
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                Ok(Value::String(Str(Cow::Owned(v.to_string()))))
+            }
+
+            fn visit_string<E: de::Error>(self, v: String) -> Result<Self::Value, E> {
+                Ok(Value::String(Str(Cow::Owned(v))))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+                ValueObject::deserialize(MapAccessDeserializer::new(map))
+                    .map(|value| Value::Object(Box::new(value)))
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                while seq.next_element::<IgnoredAny>()?.is_some() {}
+                Ok(Value::Other)
+            }
+
+            fn visit_bool<E: de::Error>(self, _: bool) -> Result<Self::Value, E> {
+                Ok(Value::Other)
+            }
+
+            fn visit_i64<E: de::Error>(self, _: i64) -> Result<Self::Value, E> {
+                Ok(Value::Other)
+            }
+
+            fn visit_u64<E: de::Error>(self, _: u64) -> Result<Self::Value, E> {
+                Ok(Value::Other)
+            }
+
+            fn visit_f64<E: de::Error>(self, _: f64) -> Result<Self::Value, E> {
+                Ok(Value::Other)
+            }
+
+            fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+                Ok(Value::Other)
+            }
+        }
+
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
+/// Fields of the object data values that are read.
+#[derive(Deserialize)]
+pub struct ValueObject<'a> {
+    // wikibase-entityid
+    #[serde(default, borrow)]
+    pub id: Option<Str<'a>>,
+    #[serde(rename = "numeric-id", default)]
+    pub numeric_id: Option<u64>,
+    #[serde(rename = "entity-type", default, borrow)]
+    pub entity_type: Option<Str<'a>>,
+
+    // globecoordinate
+    #[serde(default)]
+    pub latitude: Option<f64>,
+    #[serde(default)]
+    pub longitude: Option<f64>,
+    /// Also the precision of time values.
+    #[serde(default)]
+    pub precision: Option<f64>,
+
+    // quantity
+    #[serde(default, borrow)]
+    pub amount: Option<Str<'a>>,
+    #[serde(default, borrow)]
+    pub unit: Option<Str<'a>>,
+
+    // time
+    #[serde(default, borrow)]
+    pub time: Option<Str<'a>>,
+    /// Offset from UTC in minutes.
+    #[serde(default)]
+    pub timezone: Option<f64>,
+
+    // monolingualtext
+    #[serde(default, borrow)]
+    pub language: Option<Str<'a>>,
+    #[serde(default, borrow)]
+    pub text: Option<Str<'a>>,
+}
+
+impl ValueObject<'_> {
+    /// Reads the ID of the entity a wikibase-entityid value refers to.
+    ///
+    /// Some non-canonical data only has `numeric-id` and `entity-type` in the value, so the ID is
+    /// reconstructed from those if the string `id` is missing.
+    pub fn entity_id(&self) -> Option<Cow<'_, str>> {
+        if let Some(id) = &self.id {
+            return Some(Cow::Borrowed(id));
+        }
+
+        let numeric_id = self.numeric_id?;
+        let prefix = match self.entity_type.as_deref()? {
+            "item" => 'Q',
+            "property" => 'P',
+            "lexeme" => 'L',
+            _ => return None,
+        };
+        Some(Cow::Owned(format!("{}{}", prefix, numeric_id)))
+    }
+}
+
+/// Wikibase writes empty objects as `[]` in some places, so an array is read as the default.
+fn empty_array_as_default<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + Default,
+{
+    struct DefaultVisitor<T>(PhantomData<T>);
+
+    impl<'de, T: Deserialize<'de> + Default> Visitor<'de> for DefaultVisitor<T> {
+        type Value = T;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "an object")
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<T, A::Error> {
+            T::deserialize(MapAccessDeserializer::new(map))
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<T, A::Error> {
+            while seq.next_element::<IgnoredAny>()?.is_some() {}
+            Ok(T::default())
+        }
+    }
+
+    deserializer.deserialize_any(DefaultVisitor(PhantomData))
+}
+
+/// Reads the values of an object (like labels by language) in order, or none from `[]`.
+fn map_values<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    struct ValuesVisitor<T>(PhantomData<T>);
+
+    impl<'de, T: Deserialize<'de>> Visitor<'de> for ValuesVisitor<T> {
+        type Value = Vec<T>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "an object")
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Vec<T>, A::Error> {
+            let mut values = Vec::new();
+            while let Some((_, value)) = map.next_entry::<IgnoredAny, T>()? {
+                values.push(value);
+            }
+            Ok(values)
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<T>, A::Error> {
+            while seq.next_element::<IgnoredAny>()?.is_some() {}
+            Ok(Vec::new())
+        }
+    }
+
+    deserializer.deserialize_any(ValuesVisitor(PhantomData))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entity_id(value: &str) -> Option<String> {
+        let value: ValueObject = serde_json::from_str(value).unwrap();
+        value.entity_id().map(Cow::into_owned)
+    }
+
+    #[test]
+    fn entity_id_from_id() {
+        let value = r#"{"entity-type":"item","numeric-id":64,"id":"Q64"}"#;
+        assert_eq!(entity_id(value).as_deref(), Some("Q64"));
+        // the string ID wins
+        let value = r#"{"entity-type":"item","numeric-id":1,"id":"Q64"}"#;
+        assert_eq!(entity_id(value).as_deref(), Some("Q64"));
+    }
+
+    #[test]
+    fn entity_id_from_numeric_id() {
+        let value = r#"{"entity-type":"item","numeric-id":64}"#;
+        assert_eq!(entity_id(value).as_deref(), Some("Q64"));
+        let value = r#"{"entity-type":"property","numeric-id":17}"#;
+        assert_eq!(entity_id(value).as_deref(), Some("P17"));
+        let value = r#"{"entity-type":"lexeme","numeric-id":1}"#;
+        assert_eq!(entity_id(value).as_deref(), Some("L1"));
+    }
+
+    #[test]
+    fn entity_id_missing() {
+        // forms and senses have no numeric ID of their own
+        let value = r#"{"entity-type":"form","numeric-id":1}"#;
+        assert_eq!(entity_id(value), None);
+        let value = r#"{"entity-type":"item"}"#;
+        assert_eq!(entity_id(value), None);
+        let value = r#"{"numeric-id":64}"#;
+        assert_eq!(entity_id(value), None);
+    }
+}
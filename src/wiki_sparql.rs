@@ -1,19 +1,49 @@
 use crate::input::http::USER_AGENT;
+use crate::options::{EntityType, Options};
 use reqwest::header;
 use reqwest::Url;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
+use std::fs;
+use std::io::{self, BufReader};
+use std::path::Path;
+use thiserror::Error;
 
 const BASE_URL: &str = "https://query.wikidata.org/sparql";
 
+#[derive(Debug, Error)]
+pub enum ClassesError {
+    #[error("{0}")]
+    Http(#[from] reqwest::Error),
+    #[error("no country has the ISO code {0:?}")]
+    UnknownCountry(String),
+    #[error("could not read or write the classes file: {0}")]
+    Io(#[from] io::Error),
+    #[error("invalid classes file: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
 pub fn load_subclasses(parent_class: &str) -> reqwest::Result<HashSet<String>> {
     debug!("Loading subclasses for {:?}", parent_class);
-    let mut url = Url::parse(BASE_URL).expect("bad BASE_URL!");
-    url.query_pairs_mut().append_pair(
-        "query",
-        &format!("SELECT ?s WHERE {{ ?s wdt:P279+ wd:{} . }}", parent_class),
+    let classes = query_entities(&format!(
+        "SELECT ?s WHERE {{ ?s wdt:P279+ wd:{} . }}",
+        parent_class
+    ))?;
+
+    debug!(
+        "Successfully loaded {} subclasses for parent class {:?}",
+        classes.len(),
+        parent_class
     );
 
+    Ok(classes)
+}
+
+/// Runs a query that selects entities as `?s` and returns their IDs.
+fn query_entities(query: &str) -> reqwest::Result<HashSet<String>> {
+    let mut url = Url::parse(BASE_URL).expect("bad BASE_URL!");
+    url.query_pairs_mut().append_pair("query", query);
+
     #[derive(Deserialize)]
     struct SparqlResult {
         results: SparqlInnerResults,
@@ -42,7 +72,7 @@ pub fn load_subclasses(parent_class: &str) -> reqwest::Result<HashSet<String>> {
         .send()?
         .json()?;
 
-    let classes: HashSet<String> = result
+    Ok(result
         .results
         .bindings
         .into_iter()
@@ -55,69 +85,154 @@ pub fn load_subclasses(parent_class: &str) -> reqwest::Result<HashSet<String>> {
                     .to_string(),
             )
         })
-        .collect();
+        .collect())
+}
 
-    debug!(
-        "Successfully loaded {} subclasses for parent class {:?}",
-        classes.len(),
-        parent_class
-    );
+/// The country that places are limited to with `--only-country`, and the entities in it.
+#[derive(Serialize, Deserialize)]
+pub struct CountryRegion {
+    pub country: String,
+    /// Territorial entities and settlements whose P131 (located in) chain leads to the country,
+    /// and the country itself.
+    pub entities: HashSet<String>,
+}
 
-    Ok(classes)
+/// Loads the entities in a country, given as a QID or an ISO 3166-1 alpha-2 code, that are
+/// instances of the class roots. Other places are only ever located in these, and querying every
+/// entity in a country would time out.
+fn load_country_region(country: &str, roots: &[String]) -> Result<CountryRegion, ClassesError> {
+    let is_qid = country.len() > 1
+        && country.starts_with('Q')
+        && country[1..].chars().all(|c| c.is_ascii_digit());
+    let country = if is_qid {
+        country.to_string()
+    } else {
+        let code: String = country
+            .chars()
+            .filter(char::is_ascii_alphabetic)
+            .map(|c| c.to_ascii_uppercase())
+            .collect();
+        let mut countries: Vec<_> =
+            query_entities(&format!("SELECT ?s WHERE {{ ?s wdt:P297 \"{}\" . }}", code))?
+                .into_iter()
+                .collect();
+        countries.sort();
+        match countries.len() {
+            0 => return Err(ClassesError::UnknownCountry(code)),
+            1 => (),
+            _ => warn!(
+                "Several entities have the ISO code {}: {}; using {}",
+                code,
+                countries.join(", "),
+                countries[0]
+            ),
+        }
+        countries.swap_remove(0)
+    };
+
+    debug!("Loading the entities located in {}", country);
+    let classes: Vec<_> = roots.iter().map(|root| format!("wd:{}", root)).collect();
+    let mut entities = query_entities(&format!(
+        "SELECT DISTINCT ?s WHERE {{ VALUES ?class {{ {} }} ?s wdt:P31/wdt:P279* ?class . ?s wdt:P131+ wd:{} . }}",
+        classes.join(" "),
+        country
+    ))?;
+    entities.insert(country.clone());
+    info!(
+        "Limiting places to the {} entities in {}",
+        entities.len(),
+        country
+    );
+    Ok(CountryRegion { country, entities })
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct Classes {
     pub territorial_entities: HashSet<String>,
     pub human_settlements: HashSet<String>,
     pub excluded: HashSet<String>,
     pub excluded_settlements: HashSet<String>,
     pub second_level_admin_div: HashSet<String>,
+    pub third_level_admin_div: HashSet<String>,
+    pub sovereign_states: HashSet<String>,
     pub languages: HashSet<String>,
+    /// Only set with `--only-country`.
+    #[serde(default)]
+    pub only_country: Option<CountryRegion>,
+}
+
+/// The root classes and all of their subclasses.
+fn load_class_tree(roots: &[String]) -> reqwest::Result<HashSet<String>> {
+    let mut classes: HashSet<String> = roots.iter().cloned().collect();
+    for root in roots {
+        classes.extend(load_subclasses(root)?);
+    }
+    Ok(classes)
 }
 
 impl Classes {
-    pub fn new_from_http() -> reqwest::Result<Classes> {
-        let mut territorial_entities = load_subclasses("Q56061")?;
-        territorial_entities.insert("Q56061".into());
-
-        let mut human_settlements = load_subclasses("Q486972")?;
-        human_settlements.insert("Q486972".into());
-
-        let mut excluded: HashSet<String> = HashSet::new();
-        excluded.insert("Q2974842".into()); // lost cities
-        excluded.insert("Q123705".into()); // neighborhoods, including stuff like shipyards
-        excluded.insert("Q131596".into()); // farm
-        excluded.insert("Q40357".into()); // prisons
-        excluded.insert("Q695850".into()); // airbase
-
-        // unexcluded because this causes moscow (->Q4442912) and others to be excluded
-        // excluded.insert("Q19953632".into()); // former administrative territorial entities
-
-        for superclass in excluded.clone() {
-            for subclass in load_subclasses(&superclass)? {
-                excluded.insert(subclass);
+    /// Loads the class sets from a cache file, or fetches them and writes the cache if it doesn't
+    /// exist.
+    ///
+    /// Snapshots and deterministic builds are only reproducible with a fixed set of classes, since
+    /// the live SPARQL results change over time.
+    pub fn load(cache_file: Option<&str>, options: &Options) -> Result<Classes, ClassesError> {
+        match cache_file {
+            Some(path) if Path::new(path).exists() => {
+                debug!("Loading classes from {}", path);
+                Ok(serde_json::from_reader(BufReader::new(fs::File::open(
+                    path,
+                )?))?)
             }
-        }
-
-        let mut excluded_settlements: HashSet<String> = HashSet::new();
-        excluded_settlements.insert("Q941150".into()); // Plusregio (administrative unit in NL)
-        excluded_settlements.insert("Q159313".into()); // urban agglomeration
-        excluded_settlements.insert("Q106505045".into()); // linear pottery culture
-        excluded_settlements.insert("Q22969563".into()); // bodendenkmal
-
-        for superclass in excluded_settlements.clone() {
-            for subclass in load_subclasses(&superclass)? {
-                excluded_settlements.insert(subclass);
+            Some(path) => {
+                let classes = Classes::new_from_http(options)?;
+                debug!("Saving classes to {}", path);
+                serde_json::to_writer(fs::File::create(path)?, &classes)?;
+                Ok(classes)
             }
+            None => Classes::new_from_http(options),
         }
+    }
+
+    fn new_from_http(options: &Options) -> Result<Classes, ClassesError> {
+        let roots = &options.class_roots;
+        let territorial_entities = load_class_tree(&roots.territorial_entity)?;
+        let human_settlements = load_class_tree(&roots.settlement)?;
+        let excluded = load_class_tree(&roots.excluded)?;
+        let excluded_settlements = load_class_tree(&roots.excluded_settlement)?;
 
         // classes & subclasses of "first-level administrative country subdivision"
         // (why is this field called second_level? uh)
         let mut second_level_admin_div = load_subclasses("Q10864048")?;
         second_level_admin_div.insert("Q10864048".into());
 
-        let mut languages = load_subclasses("Q34770")?;
-        languages.insert("Q34770".into());
+        // classes & subclasses of "second-level administrative country subdivision"
+        // (third level if counting the country itself, like the field above)
+        let mut third_level_admin_div = load_subclasses("Q13220204")?;
+        third_level_admin_div.insert("Q13220204".into());
+
+        // classes & subclasses of "sovereign state", to tell them apart from dependencies
+        let mut sovereign_states = load_subclasses("Q3624078")?;
+        sovereign_states.insert("Q3624078".into());
+
+        let languages = if options.extracts(EntityType::Language) {
+            load_class_tree(&roots.language)?
+        } else {
+            HashSet::new()
+        };
+
+        let only_country = match &options.only_country {
+            Some(country) => {
+                let roots: Vec<_> = roots
+                    .territorial_entity
+                    .iter()
+                    .chain(&roots.settlement)
+                    .cloned()
+                    .collect();
+                Some(load_country_region(country, &roots)?)
+            }
+            None => None,
+        };
 
         Ok(Classes {
             human_settlements,
@@ -125,7 +240,10 @@ impl Classes {
             excluded,
             excluded_settlements,
             second_level_admin_div,
+            third_level_admin_div,
+            sovereign_states,
             languages,
+            only_country,
         })
     }
 }
@@ -1,6 +1,5 @@
-use crate::json_get;
+use crate::wiki_entity::{Qualifiers, Snak};
 use chrono::{Datelike, Timelike};
-use serde_json::Value;
 use std::fmt;
 use std::fmt::Formatter;
 use std::num::ParseIntError;
@@ -136,57 +135,32 @@ pub fn parse_wikidata_time(datetime: &str, zone_off: f64) -> Result<WikiTime, Ti
     Ok(wiki_time.add_seconds(zone_off as i32 * 60))
 }
 
-pub fn is_object_start_active(qualifiers: Option<&serde_json::Map<String, Value>>) -> Option<bool> {
-    qualifiers.and_then(|qualifiers| {
-        if let Some(start) = json_get!((qualifiers).P580[0]: object) {
-            if json_get!((start).snaktype: string) == Some("value") {
-                if let Some(time) = json_get!((start).datavalue.value: object) {
-                    if let (Some(datetime), Some(zone)) = (
-                        json_get!((time).time: string),
-                        json_get!((time).timezone: number),
-                    ) {
-                        if let Ok(time) = parse_wikidata_time(datetime, zone) {
-                            if time > WikiTime::now() {
-                                return Some(false);
-                            }
-                        }
-                    }
-                }
-            }
-            return Some(true);
-        }
-        None
-    })
+/// Parses the time of a snak with a time value.
+fn snak_time(snak: &Snak) -> Option<WikiTime> {
+    if !snak.has_value() {
+        return None;
+    }
+    let time = snak.value_object()?;
+    parse_wikidata_time(time.time.as_deref()?, time.timezone?).ok()
 }
 
-pub fn is_object_end_active(qualifiers: Option<&serde_json::Map<String, Value>>) -> Option<bool> {
-    qualifiers.and_then(|qualifiers| {
-        if let Some(end) = json_get!((qualifiers).P582[0]: object) {
-            if json_get!((end).snaktype: string) == Some("value") {
-                if let Some(time) = json_get!((end).datavalue.value: object) {
-                    if let (Some(datetime), Some(zone)) = (
-                        json_get!((time).time: string),
-                        json_get!((time).timezone: number),
-                    ) {
-                        if let Ok(time) = parse_wikidata_time(datetime, zone) {
-                            if time < WikiTime::now() {
-                                return Some(false);
-                            }
-                        }
-                    }
-                }
-            }
-            return Some(true);
-        }
-        None
-    })
+pub fn is_object_start_active(qualifiers: &Qualifiers) -> Option<bool> {
+    let start = qualifiers.start_time.first()?;
+    match snak_time(start) {
+        Some(time) if time > WikiTime::now() => Some(false),
+        _ => Some(true),
+    }
 }
 
-pub fn is_object_active(qualifiers: Option<&serde_json::Map<String, Value>>) -> bool {
-    if qualifiers.is_none() {
-        return true; // assume true if no qualifiers given
+pub fn is_object_end_active(qualifiers: &Qualifiers) -> Option<bool> {
+    let end = qualifiers.end_time.first()?;
+    match snak_time(end) {
+        Some(time) if time < WikiTime::now() => Some(false),
+        _ => Some(true),
     }
+}
 
+pub fn is_object_active(qualifiers: &Qualifiers) -> bool {
     // check if it already ended
     if is_object_end_active(qualifiers) == Some(false) {
         return false;
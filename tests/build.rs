@@ -0,0 +1,57 @@
+//! Builds the fixture dump slice with the classes of the snapshot test, so that no SPARQL queries
+//! are made.
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/// Builds the fixture into `output` with the arguments.
+fn build(output: &Path, args: &[&str]) {
+    let result = Command::new(env!("CARGO_BIN_EXE_geo-db"))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .args([
+            "--input",
+            "tests/fixtures/slice.json.bz2",
+            "--classes",
+            "tests/fixtures/classes.json",
+            "--output",
+            output.to_str().unwrap(),
+        ])
+        .args(args)
+        .output()
+        .expect("failed to run geo-db");
+    assert!(
+        result.status.success(),
+        "build failed:\n{}{}",
+        String::from_utf8_lossy(&result.stdout),
+        String::from_utf8_lossy(&result.stderr)
+    );
+}
+
+#[test]
+fn deterministic_builds_are_identical() {
+    let dir = tempfile::tempdir().unwrap();
+    for backend in ["sqlite", "ndjson"] {
+        let outputs = ["a", "b"].map(|name| dir.path().join(format!("{}.{}", name, backend)));
+        for output in &outputs {
+            build(
+                output,
+                &["--backend", backend, "--deterministic", "--threads", "4"],
+            );
+        }
+        let [a, b] = outputs.map(|output| fs::read(output).unwrap());
+        assert!(a == b, "the {} outputs differ", backend);
+    }
+}
+
+#[test]
+fn limit_stops_after_lines() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = dir.path().join("geo.ndjson");
+    // the opening bracket, Germany, Brandenburg and Berlin
+    build(&output, &["--backend", "ndjson", "--limit", "4"]);
+    let entries = fs::read_to_string(output).unwrap();
+    assert!(entries.contains(r#""id":"Q64""#));
+    // Paris is on the next line
+    assert!(!entries.contains(r#""id":"Q90""#));
+}
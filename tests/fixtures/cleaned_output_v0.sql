@@ -0,0 +1,44 @@
+-- An output of geo-db from before the schema version was stored (version 0), post-processed and
+-- cleaned up: the tables of the build are gone, except for the cities (with the ISO code of
+-- their country and their labels) and their countries, labels and languages.
+
+create table cities (
+    id string not null primary key,
+    country string,
+    population integer,
+    lat decimal(5, 3),
+    lon decimal(6, 3),
+    native_label string,
+    eo_label string
+);
+create index cities_country_index on cities (country);
+create index cities_population_index on cities (population);
+create index cities_lat_index on cities (lat);
+create index cities_lon_index on cities (lon);
+create index cities_native_label_index on cities (native_label);
+
+create table cities_countries (
+    city string not null,
+    priority integer not null,
+    country string not null,
+    primary key (city, priority, country)
+);
+
+create table cities_languages (
+  "id"	string NOT NULL,
+  "lang"	string,
+  "lang_index"	integer NOT NULL,
+  PRIMARY KEY("id","lang")
+);
+
+create table cities_labels (
+	"id"	string NOT NULL,
+	"lang"	string NOT NULL,
+	"label"	string NOT NULL,
+	PRIMARY KEY("id","lang")
+);
+
+insert into cities values ('Q64', 'DE', 3755251, 52.517, 13.383, 'Berlin', 'Berlino');
+insert into cities_countries values ('Q64', 0, 'Q183');
+insert into cities_languages values ('Q64', 'de', 0);
+insert into cities_labels values ('Q64', 'de', 'Berlin'), ('Q64', 'en', 'Berlin');
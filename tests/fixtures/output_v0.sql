@@ -0,0 +1,81 @@
+-- An output of geo-db from before the schema version was stored (version 0).
+
+create table countries (
+        id string not null primary key,
+        iso char(2) not null
+);
+create index countries_iso_index on countries (iso);
+
+create table object_languages (
+        id string not null,
+        lang_id string not null,
+        lang_index integer not null,
+        primary key (id, lang_id)
+);
+create index object_languages_lang_id_index on object_languages (lang_id);
+
+create table languages (
+        id string not null primary key,
+        code string not null);
+create index languages_code_index on languages (code);
+
+create table territorial_entities (
+    id string not null primary key,
+    is_2nd boolean not null,
+    iso string
+);
+create index territorial_entities_is_2nd on territorial_entities (is_2nd);
+
+create table territorial_entities_parents (
+    id string not null,
+    parent string not null,
+    primary key (id, parent)
+);
+create index territorial_entities_parents_parent_index on territorial_entities_parents (parent);
+
+create table cities (
+    id string not null primary key,
+    country string,
+    population integer,
+    lat decimal(5, 3),
+    lon decimal(6, 3)
+);
+create index cities_country_index on cities (country);
+create index cities_population_index on cities (population);
+create index cities_lat_index on cities (lat);
+create index cities_lon_index on cities (lon);
+
+create table cities_countries (
+    city string not null,
+    priority integer not null,
+    country string not null,
+    primary key (city, priority, country)
+);
+create index cities_countries_priority_index on cities_countries (priority);
+create index cities_countries_country_index on cities_countries (country);
+create unique index cities_countries_city_country_index on cities_countries (city, country);
+create unique index cities_countries_city_priority_index on cities_countries (city, priority);
+
+create table object_labels (
+    id string not null,
+    lang string not null,
+    native_order integer,
+    label string not null,
+    primary key (id, lang, native_order)
+);
+create index object_labels_label_index on object_labels (label);
+create index object_labels_lang_index on object_labels (lang);
+create index object_labels_native_order_index on object_labels (native_order);
+
+create table missing_p17 (id string not null primary key);
+
+insert into countries values ('Q183', 'DE');
+insert into languages values ('Q188', 'de');
+insert into object_languages values ('Q183', 'Q188', 0);
+insert into territorial_entities values ('Q1208', 1, 'DE-BB');
+insert into territorial_entities_parents values ('Q1208', 'Q183');
+insert into cities values ('Q64', 'Q183', 3755251, 52.517, 13.383);
+insert into cities_countries values ('Q64', 0, 'Q183');
+insert into object_labels values
+    ('Q64', 'de', 0, 'Berlin'),
+    ('Q64', 'en', null, 'Berlin');
@@ -0,0 +1,26 @@
+//! Runs `geo-db snapshot` over the fixture dump slice, so that changes to the extracted entries
+//! fail `cargo test`. After an intended change, regenerate the golden file with
+//! `geo-db snapshot tests/fixtures/slice.json.bz2 tests/fixtures/golden.ndjson --classes tests/fixtures/classes.json --update`.
+
+use std::process::Command;
+
+#[test]
+fn fixture_matches_golden() {
+    let output = Command::new(env!("CARGO_BIN_EXE_geo-db"))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .args([
+            "snapshot",
+            "tests/fixtures/slice.json.bz2",
+            "tests/fixtures/golden.ndjson",
+            "--classes",
+            "tests/fixtures/classes.json",
+        ])
+        .output()
+        .expect("failed to run geo-db");
+    assert!(
+        output.status.success(),
+        "snapshot differs from the golden file:\n{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+}